@@ -0,0 +1,290 @@
+//! A small declarative API for standard aggregations over delimited records (see
+//! `formats::schema`):
+//!
+//! `group_by(&schema, &["user"])?.aggregate(vec![count(), sum("bytes"), max("latency")])?`
+//!
+//! returns an `AggregateJob`, which is both the mapper and the reducer of the job. The mapper
+//! combines the records of every map partition per key before emitting them (it works as
+//! combiner), and the reducer emits one row per key: the key columns followed by the aggregated
+//! values, separated like the input (see `AggregateJob::header()`). Records whose aggregated
+//! columns are missing or not numeric are rejected (see `MRParameters::set_malformed_policy()`).
+
+use formats::schema::Schema;
+use mapreducer::{Mapper, Reducer};
+use record_types::{MEmitter, MultiRecord, REmitter, Record};
+
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Function {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// An aggregate function applied to a column; see `count()`, `sum()`, `min()` and `max()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    function: Function,
+    column: Option<String>,
+}
+
+/// Counts the records of every group.
+pub fn count() -> Aggregate {
+    Aggregate {
+        function: Function::Count,
+        column: None,
+    }
+}
+
+/// Sums up the numeric column `column`.
+pub fn sum(column: &str) -> Aggregate {
+    Aggregate {
+        function: Function::Sum,
+        column: Some(String::from(column)),
+    }
+}
+
+/// Calculates the minimum of the numeric column `column`.
+pub fn min(column: &str) -> Aggregate {
+    Aggregate {
+        function: Function::Min,
+        column: Some(String::from(column)),
+    }
+}
+
+/// Calculates the maximum of the numeric column `column`.
+pub fn max(column: &str) -> Aggregate {
+    Aggregate {
+        function: Function::Max,
+        column: Some(String::from(column)),
+    }
+}
+
+impl Aggregate {
+    fn name(&self) -> String {
+        let function = match self.function {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Min => "min",
+            Function::Max => "max",
+        };
+        match self.column {
+            None => String::from(function),
+            Some(ref c) => format!("{}({})", function, c),
+        }
+    }
+
+    /// Combines two (partial) results.
+    fn merge(&self, a: f64, b: f64) -> f64 {
+        match self.function {
+            Function::Count | Function::Sum => a + b,
+            Function::Min => a.min(b),
+            Function::Max => a.max(b),
+        }
+    }
+}
+
+/// The first step of the declaration: Groups the records with the schema `schema` by the
+/// columns `key_columns`.
+pub fn group_by(schema: &Schema, key_columns: &[&str]) -> io::Result<GroupBy> {
+    let mut key = Vec::new();
+    for c in key_columns {
+        key.push(column_index(schema, c)?);
+    }
+    Ok(GroupBy {
+        schema: schema.clone(),
+        key_columns: key,
+    })
+}
+
+fn column_index(schema: &Schema, name: &str) -> io::Result<usize> {
+    schema.index(name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown column {}", name))
+    })
+}
+
+pub struct GroupBy {
+    schema: Schema,
+    key_columns: Vec<usize>,
+}
+
+impl GroupBy {
+    /// Declares the aggregates calculated for every group.
+    pub fn aggregate(self, aggregates: Vec<Aggregate>) -> io::Result<AggregateJob> {
+        let mut columns = Vec::new();
+        for a in aggregates.iter() {
+            columns.push(match a.column {
+                None => None,
+                Some(ref c) => Some(column_index(&self.schema, c)?),
+            });
+        }
+        Ok(AggregateJob {
+            separator: self.schema.separator(),
+            key_names: self.key_columns.iter().map(|&i| self.schema.names()[i].clone()).collect(),
+            key_columns: self.key_columns,
+            aggregates,
+            columns,
+            partial: BTreeMap::new(),
+        })
+    }
+}
+
+/// The mapper and reducer implementing a declared aggregation.
+#[derive(Clone)]
+pub struct AggregateJob {
+    separator: char,
+    key_names: Vec<String>,
+    key_columns: Vec<usize>,
+    aggregates: Vec<Aggregate>,
+    // The input column of every aggregate.
+    columns: Vec<Option<usize>>,
+    // Combined values of the current map partition, by key.
+    partial: BTreeMap<String, Vec<f64>>,
+}
+
+impl AggregateJob {
+    /// Returns the header of the output rows: The key columns and the aggregates, like
+    /// `user<TAB>count<TAB>sum(bytes)`.
+    pub fn header(&self) -> String {
+        let mut names = self.key_names.clone();
+        names.extend(self.aggregates.iter().map(Aggregate::name));
+        names.join(&self.separator.to_string())
+    }
+
+    /// Parses a row into the key and the initial values of the aggregates.
+    fn parse(&self, row: &str) -> Result<(String, Vec<f64>), String> {
+        let fields: Vec<&str> = row.split(self.separator).collect();
+        let mut key = Vec::with_capacity(self.key_columns.len());
+        for &c in self.key_columns.iter() {
+            key.push(*fields.get(c).ok_or("missing key column")?);
+        }
+
+        let mut values = Vec::with_capacity(self.aggregates.len());
+        for (a, c) in self.aggregates.iter().zip(self.columns.iter()) {
+            values.push(match (&a.function, c) {
+                (&Function::Count, _) => 1.0,
+                (_, &Some(c)) => {
+                    let field = fields.get(c).ok_or_else(|| format!("missing column {}", c))?;
+                    field.trim()
+                        .parse()
+                        .map_err(|_| format!("{} is not numeric", a.name()))?
+                }
+                (_, &None) => 0.0,
+            });
+        }
+        Ok((key.join(&self.separator.to_string()), values))
+    }
+
+    fn merge(&self, into: &mut [f64], values: &[f64]) {
+        for ((a, into), v) in self.aggregates.iter().zip(into.iter_mut()).zip(values) {
+            *into = a.merge(*into, *v);
+        }
+    }
+
+    fn format_values(&self, values: &[f64]) -> String {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        values.join(&self.separator.to_string())
+    }
+
+    fn parse_values(&self, s: &str) -> Option<Vec<f64>> {
+        let values: Vec<f64> = s.split(self.separator).filter_map(|v| v.parse().ok()).collect();
+        if values.len() == self.aggregates.len() { Some(values) } else { None }
+    }
+}
+
+impl Mapper for AggregateJob {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        let (key, values) = match self.parse(&record.value) {
+            Err(reason) => return em.reject(&record, &reason),
+            Ok(p) => p,
+        };
+        if let Some(mut combined) = self.partial.remove(&key) {
+            self.merge(&mut combined, &values);
+            self.partial.insert(key, combined);
+        } else {
+            self.partial.insert(key, values);
+        }
+    }
+
+    fn finish(&mut self, em: &mut MEmitter) {
+        let partial = ::std::mem::take(&mut self.partial);
+        for (key, values) in partial {
+            em.emit(key, self.format_values(&values));
+        }
+    }
+}
+
+impl Reducer for AggregateJob {
+    fn reduce(&mut self, em: &mut REmitter, records: MultiRecord) {
+        let mut result: Option<Vec<f64>> = None;
+        for partial in records.values() {
+            let values = match self.parse_values(partial) {
+                None => continue,
+                Some(v) => v,
+            };
+            match result {
+                None => result = Some(values),
+                Some(ref mut r) => self.merge(r, &values),
+            }
+        }
+        if let Some(values) = result {
+            em.emit(format!("{}{}{}", records.key(), self.separator, self.format_values(&values)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, group_by, max, min, sum};
+    use controller::MRController;
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::schema::Schema;
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use std::fs;
+
+    #[test]
+    fn test_aggregate() {
+        let schema = Schema::from_header("user\tpath\tbytes\tlatency", '\t');
+        assert!(group_by(&schema, &["host"]).is_err());
+        assert!(group_by(&schema, &["user"]).unwrap().aggregate(vec![sum("size")]).is_err());
+
+        let job = group_by(&schema, &["user"])
+            .unwrap()
+            .aggregate(vec![count(), sum("bytes"), min("latency"), max("latency")])
+            .unwrap();
+        assert_eq!(job.header(),
+                   "user\tcount\tsum(bytes)\tmin(latency)\tmax(latency)");
+
+        let input: Vec<String> = vec!["alice\t/a\t100\t0.5",
+                                      "bob\t/b\t20\t1.5",
+                                      "alice\t/c\t12\t2",
+                                      "alice\t/c\tbroken\t2",
+                                      "bob\t/d\t1\t0.25"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_file_locations(String::from("testdata/aggregate_map_"),
+                                String::from("testdata/aggregate_out_"));
+        let stats = MRController::run(job.clone(),
+                                      job,
+                                      DefaultSharder,
+                                      params,
+                                      PosRecordIterator::new(input.into_iter()),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.records_malformed, 1);
+
+        let name = String::from("testdata/aggregate_out_0");
+        let result: Vec<String> = lines::new_from_file(&name).unwrap().collect();
+        assert_eq!(result,
+                   vec!["alice\t2\t112\t0.5\t2", "bob\t2\t21\t0.25\t1.5"]);
+        let _ = fs::remove_file(name);
+    }
+}
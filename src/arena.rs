@@ -0,0 +1,192 @@
+//! A bump arena for the keys and values of a partition. Partitions with tens of millions of small
+//! records otherwise spend much of their time allocating and freeing Strings; instead, the strings
+//! are copied back-to-back into large chunks, which are freed together with the partition.
+//!
+//! Keys that repeat often can be interned (see `StrArena::intern()`): Every distinct key is then
+//! stored once, and equal keys get equal `ArenaStr` handles, which compare without looking at
+//! the strings.
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use sort::dict_str_compare;
+
+/// Size of the chunks allocated by an arena, unless a single string is larger.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A string stored in a StrArena. It is only valid for the arena that returned it, and until that
+/// arena is cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArenaStr {
+    chunk: u32,
+    start: u32,
+    len: u32,
+}
+
+impl ArenaStr {
+    /// The empty string; valid for every arena.
+    pub fn empty() -> ArenaStr {
+        ArenaStr {
+            chunk: 0,
+            start: 0,
+            len: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// An append-only collection of strings. Chunks are never reallocated, so allocating a string
+/// never moves the strings allocated before.
+pub struct StrArena {
+    chunks: Vec<String>,
+    chunk_size: usize,
+    // The interned strings by hash (see `intern()`).
+    interned: HashMap<u64, Vec<ArenaStr>>,
+}
+
+impl StrArena {
+    pub fn new() -> StrArena {
+        StrArena::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+    pub fn with_chunk_size(chunk_size: usize) -> StrArena {
+        StrArena {
+            chunks: Vec::new(),
+            chunk_size,
+            interned: HashMap::new(),
+        }
+    }
+
+    /// Copies `s` into the arena.
+    pub fn alloc(&mut self, s: &str) -> ArenaStr {
+        if s.is_empty() {
+            return ArenaStr::empty();
+        }
+        let fits = self.chunks.last().map(|c| c.capacity() - c.len() >= s.len()).unwrap_or(false);
+        if !fits {
+            self.chunks.push(String::with_capacity(cmp::max(self.chunk_size, s.len())));
+        }
+        let chunk = self.chunks.len() - 1;
+        let c = &mut self.chunks[chunk];
+        let start = c.len();
+        c.push_str(s);
+        ArenaStr {
+            chunk: chunk as u32,
+            start: start as u32,
+            len: s.len() as u32,
+        }
+    }
+
+    /// Like `alloc()`, but returns the handle of an equal string interned before instead of
+    /// copying `s` again.
+    pub fn intern(&mut self, s: &str) -> ArenaStr {
+        let mut hasher = DefaultHasher::new();
+        s.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(candidates) = self.interned.get(&hash) {
+            if let Some(&found) = candidates.iter().find(|&&c| self.get(c) == s) {
+                return found;
+            }
+        }
+        let interned = self.alloc(s);
+        self.interned.entry(hash).or_default().push(interned);
+        interned
+    }
+
+    /// Returns the number of distinct strings interned.
+    pub fn interned(&self) -> usize {
+        self.interned.values().map(|c| c.len()).sum()
+    }
+
+    pub fn get(&self, s: ArenaStr) -> &str {
+        if s.is_empty() {
+            return "";
+        }
+        let start = s.start as usize;
+        &self.chunks[s.chunk as usize][start..start + s.len()]
+    }
+
+    /// Compares two strings of this arena in dictionary order (see `sort::dict_str_compare()`).
+    /// Equal handles, e.g. of interned strings, are equal without comparing the strings.
+    pub fn dict_compare(&self, a: ArenaStr, b: ArenaStr) -> Ordering {
+        if a == b {
+            return Ordering::Equal;
+        }
+        dict_str_compare(self.get(a), self.get(b))
+    }
+
+    /// Invalidates all strings. The first chunk is kept for reuse.
+    pub fn clear(&mut self) {
+        self.interned.clear();
+        self.chunks.truncate(1);
+        if let Some(c) = self.chunks.first_mut() {
+            c.clear();
+        }
+    }
+
+    /// Returns the number of bytes allocated for chunks.
+    pub fn capacity(&self) -> usize {
+        self.chunks.iter().map(|c| c.capacity()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArenaStr, StrArena};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn test_arena() {
+        let mut arena = StrArena::with_chunk_size(8);
+        let a = arena.alloc("abc");
+        let b = arena.alloc("defgh");
+        // Doesn't fit into the first chunk anymore.
+        let c = arena.alloc("ij");
+        // Larger than a chunk.
+        let d = arena.alloc("klmnopqrstuvwxyz");
+        let e = arena.alloc("");
+
+        assert_eq!(arena.get(a), "abc");
+        assert_eq!(arena.get(b), "defgh");
+        assert_eq!(arena.get(c), "ij");
+        assert_eq!(arena.get(d), "klmnopqrstuvwxyz");
+        assert_eq!(arena.get(e), "");
+        assert_eq!(e, ArenaStr::empty());
+        assert_eq!(arena.capacity(), 8 + 8 + 16);
+        assert_eq!(arena.dict_compare(a, b), Ordering::Less);
+
+        arena.clear();
+        assert_eq!(arena.capacity(), 8);
+        let f = arena.alloc("Abc");
+        assert_eq!(arena.get(f), "Abc");
+    }
+
+    #[test]
+    fn test_intern() {
+        let mut arena = StrArena::with_chunk_size(16);
+        let a = arena.intern("key");
+        let b = arena.intern("other");
+        assert_eq!(arena.intern("key"), a);
+        assert!(a != b);
+        // Allocated strings aren't interned.
+        assert!(arena.alloc("key") != a);
+        assert_eq!(arena.interned(), 2);
+        assert_eq!(arena.capacity(), 16);
+        let c = arena.intern("key");
+        assert_eq!(arena.dict_compare(a, c), Ordering::Equal);
+
+        arena.clear();
+        assert_eq!(arena.interned(), 0);
+        let x = arena.intern("x");
+        assert_eq!(arena.get(x), "x");
+    }
+}
@@ -0,0 +1,113 @@
+//! A catalog of named datasets shared by the jobs of a local pipeline: a directory holding the
+//! manifest of every dataset (see `dataset::Dataset::save()`) as `<name>.dataset`. Jobs with a
+//! catalog register their outputs under the job name when they finish (see
+//! `MRParameters::set_catalog()`), and later jobs look their inputs up by name instead of
+//! repeating locations, shard counts and partitionings.
+//!
+//! Manifests are replaced atomically, so that jobs can update the catalog concurrently and
+//! readers never see a partially written manifest.
+
+use dataset::Dataset;
+use record_types::Record;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MANIFEST_SUFFIX: &str = ".dataset";
+
+/// A directory of dataset manifests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Catalog {
+    dir: String,
+}
+
+impl Catalog {
+    /// Opens the catalog in `dir`, which is created if it doesn't exist.
+    pub fn open(dir: &str) -> io::Result<Catalog> {
+        fs::create_dir_all(dir)?;
+        Ok(Catalog { dir: String::from(dir) })
+    }
+
+    fn manifest_name(&self, name: &str) -> io::Result<String> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Invalid dataset name {:?}", name)));
+        }
+        Ok(format!("{}/{}{}", self.dir, name, MANIFEST_SUFFIX))
+    }
+
+    /// Registers `dataset` as `name`, replacing an earlier dataset of that name.
+    pub fn register(&self, name: &str, dataset: &Dataset) -> io::Result<()> {
+        let path = self.manifest_name(name)?;
+        let tmp = format!("{}.tmp", path);
+        dataset.save(&tmp)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Returns the dataset registered as `name`.
+    pub fn lookup(&self, name: &str) -> io::Result<Dataset> {
+        let path = self.manifest_name(name)?;
+        if !Path::new(&path).exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("No dataset {} in catalog {}", name, self.dir)));
+        }
+        Dataset::load(&path)
+    }
+
+    /// Returns the records of the dataset registered as `name`, for use as input of a job (see
+    /// `Dataset::records()`; use `Dataset::numbered_records()` for intermediate datasets).
+    pub fn input(&self, name: &str) -> io::Result<Box<dyn Iterator<Item = Record>>> {
+        self.lookup(name)?.records()
+    }
+
+    /// Returns the names of all registered datasets, sorted.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let file = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(name) = file.strip_suffix(MANIFEST_SUFFIX) {
+                names.push(String::from(name));
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Removes the dataset `name` from the catalog; its files are left in place.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.manifest_name(name)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Catalog;
+    use dataset::Dataset;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn test_catalog() {
+        let dir = "testdata/catalog_test";
+        let catalog = Catalog::open(dir).unwrap();
+        let params = MRParameters::new()
+            .set_concurrency(2, 3)
+            .set_file_locations(String::from("testdata/cat_map_"), String::from("testdata/cat_"));
+        let dataset = Dataset::from_output(&params, &DefaultSharder);
+        catalog.register("counts", &dataset).unwrap();
+        catalog.register("other", &dataset.clone().derived_from("counts")).unwrap();
+
+        assert_eq!(catalog.names().unwrap(), vec!["counts", "other"]);
+        assert_eq!(catalog.lookup("counts").unwrap(), dataset);
+        assert_eq!(catalog.lookup("other").unwrap().lineage, vec!["counts"]);
+        assert_eq!(catalog.lookup("missing").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(catalog.register("../escape", &dataset).is_err());
+
+        catalog.remove("other").unwrap();
+        assert_eq!(catalog.names().unwrap(), vec!["counts"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -10,6 +10,7 @@ pub struct ClosureMapReducer {
     mapper: MapperF,
     reducer: ReducerF,
     sharder: SharderF,
+    associative: bool,
 }
 
 impl Clone for ClosureMapReducer {
@@ -18,6 +19,7 @@ impl Clone for ClosureMapReducer {
             mapper: self.mapper,
             reducer: self.reducer,
             sharder: self.sharder,
+            associative: self.associative,
         }
     }
 }
@@ -29,12 +31,17 @@ impl ClosureMapReducer {
             mapper: mapper,
             reducer: reducer,
             sharder: _std_shard,
+            associative: false,
         }
     }
     /// Set the function used for sharding.
     pub fn set_sharder(&mut self, s: SharderF) {
         self.sharder = s;
     }
+    /// Declares the reduce function as associative (see `Reducer::associative()`).
+    pub fn set_associative(&mut self, associative: bool) {
+        self.associative = associative;
+    }
 }
 
 impl Mapper for ClosureMapReducer {
@@ -46,6 +53,9 @@ impl Reducer for ClosureMapReducer {
     fn reduce(&mut self, e: &mut REmitter, r: MultiRecord) {
         (self.reducer)(e, r)
     }
+    fn associative(&self) -> bool {
+        self.associative
+    }
 }
 impl Sharder for ClosureMapReducer {
     fn shard(&mut self, n: usize, k: &String) -> usize {
@@ -0,0 +1,187 @@
+//! Codecs converting between typed values and the representation of records: Keys are text,
+//! values of binary inputs and outputs are bytes (see `REmitter::emit_bytes()` and
+//! `WriteLogReader::into_entries()`).
+//!
+//! Binary inputs, e.g. WriteLogs of serialized messages, are decoded with a `ValueCodec` and
+//! converted into the records the mappers read by `decode_records()`; reducers encode typed
+//! results with `REmitter::emit_encoded()`. Codecs for protocol buffers and flatbuffers are
+//! available behind the `prost` and `flatbuffers` features (see `formats::protobuf` and
+//! `formats::flatbuffer`).
+
+use formats::writelog::Entries;
+use malformed::MalformedHandler;
+use record_types::Record;
+use stats::{InputStats, Stats};
+
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+/// Converts keys of type `T` from and to text.
+pub trait KeyCodec<T>: Send + Clone {
+    fn decode_key(&self, key: &str) -> Result<T, String>;
+    fn encode_key(&self, key: &T) -> String;
+}
+
+/// Converts values of type `T` from and to bytes.
+pub trait ValueCodec<T>: Send + Clone {
+    fn decode(&self, bytes: &[u8]) -> Result<T, String>;
+    /// Appends the encoding of `value` to `out`.
+    fn encode(&self, value: &T, out: &mut Vec<u8>);
+}
+
+/// Keys that are parsed with `FromStr` and formatted with `Display`, e.g. numbers.
+pub struct ParseCodec<T> {
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> ParseCodec<T> {
+    pub fn new() -> ParseCodec<T> {
+        ParseCodec { _type: PhantomData }
+    }
+}
+
+impl<T> Default for ParseCodec<T> {
+    fn default() -> ParseCodec<T> {
+        ParseCodec::new()
+    }
+}
+
+impl<T> Clone for ParseCodec<T> {
+    fn clone(&self) -> ParseCodec<T> {
+        ParseCodec::new()
+    }
+}
+
+impl<T: FromStr + Display> KeyCodec<T> for ParseCodec<T>
+    where T::Err: Display
+{
+    fn decode_key(&self, key: &str) -> Result<T, String> {
+        key.parse().map_err(|e: T::Err| e.to_string())
+    }
+
+    fn encode_key(&self, key: &T) -> String {
+        key.to_string()
+    }
+}
+
+/// Values that are UTF-8 text.
+#[derive(Clone, Default)]
+pub struct Utf8Codec;
+
+impl ValueCodec<String> for Utf8Codec {
+    fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn encode(&self, value: &String, out: &mut Vec<u8>) {
+        out.extend_from_slice(value.as_bytes());
+    }
+}
+
+/// Decodes the entries of a binary input with `codec` and converts every value to a record with
+/// `to_record`, e.g. keyed by a field of a message. Entries that can't be decoded are passed to
+/// `malformed` (usually `params.malformed`).
+pub fn decode_records<T, C>(entries: Entries,
+                            codec: C,
+                            to_record: fn(T) -> Record,
+                            malformed: MalformedHandler)
+                            -> DecodedRecords<T, C>
+    where C: ValueCodec<T>
+{
+    DecodedRecords {
+        entries,
+        codec,
+        to_record,
+        malformed,
+        decode_errors: 0,
+    }
+}
+
+/// The records of a binary input (see `decode_records()`).
+pub struct DecodedRecords<T, C: ValueCodec<T>> {
+    entries: Entries,
+    codec: C,
+    to_record: fn(T) -> Record,
+    malformed: MalformedHandler,
+    decode_errors: usize,
+}
+
+impl<T, C: ValueCodec<T>> Iterator for DecodedRecords<T, C> {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        for entry in self.entries.by_ref() {
+            match self.codec.decode(&entry) {
+                Ok(value) => return Some((self.to_record)(value)),
+                Err(e) => {
+                    self.decode_errors += 1;
+                    self.malformed.handle(&entry, &e)
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Entries that the codec can't decode count as decode errors.
+impl<T, C: ValueCodec<T>> Stats for DecodedRecords<T, C> {
+    fn stats(&self) -> InputStats {
+        let mut stats = self.entries.stats();
+        stats.records -= self.decode_errors;
+        stats.decode_errors += self.decode_errors;
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyCodec, ParseCodec, Utf8Codec, ValueCodec, decode_records};
+    use formats::writelog::{WriteLogReader, WriteLogWriter};
+    use malformed::{MalformedHandler, MalformedPolicy};
+    use record_types::{REmitter, Record};
+    use std::fs;
+    use std::io::Write;
+
+    fn to_record(v: String) -> Record {
+        Record {
+            key: v.len().to_string(),
+            value: v,
+        }
+    }
+
+    #[test]
+    fn test_codecs() {
+        let keys = ParseCodec::<u64>::new();
+        assert_eq!(keys.decode_key("42"), Ok(42));
+        assert!(keys.decode_key("x").is_err());
+        assert_eq!(keys.encode_key(&7), "7");
+
+        let name = String::from("testdata/codec_input");
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&name, false).unwrap();
+            let mut em = REmitter::new();
+            em.emit_encoded(&Utf8Codec, &String::from("abc"));
+            em._drain(|v| {
+                w.write_all(v.as_bytes()).unwrap();
+            });
+            w.write_all(&[0xff, 0xfe]).unwrap();
+            w.write_all(b"de").unwrap();
+        }
+
+        let handler = MalformedHandler::new(MalformedPolicy::Skip);
+        let entries = WriteLogReader::new_from_file(&name).unwrap().into_entries();
+        let records: Vec<(String, String)> =
+            decode_records(entries, Utf8Codec, to_record, handler.clone())
+                .map(|r| (r.key, r.value))
+                .collect();
+        assert_eq!(records,
+                   vec![(String::from("3"), String::from("abc")),
+                        (String::from("2"), String::from("de"))]);
+        assert_eq!(handler.count(), 1);
+
+        let mut out = Vec::new();
+        Utf8Codec.encode(&String::from("x"), &mut out);
+        assert_eq!(Utf8Codec.decode(&out), Ok(String::from("x")));
+        let _ = fs::remove_file(name);
+    }
+}
@@ -0,0 +1,223 @@
+//! Co-grouping of two inputs: Every input has its own mapper, and the reducer is called once per
+//! key with the values that the left and the right mapper emitted for it, in two separate lists.
+//! This is the building block for inner, outer and anti joins.
+//!
+//! The values are told apart by a tag that `CoGroupMapper` prepends to every emitted value and
+//! that `CoGroup` strips again before calling the `CoGroupReducer`. The intermediate records are
+//! therefore slightly larger than those of a plain job; co-group jobs can't be key-only (see
+//! `MRParameters::set_key_only()`), as the tags are values.
+
+use controller::MRController;
+use mapreducer::{Mapper, Reducer, Sharder};
+use parameters::MRParameters;
+use phases::output::SinkGenerator;
+use record_types::{MEmitter, MultiRecord, REmitter, Record};
+use stats::JobResult;
+
+use std::iter::{Chain, Map};
+
+/// Tags the records (and intermediate values) of the left input.
+pub const LEFT_TAG: char = 'A';
+/// Tags the records (and intermediate values) of the right input.
+pub const RIGHT_TAG: char = 'B';
+
+pub trait CoGroupReducer: Send + Clone {
+    /// Called once for every key emitted by at least one of the mappers, with the values from
+    /// the left and from the right input; one of them may be empty.
+    fn reduce(&mut self, em: &mut REmitter, key: &str, left: Vec<String>, right: Vec<String>);
+
+    /// Called once after all keys of a reduce partition have been passed to reduce().
+    /// The default implementation does nothing.
+    fn finish(&mut self, em: &mut REmitter) {
+        let _ = em;
+    }
+}
+
+/// CoGroupReducer::reduce() function type.
+pub type CoGroupReducerF = fn(&mut REmitter, &str, Vec<String>, Vec<String>);
+
+impl CoGroupReducer for CoGroupReducerF {
+    fn reduce(&mut self, em: &mut REmitter, key: &str, left: Vec<String>, right: Vec<String>) {
+        self(em, key, left, right)
+    }
+}
+
+/// Maps the records of both inputs, as tagged by `tag_inputs()`, with the mapper of their side,
+/// tagging the emitted values.
+#[derive(Clone)]
+pub struct CoGroupMapper<A: Mapper, B: Mapper> {
+    left: A,
+    right: B,
+}
+
+impl<A: Mapper, B: Mapper> CoGroupMapper<A, B> {
+    pub fn new(left: A, right: B) -> CoGroupMapper<A, B> {
+        CoGroupMapper { left, right }
+    }
+}
+
+impl<A: Mapper, B: Mapper> Mapper for CoGroupMapper<A, B> {
+    fn map(&mut self, em: &mut MEmitter, mut record: Record) {
+        let tag = if record.key.is_empty() {
+            None
+        } else {
+            Some(record.key.remove(0))
+        };
+        em._set_value_tag(tag);
+        match tag {
+            Some(LEFT_TAG) => self.left.map(em, record),
+            Some(RIGHT_TAG) => self.right.map(em, record),
+            _ => panic!("Input record {:?} wasn't tagged for a co-group job", record.key),
+        }
+        em._set_value_tag(None);
+    }
+
+    fn finish(&mut self, em: &mut MEmitter) {
+        em._set_value_tag(Some(LEFT_TAG));
+        self.left.finish(em);
+        em._set_value_tag(Some(RIGHT_TAG));
+        self.right.finish(em);
+        em._set_value_tag(None);
+    }
+}
+
+/// Splits the values of every group by their tag and passes them to a `CoGroupReducer`.
+#[derive(Clone)]
+pub struct CoGroup<R: CoGroupReducer> {
+    reducer: R,
+}
+
+impl<R: CoGroupReducer> CoGroup<R> {
+    pub fn new(reducer: R) -> CoGroup<R> {
+        CoGroup { reducer }
+    }
+}
+
+impl<R: CoGroupReducer> Reducer for CoGroup<R> {
+    fn reduce(&mut self, em: &mut REmitter, records: MultiRecord) {
+        let key = records.key().clone();
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for mut v in records {
+            let tag = if v.is_empty() {
+                None
+            } else {
+                Some(v.remove(0))
+            };
+            match tag {
+                Some(LEFT_TAG) => left.push(v),
+                Some(RIGHT_TAG) => right.push(v),
+                _ => panic!("Intermediate value for key {:?} wasn't tagged", key),
+            }
+        }
+        self.reducer.reduce(em, &key, left, right);
+    }
+
+    fn finish(&mut self, em: &mut REmitter) {
+        self.reducer.finish(em);
+    }
+}
+
+fn tag_left(mut r: Record) -> Record {
+    r.key.insert(0, LEFT_TAG);
+    r
+}
+
+fn tag_right(mut r: Record) -> Record {
+    r.key.insert(0, RIGHT_TAG);
+    r
+}
+
+/// The input of a co-group job, as returned by `tag_inputs()`.
+pub type TaggedInputs<InA, InB> = Chain<Map<InA, fn(Record) -> Record>,
+                                        Map<InB, fn(Record) -> Record>>;
+
+/// Concatenates the inputs of a co-group job, tagging the key of every record with the side it
+/// belongs to.
+pub fn tag_inputs<InA, InB>(left: InA, right: InB) -> TaggedInputs<InA, InB>
+    where InA: Iterator<Item = Record>,
+          InB: Iterator<Item = Record>
+{
+    left.map(tag_left as fn(Record) -> Record).chain(right.map(tag_right as fn(Record) -> Record))
+}
+
+/// Runs a co-group job: The records of `left.1` are mapped by `left.0`, those of `right.1` by
+/// `right.0`, and `reducer` is called with the values of both sides per key.
+pub fn run_cogroup<A, B, InA, InB, R, S, Out>(left: (A, InA),
+                                              right: (B, InB),
+                                              reducer: R,
+                                              sharder: S,
+                                              params: MRParameters,
+                                              out: Out)
+                                              -> JobResult
+    where A: Mapper,
+          B: Mapper,
+          InA: Iterator<Item = Record>,
+          InB: Iterator<Item = Record>,
+          R: CoGroupReducer,
+          S: Sharder,
+          Out: SinkGenerator
+{
+    MRController::run(CoGroupMapper::new(left.0, right.0),
+                      CoGroup::new(reducer),
+                      sharder,
+                      params,
+                      tag_inputs(left.1, right.1),
+                      out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoGroupReducerF, run_cogroup};
+    use closure_mr::ClosureMapReducer;
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+    use std::vec;
+
+    fn split_mapper(e: &mut MEmitter, r: Record) {
+        let mut fields = r.value.split_whitespace();
+        if let (Some(k), Some(v)) = (fields.next(), fields.next()) {
+            e.emit_str(k, v);
+        }
+    }
+
+    fn input(lines: &[&str]) -> PosRecordIterator<vec::IntoIter<String>> {
+        let lines: Vec<String> = lines.iter().map(|l| String::from(*l)).collect();
+        PosRecordIterator::new(lines.into_iter())
+    }
+
+    fn unused_reducer(_: &mut REmitter, _: MultiRecord) {}
+
+    fn outer_join(e: &mut REmitter, key: &str, mut left: Vec<String>, right: Vec<String>) {
+        left.sort();
+        e.emit(format!("{} [{}] [{}]", key, left.join(","), right.join(",")));
+    }
+
+    #[test]
+    fn test_cogroup() {
+        let users = input(&["alice 1", "bob 2", "carol 3", "alice 4"]);
+        let orders = input(&["bob b", "dave d"]);
+        let mapper = ClosureMapReducer::new(split_mapper, unused_reducer);
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_file_locations(String::from("testdata/cogroup_map_"),
+                                String::from("testdata/cogroup_out_"));
+        let result = run_cogroup((mapper.clone(), users),
+                                 (mapper, orders),
+                                 outer_join as CoGroupReducerF,
+                                 DefaultSharder,
+                                 params,
+                                 LinesSinkGenerator::new_to_files());
+        assert_eq!(result.stats.reduce_input_records, 6);
+
+        let outputs: Vec<String> = lines::new_from_file(&String::from("testdata/cogroup_out_0"))
+            .unwrap()
+            .collect();
+        assert_eq!(outputs,
+                   vec!["alice [1,4] []", "bob [2] [b]", "carol [3] []", "dave [] [d]"]);
+        let _ = fs::remove_file("testdata/cogroup_out_0");
+    }
+}
@@ -1,19 +1,117 @@
 //! Controls the execution of a mapreduce instance.
 
-use phases::output::{SinkGenerator, open_reduce_inputs, get_reduce_output_name};
+use phases::output::{OutputGuard, SinkGenerator, create_reduce_output_name,
+                     discover_map_partitions, key_histogram_name, list_intermediate_files,
+                     load_bloom_filters, map_bloom_name, map_dictionary_name, map_index_name,
+                     map_multiplexed_name, map_output_name, open_reduce_inputs, run_marker_name};
+use formats::lines::{self, FileSplit};
+use formats::partitioned::{ReduceSink, RoutedSink};
+use formats::util::{KeyRangeIterator, PosRecordIterator, RunningJob, open_files_share,
+                    raise_open_files_limit};
 use formats::writelog::WriteLogGenerator;
+use catalog::Catalog;
+use dataset::{Dataset, RekeyMapper};
+use executor::ExecutorHandle;
+use incremental::{FileState, IncrementalState, WatchOptions, file_map_location,
+                  remove_intermediates};
 use input_cache::InputCache;
 use phases::map::MapPartition;
-use mapreducer::{Mapper, Reducer, Sharder};
-use parameters::MRParameters;
+use object_store::{delete_objects, get_shard_inputs, put_map_outputs};
+use mapreducer::{DefaultSharder, IdentityMapper, Mapper, RangeSharder, Reducer, Sharder};
+use parameters::{MRParameters, MapScheduling, StaleIntermediates, TempRetention};
+use priority;
 use record_types::Record;
 use phases::reduce::ReducePartition;
+use sampling::{sample_mapped_keys, split_points};
+use phases::shuffle::ShuffleBuffer;
+use stats::{InputStats, JobResult, JobStats, MapSortTimings};
+use termination::Termination;
+use trace::{self, Step};
+use warm_start::open_previous_input;
 
-use std::sync::mpsc::sync_channel;
+use std::cmp;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel, sync_channel};
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 extern crate scoped_threadpool;
 use self::scoped_threadpool::Pool;
 
+// Open files reserved for inputs, reduce outputs and the rest of the process when calculating how
+// many map partitions can run concurrently.
+const RESERVED_OPEN_FILES: usize = 64;
+
+#[cfg(unix)]
+extern crate libc;
+
+// The intermediate locations and output prefixes of the jobs running in this process (see
+// `LocationClaim`).
+static CLAIMED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static RELEASED: Condvar = Condvar::new();
+
+/// Keeps the intermediate location and output prefix of a running job from being used by other
+/// jobs of this process, which would remove or overwrite its files. Released when dropped.
+struct LocationClaim {
+    paths: Vec<String>,
+}
+
+impl LocationClaim {
+    /// Claims `paths`, waiting while another job of this process uses one of them.
+    fn acquire(paths: Vec<String>) -> LocationClaim {
+        let mut claimed = CLAIMED.lock().unwrap_or_else(|e| e.into_inner());
+        let mut warned = false;
+        while paths.iter().any(|p| claimed.contains(p)) {
+            if !warned {
+                println!("WARN: Waiting for another job of this process using {:?}; give \
+                          concurrent jobs distinct file locations to run them in parallel",
+                         paths);
+                warned = true;
+            }
+            claimed = RELEASED.wait(claimed).unwrap_or_else(|e| e.into_inner());
+        }
+        claimed.extend(paths.iter().cloned());
+        LocationClaim { paths }
+    }
+}
+
+impl Drop for LocationClaim {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED.lock().unwrap_or_else(|e| e.into_inner());
+        for path in &self.paths {
+            if let Some(i) = claimed.iter().position(|p| p == path) {
+                claimed.swap_remove(i);
+            }
+        }
+        RELEASED.notify_all();
+    }
+}
+
+/// Ends the map side of an in-memory shuffle (see `MRController::run_in_memory()`) when the
+/// last mapper thread exits, also by panicking: Like `MRController::end_phase()`, it records an
+/// early termination in `truncated` and resets the flag, so that the reducers, which are already
+/// running, reduce all shuffled records.
+struct MapSideEnd<'a> {
+    running: &'a AtomicUsize,
+    termination: &'a Termination,
+    truncated: &'a AtomicBool,
+}
+
+impl<'a> Drop for MapSideEnd<'a> {
+    fn drop(&mut self) {
+        if self.running.fetch_sub(1, AtomicOrdering::SeqCst) == 1 &&
+           self.termination.is_terminated() {
+            self.truncated.store(true, AtomicOrdering::SeqCst);
+            self.termination.reset();
+        }
+    }
+}
+
 pub struct MRController<M: Mapper, R: Reducer, S: Sharder> {
     params: MRParameters,
     m: M,
@@ -22,49 +120,513 @@ pub struct MRController<M: Mapper, R: Reducer, S: Sharder> {
 
     // How many map partitions have been run?
     map_partitions_run: usize,
+    // Statistics of the map phase.
+    map_stats: JobStats,
+    // The malformed record counter of params before the job; the handler may be shared between
+    // jobs.
+    malformed_before: usize,
+    input_before: InputStats,
+    // Set while the job uses its file locations (see `claim_location()`).
+    claim: Option<LocationClaim>,
+    _running: RunningJob,
 }
 
 
 impl<M: Mapper, R: Reducer, S: Sharder> MRController<M, R, S> {
-    /// Create a new mapreduce instance and execute it immediately.
+    fn new(m: M, r: R, s: S, params: MRParameters) -> MRController<M, R, S> {
+        let mut params = params.resolve_locations();
+        if params.object_store.is_some() && params.multiplexed_intermediates {
+            panic!("Multiplexed intermediates can't be kept in an object store");
+        }
+        if params.reduce_chunk_values > 0 && !r.associative() {
+            println!("WARN: The reducer isn't associative; groups are reduced as a whole");
+        }
+        // Every job selects its map input structure on its own data.
+        params.map_sort_timings = MapSortTimings::new();
+        MRController {
+            malformed_before: params.malformed.count(),
+            input_before: params.input_stats.get(),
+            params,
+            m,
+            r,
+            s,
+            map_partitions_run: 0,
+            map_stats: JobStats::new(),
+            claim: None,
+            _running: RunningJob::start(),
+        }
+    }
+
+    /// Create a new mapreduce instance and execute it immediately. Returns statistics about the
+    /// job and the output shards it has written.
     ///
-    /// You can use `DefaultSharder` as `sharder` argument.
+    /// You can use `DefaultSharder` as `sharder` argument. Intermediate records may be shuffled
+    /// in memory (see `MRParameters::set_in_memory_shuffle()` and
+    /// `MRParameters::set_shuffle_memory_limit()`).
     pub fn run<In: Iterator<Item = Record>, Out: SinkGenerator>(mapper: M,
                                                                 reducer: R,
                                                                 sharder: S,
                                                                 params: MRParameters,
-                                                                inp: In,
-                                                                out: Out) {
-        let mut controller = MRController {
-            params: params,
-            m: mapper,
-            r: reducer,
-            s: sharder,
-            map_partitions_run: 0,
+                                                                mut inp: In,
+                                                                out: Out)
+                                                                -> JobResult {
+        let start = Instant::now();
+        let mut controller = MRController::new(mapper, reducer, sharder, params);
+        controller.claim_location();
+        let limit = controller.params.in_memory_shuffle_bytes;
+        let (records, complete) = if limit > 0 {
+            MRController::<M, R, S>::read_small_input(&mut inp, limit)
+        } else {
+            (Vec::new(), false)
         };
-        controller.run_map(inp);
-        controller.run_reduce(out);
-        controller.clean_up();
+        if complete || controller.params.shuffle_memory_limit > 0 {
+            return controller.run_in_memory(records.into_iter().chain(inp), out, start);
+        }
+        controller.run_map(records.into_iter().chain(inp));
+        controller.finish(out, start)
     }
 
-    fn run_map<In: Iterator<Item = Record>>(&mut self, mut input: In) {
-        let mut pool = Pool::new(self.params.mappers as u32);
-        // Create channels for worker synchronization; this ensures that there are only as many
-        // mapper threads running as specified.
-        let (send, recv) = sync_channel(self.params.mappers);
+    /// Like `run()`, but takes the input as several independent splits, for example the
+    /// splits of a large file produced by `formats::lines::split_file()`. Every mapper thread
+    /// reads the splits it processes by itself, so that the input is read in parallel.
+    pub fn run_splits<In: Iterator<Item = Record> + Send, Out: SinkGenerator>(mapper: M,
+                                                                              reducer: R,
+                                                                              sharder: S,
+                                                                              params: MRParameters,
+                                                                              splits: Vec<In>,
+                                                                              out: Out)
+                                                                              -> JobResult {
+        let start = Instant::now();
+        let mut controller = MRController::new(mapper, reducer, sharder, params);
+        controller.claim_location();
+        controller.run_map_splits(splits);
+        controller.finish(out, start)
+    }
+
+    /// Like `run_splits()`, but every split comes with its size (e.g. `FileSplit::size()`), and
+    /// the splits are mapped in the order set by `MRParameters::set_map_scheduling()`.
+    pub fn run_sized_splits<In: Iterator<Item = Record> + Send, Out: SinkGenerator>
+        (mapper: M,
+         reducer: R,
+         sharder: S,
+         params: MRParameters,
+         splits: Vec<(u64, In)>,
+         out: Out)
+         -> JobResult {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let splits = schedule_splits(splits, params.map_scheduling, seed as u64);
+        MRController::run_splits(mapper, reducer, sharder, params, splits, out)
+    }
+
+    /// Runs a job incrementally over the text files `files`: Only files that are new or have
+    /// changed (by modification time or size) since the last run are mapped; the intermediate
+    /// files of unchanged files are retained from the last run and merged with the new ones in
+    /// the reduce phase. The state is kept in `state_file`.
+    ///
+    /// The intermediate files are always kept at `params.map_output_location`, regardless of
+    /// `keep_temp_files`. The sharder must assign keys to shards stably between runs (for
+    /// example `StableSharder`). If the number of reducers changes, all files are mapped again.
+    pub fn run_incremental<Out: SinkGenerator>(mapper: M,
+                                               reducer: R,
+                                               sharder: S,
+                                               params: MRParameters,
+                                               files: Vec<String>,
+                                               state_file: &String,
+                                               out: Out)
+                                               -> io::Result<JobStats> {
+        MRController::incremental(mapper, reducer, sharder, params, files, state_file, out)
+            .map(|result| result.stats)
+    }
+
+    /// Like `run_incremental()`, but returns the outputs as well.
+    fn incremental<Out: SinkGenerator>(mapper: M,
+                                       reducer: R,
+                                       sharder: S,
+                                       params: MRParameters,
+                                       files: Vec<String>,
+                                       state_file: &String,
+                                       out: Out)
+                                       -> io::Result<JobResult> {
+        let start = Instant::now();
+        let base_location = params.map_output_location.clone();
+        let reducers = params.reducers;
+        let mut controller = MRController::new(mapper, reducer, sharder, params);
+
+        let mut old = IncrementalState::load(state_file)?;
+        if old.reducers != reducers {
+            for (name, file) in old.files.iter() {
+                remove_intermediates(&file_map_location(&base_location, name),
+                                     file.partitions,
+                                     old.reducers);
+            }
+            old = IncrementalState::new(reducers);
+        }
+
+        let mut state = IncrementalState::new(reducers);
+        let mut partitions_run = 0;
+        for name in files {
+            let mut current = FileState::of_file(&name)?;
+            let location = file_map_location(&base_location, &name);
 
-        for _ in 0..self.params.mappers {
-            let _ = send.send(true);
+            match old.files.remove(&name) {
+                Some(ref previous) if previous.unchanged(&current) => {
+                    current.partitions = previous.partitions;
+                }
+                previous => {
+                    if let Some(previous) = previous {
+                        remove_intermediates(&location, previous.partitions, reducers);
+                    }
+                    // After an early termination, the remaining changed files are left out
+                    // (and mapped in the next run).
+                    if controller.params.termination.is_terminated() {
+                        continue;
+                    }
+                    controller.params.map_output_location = location.clone();
+                    controller.map_partitions_run = 0;
+                    let reader = lines::new_from_file(&name)?.for_job(&controller.params);
+                    controller.run_map(PosRecordIterator::new(reader));
+                    partitions_run += controller.map_partitions_run;
+                    if controller.params.termination.is_terminated() {
+                        remove_intermediates(&location, controller.map_partitions_run, reducers);
+                        continue;
+                    }
+                    current.partitions = controller.map_partitions_run;
+                }
+            }
+            state.files.insert(name, current);
+        }
+        let truncated = controller.end_phase();
+        // Files that have disappeared since the last run.
+        for (name, file) in old.files.iter() {
+            remove_intermediates(&file_map_location(&base_location, name),
+                                 file.partitions,
+                                 reducers);
         }
 
-        pool.scoped(move |scope| {
-            loop {
-                let _ = recv.recv();
+        let sources = state.files
+            .iter()
+            .map(|(name, file)| (file_map_location(&base_location, name), file.partitions))
+            .collect();
+        controller.params.map_output_location = base_location;
+        let mut result = controller.run_reduce(out, sources, false);
+        result.stats.truncated |= truncated;
+        state.save(state_file)?;
+
+        controller.map_stats.map_partitions = partitions_run;
+        result.stats.merge(&controller.map_stats);
+        controller.record_job(&mut result.stats, start);
+        Ok(result)
+    }
+
+    /// Watches the directory `watch.dir` and runs incremental batches (see `run_incremental()`)
+    /// over the files ending with `watch.with_suffix` whenever files appear, change or disappear.
+    /// The directory is polled every `watch.interval`. This way, the outputs are kept up to date
+    /// with a directory that files are continuously added to.
+    ///
+    /// Every batch writes a new generation of outputs, named
+    /// `<reduce_output_shard_prefix><generation>_<shard>` (with an output name template,
+    /// `<reduce_output_shard_prefix><generation>_` is its `{prefix}`); once a batch has finished,
+    /// the output files reported by the previous batch are removed (if the sink writes files),
+    /// including part files and compressed outputs. Returns the statistics of the last batch
+    /// after `watch.max_batches` batches have been run, or runs forever if it is None.
+    pub fn run_watch<Out: SinkGenerator>(mapper: M,
+                                         reducer: R,
+                                         sharder: S,
+                                         params: MRParameters,
+                                         watch: WatchOptions,
+                                         out: Out)
+                                         -> io::Result<JobStats> {
+        let output_prefix = params.reduce_output_shard_prefix.clone();
+        let mut stats = JobStats::new();
+        let mut generation = 0;
+        let mut previous_files: Vec<String> = Vec::new();
+
+        while watch.max_batches.map(|max| generation < max).unwrap_or(true) {
+            let files = lines::list_dir(&watch.dir, &watch.with_suffix)?;
+            // The first batch always runs, in order to write the outputs of this process.
+            if generation > 0 &&
+               IncrementalState::load(&watch.state_file)?.is_current(params.reducers, &files) {
+                thread::sleep(watch.interval);
+                continue;
+            }
+
+            let batch_params = params.clone()
+                .set_file_locations(params.map_output_location.clone(),
+                                    format!("{}{}_", output_prefix, generation));
+            let result = MRController::incremental(mapper.clone(),
+                                                   reducer.clone(),
+                                                   sharder.clone(),
+                                                   batch_params,
+                                                   files,
+                                                   &watch.state_file,
+                                                   out.clone())?;
+            let files: Vec<String> = result.outputs.iter().flat_map(|o| o.files()).collect();
+            // Templates without `{prefix}` name every generation's outputs the same.
+            for previous in previous_files.iter().filter(|f| !files.contains(f)) {
+                let _ = fs::remove_file(previous);
+            }
+            previous_files = files;
+            stats = result.stats;
+            generation += 1;
+        }
+        Ok(stats)
+    }
 
-                let m = self.m.clone();
-                let s = self.s.clone();
+    /// Runs the reduce phase after the map phase has finished, cleans up and returns the
+    /// result of the job.
+    fn finish<Out: SinkGenerator>(mut self, out: Out, start: Instant) -> JobResult {
+        let truncated = self.end_phase();
+        let sources = self.intermediates();
+        let mut result = self.run_reduce(out, sources, false);
+        result.stats.truncated |= truncated;
+        self.clean_up();
+        self.release_location();
+
+        self.map_stats.map_partitions = self.map_partitions_run;
+        result.stats.merge(&self.map_stats);
+        self.record_job(&mut result.stats, start);
+        result
+    }
+
+    /// Reads records from `input` until they take up `limit` bytes (keys and values). Returns the
+    /// records and whether `input` ended before the limit was reached.
+    fn read_small_input<In: Iterator<Item = Record>>(input: &mut In,
+                                                     limit: usize)
+                                                     -> (Vec<Record>, bool) {
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        for record in input {
+            bytes += record.key.len() + record.value.len();
+            records.push(record);
+            if bytes >= limit {
+                return (records, false);
+            }
+        }
+        (records, true)
+    }
+
+    /// Runs the whole job without intermediate files (see
+    /// `MRParameters::set_in_memory_shuffle()`): Mapper threads send the sorted records of every
+    /// map partition and reduce shard over a bounded channel to the shard's reducer thread, which
+    /// buffers them (spilling them to disk if they exceed `params.shuffle_memory_limit`) until
+    /// all partitions have been mapped, and then merges and reduces them. When the last mapper
+    /// thread exits, the map phase ends (see `MapSideEnd`), so that an early termination of the
+    /// map phase doesn't cut the reduce phase short.
+    ///
+    /// All mappers and reducers need threads at the same time, as the reducers drain the
+    /// channels that the mappers block on; a shared executor (`params.executor`), which may run
+    /// fewer tasks than that, could deadlock, so the job starts threads of its own. Mappers aren't
+    /// limited by the number of open files (see `concurrent_mappers()`) either, as they don't
+    /// write intermediate files.
+    fn run_in_memory<In: Iterator<Item = Record>, Out: SinkGenerator>(mut self,
+                                                                      mut input: In,
+                                                                      out: Out,
+                                                                      start: Instant)
+                                                                      -> JobResult {
+        // Input partitions are queued like in `run_map()`.
+        let (send, recv) = sync_channel::<(usize, InputCache)>(self.params.map_queue_length);
+        let recv = Arc::new(Mutex::new(recv));
+        let (mut senders, mut receivers) = (Vec::new(), Vec::new());
+        for _ in 0..self.params.reducers {
+            let (send, recv) = sync_channel::<Vec<Record>>(self.params.mappers);
+            senders.push(send);
+            receivers.push(recv);
+        }
+        // Every reduce partition sends its statistics and output back over this channel.
+        let (done, results) = channel();
+        let outp = out.with_durability(self.params.durability).with_io_retry(self.params.io_retry);
+        // Reducers run at the same time as mappers, so that the channels are drained.
+        let mut pool = Pool::new((self.params.mappers + self.params.reducers) as u32);
+        let running_mappers = AtomicUsize::new(self.params.mappers);
+        let map_truncated = AtomicBool::new(false);
+
+        {
+            let _span = trace::enter(Step::MapPhase, 0);
+            let params = &self.params;
+            let (mapper, reducer, sharder) = (&self.m, &self.r, &self.s);
+            let map_stats = &mut self.map_stats;
+            let partitions = &mut self.map_partitions_run;
+            let (running_mappers, map_truncated) = (&running_mappers, &map_truncated);
+
+            pool.scoped(move |scope| {
+                for _ in 0..params.mappers {
+                    let recv = recv.clone();
+                    let senders = senders.clone();
+                    let mapper = mapper.clone();
+                    let sharder = sharder.clone();
+
+                    scope.execute(move || {
+                        // Dropped before `senders`, so that the map phase has ended when the
+                        // reducers' channels close.
+                        let _map_side = MapSideEnd {
+                            running: running_mappers,
+                            termination: &params.termination,
+                            truncated: map_truncated,
+                        };
+                        priority::apply_niceness(params.map_niceness);
+                        loop {
+                            let next = recv.lock().unwrap().recv();
+                            let (id, inp) = match next {
+                                Err(_) => break,
+                                Ok(p) => p,
+                            };
+                            if params.termination.is_terminated() {
+                                continue;
+                            }
+                            let _worker = params.metrics.as_ref().map(|m| m.active_worker());
+                            let map_part = MapPartition::_new(params.clone().set_shard_id(id),
+                                                              inp,
+                                                              mapper.clone(),
+                                                              sharder.clone(),
+                                                              WriteLogGenerator::new());
+                            let runs = map_part._run_in_memory();
+                            let _span = trace::enter(Step::ShuffleWrite, id);
+                            for (shard, run) in runs.into_iter().enumerate() {
+                                if !run.is_empty() && senders[shard].send(run).is_err() {
+                                    panic!("reducer thread for shard {} has exited", shard);
+                                }
+                            }
+                        }
+                    });
+                }
+                // The reducers stop collecting once all mappers have dropped their senders.
+                drop(senders);
+
+                for (i, recv) in receivers.into_iter().enumerate() {
+                    let r = reducer.clone();
+                    let params = params.clone().set_shard_id(i);
+                    let output = outp.clone();
+                    let done = done.clone();
+
+                    scope.execute(move || {
+                        priority::apply_niceness(params.reduce_niceness);
+                        let mut buffer = ShuffleBuffer::new(&params, i);
+                        for run in recv.iter() {
+                            if let Err(e) = buffer.add(run) {
+                                panic!("couldn't spill shuffled records: {}", e);
+                            }
+                        }
+                        let mut inputs = match buffer.inputs() {
+                            Err(e) => panic!("couldn't read spilled records: {}", e),
+                            Ok(inputs) => inputs,
+                        };
+
+                        let _worker = params.metrics.as_ref().map(|m| m.active_worker());
+                        let name = create_reduce_output_name(&params);
+                        let previous = match open_previous_input(&name, &params) {
+                            Ok(previous) => previous,
+                            Err(e) => panic!("couldn't read previous output {}: {}", name, e),
+                        };
+                        let previous = previous.map(|(previous, reader)| {
+                            inputs.push(Box::new(reader));
+                            previous
+                        });
+                        let router = RoutedSink::for_job(&output, &name, &params);
+                        let output = ReduceSink::open(&output, &name, &params);
+                        let mut reduce_part = ReducePartition::new(r, params, inputs, output)
+                            .set_output_guard(OutputGuard::new(vec![name]));
+                        if let Some(router) = router {
+                            reduce_part = reduce_part.set_output_router(Box::new(router));
+                        }
+                        let (mut stats, output) = reduce_part._run();
+                        stats.shuffle_spills = buffer.spills().len();
+                        stats.shuffle_spilled_bytes = buffer.spilled_bytes();
+                        buffer.remove_spills();
+                        if let Some(previous) = previous {
+                            let _ = fs::remove_file(previous);
+                        }
+                        let _ = done.send((stats, output));
+                    });
+                }
+
+                while !params.termination.is_terminated() {
+                    let inp = MRController::<M, R, S>::read_map_input(&mut input,
+                                                                      params.map_partition_size);
+                    if inp.len() == 0 {
+                        break;
+                    }
+                    map_stats.map_input_records += inp.len();
+                    map_stats.map_input_bytes += inp.bytes();
+                    if send.send((*partitions, inp)).is_err() {
+                        panic!("all mapper threads have exited");
+                    }
+                    *partitions += 1;
+                }
+                // Closing the queue lets the mapper threads exit once it is drained.
+                drop(send);
+            });
+        }
+
+        let mut result = JobResult::default();
+        for (partition_stats, output) in results.try_iter() {
+            result.stats.merge(&partition_stats);
+            result.outputs.push(output);
+        }
+        result.outputs.sort_by_key(|o| o.shard);
+        result.stats.truncated |= map_truncated.load(AtomicOrdering::SeqCst);
+        self.release_location();
+        self.map_stats.map_partitions = self.map_partitions_run;
+        result.stats.merge(&self.map_stats);
+        self.record_job(&mut result.stats, start);
+        result
+    }
+
+    fn run_map<In: Iterator<Item = Record>>(&mut self, mut input: In) {
+        let _span = trace::enter(Step::MapPhase, 0);
+        let mappers = self.concurrent_mappers();
+        let (pool, cap) = self.executor(mappers);
+        // Input partitions are put into this queue, from which idle mapper threads take the next
+        // partition; this way, fast threads pick up the remaining work while others are busy with
+        // expensive partitions. The queue is bounded in order to limit memory usage.
+        let (send, recv) = sync_channel::<(MRParameters, InputCache)>(self.params
+            .map_queue_length);
+        let recv = Arc::new(Mutex::new(recv));
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        let mapper = self.m.clone();
+        let sharder = self.s.clone();
+        let metrics = self.params.metrics.clone();
+        let final_metrics = self.params.metrics.clone();
+        let map_niceness = self.task_niceness(self.params.map_niceness);
+
+        pool.scoped(cap, move |scope| {
+            for _ in 0..mappers {
+                let recv = recv.clone();
+                let depth = depth.clone();
+                let mapper = mapper.clone();
+                let sharder = sharder.clone();
+                let metrics = metrics.clone();
+
+                scope.execute(move || {
+                    priority::apply_niceness(map_niceness);
+                    loop {
+                        // The lock is released as soon as a partition has been received.
+                        let next = recv.lock().unwrap().recv();
+                        let (params, inp) = match next {
+                            Err(_) => break,
+                            Ok(p) => p,
+                        };
+                        let queued = depth.fetch_sub(1, AtomicOrdering::SeqCst) - 1;
+                        // Drain the queue without mapping after an early termination.
+                        if params.termination.is_terminated() {
+                            continue;
+                        }
+
+                        if let Some(ref registry) = metrics {
+                            registry.set_map_queue_depth(queued);
+                        }
+                        let _worker = metrics.as_ref().map(|m| m.active_worker());
+                        MRController::<M, R, S>::map_runner(mapper.clone(),
+                                                            sharder.clone(),
+                                                            params,
+                                                            inp);
+                    }
+                });
+            }
+
+            while !self.params.termination.is_terminated() {
                 // Can't necessarily send the input handle to the mapper thread, therefore read
-                // input before spawn.
+                // input before queueing it.
                 let inp = MRController::<M, R, S>::read_map_input(&mut input,
                                                                   self.params.map_partition_size);
 
@@ -72,27 +634,155 @@ impl<M: Mapper, R: Reducer, S: Sharder> MRController<M, R, S> {
                     break;
                 }
 
+                self.map_stats.map_input_records += inp.len();
+                self.map_stats.map_input_bytes += inp.bytes();
+
                 let params = self.params.clone().set_shard_id(self.map_partitions_run as usize);
-                let done = send.clone();
+                let queued = depth.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                if let Some(ref registry) = self.params.metrics {
+                    registry.set_map_queue_depth(queued);
+                }
 
-                scope.execute(move || {
-                    MRController::<M, R, S>::map_runner(m, s, params, inp);
-                    let _ = done.send(true);
-                });
+                if send.send((params, inp)).is_err() {
+                    panic!("all mapper threads have exited");
+                }
                 self.map_partitions_run += 1;
             }
 
-            scope.join_all();
+            // Closing the queue lets the mapper threads exit once it is drained.
+            drop(send);
         });
+
+        // Updates from the reading and the mapper threads may have been reported out of order.
+        if let Some(ref registry) = final_metrics {
+            registry.set_map_queue_depth(0);
+        }
+    }
+
+    fn run_map_splits<In: Iterator<Item = Record> + Send>(&mut self, splits: Vec<In>) {
+        let _span = trace::enter(Step::MapPhase, 0);
+        let mappers = self.concurrent_mappers();
+        let (pool, cap) = self.executor(mappers);
+        let map_niceness = self.task_niceness(self.params.map_niceness);
+        let queue = Mutex::new(splits.into_iter());
+        // Every split may result in several partitions; they are numbered consecutively.
+        let partitions = AtomicUsize::new(0);
+        let (send, recv) = channel();
+
+        {
+            let queue = &queue;
+            let partitions = &partitions;
+            let params = &self.params;
+            let mapper = self.m.clone();
+            let sharder = self.s.clone();
+
+            pool.scoped(cap, move |scope| {
+                for _ in 0..mappers {
+                    let done = send.clone();
+                    let mapper = mapper.clone();
+                    let sharder = sharder.clone();
+
+                    scope.execute(move || {
+                        priority::apply_niceness(map_niceness);
+                        let mut stats = JobStats::new();
+
+                        while !params.termination.is_terminated() {
+                            let next = queue.lock().unwrap().next();
+                            let mut split = match next {
+                                None => break,
+                                Some(s) => s,
+                            };
+
+                            while !params.termination.is_terminated() {
+                                let size = params.map_partition_size;
+                                let inp = MRController::<M, R, S>::read_map_input(&mut split,
+                                                                                  size);
+                                if inp.len() == 0 {
+                                    break;
+                                }
+                                stats.map_input_records += inp.len();
+                                stats.map_input_bytes += inp.bytes();
+
+                                let id = partitions.fetch_add(1, AtomicOrdering::SeqCst);
+                                let params = params.clone().set_shard_id(id);
+
+                                let _worker =
+                                    params.metrics.as_ref().map(|m| m.active_worker());
+                                MRController::<M, R, S>::map_runner(mapper.clone(),
+                                                                    sharder.clone(),
+                                                                    params,
+                                                                    inp);
+                            }
+                        }
+                        let _ = done.send(stats);
+                    });
+                }
+            });
+        }
+
+        for split_stats in recv.try_iter() {
+            self.map_stats.merge(&split_stats);
+        }
+        self.map_partitions_run = partitions.load(AtomicOrdering::SeqCst);
+    }
+
+    /// Returns how many map partitions can run concurrently without exceeding the limit on open
+    /// files: Every running partition keeps an intermediate file (and an index sidecar, if
+    /// enabled) per reducer open. The limit is raised as far as allowed first; if it is still too
+    /// low, fewer than `params.mappers` partitions run concurrently.
+    fn concurrent_mappers(&self) -> usize {
+        let mappers = self.params.mappers;
+        let per_partition = self.params.reducers *
+                            if self.params.intermediate_key_index { 2 } else { 1 };
+        let limit = raise_open_files_limit(mappers * per_partition + RESERVED_OPEN_FILES)
+            .map(open_files_share);
+        let fitting = mappers_within_limit(mappers, per_partition, limit);
+        if fitting < mappers {
+            println!("WARN: Running {} instead of {} mappers, as only {:?} files may be open",
+                     fitting,
+                     mappers,
+                     limit);
+        }
+        fitting
+    }
+
+    /// Returns the executor running the `tasks` partitions of a phase, and how many of them may
+    /// run at the same time: the executor set with `MRParameters::set_executor()`, or new
+    /// threads for every partition.
+    fn executor(&self, tasks: usize) -> (ExecutorHandle, usize) {
+        match self.params.executor {
+            Some(ref executor) if self.params.executor_job_cap > 0 => {
+                (executor.clone(), cmp::min(tasks, self.params.executor_job_cap))
+            }
+            Some(ref executor) => (executor.clone(), tasks),
+            None => (ExecutorHandle::new(tasks, 0), tasks),
+        }
+    }
+
+    /// Returns the niceness that the worker threads of a phase apply: none on a shared executor.
+    fn task_niceness(&self, niceness: i32) -> i32 {
+        if self.params.executor.is_some() { 0 } else { niceness }
     }
 
     fn map_runner(mapper: M, sharder: S, params: MRParameters, inp: InputCache) {
         if inp.len() == 0 {
             return;
         }
-        let intermed_out = WriteLogGenerator::new();
+        let intermed_out = WriteLogGenerator::with_batching(params.map_output_batch_records,
+                                                            params.map_output_batch_bytes)
+            .with_durability(params.durability)
+            .with_io_retry(params.io_retry);
+        let store = params.object_store.clone();
+        let (location, partition) = (params.map_output_location.clone(), params.shard_id);
         let map_part = MapPartition::_new(params, inp, mapper, sharder, intermed_out);
         map_part._run();
+        if let Some((store, _)) = store {
+            if let Err(e) = put_map_outputs(&*store, &location, partition) {
+                panic!("couldn't put the outputs of map partition {} into the object store: {}",
+                       partition,
+                       e)
+            }
+        }
     }
 
     fn read_map_input<In: Iterator<Item = Record>>(it: &mut In, approx_bytes: usize) -> InputCache {
@@ -101,40 +791,1952 @@ impl<M: Mapper, R: Reducer, S: Sharder> MRController<M, R, S> {
     }
 
 
-    fn run_reduce<Out: SinkGenerator>(&self, outp: Out) {
-        let mut pool = Pool::new(self.params.reducers as u32);
+    /// Returns the locations of the intermediate files written by the map phase, together with
+    /// the number of map partitions at each location.
+    fn intermediates(&self) -> Vec<(String, usize)> {
+        vec![(self.params.map_output_location.clone(), self.map_partitions_run)]
+    }
 
-        pool.scoped(move |scope| {
+    /// Reduces the intermediate files of `sources`. If `join` is set, the records of every source
+    /// are checked against the Bloom filters of all other sources (if they have filters).
+    fn run_reduce<Out: SinkGenerator>(&self,
+                                      outp: Out,
+                                      sources: Vec<(String, usize)>,
+                                      join: bool)
+                                      -> JobResult {
+        let _span = trace::enter(Step::ReducePhase, 0);
+        let (pool, cap) = self.executor(self.params.reducers);
+        let reduce_niceness = self.task_niceness(self.params.reduce_niceness);
+        // Every reduce partition sends its statistics and output back over this channel.
+        let (send, recv) = channel();
+        let sources = &sources;
+        let outp = outp.with_durability(self.params.durability).with_io_retry(self.params.io_retry);
+        let keep_temp_files = self.params.keep_temp_files;
+
+        pool.scoped(cap, move |scope| {
             for i in 0..self.params.reducers {
                 let r = self.r.clone();
                 let params = self.params.clone().set_shard_id(i);
-                let map_partitions = self.map_partitions_run;
+                let range = self.s.key_range(self.params.reducers, i);
                 let output = outp.clone();
+                let done = send.clone();
 
                 scope.execute(move || {
-                    let inputs = open_reduce_inputs(&params.map_output_location, map_partitions, i);
-                    let output = output.new_output(&get_reduce_output_name(&params));
-                    let reduce_part = ReducePartition::new(r, params, inputs, output);
-                    reduce_part._run();
+                    priority::apply_niceness(reduce_niceness);
+                    let _worker = params.metrics.as_ref().map(|m| m.active_worker());
+                    let mut fetched = Vec::new();
+                    if let Some((ref store, _)) = params.object_store {
+                        for &(ref location, partitions) in sources.iter() {
+                            match get_shard_inputs(&**store, location, partitions, i) {
+                                Ok(files) => fetched.extend(files),
+                                Err(e) => {
+                                    panic!("couldn't get the inputs of reduce shard {} from the \
+                                            object store: {}",
+                                           i,
+                                           e)
+                                }
+                            }
+                        }
+                    }
+                    let blooms: Vec<_> = sources.iter()
+                        .map(|&(ref location, partitions)| if join {
+                            load_bloom_filters(location, partitions, i).map(Arc::new)
+                        } else {
+                            None
+                        })
+                        .collect();
+                    let mut inputs = Vec::new();
+                    for (j, &(ref location, partitions)) in sources.iter().enumerate() {
+                        let join_filters = blooms.iter()
+                            .enumerate()
+                            .filter(|&(k, _)| k != j)
+                            .filter_map(|(_, b)| b.clone())
+                            .collect();
+                        inputs.extend(open_reduce_inputs(location,
+                                                         partitions,
+                                                         i,
+                                                         range.clone(),
+                                                         &params,
+                                                         join_filters));
+                    }
+                    let name = create_reduce_output_name(&params);
+                    match open_previous_input(&name, &params) {
+                        Ok(Some((previous, reader))) => {
+                            inputs.push(KeyRangeIterator::new(reader, None, None));
+                            fetched.push(previous);
+                        }
+                        Ok(None) => (),
+                        Err(e) => panic!("couldn't read previous output {}: {}", name, e),
+                    }
+                    let router = RoutedSink::for_job(&output, &name, &params);
+                    let output = ReduceSink::open(&output, &name, &params);
+                    let mut reduce_part = ReducePartition::new(r, params, inputs, output)
+                        .set_output_guard(OutputGuard::new(vec![name]));
+                    if let Some(router) = router {
+                        reduce_part = reduce_part.set_output_router(Box::new(router));
+                    }
+                    // Failures are caught when intermediate files are kept, so that the
+                    // retention can be applied before the job fails.
+                    let result = if keep_temp_files {
+                        panic::catch_unwind(AssertUnwindSafe(|| reduce_part._run()))
+                    } else {
+                        Ok(reduce_part._run())
+                    };
+                    for file in fetched {
+                        let _ = fs::remove_file(file);
+                    }
+                    let _ = done.send((i, result));
                 });
             }
         });
+
+        let mut result = JobResult::default();
+        let mut failures = Vec::new();
+        for (shard, partition_result) in recv.try_iter() {
+            match partition_result {
+                Ok((partition_stats, output)) => {
+                    result.stats.merge(&partition_stats);
+                    result.outputs.push(output);
+                }
+                Err(cause) => failures.push((shard, cause)),
+            }
+        }
+        if !failures.is_empty() {
+            if *sources == self.intermediates() {
+                let failed: Vec<usize> = failures.iter().map(|&(shard, _)| shard).collect();
+                self.retain_temp_files(&failed);
+                self.release_location();
+            }
+            panic::resume_unwind(failures.remove(0).1);
+        }
+        result.outputs.sort_by_key(|o| o.shard);
+        result
     }
 
-    fn clean_up(&self) {
-        use std::fs;
-        use std::fmt;
+    /// Returns whether the current phase has been terminated early, and resets the termination
+    /// flag for the next phase.
+    fn end_phase(&self) -> bool {
+        let terminated = self.params.termination.is_terminated();
+        self.params.termination.reset();
+        terminated
+    }
+
+    /// Completes the statistics of a finished job and reports them to the metrics registry, if
+    /// there is one.
+    fn record_job(&self, stats: &mut JobStats, start: Instant) {
+        stats.records_malformed = self.params.malformed.count() - self.malformed_before;
+        stats.input = self.params.input_stats.get().since(&self.input_before);
+        stats.map_input_sort = self.params.map_sort_timings.get();
+        stats.truncated |= self.end_phase();
+        if let Some(ref histogram) = stats.key_histogram {
+            let name = key_histogram_name(&self.params);
+            if let Err(e) = histogram.write_to(&name) {
+                println!("WARN: Couldn't write key histogram {}: {}", name, e);
+            }
+        }
+        if let Some(ref catalog) = self.params.catalog {
+            self.register_datasets(catalog, stats);
+        }
+        if let Some(ref metrics) = self.params.metrics {
+            metrics.record_job(stats, start.elapsed());
+        }
+    }
+
+    /// Registers the outputs of the finished job in `catalog` (see `MRParameters::set_catalog()`).
+    fn register_datasets(&self, catalog: &Catalog, stats: &JobStats) {
+        let name = &self.params.job_name;
+        let mut datasets = Vec::new();
+        if self.params.output_name_template.is_none() && self.params.output_partitioning.is_none() {
+            datasets.push((name.clone(), Dataset::from_output(&self.params, &self.s)));
+        }
+        if self.params.keep_temp_files && stats.map_partitions > 0 {
+            let intermediates =
+                Dataset::from_intermediates(&self.params, &self.s, stats.map_partitions);
+            datasets.push((format!("{}.intermediates", name), intermediates));
+        }
+        for (name, dataset) in datasets {
+            if let Err(e) = catalog.register(&name, &dataset) {
+                println!("WARN: Couldn't register dataset {} in catalog: {}", name, e);
+            }
+        }
+    }
+
+    /// Marks the intermediate location as used by this process (see
+    /// `phases::output::run_marker_name()`). If a run that has crashed left its marker, the
+    /// intermediate files at the location are handled according to
+    /// `params.stale_intermediates` first.
+    ///
+    /// Jobs of this process using the same intermediate location or output prefix run one after
+    /// another (see `LocationClaim`).
+    fn claim_location(&mut self) {
+        self.claim = Some(LocationClaim::acquire(vec![self.params.map_output_location.clone(),
+                                                      self.params
+                                                          .reduce_output_shard_prefix
+                                                          .clone()]));
+        let location = &self.params.map_output_location;
+        let marker = run_marker_name(location);
+        if let Ok(contents) = fs::read_to_string(&marker) {
+            match contents.trim().parse::<u32>() {
+                // Left by an earlier job of this process; no running job uses the location.
+                Ok(pid) if pid == process::id() => (),
+                Ok(pid) if process_alive(pid) => {
+                    println!("WARN: Intermediate location {} is in use by process {}",
+                             location,
+                             pid)
+                }
+                _ => {
+                    match cleanup_stale(location, self.params.stale_intermediates) {
+                        Ok(0) => (),
+                        Ok(n) => {
+                            println!("WARN: Found {} intermediate files of a crashed run at {}",
+                                     n,
+                                     location)
+                        }
+                        Err(e) => {
+                            println!("WARN: Couldn't clean up intermediate location {}: {}",
+                                     location,
+                                     e)
+                        }
+                    }
+                }
+            }
+        }
+        if let Err(e) = fs::write(&marker, process::id().to_string()) {
+            println!("WARN: Couldn't write run marker {}: {}", marker, e);
+        }
+    }
+
+    /// Removes the marker written by `claim_location()`.
+    fn release_location(&self) {
+        let _ = fs::remove_file(run_marker_name(&self.params.map_output_location));
+    }
 
+    fn clean_up(&self) {
         if !self.params.keep_temp_files {
-            for mpart in 0..self.map_partitions_run {
-                for rshard in 0..self.params.reducers {
-                    let name = fmt::format(format_args!("{}{}.{}",
-                                                        self.params.map_output_location,
-                                                        mpart,
-                                                        rshard));
-                    let _ = fs::remove_file(name);
+            remove_intermediates(&self.params.map_output_location,
+                                 self.map_partitions_run,
+                                 self.params.reducers);
+            if let Some((ref store, _)) = self.params.object_store {
+                let prefix = format!("{}-", self.params.map_output_location);
+                if let Err(e) = delete_objects(&**store, &prefix) {
+                    println!("WARN: Couldn't delete intermediate objects {}*: {}", prefix, e);
                 }
             }
+        } else {
+            self.retain_temp_files(&[]);
+        }
+    }
+
+    /// Applies `params.temp_retention` to the kept intermediate files, given the reduce shards
+    /// that have failed.
+    fn retain_temp_files(&self, failed: &[usize]) {
+        if let Err(e) = retain_intermediates(&self.params.map_output_location,
+                                             self.map_partitions_run,
+                                             self.params.reducers,
+                                             failed,
+                                             self.params.temp_retention) {
+            println!("WARN: Couldn't apply retention to intermediate files: {}", e);
+        }
+    }
+}
+
+impl<R: Reducer, S: Sharder> MRController<IdentityMapper, R, S> {
+    /// Groups and reduces the records of `input` (see `dataset::Dataset`). If the dataset
+    /// consists of intermediate files that are already partitioned like this job's (same number
+    /// of reducers and same `Sharder::partitioning()`), they are reduced directly, without
+    /// sorting and sharding them again; the dataset's files are left in place. Otherwise, the
+    /// records are re-sharded by running the map phase over them.
+    pub fn reduce_dataset<Out: SinkGenerator>(reducer: R,
+                                              sharder: S,
+                                              params: MRParameters,
+                                              input: &Dataset,
+                                              out: Out)
+                                              -> io::Result<JobStats> {
+        if !input.is_partitioned_like(&params, &sharder) {
+            return Ok(MRController::run(RekeyMapper,
+                                        reducer,
+                                        sharder,
+                                        params,
+                                        input.numbered_records()?,
+                                        out)
+                .stats);
+        }
+
+        let start = Instant::now();
+        let mut controller = MRController::new(IdentityMapper, reducer, sharder, params);
+        controller.map_partitions_run = input.partitions;
+        let sources = vec![(input.location.clone(), input.partitions)];
+        let mut stats = controller.run_reduce(out, sources, false).stats;
+        controller.record_job(&mut stats, start);
+        Ok(stats)
+    }
+
+    /// Reduces the records of several datasets together, for inner joins: The reducer is called
+    /// once per key with the values of all inputs (which the mappers should have tagged to tell
+    /// them apart). All inputs must consist of intermediate files partitioned like this job's
+    /// (see `reduce_dataset()`). If they were written with Bloom filters (see
+    /// `MRParameters::set_intermediate_bloom_filter()`), records whose key is missing from one of
+    /// the other inputs are mostly skipped before being merged; the reducer still has to check
+    /// that a key occurs in all inputs, as Bloom filters have false positives.
+    pub fn reduce_join<Out: SinkGenerator>(reducer: R,
+                                           sharder: S,
+                                           params: MRParameters,
+                                           inputs: &[&Dataset],
+                                           out: Out)
+                                           -> io::Result<JobStats> {
+        if let Some(input) = inputs.iter().find(|i| !i.is_partitioned_like(&params, &sharder)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Dataset at {} isn't partitioned like the job",
+                                              input.location)));
+        }
+
+        let start = Instant::now();
+        let controller = MRController::new(IdentityMapper, reducer, sharder, params);
+        let sources = inputs.iter().map(|i| (i.location.clone(), i.partitions)).collect();
+        let mut stats = controller.run_reduce(out, sources, true).stats;
+        controller.record_job(&mut stats, start);
+        Ok(stats)
+    }
+}
+
+impl<M: Mapper, R: Reducer> MRController<M, R, RangeSharder> {
+    /// Runs a job over the text file splits `splits` (see `formats::lines::split_file()`) whose
+    /// output is in total order: Before the map phase, a sampling pass maps a fraction of the
+    /// input (see `MRParameters::set_key_sample_fraction()`) and chooses split points so that
+    /// every reducer receives about the same share of the intermediate keys (see
+    /// `sampling::split_points()`); the job is then run with a `RangeSharder` using them. Input
+    /// records are numbered per split, like `PosRecordIterator` does.
+    ///
+    /// The sample is taken deterministically, depending on `MRParameters::set_shard_seed()`.
+    pub fn run_total_order<Out: SinkGenerator>(mapper: M,
+                                               reducer: R,
+                                               params: MRParameters,
+                                               splits: Vec<FileSplit>,
+                                               out: Out)
+                                               -> io::Result<JobResult> {
+        let mut keys = Vec::new();
+        for (i, split) in splits.iter().enumerate() {
+            let input = PosRecordIterator::new(split.lines()?.for_job(&params));
+            let seed = params.shard_seed.wrapping_add(i as u64);
+            // The sample is mapped by a copy, so that the job's mapper starts out fresh.
+            let sample = sample_mapped_keys(&mut mapper.clone(),
+                                            input,
+                                            params.key_sample_fraction,
+                                            seed);
+            keys.extend(sample.keys().iter().cloned());
+        }
+        let sharder = RangeSharder::new(split_points(keys, params.reducers));
+
+        let mut inputs = Vec::with_capacity(splits.len());
+        for split in splits.iter() {
+            let input = PosRecordIterator::new(split.lines()?.for_job(&params));
+            inputs.push(params.input_stats.collect(input));
+        }
+        Ok(MRController::run_splits(mapper, reducer, sharder, params, inputs, out))
+    }
+}
+
+impl<R: Reducer> MRController<IdentityMapper, R, DefaultSharder> {
+    /// Runs only the reduce phase, using the intermediate files that an earlier run kept at
+    /// `params.map_output_location` (see `MRParameters::keep_temp_files()`). This is useful for
+    /// iterating on reducer code without re-running the map phase every time.
+    ///
+    /// `params` should be the same as for the original run (especially the number of reducers);
+    /// note that the intermediate files are removed afterwards unless `keep_temp_files` is still
+    /// set.
+    pub fn run_reduce_only<Out: SinkGenerator>(params: MRParameters,
+                                               reducer: R,
+                                               out: Out)
+                                               -> io::Result<JobStats> {
+        let start = Instant::now();
+        let partitions = discover_map_partitions(&params.map_output_location, params.reducers)?;
+        let mut controller = MRController::new(IdentityMapper, reducer, DefaultSharder, params);
+        controller.map_partitions_run = partitions;
+        let mut stats = controller.run_reduce(out, controller.intermediates(), false).stats;
+        controller.clean_up();
+        controller.record_job(&mut stats, start);
+        Ok(stats)
+    }
+}
+
+/// Orders `splits` (each with its size) according to `scheduling`; `seed` seeds the random
+/// order.
+fn schedule_splits<T>(mut splits: Vec<(u64, T)>, scheduling: MapScheduling, seed: u64) -> Vec<T> {
+    match scheduling {
+        MapScheduling::Fifo => (),
+        // The sort is stable, so that splits of equal size keep their order.
+        MapScheduling::LargestFirst => splits.sort_by_key(|s| ::std::cmp::Reverse(s.0)),
+        MapScheduling::Random => {
+            // Fisher-Yates shuffle driven by a xorshift generator.
+            let mut state = seed | 1;
+            for i in (1..splits.len()).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                splits.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+        }
+    }
+    splits.into_iter().map(|(_, split)| split).collect()
+}
+
+/// Returns how many of `mappers` map partitions, each keeping `per_partition` files open, fit
+/// into the limit on open files `limit` (None if unlimited). At least one partition runs.
+fn mappers_within_limit(mappers: usize, per_partition: usize, limit: Option<usize>) -> usize {
+    match limit {
+        None => mappers,
+        Some(limit) => {
+            let fitting = limit.saturating_sub(RESERVED_OPEN_FILES) /
+                          ::std::cmp::max(1, per_partition);
+            ::std::cmp::max(1, ::std::cmp::min(mappers, fitting))
+        }
+    }
+}
+
+/// Removes the intermediate files (with their sidecars) of `partitions` map partitions at
+/// `location` that `retention` doesn't keep, given the reduce shards that have `failed`. A
+/// multiplexed file holds the data of all shards; it is kept as a whole if any shard failed.
+/// Returns how many bytes are kept.
+pub fn retain_intermediates(location: &String,
+                            partitions: usize,
+                            reducers: usize,
+                            failed: &[usize],
+                            retention: TempRetention)
+                            -> io::Result<u64> {
+    // Groups of files that are kept or removed together, in the order they are retained.
+    let mut groups = Vec::new();
+    for part in 0..partitions {
+        let name = map_multiplexed_name(location, part);
+        groups.push((!failed.is_empty(), vec![map_dictionary_name(&name), name]));
+        for shard in 0..reducers {
+            let name = map_output_name(location, part, shard);
+            groups.push((failed.contains(&shard),
+                         vec![map_index_name(&name),
+                              map_bloom_name(&name),
+                              map_dictionary_name(&name),
+                              name]));
+        }
+    }
+
+    let mut kept = 0;
+    for (is_failed, files) in groups {
+        let mut size = 0;
+        for file in files.iter() {
+            match fs::metadata(file) {
+                Ok(meta) => size += meta.len(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let keep = (is_failed || !retention.failed_only) &&
+                   retention.max_bytes.map(|max| kept + size <= max).unwrap_or(true);
+        if keep {
+            kept += size;
+            continue;
+        }
+        for file in files.iter() {
+            match fs::remove_file(file) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// Handles the intermediate files, index and Bloom filter sidecars and shuffle spills at
+/// `location` according to `policy`, regardless of which run has written them; returns how many
+/// files were found. Jobs call this when they find the run marker of a crashed run at their
+/// intermediate location (see `MRParameters::set_stale_intermediates()`); no job may be using
+/// the location while this runs.
+pub fn cleanup_stale(location: &String, policy: StaleIntermediates) -> io::Result<usize> {
+    let files = list_intermediate_files(location)?;
+    for file in files.iter() {
+        match policy {
+            StaleIntermediates::Keep => (),
+            StaleIntermediates::Remove => fs::remove_file(file)?,
+            StaleIntermediates::Quarantine => {
+                let mut quarantined = file.clone().into_os_string();
+                quarantined.push(".stale");
+                fs::rename(file, quarantined)?
+            }
+        }
+    }
+    Ok(files.len())
+}
+
+/// Returns whether a process with ID `pid` is running. Processes are assumed to be running on
+/// platforms where this can't be checked.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use closure_mr::ClosureMapReducer;
+    use controller::{MRController, cleanup_stale, mappers_within_limit, retain_intermediates,
+                     schedule_splits};
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::util::PosRecordIterator;
+    use formats::writelog::{WriteLogReader, read_value_version};
+    use catalog::Catalog;
+    use dataset::Dataset;
+    use executor::ExecutorHandle;
+    use incremental::WatchOptions;
+    use jobs::reshard_intermediates;
+    use malformed::MalformedPolicy;
+    use memory::MemoryBudget;
+    use std::io::Write;
+    use mapreducer::{DefaultSharder, IdentityMapper, MapperF, RangeSharder, StableSharder};
+    use metrics::MetricsRegistry;
+    use object_store::{LocalStore, ObjectStore, StoredFiles};
+    use parameters::{KeyEncoding, MRParameters, MapInputSort, MapScheduling, OutputLimitPolicy,
+                     ReduceStrategy, StaleIntermediates, TempRetention};
+    use phases::output::{discover_map_partitions, list_intermediate_files, map_bloom_name,
+                         map_index_name, map_multiplexed_name, map_output_name, read_segment,
+                         run_marker_name, shuffle_spill_name};
+    use record_types::{MEmitter, REmitter, Record, MultiRecord};
+    use warm_start::previous_value;
+
+    use std::fs;
+    use std::panic;
+    use std::str;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit(String::from(w), String::from("1"));
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{} {}", recs.key(), recs.values().len()));
+    }
+
+    fn key_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(recs.key().clone());
+    }
+
+    fn get_input() -> PosRecordIterator<::std::vec::IntoIter<String>> {
+        let lines: Vec<String> = vec!["abc def", "def ghi", "abc abc xyz"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        PosRecordIterator::new(lines.into_iter())
+    }
+
+    fn read_outputs(prefix: &str, reducers: usize) -> Vec<String> {
+        let mut result = Vec::new();
+        for i in 0..reducers {
+            let name = format!("{}{}", prefix, i);
+            result.extend(lines::new_from_file(&name).unwrap());
+            let _ = fs::remove_file(name);
+        }
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn test_mappers_within_limit() {
+        assert_eq!(mappers_within_limit(16, 512, None), 16);
+        assert_eq!(mappers_within_limit(16, 512, Some(1024 * 1024)), 16);
+        assert_eq!(mappers_within_limit(16, 512, Some(4096)), 7);
+        assert_eq!(mappers_within_limit(16, 512, Some(256)), 1);
+        assert_eq!(mappers_within_limit(4, 0, Some(100)), 4);
+    }
+
+    fn failing_reducer(e: &mut REmitter, recs: MultiRecord) {
+        if recs.key() == "xyz" {
+            panic!("failing on purpose");
+        }
+        count_reducer(e, recs)
+    }
+
+    #[test]
+    fn test_temp_retention() {
+        let location = String::from("testdata/ctrl_retention_map_");
+        let params = MRParameters::new()
+            .set_concurrency(2, 3)
+            .keep_temp_files(true)
+            .set_temp_retention(TempRetention {
+                failed_only: true,
+                max_bytes: None,
+            })
+            .set_file_locations(location.clone(), String::from("testdata/ctrl_retention_out_"));
+        let sharder = RangeSharder::new(vec![String::from("d"), String::from("h")]);
+        let result = panic::catch_unwind(|| {
+            MRController::run(ClosureMapReducer::new(word_mapper, failing_reducer),
+                              ClosureMapReducer::new(word_mapper, failing_reducer),
+                              sharder,
+                              params,
+                              get_input(),
+                              LinesSinkGenerator::new_to_files())
+        });
+        assert!(result.is_err());
+        // Only the input of the failed shard 2 ("xyz") is kept.
+        assert!(fs::metadata(map_output_name(&location, 0, 0)).is_err());
+        assert!(fs::metadata(map_output_name(&location, 0, 1)).is_err());
+        assert!(fs::metadata(map_output_name(&location, 0, 2)).is_ok());
+        assert!(fs::metadata(run_marker_name(&location)).is_err());
+        // The partial output of the failed shard has been removed.
+        assert!(fs::metadata("testdata/ctrl_retention_out_2").is_err());
+        read_outputs("testdata/ctrl_retention_out_", 2);
+
+        for part in 0..2 {
+            for shard in 0..3 {
+                fs::write(map_output_name(&location, part, shard), "0123456789").unwrap();
+            }
+        }
+        let cap = TempRetention {
+            failed_only: false,
+            max_bytes: Some(25),
+        };
+        assert_eq!(retain_intermediates(&location, 2, 3, &[], cap).unwrap(), 20);
+        assert_eq!(list_intermediate_files(&location).unwrap().len(), 2);
+        assert!(fs::metadata(map_output_name(&location, 0, 1)).is_ok());
+        assert_eq!(cleanup_stale(&location, StaleIntermediates::Remove).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_cleanup_stale() {
+        let location = String::from("testdata/ctrl_stale_map_");
+        let stale = [map_output_name(&location, 7, 0),
+                       map_index_name(&map_output_name(&location, 7, 1)),
+                       shuffle_spill_name(&location, 0, 3)];
+        let write_stale = |pid: &str| {
+            fs::write(run_marker_name(&location), pid).unwrap();
+            for name in stale.iter() {
+                fs::write(name, "stale").unwrap();
+            }
+        };
+        let run = |policy| {
+            let params = MRParameters::new()
+                .set_concurrency(1, 2)
+                .set_stale_intermediates(policy)
+                .set_file_locations(location.clone(), String::from("testdata/ctrl_stale_out_"));
+            MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                              ClosureMapReducer::new(word_mapper, count_reducer),
+                              DefaultSharder,
+                              params,
+                              get_input(),
+                              LinesSinkGenerator::new_to_files());
+            read_outputs("testdata/ctrl_stale_out_", 2)
+        };
+
+        // The marker of a process that has exited.
+        write_stale("999999999");
+        assert_eq!(run(StaleIntermediates::Remove),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        assert!(stale.iter().all(|name| fs::metadata(name).is_err()));
+        assert!(fs::metadata(run_marker_name(&location)).is_err());
+
+        write_stale("999999999");
+        let _ = run(StaleIntermediates::Quarantine);
+        for name in stale.iter() {
+            assert!(fs::metadata(name).is_err());
+            fs::remove_file(format!("{}.stale", name)).unwrap();
+        }
+
+        // Files are left alone while their process is running.
+        write_stale(&::std::process::id().to_string());
+        let _ = run(StaleIntermediates::Remove);
+        assert_eq!(cleanup_stale(&location, StaleIntermediates::Keep).unwrap(), 3);
+        assert_eq!(cleanup_stale(&location, StaleIntermediates::Remove).unwrap(), 3);
+        assert_eq!(list_intermediate_files(&location).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_run_reduce_only() {
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .keep_temp_files(true)
+            .set_file_locations(String::from("testdata/ctrl_rro_map_"),
+                                String::from("testdata/ctrl_rro_out_"));
+
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params.clone(),
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.map_partitions, 1);
+        assert_eq!(stats.map_input_records, 3);
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_rro_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        let stats = MRController::run_reduce_only(params.keep_temp_files(false),
+                                                  ClosureMapReducer::new(word_mapper,
+                                                                         key_reducer),
+                                                  LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_rro_out_", reducers),
+                   vec!["abc", "def", "ghi", "xyz"]);
+
+        // The intermediate files have been cleaned up.
+        assert!(fs::metadata("testdata/ctrl_rro_map_-0.0").is_err());
+    }
+
+    #[test]
+    fn test_reshard_intermediates() {
+        let location = String::from("testdata/ctrl_reshard_map_");
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .keep_temp_files(true)
+            .set_intermediate_value_version(1)
+            .set_file_locations(location.clone(), String::from("testdata/ctrl_reshard_out_"));
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params.clone(),
+                          get_input(),
+                          LinesSinkGenerator::new_to_files());
+        read_outputs("testdata/ctrl_reshard_out_", 2);
+
+        // The kept files are resharded for 3 reducers, replacing the old ones.
+        let params = params.set_concurrency(2, 3).keep_temp_files(false);
+        assert_eq!(reshard_intermediates(&location, 2, DefaultSharder, &params).unwrap(), 1);
+        assert_eq!(discover_map_partitions(&location, 3).unwrap(), 1);
+        assert_eq!(read_value_version(&map_output_name(&location, 0, 2)).unwrap(), 1);
+        let stats = MRController::run_reduce_only(params,
+                                                  ClosureMapReducer::new(word_mapper,
+                                                                         count_reducer),
+                                                  LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_reshard_out_", 3),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        assert!(list_intermediate_files(&location).unwrap().is_empty());
+    }
+
+    fn sum_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let sum: usize = recs.values().iter().map(|v| v.parse::<usize>().unwrap()).sum();
+        e.emit(format!("{} {}", recs.key(), sum));
+    }
+
+    fn times_ten(v: String) -> String {
+        v + "0"
+    }
+
+    #[test]
+    fn test_value_versions() {
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .keep_temp_files(true)
+            .set_intermediate_value_version(1)
+            .set_file_locations(String::from("testdata/ctrl_versions_map_"),
+                                String::from("testdata/ctrl_versions_out_"));
+        MRController::run(ClosureMapReducer::new(word_mapper, sum_reducer),
+                          ClosureMapReducer::new(word_mapper, sum_reducer),
+                          DefaultSharder,
+                          params.clone(),
+                          get_input(),
+                          LinesSinkGenerator::new_to_files());
+        assert_eq!(read_outputs("testdata/ctrl_versions_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        let name = map_output_name(&params.map_output_location, 0, 0);
+        assert_eq!(read_value_version(&name).unwrap(), 1);
+
+        // Version 2 stores tenfold values; the retained files are converted.
+        MRController::run_reduce_only(params.set_intermediate_value_version(2)
+                                          .add_value_decoder(1, times_ten)
+                                          .keep_temp_files(false),
+                                      ClosureMapReducer::new(word_mapper, sum_reducer),
+                                      LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(read_outputs("testdata/ctrl_versions_out_", reducers),
+                   vec!["abc 30", "def 20", "ghi 10", "xyz 10"]);
+    }
+
+    #[test]
+    fn test_run_range_partitioned() {
+        let reducers = 3;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_intermediate_key_index(true)
+            .set_file_locations(String::from("testdata/ctrl_range_map_"),
+                                String::from("testdata/ctrl_range_out_"));
+        let sharder = RangeSharder::new(vec![String::from("d"), String::from("h")]);
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       sharder,
+                                       params,
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+
+        assert_eq!(result.output_paths(),
+                   vec!["testdata/ctrl_range_out_0",
+                        "testdata/ctrl_range_out_1",
+                        "testdata/ctrl_range_out_2"]);
+        let out = &result.outputs[1];
+        assert_eq!((out.shard, out.records, out.bytes), (1, 2, "def 2ghi 1".len()));
+        assert_eq!(out.key_range, Some((String::from("def"), String::from("ghi"))));
+        assert_eq!(result.outputs[2].key_range,
+                   Some((String::from("xyz"), String::from("xyz"))));
+
+        assert_eq!(lines::new_from_file(&String::from("testdata/ctrl_range_out_0"))
+                       .unwrap()
+                       .collect::<Vec<_>>(),
+                   vec!["abc 3"]);
+        assert_eq!(lines::new_from_file(&String::from("testdata/ctrl_range_out_1"))
+                       .unwrap()
+                       .collect::<Vec<_>>(),
+                   vec!["def 2", "ghi 1"]);
+        assert_eq!(read_outputs("testdata/ctrl_range_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        assert!(fs::metadata("testdata/ctrl_range_map_-0.0.idx").is_err());
+    }
+
+    #[test]
+    fn test_run_total_order() {
+        let path = String::from("testdata/ctrl_total_input.txt");
+        {
+            let mut f = fs::File::create(&path).unwrap();
+            for i in 0..300 {
+                let _ = writeln!(f, "w{:03} w{:03}", i, i % 30);
+            }
+        }
+        let reducers = 3;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_key_sample_fraction(0.5)
+            .set_file_locations(String::from("testdata/ctrl_total_map_"),
+                                String::from("testdata/ctrl_total_out_"));
+        let result =
+            MRController::run_total_order(ClosureMapReducer::new(word_mapper, count_reducer),
+                                          ClosureMapReducer::new(word_mapper, count_reducer),
+                                          params,
+                                          lines::split_file(&path, 2).unwrap(),
+                                          LinesSinkGenerator::new_to_files())
+                .unwrap();
+
+        assert_eq!(result.stats.map_input_records, 300);
+        let mut concatenated = Vec::new();
+        for out in result.outputs.iter() {
+            // Every shard receives about a third of the 600 intermediate records.
+            let counts: Vec<usize> = lines::new_from_file(&out.path)
+                .unwrap()
+                .map(|l| l.split(' ').nth(1).unwrap().parse().unwrap())
+                .collect();
+            let records: usize = counts.iter().sum();
+            assert!(records > 100 && records < 300, "{} records", records);
+            concatenated.extend(lines::new_from_file(&out.path).unwrap());
+            let _ = fs::remove_file(&out.path);
+        }
+        let mut sorted = concatenated.clone();
+        sorted.sort();
+        assert_eq!(concatenated, sorted);
+        assert_eq!(concatenated.len(), 300);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_run_shared_executor() {
+        let executor = ExecutorHandle::new(2, 0);
+        let run = |name: &'static str, executor: ExecutorHandle| {
+            let params = MRParameters::new()
+                .set_concurrency(3, 3)
+                .set_executor(executor, 1)
+                .set_file_locations(format!("testdata/ctrl_exec_{}_map_", name),
+                                    format!("testdata/ctrl_exec_{}_out_", name));
+            MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                              ClosureMapReducer::new(word_mapper, count_reducer),
+                              DefaultSharder,
+                              params,
+                              get_input(),
+                              LinesSinkGenerator::new_to_files());
+            read_outputs(&format!("testdata/ctrl_exec_{}_out_", name), 3)
+        };
+        let other = {
+            let executor = executor.clone();
+            thread::spawn(move || run("a", executor))
+        };
+        let expected = vec!["abc 3", "def 2", "ghi 1", "xyz 1"];
+        assert_eq!(run("b", executor.clone()), expected);
+        assert_eq!(other.join().unwrap(), expected);
+        assert_eq!(executor.active_jobs(), 0);
+    }
+
+    #[test]
+    fn test_run_multiplexed() {
+        let reducers = 3;
+        let location = String::from("testdata/ctrl_mux_map_");
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_multiplexed_intermediates(true)
+            .set_intermediate_key_index(true)
+            .set_intermediate_value_version(1)
+            .set_intermediate_sync_interval(16)
+            .keep_temp_files(true)
+            .set_file_locations(location.clone(), String::from("testdata/ctrl_mux_out_"));
+        let sharder = RangeSharder::new(vec![String::from("d"), String::from("h")]);
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       sharder,
+                                       params.clone(),
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        assert_eq!(result.stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_mux_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        // One file per map partition, with a segment per shard.
+        let mux = map_multiplexed_name(&location, 0);
+        let segments: Vec<_> = (0..reducers).map(|s| read_segment(&mux, s).unwrap()).collect();
+        assert!(segments[0].0 > 0 && segments[0].1 <= segments[1].0);
+        assert!(segments[1].1 <= segments[2].0);
+        assert!(read_segment(&mux, reducers).is_err());
+        assert!(fs::metadata(map_output_name(&location, 0, 0)).is_err());
+
+        let stats = MRController::run_reduce_only(params.keep_temp_files(false),
+                                                  ClosureMapReducer::new(word_mapper,
+                                                                         count_reducer),
+                                                  LinesSinkGenerator::new_to_files());
+        assert!(stats.is_err());
+        assert_eq!(cleanup_stale(&location, StaleIntermediates::Remove).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_run_many_partitions() {
+        let reducers = 2;
+        let metrics = MetricsRegistry::new();
+        // Every input record ends up in its own partition.
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_partition_size(1)
+            .set_map_queue_length(2)
+            .set_map_input_sort(MapInputSort::Auto)
+            .set_metrics(metrics.clone())
+            .set_file_locations(String::from("testdata/ctrl_parts_map_"),
+                                String::from("testdata/ctrl_parts_out_"));
+
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.map_partitions, 3);
+        // Both structures are tried, but not often enough to select one.
+        let sort = stats.map_input_sort;
+        assert_eq!((sort.btree.partitions, sort.sorted_vec.partitions, sort.selected),
+                   (2, 1, None));
+        assert_eq!(sort.btree.records + sort.sorted_vec.records, 3);
+        assert_eq!(read_outputs("testdata/ctrl_parts_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        let text = metrics.render();
+        assert!(text.contains("\nlocalmr_map_queue_depth 0\n"));
+        assert!(text.contains("\nlocalmr_active_workers 0\n"));
+        assert!(text.contains("\nlocalmr_jobs_total 1\n"));
+    }
+
+    #[test]
+    fn test_run_key_spellings() {
+        let run_spellings = |insensitive| {
+            // Every line ends up in its own partition.
+            let lines = vec![String::from("Abc abc"), String::from("ABC abc")];
+            let params = MRParameters::new()
+                .set_concurrency(2, 1)
+                .set_partition_size(1)
+                .set_reduce_group_opts(1, insensitive)
+                .set_file_locations(String::from("testdata/ctrl_spell_map_"),
+                                    String::from("testdata/ctrl_spell_out_"));
+            let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                          ClosureMapReducer::new(word_mapper, count_reducer),
+                                          DefaultSharder,
+                                          params,
+                                          PosRecordIterator::new(lines.into_iter()),
+                                          LinesSinkGenerator::new_to_files())
+                .stats;
+            assert_eq!(stats.map_partitions, 2);
+            read_outputs("testdata/ctrl_spell_out_", 1)
+        };
+        assert_eq!(run_spellings(false), vec!["ABC 1", "Abc 1", "abc 2"]);
+        assert_eq!(run_spellings(true), vec!["abc 4"]);
+    }
+
+    #[test]
+    fn test_run_in_memory() {
+        let reducers = 2;
+        let location = String::from("testdata/ctrl_mem_map_");
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_partition_size(1)
+            .keep_temp_files(true)
+            .set_in_memory_shuffle(1024)
+            .set_file_locations(location.clone(), String::from("testdata/ctrl_mem_out_"));
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       DefaultSharder,
+                                       params.clone(),
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        assert_eq!(result.stats.map_partitions, 3);
+        assert_eq!(result.stats.reduce_input_records, 7);
+        assert_eq!(result.outputs.len(), reducers);
+        assert_eq!(read_outputs("testdata/ctrl_mem_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        assert!(fs::metadata(map_output_name(&location, 0, 0)).is_err());
+
+        // The input exceeds the limit; intermediate files are written.
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params.set_in_memory_shuffle(10),
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.map_partitions, 3);
+        assert_eq!(read_outputs("testdata/ctrl_mem_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        for part in 0..3 {
+            for shard in 0..reducers {
+                let name = map_output_name(&location, part, shard);
+                assert!(fs::metadata(&name).is_ok());
+                let _ = fs::remove_file(name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_shuffle_spill() {
+        let reducers = 2;
+        let location = String::from("testdata/ctrl_spill_map_");
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_partition_size(1)
+            .set_shuffle_memory_limit(4)
+            .set_file_locations(location.clone(), String::from("testdata/ctrl_spill_out_"));
+
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.map_partitions, 3);
+        assert_eq!(stats.reduce_input_records, 7);
+        assert!(stats.shuffle_spills > 0);
+        assert!(stats.shuffle_spilled_bytes > 0);
+        assert_eq!(read_outputs("testdata/ctrl_spill_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        for shard in 0..reducers {
+            assert!(fs::metadata(shuffle_spill_name(&location, shard, 0)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_run_memory_budget() {
+        let reducers = 2;
+        let budget = MemoryBudget::new(1 << 20);
+        let registry = MetricsRegistry::new();
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_partition_size(1)
+            .set_in_memory_shuffle(1024)
+            .set_memory_budget(budget.clone())
+            .set_metrics(registry.clone())
+            .set_file_locations(String::from("testdata/ctrl_budget_map_"),
+                                String::from("testdata/ctrl_budget_out_"));
+
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_budget_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        // All partitions have released their memory.
+        assert_eq!(budget.held(), 0);
+        assert!(budget.peak() > 0);
+        assert!(registry.render().contains("\nlocalmr_memory_held_bytes 0\n"));
+    }
+
+    #[test]
+    fn test_run_hash_buckets() {
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_reduce_strategy(ReduceStrategy::HashBuckets(4))
+            .set_file_locations(String::from("testdata/ctrl_buckets_map_"),
+                                String::from("testdata/ctrl_buckets_out_"));
+
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_buckets_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        let location = String::from("testdata/ctrl_buckets_map_");
+        assert!(list_intermediate_files(&location).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_output_size_limit() {
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_output_size_limit(10, OutputLimitPolicy::Rotate)
+            .set_file_locations(String::from("testdata/ctrl_limit_map_"),
+                                String::from("testdata/ctrl_limit_out_"));
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       DefaultSharder,
+                                       params,
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        let out = &result.outputs[0];
+        assert_eq!(out.parts, vec!["testdata/ctrl_limit_out_0.1"]);
+        let part: Vec<String> = lines::new_from_file(&out.parts[0]).unwrap().collect();
+        let _ = fs::remove_file(&out.parts[0]);
+        assert_eq!(read_outputs("testdata/ctrl_limit_out_", 1), vec!["abc 3", "def 2"]);
+        assert_eq!(part, vec!["ghi 1", "xyz 1"]);
+    }
+
+    #[test]
+    fn test_run_object_store() {
+        let store = Arc::new(LocalStore::open("testdata/ctrl_store").unwrap());
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_object_store(store.clone(), StoredFiles::IntermediatesAndOutputs)
+            .set_file_locations(String::from("testdata/ctrl_store_map_"),
+                                String::from("testdata/ctrl_store_out_"));
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       DefaultSharder,
+                                       params,
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        // The intermediate objects have been deleted, and the outputs are kept in the store.
+        let location = String::from("testdata/ctrl_store_map_");
+        assert!(list_intermediate_files(&location).unwrap().is_empty());
+        assert_eq!(store.list("testdata/ctrl_store_").unwrap(),
+                   vec!["testdata/ctrl_store_out_0", "testdata/ctrl_store_out_1"]);
+        let mut outputs = Vec::new();
+        for out in result.outputs.iter() {
+            assert!(fs::metadata(&out.path).is_err());
+            store.get(&out.path, &mut outputs).unwrap();
+        }
+        let mut lines: Vec<&str> = str::from_utf8(&outputs).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(lines, vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        let _ = fs::remove_dir_all("testdata/ctrl_store");
+    }
+
+    // Routes the keys by their first letter, except for x, y and z.
+    fn first_letter(key: &str) -> String {
+        match key.chars().next() {
+            Some(c) if c < 'x' => c.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn test_run_output_routing() {
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_output_routing(first_letter, 1)
+            .set_file_locations(String::from("testdata/ctrl_route_map_"),
+                                String::from("testdata/ctrl_route_out_"));
+
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       DefaultSharder,
+                                       params,
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        let routes = &result.outputs[0].routes;
+        assert_eq!(*routes,
+                   vec!["testdata/ctrl_route_out_0-a",
+                        "testdata/ctrl_route_out_0-d",
+                        "testdata/ctrl_route_out_0-g"]);
+        let routed: Vec<Vec<String>> = routes.iter()
+            .map(|r| {
+                let lines = lines::new_from_file(r).unwrap().collect();
+                let _ = fs::remove_file(r);
+                lines
+            })
+            .collect();
+        assert_eq!(routed, vec![vec!["abc 3"], vec!["def 2"], vec!["ghi 1"]]);
+        assert_eq!(read_outputs("testdata/ctrl_route_out_", 1), vec!["xyz 1"]);
+    }
+
+    fn parse_count(line: &str) -> Option<(String, String)> {
+        let (key, count) = line.split_once(' ')?;
+        Some((String::from(key), String::from(count)))
+    }
+
+    // Adds the new occurrences of a key to its previous count.
+    fn total_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let total: usize = recs.values()
+            .iter()
+            .map(|v| previous_value(v).map_or(1, |count| count.parse().unwrap()))
+            .sum();
+        e.emit(format!("{} {}", recs.key(), total));
+    }
+
+    #[test]
+    fn test_run_warm_start() {
+        for in_memory in [false, true].iter() {
+            let mut params = MRParameters::new()
+                .set_concurrency(2, 2)
+                .set_warm_start(parse_count, None)
+                .set_file_locations(String::from("testdata/ctrl_warm_map_"),
+                                    String::from("testdata/ctrl_warm_out_"));
+            if *in_memory {
+                params = params.set_in_memory_shuffle(1 << 20);
+            }
+            for _ in 0..2 {
+                MRController::run(ClosureMapReducer::new(word_mapper, total_reducer),
+                                  ClosureMapReducer::new(word_mapper, total_reducer),
+                                  DefaultSharder,
+                                  params.clone(),
+                                  get_input(),
+                                  LinesSinkGenerator::new_to_files());
+            }
+            assert_eq!(read_outputs("testdata/ctrl_warm_out_", 2),
+                       vec!["abc 6", "def 4", "ghi 2", "xyz 2"]);
+            assert!(fs::metadata("testdata/ctrl_warm_out_0.previous").is_err());
+        }
+    }
+
+    #[test]
+    fn test_run_splits() {
+        let path = String::from("testdata/ctrl_splits_input.txt");
+        {
+            let mut f = fs::File::create(&path).unwrap();
+            for _ in 0..50 {
+                let _ = writeln!(f, "abc def");
+                let _ = writeln!(f, "ghi abc");
+            }
+        }
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(3, reducers)
+            .set_partition_size(100)
+            .set_file_locations(String::from("testdata/ctrl_splits_map_"),
+                                String::from("testdata/ctrl_splits_out_"));
+        let splits = lines::split_file(&path, 4)
+            .unwrap()
+            .into_iter()
+            .map(|s| params.input_stats.collect(PosRecordIterator::new(s.lines().unwrap())))
+            .collect();
+        let stats = MRController::run_splits(ClosureMapReducer::new(word_mapper, count_reducer),
+                                             ClosureMapReducer::new(word_mapper, count_reducer),
+                                             DefaultSharder,
+                                             params,
+                                             splits,
+                                             LinesSinkGenerator::new_to_files())
+            .stats;
+
+        assert_eq!(stats.map_input_records, 100);
+        assert_eq!((stats.input.records, stats.input.bytes), (100, 800));
+        assert!(stats.map_partitions >= 4);
+        assert_eq!(read_outputs("testdata/ctrl_splits_out_", reducers),
+                   vec!["abc 100", "def 50", "ghi 50"]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_schedule_splits() {
+        let splits = vec![(10, "a"), (30, "b"), (20, "c"), (30, "d")];
+        assert_eq!(schedule_splits(splits.clone(), MapScheduling::Fifo, 0),
+                   vec!["a", "b", "c", "d"]);
+        assert_eq!(schedule_splits(splits.clone(), MapScheduling::LargestFirst, 0),
+                   vec!["b", "d", "c", "a"]);
+        let mut shuffled = schedule_splits(splits, MapScheduling::Random, 42);
+        shuffled.sort();
+        assert_eq!(shuffled, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_run_incremental() {
+        let files = vec![String::from("testdata/ctrl_inc_in_0"),
+                         String::from("testdata/ctrl_inc_in_1")];
+        let state = String::from("testdata/ctrl_inc_state");
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_file_locations(String::from("testdata/ctrl_inc_map_"),
+                                String::from("testdata/ctrl_inc_out_"));
+        let run = || {
+            MRController::run_incremental(ClosureMapReducer::new(word_mapper, count_reducer),
+                                          ClosureMapReducer::new(word_mapper, count_reducer),
+                                          StableSharder::new(0),
+                                          params.clone(),
+                                          files.clone(),
+                                          &state,
+                                          LinesSinkGenerator::new_to_files())
+                .unwrap()
+        };
+
+        let _ = fs::remove_file(&state);
+        fs::write(&files[0], "abc def\nabc\n").unwrap();
+        fs::write(&files[1], "def ghi\n").unwrap();
+
+        let stats = run();
+        assert_eq!(stats.map_partitions, 2);
+        assert_eq!(read_outputs("testdata/ctrl_inc_out_", reducers),
+                   vec!["abc 2", "def 2", "ghi 1"]);
+
+        // Nothing changed: nothing is mapped, but the result is the same.
+        let stats = run();
+        assert_eq!(stats.map_partitions, 0);
+        assert_eq!(stats.map_input_records, 0);
+        assert_eq!(read_outputs("testdata/ctrl_inc_out_", reducers),
+                   vec!["abc 2", "def 2", "ghi 1"]);
+
+        fs::write(&files[1], "def ghi xyz\n").unwrap();
+        let stats = run();
+        assert_eq!(stats.map_partitions, 1);
+        assert_eq!(read_outputs("testdata/ctrl_inc_out_", reducers),
+                   vec!["abc 2", "def 2", "ghi 1", "xyz 1"]);
+
+        // Removing all inputs removes the retained intermediate files too.
+        let stats = MRController::run_incremental(ClosureMapReducer::new(word_mapper,
+                                                                         count_reducer),
+                                                  ClosureMapReducer::new(word_mapper,
+                                                                         count_reducer),
+                                                  StableSharder::new(0),
+                                                  params.clone(),
+                                                  vec![],
+                                                  &state,
+                                                  LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(stats.reduce_input_records, 0);
+        let _ = read_outputs("testdata/ctrl_inc_out_", reducers);
+        let _ = fs::remove_file(&state);
+        for f in files.iter() {
+            let _ = fs::remove_file(f);
+        }
+    }
+
+    // Polls until the file `name` exists; panics after 10 seconds.
+    fn wait_for_file(name: &str) {
+        for _ in 0..2000 {
+            if fs::metadata(name).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("timed out waiting for {}", name);
+    }
+
+    #[test]
+    fn test_run_watch() {
+        let dir = String::from("testdata/ctrl_watch_in");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write("testdata/ctrl_watch_in/a.log", "abc def\n").unwrap();
+
+        // Adds files once the first batch has written its outputs.
+        let writer = thread::spawn(|| {
+            wait_for_file("testdata/ctrl_watch_out_0_0");
+            fs::write("testdata/ctrl_watch_in/b.log", "abc\n").unwrap();
+            // Has the wrong suffix.
+            fs::write("testdata/ctrl_watch_in/c.txt", "xyz\n").unwrap();
+        });
+
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_file_locations(String::from("testdata/ctrl_watch_map_"),
+                                String::from("testdata/ctrl_watch_out_"));
+        let options = WatchOptions::new(dir.clone(),
+                                        String::from(".log"),
+                                        String::from("testdata/ctrl_watch_st"))
+            .set_interval(Duration::from_millis(10))
+            .set_max_batches(2);
+        let stats = MRController::run_watch(ClosureMapReducer::new(word_mapper, count_reducer),
+                                            ClosureMapReducer::new(word_mapper, count_reducer),
+                                            StableSharder::new(0),
+                                            params,
+                                            options,
+                                            LinesSinkGenerator::new_to_files())
+            .unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(stats.map_partitions, 1);
+        assert!(fs::metadata("testdata/ctrl_watch_out_0_0").is_err());
+        assert_eq!(read_outputs("testdata/ctrl_watch_out_1_", reducers),
+                   vec!["abc 2", "def 1"]);
+
+        let _ = fs::remove_dir_all(&dir);
+        for entry in fs::read_dir("testdata").unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_string_lossy().starts_with("testdata/ctrl_watch_") {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_watch_template() {
+        let dir = String::from("testdata/ctrl_watch_tmpl_in");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write("testdata/ctrl_watch_tmpl_in/a.log", "abc def\n").unwrap();
+
+        let writer = thread::spawn(|| {
+            wait_for_file("testdata/ctrl_watch_tmpl_out_0_part-00.txt");
+            fs::write("testdata/ctrl_watch_tmpl_in/b.log", "abc\n").unwrap();
+        });
+
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_file_locations(String::from("testdata/ctrl_watch_tmpl_map_"),
+                                String::from("testdata/ctrl_watch_tmpl_out_"))
+            .set_output_name_template("{prefix}part-{shard:02}.txt".parse().unwrap());
+        let options = WatchOptions::new(dir.clone(),
+                                        String::from(".log"),
+                                        String::from("testdata/ctrl_watch_tmpl_st"))
+            .set_interval(Duration::from_millis(10))
+            .set_max_batches(2);
+        MRController::run_watch(ClosureMapReducer::new(word_mapper, count_reducer),
+                                ClosureMapReducer::new(word_mapper, count_reducer),
+                                StableSharder::new(0),
+                                params,
+                                options,
+                                LinesSinkGenerator::new_to_files())
+            .unwrap();
+        writer.join().unwrap();
+
+        // The outputs of the first batch are removed by the names they were written with.
+        assert!(fs::metadata("testdata/ctrl_watch_tmpl_out_0_part-00.txt").is_err());
+        assert!(fs::metadata("testdata/ctrl_watch_tmpl_out_0_part-01.txt").is_err());
+        let mut outputs = Vec::new();
+        for shard in 0..reducers {
+            let name = format!("testdata/ctrl_watch_tmpl_out_1_part-{:02}.txt", shard);
+            outputs.extend(lines::new_from_file(&name).unwrap());
+        }
+        outputs.sort();
+        assert_eq!(outputs, vec!["abc 2", "def 1"]);
+
+        let _ = fs::remove_dir_all(&dir);
+        for entry in fs::read_dir("testdata").unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_string_lossy().starts_with("testdata/ctrl_watch_tmpl_") {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn strict_word_mapper(e: &mut MEmitter, r: Record) {
+        if r.value.contains("xyz") {
+            e.reject(&r, "contains xyz");
+            return;
+        }
+        word_mapper(e, r);
+    }
+
+    #[test]
+    fn test_run_dead_letter() {
+        let dead_letter = String::from("testdata/ctrl_dead_letter");
+        let _ = fs::remove_file(&dead_letter);
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_malformed_policy(MalformedPolicy::DeadLetter(dead_letter.clone()))
+            .set_file_locations(String::from("testdata/ctrl_dl_map_"),
+                                String::from("testdata/ctrl_dl_out_"));
+        let stats = MRController::run(ClosureMapReducer::new(strict_word_mapper, count_reducer),
+                                      ClosureMapReducer::new(strict_word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+
+        assert_eq!(stats.records_malformed, 1);
+        assert_eq!(read_outputs("testdata/ctrl_dl_out_", 1),
+                   vec!["abc 1", "def 2", "ghi 1"]);
+        let rejected: Vec<String> = WriteLogReader::new_from_file(&dead_letter).unwrap().collect();
+        assert_eq!(rejected, vec!["abc abc xyz", "contains xyz"]);
+        let _ = fs::remove_file(&dead_letter);
+    }
+
+    fn terminating_mapper(e: &mut MEmitter, r: Record) {
+        let found = r.value.contains("ghi");
+        word_mapper(e, r);
+        if found {
+            e.terminate();
+        }
+    }
+
+    #[test]
+    fn test_run_concurrent_jobs() {
+        // Defaults shared by the jobs of a process; every job runs in a directory of its own,
+        // with a termination flag and malformed record counter of its own.
+        let template = MRParameters::new().set_concurrency(2, 2);
+        let run = |dir: &str, mapper: MapperF| {
+            fs::create_dir_all(dir).unwrap();
+            let params = template.clone().isolate().set_working_dir(dir);
+            let stats = MRController::run(ClosureMapReducer::new(mapper, count_reducer),
+                                          ClosureMapReducer::new(mapper, count_reducer),
+                                          DefaultSharder,
+                                          params,
+                                          get_input(),
+                                          LinesSinkGenerator::new_to_files())
+                .stats;
+            let outputs = read_outputs(&format!("{}/output_", dir), 2);
+            let _ = fs::remove_dir_all(dir);
+            (stats, outputs)
+        };
+        let (strict, terminated) = thread::scope(|scope| {
+            let strict = scope.spawn(|| run("testdata/ctrl_concurrent_a", strict_word_mapper));
+            let terminated =
+                scope.spawn(|| run("testdata/ctrl_concurrent_b", terminating_mapper));
+            (strict.join().unwrap(), terminated.join().unwrap())
+        });
+        assert_eq!(strict.0.records_malformed, 1);
+        assert!(!strict.0.truncated);
+        assert_eq!(strict.1, vec!["abc 1", "def 2", "ghi 1"]);
+        assert_eq!(terminated.0.records_malformed, 0);
+        assert!(terminated.0.truncated);
+
+        // Jobs using the same locations run one after another instead of overwriting each
+        // other's files.
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_file_locations(String::from("testdata/ctrl_shared_map_"),
+                                String::from("testdata/ctrl_shared_out_"));
+        let run = || {
+            MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                              ClosureMapReducer::new(word_mapper, count_reducer),
+                              DefaultSharder,
+                              params.clone(),
+                              get_input(),
+                              LinesSinkGenerator::new_to_files())
+                .stats
+        };
+        let stats = thread::scope(|scope| {
+            let other = scope.spawn(run);
+            vec![run(), other.join().unwrap()]
+        });
+        for s in stats {
+            assert_eq!(s.reduce_input_records, 7);
+            assert!(!s.truncated);
+        }
+        assert_eq!(read_outputs("testdata/ctrl_shared_out_", 2),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+    }
+
+    fn stop_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit(String::from(w), String::from("1"));
+            if w == "stop" {
+                e.terminate();
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_terminate_shuffles() {
+        let input = || {
+            let lines = vec!["a b c d e f g stop", "h i"];
+            PosRecordIterator::new(lines.into_iter().map(String::from))
+        };
+        let params = MRParameters::new()
+            .set_concurrency(1, 1)
+            .set_file_locations(String::from("testdata/ctrl_term_shuffle_map_"),
+                                String::from("testdata/ctrl_term_shuffle_out_"));
+        // Intermediate files, the in-memory shuffle, and the in-memory shuffle spilling to disk.
+        let jobs = vec![params.clone(),
+                        params.clone().set_in_memory_shuffle(1024),
+                        params.set_shuffle_memory_limit(4)];
+        let mut outputs = Vec::new();
+        for (i, params) in jobs.into_iter().enumerate() {
+            let stats = MRController::run(ClosureMapReducer::new(stop_mapper, count_reducer),
+                                          ClosureMapReducer::new(stop_mapper, count_reducer),
+                                          DefaultSharder,
+                                          params,
+                                          input(),
+                                          LinesSinkGenerator::new_to_files())
+                .stats;
+            assert!(stats.truncated);
+            assert_eq!(stats.shuffle_spills > 0, i == 2);
+            outputs.push(read_outputs("testdata/ctrl_term_shuffle_out_", 1));
+        }
+        assert_eq!(outputs[0],
+                   vec!["a 1", "b 1", "c 1", "d 1", "e 1", "f 1", "g 1", "stop 1"]);
+        assert_eq!(outputs[1], outputs[0]);
+        assert_eq!(outputs[2], outputs[0]);
+    }
+
+    fn terminating_reducer(e: &mut REmitter, recs: MultiRecord) {
+        count_reducer(e, recs);
+        e.terminate();
+    }
+
+    #[test]
+    fn test_run_terminate() {
+        let params = MRParameters::new()
+            .set_concurrency(1, 1)
+            .set_file_locations(String::from("testdata/ctrl_term_map_"),
+                                String::from("testdata/ctrl_term_out_"));
+        let stats = MRController::run(ClosureMapReducer::new(terminating_mapper, count_reducer),
+                                      ClosureMapReducer::new(terminating_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params.clone(),
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert!(stats.truncated);
+        assert_eq!(read_outputs("testdata/ctrl_term_out_", 1),
+                   vec!["abc 1", "def 2", "ghi 1"]);
+
+        // The flag has been reset, so that the parameters can be reused.
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, terminating_reducer),
+                                      ClosureMapReducer::new(word_mapper, terminating_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert!(stats.truncated);
+        assert_eq!(stats.map_input_records, 3);
+        assert_eq!(read_outputs("testdata/ctrl_term_out_", 1), vec!["abc 3"]);
+    }
+
+    #[test]
+    fn test_reduce_dataset() {
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .keep_temp_files(true)
+            .set_file_locations(String::from("testdata/ctrl_ds_map_"),
+                                String::from("testdata/ctrl_ds_out_"));
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      StableSharder::new(1),
+                                      params.clone(),
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        let _ = read_outputs("testdata/ctrl_ds_out_", reducers);
+        let dataset =
+            Dataset::from_intermediates(&params, &StableSharder::new(1), stats.map_partitions);
+
+        // Same partitioning: the intermediate files are reduced directly.
+        let stats = MRController::<IdentityMapper, _, _>::reduce_dataset(
+            ClosureMapReducer::new(word_mapper, count_reducer),
+            StableSharder::new(1),
+            params.clone(),
+            &dataset,
+            LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(stats.map_partitions, 0);
+        assert_eq!(read_outputs("testdata/ctrl_ds_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        // Different number of reducers: the records are re-sharded.
+        let params = params.set_concurrency(2, 3)
+            .keep_temp_files(false)
+            .set_file_locations(String::from("testdata/ctrl_ds_map2_"),
+                                String::from("testdata/ctrl_ds_out_"));
+        let stats = MRController::<IdentityMapper, _, _>::reduce_dataset(
+            ClosureMapReducer::new(word_mapper, count_reducer),
+            StableSharder::new(1),
+            params,
+            &dataset,
+            LinesSinkGenerator::new_to_files())
+            .unwrap();
+        assert_eq!(stats.map_partitions, 1);
+        assert_eq!(read_outputs("testdata/ctrl_ds_out_", 3),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        for file in dataset.files() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    fn key_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_key(String::from(w));
+        }
+    }
+
+    #[test]
+    fn test_run_key_only() {
+        let params = MRParameters::new()
+            .set_concurrency(1, 1)
+            .set_key_only(true)
+            .keep_temp_files(true)
+            .set_file_locations(String::from("testdata/ctrl_keys_map_"),
+                                String::from("testdata/ctrl_keys_out_"));
+        let stats = MRController::run(ClosureMapReducer::new(key_mapper, count_reducer),
+                                      ClosureMapReducer::new(key_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.reduce_input_records, 7);
+        assert_eq!(read_outputs("testdata/ctrl_keys_out_", 1),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+
+        // The intermediate file contains one entry per key.
+        let name = String::from("testdata/ctrl_keys_map_-0.0");
+        assert_eq!(WriteLogReader::new_from_file(&name).unwrap().count(), 7);
+        let _ = fs::remove_file(name);
+    }
+
+    fn str_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn str_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit_str(&format!("{} {}", recs.key(), recs.values().len()));
+    }
+
+    #[test]
+    fn test_run_emit_str() {
+        let reducers = 2;
+        let params = MRParameters::new()
+            .set_concurrency(2, reducers)
+            .set_file_locations(String::from("testdata/ctrl_str_map_"),
+                                String::from("testdata/ctrl_str_out_"));
+        MRController::run(ClosureMapReducer::new(str_mapper, str_reducer),
+                          ClosureMapReducer::new(str_mapper, str_reducer),
+                          DefaultSharder,
+                          params,
+                          get_input(),
+                          LinesSinkGenerator::new_to_files());
+        assert_eq!(read_outputs("testdata/ctrl_str_out_", reducers),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+    }
+
+    #[test]
+    fn test_run_output_name_template() {
+        let params = MRParameters::new()
+            .set_concurrency(1, 2)
+            .set_job_name("words")
+            .set_output_name_template("{prefix}{job}/part-{shard:03}".parse().unwrap())
+            .set_file_locations(String::from("testdata/ctrl_tmpl_map_"),
+                                String::from("testdata/ctrl_tmpl_"));
+        let result = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                       ClosureMapReducer::new(word_mapper, count_reducer),
+                                       DefaultSharder,
+                                       params,
+                                       get_input(),
+                                       LinesSinkGenerator::new_to_files());
+        assert_eq!(result.output_paths(),
+                   vec!["testdata/ctrl_tmpl_words/part-000", "testdata/ctrl_tmpl_words/part-001"]);
+        let mut results = Vec::new();
+        for path in result.output_paths() {
+            results.extend(lines::new_from_file(&path).unwrap());
+        }
+        results.sort();
+        assert_eq!(results, vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        let _ = fs::remove_dir_all("testdata/ctrl_tmpl_words");
+    }
+
+    #[test]
+    fn test_run_key_histogram() {
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_key_histogram(2)
+            .set_file_locations(String::from("testdata/ctrl_hist_map_"),
+                                String::from("testdata/ctrl_hist_out_"));
+        let stats = MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer),
+                                      DefaultSharder,
+                                      params,
+                                      get_input(),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        read_outputs("testdata/ctrl_hist_out_", 2);
+        let top: Vec<(String, u64)> = stats.key_histogram
+            .unwrap()
+            .top_by_records()
+            .into_iter()
+            .map(|c| (c.key, c.records))
+            .collect();
+        assert_eq!(top, vec![(String::from("abc"), 3), (String::from("def"), 2)]);
+
+        let name = "testdata/ctrl_hist_out_key_histogram";
+        let written = fs::read_to_string(name).unwrap();
+        assert_eq!(written.lines().next(), Some("records\tabc\t3\t12"));
+        let _ = fs::remove_file(name);
+    }
+
+    #[test]
+    fn test_run_sync_markers() {
+        let params = MRParameters::new()
+            .set_concurrency(1, 2)
+            .set_intermediate_sync_interval(1)
+            .set_intermediate_key_index(true)
+            .set_recover_intermediates(true)
+            .set_file_locations(String::from("testdata/ctrl_sync_map_"),
+                                String::from("testdata/ctrl_sync_out_"));
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params,
+                          get_input(),
+                          LinesSinkGenerator::new_to_files());
+        assert_eq!(read_outputs("testdata/ctrl_sync_out_", 2),
+                   vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+    }
+
+    #[test]
+    fn test_run_catalog() {
+        let dir = "testdata/ctrl_catalog";
+        let catalog = Catalog::open(dir).unwrap();
+        let params = MRParameters::new()
+            .set_concurrency(1, 2)
+            .set_job_name("words")
+            .set_catalog(catalog.clone())
+            .set_file_locations(String::from("testdata/ctrl_catalog_map_"),
+                                String::from("testdata/ctrl_catalog_out_"));
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params,
+                          get_input(),
+                          LinesSinkGenerator::new_to_files());
+        assert_eq!(catalog.names().unwrap(), vec!["words"]);
+        let dataset = catalog.lookup("words").unwrap();
+        assert_eq!(dataset.shards, 2);
+        assert_eq!(dataset.job_parameter("name"), Some("words"));
+        // The output is the input of the next job.
+        let mut lines: Vec<String> = catalog.input("words").unwrap().map(|r| r.value).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["abc 3", "def 2", "ghi 1", "xyz 1"]);
+        read_outputs("testdata/ctrl_catalog_out_", 2);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_run_key_encodings() {
+        for &(encoding, multiplexed) in &[(KeyEncoding::Runs, false),
+                                          (KeyEncoding::Dictionary, false),
+                                          (KeyEncoding::Dictionary, true)] {
+            // Sync markers every few records force keys to be repeated within a run of records.
+            let params = MRParameters::new()
+                .set_concurrency(1, 2)
+                .set_intern_keys(true)
+                .set_intermediate_key_encoding(encoding)
+                .set_multiplexed_intermediates(multiplexed)
+                .set_intermediate_sync_interval(40)
+                .set_file_locations(String::from("testdata/ctrl_dict_map_"),
+                                    String::from("testdata/ctrl_dict_out_"));
+            let input = vec![String::from("abc abc abc abc abc def"), String::from("def abc ghi")];
+            MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                              ClosureMapReducer::new(word_mapper, count_reducer),
+                              DefaultSharder,
+                              params,
+                              PosRecordIterator::new(input.into_iter()),
+                              LinesSinkGenerator::new_to_files());
+            assert_eq!(read_outputs("testdata/ctrl_dict_out_", 2),
+                       vec!["abc 6", "def 2", "ghi 1"],
+                       "{:?}",
+                       encoding);
+        }
+    }
+
+    fn emit_tagged(e: &mut MEmitter, r: Record, tag: &str) {
+        let mut fields = r.value.split_whitespace();
+        if let (Some(k), Some(v)) = (fields.next(), fields.next()) {
+            e.emit(String::from(k), format!("{}{}", tag, v));
+        }
+    }
+
+    fn left_mapper(e: &mut MEmitter, r: Record) {
+        emit_tagged(e, r, "L")
+    }
+
+    fn right_mapper(e: &mut MEmitter, r: Record) {
+        emit_tagged(e, r, "R")
+    }
+
+    fn join_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let mut values = recs.values().clone();
+        values.sort();
+        e.emit(format!("{} {}", recs.key(), values.join(" ")));
+    }
+
+    #[test]
+    fn test_reduce_join() {
+        let reducers = 2;
+        let mut datasets = Vec::new();
+        let sides: Vec<(&str, MapperF, Vec<&str>)> =
+            vec![("L", left_mapper, vec!["alice 1", "bob 2", "carol 3"]),
+                 ("R", right_mapper, vec!["bob b", "carol c", "dave d", "eve e"])];
+        for (tag, mapper, lines) in sides {
+            let params = MRParameters::new()
+                .set_concurrency(1, reducers)
+                .keep_temp_files(true)
+                .set_intermediate_bloom_filter(10)
+                .set_file_locations(format!("testdata/ctrl_join_{}_map_", tag),
+                                    String::from("testdata/ctrl_join_out_"));
+            let input: Vec<String> = lines.into_iter().map(String::from).collect();
+            let mr = ClosureMapReducer::new(mapper, join_reducer);
+            let stats = MRController::run(mr.clone(),
+                                          mr,
+                                          StableSharder::new(1),
+                                          params.clone(),
+                                          PosRecordIterator::new(input.into_iter()),
+                                          LinesSinkGenerator::new_to_files())
+                .stats;
+            let _ = read_outputs("testdata/ctrl_join_out_", reducers);
+            datasets.push(Dataset::from_intermediates(&params,
+                                                      &StableSharder::new(1),
+                                                      stats.map_partitions));
+        }
+
+        let params = MRParameters::new()
+            .set_concurrency(1, reducers)
+            .set_file_locations(String::from("testdata/ctrl_join_map_"),
+                                String::from("testdata/ctrl_join_out_"));
+        let stats = MRController::<IdentityMapper, _, _>::reduce_join(
+            ClosureMapReducer::new(left_mapper, join_reducer),
+            StableSharder::new(1),
+            params,
+            &[&datasets[0], &datasets[1]],
+            LinesSinkGenerator::new_to_files())
+            .unwrap();
+        // Keys without join partner are skipped before reducing.
+        assert_eq!(stats.reduce_input_records, 4);
+        assert_eq!(read_outputs("testdata/ctrl_join_out_", reducers),
+                   vec!["bob L2 Rb", "carol L3 Rc"]);
+
+        for dataset in datasets {
+            for file in dataset.files() {
+                let _ = fs::remove_file(map_bloom_name(&file));
+                let _ = fs::remove_file(file);
+            }
         }
     }
 }
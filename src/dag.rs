@@ -0,0 +1,257 @@
+//! Execution of several dependent jobs as a graph. Every node of a `JobGraph` is a job (usually
+//! a mapreduce run via `MRController`, but any function producing `JobStats` will do, e.g. a
+//! map-only transformation) that reads and writes named datasets. A node runs once all nodes
+//! producing its input datasets have finished; independent nodes run in parallel.
+//!
+//! Datasets that no node produces are expected to exist before the graph is run. As nodes may
+//! run at the same time, every job must use its own location for intermediate files
+//! (`MRParameters::set_file_locations()`).
+
+use stats::JobStats;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::mpsc::channel;
+use std::thread;
+
+type JobF = Box<dyn FnOnce() -> io::Result<JobStats> + Send>;
+
+struct Node {
+    name: String,
+    inputs: Vec<String>,
+    job: JobF,
+}
+
+/// A set of jobs with dependencies between them, declared by the datasets they read and write.
+pub struct JobGraph {
+    nodes: Vec<Node>,
+    // dataset -> index of producing node
+    producers: BTreeMap<String, usize>,
+    parallelism: usize,
+}
+
+impl Default for JobGraph {
+    fn default() -> JobGraph {
+        JobGraph::new()
+    }
+}
+
+impl JobGraph {
+    pub fn new() -> JobGraph {
+        JobGraph {
+            nodes: Vec::new(),
+            producers: BTreeMap::new(),
+            parallelism: 2,
+        }
+    }
+
+    /// How many jobs may run at the same time. Note that every job uses its own mapper and
+    /// reducer threads.
+    ///
+    /// Default: 2
+    pub fn set_parallelism(mut self, n: usize) -> JobGraph {
+        self.parallelism = n.max(1);
+        self
+    }
+
+    /// Adds a job called `name` reading the datasets `inputs` and writing the datasets `outputs`.
+    /// Returns an error if the name is already used or if another job writes one of the outputs.
+    pub fn add_job<F>(&mut self,
+                      name: &str,
+                      inputs: &[&str],
+                      outputs: &[&str],
+                      job: F)
+                      -> io::Result<()>
+        where F: FnOnce() -> io::Result<JobStats> + Send + 'static
+    {
+        if self.nodes.iter().any(|n| n.name == name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Duplicate job name {}", name)));
+        }
+        for output in outputs {
+            if self.producers.contains_key(*output) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Dataset {} is written by several jobs",
+                                                  output)));
+            }
+        }
+
+        let index = self.nodes.len();
+        for output in outputs {
+            self.producers.insert(String::from(*output), index);
+        }
+        self.nodes.push(Node {
+            name: String::from(name),
+            inputs: inputs.iter().map(|i| String::from(*i)).collect(),
+            job: Box::new(job),
+        });
+        Ok(())
+    }
+
+    /// For every node, returns the indices of the nodes depending on it, and the number of nodes
+    /// it depends on.
+    fn dependencies(&self) -> (Vec<Vec<usize>>, Vec<usize>) {
+        let mut dependents = vec![Vec::new(); self.nodes.len()];
+        let mut pending = vec![0; self.nodes.len()];
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut producers: Vec<usize> =
+                node.inputs.iter().filter_map(|d| self.producers.get(d).cloned()).collect();
+            producers.sort();
+            producers.dedup();
+            for p in producers {
+                dependents[p].push(i);
+                pending[i] += 1;
+            }
+        }
+        (dependents, pending)
+    }
+
+    /// Returns the job names in an order in which they can be run, or an error if the
+    /// dependencies contain a cycle.
+    pub fn order(&self) -> io::Result<Vec<String>> {
+        let (dependents, mut pending) = self.dependencies();
+        let mut ready: VecDeque<usize> =
+            (0..self.nodes.len()).filter(|&i| pending[i] == 0).collect();
+        let mut order = Vec::new();
+
+        while let Some(i) = ready.pop_front() {
+            order.push(self.nodes[i].name.clone());
+            for &d in dependents[i].iter() {
+                pending[d] -= 1;
+                if pending[d] == 0 {
+                    ready.push_back(d);
+                }
+            }
+        }
+
+        if order.len() < self.nodes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The job dependencies contain a cycle"));
+        }
+        Ok(order)
+    }
+
+    /// Runs all jobs in dependency order and returns the statistics of every job by name. If a
+    /// job fails, no further jobs are started, and the first error is returned once the running
+    /// jobs have finished.
+    pub fn run(self) -> io::Result<BTreeMap<String, JobStats>> {
+        self.order()?;
+        let (dependents, mut pending) = self.dependencies();
+        let parallelism = self.parallelism;
+
+        let mut names = Vec::with_capacity(self.nodes.len());
+        let mut jobs = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes {
+            names.push(node.name);
+            jobs.push(Some(node.job));
+        }
+
+        let mut ready: VecDeque<usize> = (0..jobs.len()).filter(|&i| pending[i] == 0).collect();
+        let (send, recv) = channel();
+        let mut running = 0;
+        let mut stats = BTreeMap::new();
+        let mut error = None;
+
+        loop {
+            while error.is_none() && running < parallelism {
+                let i = match ready.pop_front() {
+                    None => break,
+                    Some(i) => i,
+                };
+                let job = jobs[i].take().unwrap();
+                let done = send.clone();
+                thread::spawn(move || {
+                    let _ = done.send((i, job()));
+                });
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+
+            let (i, result) = match recv.recv() {
+                Err(_) => break,
+                Ok(r) => r,
+            };
+            running -= 1;
+            match result {
+                Err(e) => {
+                    if error.is_none() {
+                        error = Some(io::Error::new(e.kind(),
+                                                    format!("Job {} failed: {}", names[i], e)));
+                    }
+                }
+                Ok(s) => {
+                    stats.insert(names[i].clone(), s);
+                    for &d in dependents[i].iter() {
+                        pending[d] -= 1;
+                        if pending[d] == 0 {
+                            ready.push_back(d);
+                        }
+                    }
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(stats),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobGraph;
+    use stats::JobStats;
+
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    fn logging_job(name: &'static str,
+                   log: &Arc<Mutex<Vec<&'static str>>>)
+                   -> impl FnOnce() -> io::Result<JobStats> + Send + 'static {
+        let log = log.clone();
+        move || {
+            log.lock().unwrap().push(name);
+            let mut stats = JobStats::new();
+            stats.map_partitions = 1;
+            Ok(stats)
+        }
+    }
+
+    #[test]
+    fn test_job_graph() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut graph = JobGraph::new().set_parallelism(3);
+        graph.add_job("join", &["counts", "users"], &["report"], logging_job("join", &log))
+            .unwrap();
+        graph.add_job("count", &["logs"], &["counts"], logging_job("count", &log)).unwrap();
+        graph.add_job("users", &[], &["users"], logging_job("users", &log)).unwrap();
+        assert!(graph.add_job("count2", &[], &["counts"], logging_job("count", &log)).is_err());
+
+        assert_eq!(graph.order().unwrap(), vec!["count", "users", "join"]);
+        let stats = graph.run().unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats["join"].map_partitions, 1);
+        assert_eq!(log.lock().unwrap().last(), Some(&"join"));
+    }
+
+    #[test]
+    fn test_job_graph_errors() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut graph = JobGraph::new();
+        graph.add_job("a", &["y"], &["x"], logging_job("a", &log)).unwrap();
+        graph.add_job("b", &["x"], &["y"], logging_job("b", &log)).unwrap();
+        assert!(graph.order().is_err());
+        assert!(graph.run().is_err());
+        assert!(log.lock().unwrap().is_empty());
+
+        let mut graph = JobGraph::new();
+        graph.add_job("fail", &[], &["x"], || Err(io::Error::other("broken"))).unwrap();
+        graph.add_job("after", &["x"], &[], logging_job("after", &log)).unwrap();
+        assert!(graph.run().is_err());
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
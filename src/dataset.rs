@@ -0,0 +1,325 @@
+//! Datasets: the outputs of jobs, described by a small manifest, so that subsequent jobs can use
+//! them as input. The manifest records the format of the files, how the records are partitioned
+//! into shards, whether they are sorted, and which datasets they were derived from (lineage).
+//!
+//! Datasets can be registered by name in a `catalog::Catalog`, which jobs update automatically
+//! (see `MRParameters::set_catalog()`).
+//!
+//! A dataset of intermediate files (kept with `MRParameters::keep_temp_files()`) is already
+//! sharded and sorted; `MRController::reduce_dataset()` reduces it directly, without running the
+//! map phase again, if the partitioning is the same as that of the job.
+
+use formats::lines;
+use formats::schema::Schema;
+use formats::util::PosRecordIterator;
+use formats::writelog::{FilteredRecordReader, WriteLogReader};
+use mapreducer::{Mapper, Sharder};
+use parameters::MRParameters;
+use phases::output::{load_key_dictionary, map_output_name};
+use record_types::{MEmitter, Record};
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+/// How the files of a dataset are stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatasetFormat {
+    /// Reduce outputs: One text file per shard, named `<location><shard>`.
+    Lines,
+    /// Intermediate files of the map phase: WriteLog files containing (key, value) records
+    /// sorted by key, named `<location>-<partition>.<shard>`.
+    Intermediate,
+}
+
+/// Describes a dataset. See the module documentation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dataset {
+    pub location: String,
+    pub format: DatasetFormat,
+    /// Number of shards.
+    pub shards: usize,
+    /// Number of map partitions (only for `Intermediate` datasets).
+    pub partitions: usize,
+    /// The partitioning of the shards, as returned by `Sharder::partitioning()`.
+    pub partitioning: Option<String>,
+    /// Whether the records of every file are sorted by key.
+    pub sorted: bool,
+    /// Whether the records have no values (only for `Intermediate` datasets; see
+    /// `MRParameters::set_key_only()`).
+    pub key_only: bool,
+    /// The names of the datasets this one was derived from.
+    pub lineage: Vec<String>,
+    /// The columns of the records, if they are delimited text.
+    pub schema: Option<Schema>,
+    /// The parameters of the job that produced the dataset, as (name, value) pairs.
+    pub job: Vec<(String, String)>,
+}
+
+/// Returns the parameters of a job that are recorded in the manifests of its datasets.
+fn job_parameters(params: &MRParameters) -> Vec<(String, String)> {
+    vec![(String::from("name"), params.job_name.clone()),
+         (String::from("mappers"), params.mappers.to_string()),
+         (String::from("reducers"), params.reducers.to_string()),
+         (String::from("map_partition_size"), params.map_partition_size.to_string()),
+         (String::from("stable_merge"), params.stable_merge.to_string())]
+}
+
+impl Dataset {
+    /// Describes the output of a job with `params` that used `sharder`.
+    pub fn from_output<S: Sharder>(params: &MRParameters, sharder: &S) -> Dataset {
+        Dataset {
+            location: params.reduce_output_shard_prefix.clone(),
+            format: DatasetFormat::Lines,
+            shards: params.reducers,
+            partitions: 0,
+            partitioning: sharder.partitioning(),
+            sorted: true,
+            key_only: false,
+            lineage: Vec::new(),
+            schema: None,
+            job: job_parameters(params),
+        }
+    }
+
+    /// Describes the intermediate files kept by a job with `params` that used `sharder` and ran
+    /// `partitions` map partitions (see `JobStats::map_partitions`).
+    pub fn from_intermediates<S: Sharder>(params: &MRParameters,
+                                          sharder: &S,
+                                          partitions: usize)
+                                          -> Dataset {
+        Dataset {
+            location: params.map_output_location.clone(),
+            format: DatasetFormat::Intermediate,
+            shards: params.reducers,
+            partitions,
+            partitioning: sharder.partitioning(),
+            sorted: params.sorts_intermediates(),
+            key_only: params.key_only,
+            lineage: Vec::new(),
+            schema: None,
+            job: job_parameters(params),
+        }
+    }
+
+    /// Records that this dataset was derived from the dataset `name`.
+    pub fn derived_from(mut self, name: &str) -> Dataset {
+        self.lineage.push(String::from(name));
+        self
+    }
+
+    /// Records the columns of the records.
+    pub fn with_schema(mut self, schema: Schema) -> Dataset {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Returns the parameter `name` of the job that produced the dataset.
+    pub fn job_parameter(&self, name: &str) -> Option<&str> {
+        self.job.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns true if the records of this dataset are sharded and sorted like a job with
+    /// `params` and `sharder` would shard and sort them.
+    pub fn is_partitioned_like<S: Sharder>(&self, params: &MRParameters, sharder: &S) -> bool {
+        self.format == DatasetFormat::Intermediate && self.sorted &&
+        self.shards == params.reducers && self.key_only == params.key_only &&
+        self.partitioning.is_some() && self.partitioning == sharder.partitioning()
+    }
+
+    /// Returns the names of all files of the dataset.
+    pub fn files(&self) -> Vec<String> {
+        match self.format {
+            DatasetFormat::Lines => {
+                (0..self.shards).map(|s| format!("{}{}", self.location, s)).collect()
+            }
+            DatasetFormat::Intermediate => {
+                let mut files = Vec::new();
+                for part in 0..self.partitions {
+                    for shard in 0..self.shards {
+                        files.push(map_output_name(&self.location, part, shard));
+                    }
+                }
+                files
+            }
+        }
+    }
+
+    /// Returns an iterator over all records of the dataset, e.g. for use as input of the map
+    /// phase. Records of `Lines` datasets are numbered like by `PosRecordIterator`.
+    pub fn records(&self) -> io::Result<Box<dyn Iterator<Item = Record>>> {
+        let mut readers = Vec::new();
+        for file in self.files() {
+            readers.push((fs::File::open(&file)?, file));
+        }
+        Ok(match self.format {
+            DatasetFormat::Lines => {
+                let lines = readers.into_iter().flat_map(|(f, _)| lines::LinesReader::new(f));
+                Box::new(PosRecordIterator::new(lines))
+            }
+            DatasetFormat::Intermediate => {
+                let key_only = self.key_only;
+                Box::new(readers.into_iter().flat_map(move |(f, name)| {
+                    let reader = WriteLogReader::new(Box::new(io::BufReader::new(f)));
+                    FilteredRecordReader::new(reader, None)
+                        .set_key_dictionary(load_key_dictionary(&name))
+                        .set_key_only(key_only)
+                }))
+            }
+        })
+    }
+
+    /// Like `records()`, but the records are keyed by their position, and the original key is
+    /// packed into the value; `RekeyMapper` restores the original records. This allows using
+    /// datasets with repeated keys (e.g. intermediate files) as input of the map phase, which
+    /// expects unique keys.
+    pub fn numbered_records(&self) -> io::Result<Box<dyn Iterator<Item = Record>>> {
+        Ok(Box::new(self.records()?.enumerate().map(|(i, r)| {
+            Record {
+                key: (i + 1).to_string(),
+                value: format!("{}\t{}{}", r.key.len(), r.key, r.value),
+            }
+        })))
+    }
+
+    /// Writes the manifest to `path`.
+    pub fn save(&self, path: &String) -> io::Result<()> {
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        let format = match self.format {
+            DatasetFormat::Lines => "lines",
+            DatasetFormat::Intermediate => "intermediate",
+        };
+        writeln!(f, "location\t{}", self.location)?;
+        writeln!(f, "format\t{}", format)?;
+        writeln!(f, "shards\t{}", self.shards)?;
+        writeln!(f, "partitions\t{}", self.partitions)?;
+        if let Some(ref partitioning) = self.partitioning {
+            writeln!(f, "partitioning\t{}", partitioning)?;
+        }
+        writeln!(f, "sorted\t{}", self.sorted)?;
+        writeln!(f, "key_only\t{}", self.key_only)?;
+        for parent in self.lineage.iter() {
+            writeln!(f, "lineage\t{}", parent)?;
+        }
+        // The separator, followed by the names separated by it.
+        if let Some(ref schema) = self.schema {
+            let separator = schema.separator().to_string();
+            writeln!(f, "schema\t{}{}", separator, schema.names().join(&separator))?;
+        }
+        for (name, value) in self.job.iter() {
+            writeln!(f, "job\t{}={}", name, value)?;
+        }
+        f.flush()
+    }
+
+    /// Reads a manifest written by `save()`.
+    pub fn load(path: &String) -> io::Result<Dataset> {
+        let invalid = |what: &str| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Invalid dataset manifest {}: {}", path, what))
+        };
+        let mut dataset = Dataset {
+            location: String::new(),
+            format: DatasetFormat::Lines,
+            shards: 0,
+            partitions: 0,
+            partitioning: None,
+            sorted: false,
+            key_only: false,
+            lineage: Vec::new(),
+            schema: None,
+            job: Vec::new(),
+        };
+
+        for line in io::BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            let mut fields = line.splitn(2, '\t');
+            let (name, value) = match (fields.next(), fields.next()) {
+                (Some(n), Some(v)) => (n, v),
+                _ => continue,
+            };
+            match name {
+                "location" => dataset.location = String::from(value),
+                "format" => {
+                    dataset.format = match value {
+                        "lines" => DatasetFormat::Lines,
+                        "intermediate" => DatasetFormat::Intermediate,
+                        _ => return Err(invalid(&line)),
+                    }
+                }
+                "shards" => dataset.shards = value.parse().map_err(|_| invalid(&line))?,
+                "partitions" => dataset.partitions = value.parse().map_err(|_| invalid(&line))?,
+                "partitioning" => dataset.partitioning = Some(String::from(value)),
+                "sorted" => dataset.sorted = value == "true",
+                "key_only" => dataset.key_only = value == "true",
+                "lineage" => dataset.lineage.push(String::from(value)),
+                "schema" => {
+                    let mut chars = value.chars();
+                    let separator = chars.next().ok_or_else(|| invalid(&line))?;
+                    dataset.schema = Some(Schema::from_header(chars.as_str(), separator));
+                }
+                "job" => {
+                    let (name, value) = value.split_once('=').ok_or_else(|| invalid(&line))?;
+                    dataset.job.push((String::from(name), String::from(value)));
+                }
+                // Ignore unknown fields written by newer versions.
+                _ => (),
+            }
+        }
+        if dataset.location.is_empty() || dataset.shards == 0 {
+            return Err(invalid("location or shards missing"));
+        }
+        Ok(dataset)
+    }
+}
+
+/// A Mapper restoring the records packed by `Dataset::numbered_records()`.
+#[derive(Clone)]
+pub struct RekeyMapper;
+
+impl Mapper for RekeyMapper {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        let mut packed = record.value.splitn(2, '\t');
+        let (len, rest) = match (packed.next().map(str::parse::<usize>), packed.next()) {
+            (Some(Ok(len)), Some(rest)) if rest.is_char_boundary(len) => (len, rest),
+            _ => return em.reject(&record, "not a packed record"),
+        };
+        em.emit(String::from(&rest[..len]), String::from(&rest[len..]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dataset, DatasetFormat};
+    use formats::schema::Schema;
+    use mapreducer::{DefaultSharder, StableSharder};
+    use parameters::MRParameters;
+    use std::fs;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let path = String::from("testdata/dataset_manifest");
+        let params = MRParameters::new()
+            .set_concurrency(2, 3)
+            .set_file_locations(String::from("testdata/ds_map_"), String::from("testdata/ds_out_"));
+        let dataset = Dataset::from_intermediates(&params, &StableSharder::new(7), 2)
+            .derived_from("logs")
+            .with_schema(Schema::from_header("name\tcount", '\t'));
+        dataset.save(&path).unwrap();
+
+        let loaded = Dataset::load(&path).unwrap();
+        assert_eq!(loaded, dataset);
+        assert_eq!(loaded.format, DatasetFormat::Intermediate);
+        assert_eq!(loaded.files().len(), 6);
+        assert_eq!(loaded.schema.as_ref().unwrap().index("count"), Some(1));
+        assert_eq!(loaded.job_parameter("reducers"), Some("3"));
+        assert!(loaded.is_partitioned_like(&params, &StableSharder::new(7)));
+        assert!(!loaded.is_partitioned_like(&params, &StableSharder::new(8)));
+        let other = params.clone().set_concurrency(2, 2);
+        assert!(!loaded.is_partitioned_like(&other, &StableSharder::new(7)));
+        let other = params.clone().set_key_only(true);
+        assert!(!loaded.is_partitioned_like(&other, &StableSharder::new(7)));
+        assert!(!Dataset::from_output(&params, &DefaultSharder)
+            .is_partitioned_like(&params, &DefaultSharder));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,330 @@
+//! A pool of worker threads that several jobs can share (see `MRParameters::set_executor()`).
+//! Without one, every job starts threads of its own for every phase, which is wasteful when many
+//! small jobs run concurrently, e.g. in a server.
+//!
+//! Every phase of a job submits its tasks to a queue of its own. Idle threads take the next task
+//! from the queues in turn (round-robin), so that jobs get about the same share of the threads
+//! regardless of how many tasks they submit; a job can further be limited to a maximum number of
+//! concurrently running tasks.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use priority;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+/// The tasks of one scope (usually one phase of a job).
+struct JobQueue {
+    id: u64,
+    cap: usize,
+    running: usize,
+    // Queued and running tasks.
+    pending: usize,
+    tasks: VecDeque<Task>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+struct State {
+    jobs: Vec<JobQueue>,
+    // The queue to take the next task from, for round-robin scheduling.
+    next: usize,
+    next_id: u64,
+    shutdown: bool,
+}
+
+impl State {
+    fn job(&mut self, id: u64) -> &mut JobQueue {
+        self.jobs.iter_mut().find(|j| j.id == id).expect("unknown executor job")
+    }
+
+    fn take_next(&mut self) -> Option<(u64, Task)> {
+        let n = self.jobs.len();
+        for k in 0..n {
+            let i = (self.next + k) % n;
+            let job = &mut self.jobs[i];
+            if job.running < job.cap {
+                if let Some(task) = job.tasks.pop_front() {
+                    job.running += 1;
+                    self.next = i + 1;
+                    return Some((job.id, task));
+                }
+            }
+        }
+        None
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when a task is queued or a slot of a capped job becomes free.
+    work: Condvar,
+    // Signalled when a task has finished.
+    done: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Threads {
+    shared: Arc<Shared>,
+    threads: usize,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Drop for Threads {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.work.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A handle to a pool of worker threads; clones refer to the same threads. The threads exit once
+/// the last handle has been dropped.
+#[derive(Clone)]
+pub struct ExecutorHandle {
+    threads: Arc<Threads>,
+}
+
+// The state is only changed under the lock, and tasks panicking are caught by the threads, so
+// that the executor stays usable when a job panics.
+impl UnwindSafe for ExecutorHandle {}
+impl RefUnwindSafe for ExecutorHandle {}
+
+impl ExecutorHandle {
+    /// Starts `threads` worker threads (at least one). If `niceness` is positive, their priority
+    /// is lowered (see `priority::apply_niceness()`); the niceness set in the parameters of the
+    /// jobs using the executor is ignored, as the priority of a thread can't be raised again for
+    /// the next job.
+    pub fn new(threads: usize, niceness: i32) -> ExecutorHandle {
+        let threads = if threads == 0 { 1 } else { threads };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: Vec::new(),
+                next: 0,
+                next_id: 0,
+                shutdown: false,
+            }),
+            work: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    priority::apply_niceness(niceness);
+                    work(&shared)
+                })
+            })
+            .collect();
+        ExecutorHandle {
+            threads: Arc::new(Threads {
+                shared,
+                threads,
+                workers,
+            }),
+        }
+    }
+
+    /// Returns the number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads.threads
+    }
+
+    /// Returns the number of scopes (job phases) currently using the executor.
+    pub fn active_jobs(&self) -> usize {
+        self.threads.shared.lock().jobs.len()
+    }
+
+    /// Runs `f`, which can submit tasks borrowing from the caller to the executor with
+    /// `Scope::execute()`. At most `cap` of the tasks run at the same time (0 means no limit
+    /// besides the number of threads). Returns once all tasks have finished; if a task panicked,
+    /// the panic is propagated to the caller.
+    pub fn scoped<'scope, F, T>(&self, cap: usize, f: F) -> T
+        where F: FnOnce(&Scope<'scope>) -> T
+    {
+        let shared = &self.threads.shared;
+        let id = {
+            let mut state = shared.lock();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.jobs.push(JobQueue {
+                id,
+                cap: if cap == 0 { usize::MAX } else { cap },
+                running: 0,
+                pending: 0,
+                tasks: VecDeque::new(),
+                panic: None,
+            });
+            id
+        };
+        let scope = Scope {
+            shared: shared.clone(),
+            id,
+            _scope: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        // The tasks may borrow from the caller, so they must be finished even if f panicked.
+        let task_panic = {
+            let mut state = shared.lock();
+            while state.job(id).pending > 0 {
+                state = shared.done.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            let i = state.jobs.iter().position(|j| j.id == id).unwrap();
+            state.jobs.remove(i).panic
+        };
+        match (result, task_panic) {
+            (Err(cause), _) | (Ok(_), Some(cause)) => panic::resume_unwind(cause),
+            (Ok(value), None) => value,
+        }
+    }
+}
+
+/// Submits tasks to an executor (see `ExecutorHandle::scoped()`).
+pub struct Scope<'scope> {
+    shared: Arc<Shared>,
+    id: u64,
+    // Invariant in 'scope, like the scopes of scoped_threadpool.
+    _scope: PhantomData<::std::cell::Cell<&'scope mut ()>>,
+}
+
+impl<'scope> Scope<'scope> {
+    /// Queues `task` to be run by one of the executor's threads.
+    pub fn execute<F: FnOnce() + Send + 'scope>(&self, task: F) {
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(task);
+        // The task doesn't outlive 'scope, as ExecutorHandle::scoped() waits for it to finish.
+        let task: Task = unsafe { mem::transmute(task) };
+        {
+            let mut state = self.shared.lock();
+            let job = state.job(self.id);
+            job.pending += 1;
+            job.tasks.push_back(task);
+        }
+        self.shared.work.notify_one();
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let (id, task) = {
+            let mut state = shared.lock();
+            loop {
+                if let Some(next) = state.take_next() {
+                    break next;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = shared.work.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(task));
+        {
+            let mut state = shared.lock();
+            let job = state.job(id);
+            job.running -= 1;
+            job.pending -= 1;
+            if let Err(cause) = result {
+                if job.panic.is_none() {
+                    job.panic = Some(cause);
+                }
+            }
+        }
+        // Another task of a capped job may be able to run now.
+        shared.work.notify_one();
+        shared.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExecutorHandle;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_executor_caps() {
+        let executor = ExecutorHandle::new(4, 0);
+        let (running, max) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let mut results = Vec::new();
+        executor.scoped(2, |scope| {
+            for _ in 0..8 {
+                let (running, max) = (&running, &max);
+                scope.execute(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            results.push(1);
+        });
+        assert_eq!(results, vec![1]);
+        assert_eq!(max.load(Ordering::SeqCst), 2);
+        assert_eq!(executor.active_jobs(), 0);
+
+        let caught = panic::catch_unwind(AssertUnwindSafe(|| {
+            executor.scoped(0, |scope| scope.execute(|| panic!("task failed")));
+        }));
+        assert!(caught.is_err());
+        // The threads survive panicking tasks.
+        let n = AtomicUsize::new(0);
+        executor.scoped(0, |scope| {
+            for _ in 0..10 {
+                scope.execute(|| {
+                    n.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(n.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_executor_fairness() {
+        // With one thread, the tasks of two jobs alternate, although the first job queued all
+        // of its tasks first.
+        let executor = ExecutorHandle::new(1, 0);
+        let order = Mutex::new(Vec::new());
+        let (order, started) = (&order, &AtomicUsize::new(0));
+        thread::scope(|s| {
+            for job in 0..2 {
+                let executor = executor.clone();
+                s.spawn(move || {
+                    executor.scoped(0, |scope| {
+                        for _ in 0..4 {
+                            scope.execute(move || {
+                                // Hold back the first task until both jobs have queued theirs.
+                                while started.load(Ordering::SeqCst) < 2 {
+                                    thread::yield_now();
+                                }
+                                order.lock().unwrap().push(job);
+                            });
+                        }
+                        started.fetch_add(1, Ordering::SeqCst);
+                    });
+                });
+            }
+        });
+        let order = order.lock().unwrap();
+        assert_eq!(order.len(), 8);
+        // After the first task, the jobs alternate.
+        for w in order[1..].windows(2) {
+            assert!(w[0] != w[1], "{:?}", *order);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Typed value folding, for jobs that accumulate numbers per key (counts, sums, minima etc.):
+//!
+//! `FoldJob::new(word_mapper, |count: &mut u64, n| *count += n)`
+//!
+//! is both the mapper and the reducer of the job. Its map function emits typed values to a
+//! `FoldEmitter`, which folds the values of every key right away, so that a map partition emits
+//! one value per key (it works as combiner). Intermediate files store the folded values in a
+//! compact encoding (see `Accumulator`) instead of formatting and parsing them as decimal
+//! strings, and the reducer folds them and writes one row per key: the key and the result,
+//! separated by a tab.
+
+use mapreducer::{Mapper, Reducer};
+use record_types::{MEmitter, MultiRecord, REmitter, Record};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// A value that is folded per key and stored in intermediate files in a compact encoding.
+///
+/// Implemented for `u64`, `i64` and `f64`, which are encoded as variable-length integers of six
+/// bits per byte (a count of 1 takes one byte), and for tuples of accumulators, which are stored
+/// back-to-back. Encodings consist of ASCII characters, as intermediate values are strings.
+pub trait Accumulator: Clone + Send {
+    /// Appends the encoding of the value to `out`.
+    fn encode(&self, out: &mut String);
+    /// Decodes a value from the beginning of `encoded`, and advances `encoded` past it. Returns
+    /// None if `encoded` doesn't start with a valid encoding.
+    fn decode(encoded: &mut &[u8]) -> Option<Self>;
+    /// Appends the value as it is written to the output.
+    fn format(&self, out: &mut String);
+}
+
+// The bit of an encoded byte telling that more bytes follow.
+const MORE: u8 = 0x40;
+
+fn encode_varint(mut value: u64, out: &mut String) {
+    while value >= MORE as u64 {
+        out.push(char::from(MORE | (value & 0x3f) as u8));
+        value >>= 6;
+    }
+    out.push(char::from(value as u8));
+}
+
+fn decode_varint(encoded: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = encoded.split_first()?;
+        *encoded = rest;
+        if byte >= 0x80 || shift > 60 {
+            return None;
+        }
+        value |= ((byte & 0x3f) as u64) << shift;
+        if byte & MORE == 0 {
+            return Some(value);
+        }
+        shift += 6;
+    }
+}
+
+impl Accumulator for u64 {
+    fn encode(&self, out: &mut String) {
+        encode_varint(*self, out)
+    }
+
+    fn decode(encoded: &mut &[u8]) -> Option<u64> {
+        decode_varint(encoded)
+    }
+
+    fn format(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Accumulator for i64 {
+    /// Encodes small negative numbers compactly as well (zigzag encoding).
+    fn encode(&self, out: &mut String) {
+        encode_varint(((*self << 1) ^ (*self >> 63)) as u64, out)
+    }
+
+    fn decode(encoded: &mut &[u8]) -> Option<i64> {
+        decode_varint(encoded).map(|v| (v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn format(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Accumulator for f64 {
+    fn encode(&self, out: &mut String) {
+        encode_varint(self.to_bits(), out)
+    }
+
+    fn decode(encoded: &mut &[u8]) -> Option<f64> {
+        decode_varint(encoded).map(f64::from_bits)
+    }
+
+    fn format(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl<A: Accumulator, B: Accumulator> Accumulator for (A, B) {
+    fn encode(&self, out: &mut String) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(encoded: &mut &[u8]) -> Option<(A, B)> {
+        Some((A::decode(encoded)?, B::decode(encoded)?))
+    }
+
+    /// The values are separated by tabs.
+    fn format(&self, out: &mut String) {
+        self.0.format(out);
+        out.push('\t');
+        self.1.format(out);
+    }
+}
+
+impl<A: Accumulator, B: Accumulator, C: Accumulator> Accumulator for (A, B, C) {
+    fn encode(&self, out: &mut String) {
+        self.0.encode(out);
+        self.1.encode(out);
+        self.2.encode(out);
+    }
+
+    fn decode(encoded: &mut &[u8]) -> Option<(A, B, C)> {
+        Some((A::decode(encoded)?, B::decode(encoded)?, C::decode(encoded)?))
+    }
+
+    /// The values are separated by tabs.
+    fn format(&self, out: &mut String) {
+        self.0.format(out);
+        out.push('\t');
+        self.1.format(out);
+        out.push('\t');
+        self.2.format(out);
+    }
+}
+
+/// Folds `value` into `into`.
+pub type FoldF<A> = fn(&mut A, A);
+/// The map function of a `FoldJob`, emitting typed values to the emitter.
+pub type FoldMapperF<A> = fn(&mut FoldEmitter<A>, Record);
+
+/// Emitter of the map function of a `FoldJob`: Folds the values emitted for every key.
+#[derive(Clone)]
+pub struct FoldEmitter<A: Accumulator> {
+    fold: FoldF<A>,
+    folded: HashMap<String, A>,
+    // Input records rejected by the map function, with the reason.
+    rejected: Vec<(Record, String)>,
+}
+
+impl<A: Accumulator> FoldEmitter<A> {
+    fn new(fold: FoldF<A>) -> FoldEmitter<A> {
+        FoldEmitter {
+            fold,
+            folded: HashMap::new(),
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Folds `value` into the value of `key`.
+    pub fn emit(&mut self, key: &str, value: A) {
+        match self.folded.get_mut(key) {
+            Some(folded) => (self.fold)(folded, value),
+            None => {
+                self.folded.insert(String::from(key), value);
+            }
+        }
+    }
+
+    /// Rejects an input record (see `MEmitter::reject()`).
+    pub fn reject(&mut self, original: &Record, reason: &str) {
+        self.rejected.push((original.clone(), String::from(reason)));
+    }
+}
+
+/// The mapper and reducer of a job folding typed values per key.
+#[derive(Clone)]
+pub struct FoldJob<A: Accumulator> {
+    map: FoldMapperF<A>,
+    emitter: FoldEmitter<A>,
+    max_keys: usize,
+    encoded: String,
+}
+
+impl<A: Accumulator> FoldJob<A> {
+    /// Returns a job mapping records with `map` and folding the values of every key with `fold`.
+    pub fn new(map: FoldMapperF<A>, fold: FoldF<A>) -> FoldJob<A> {
+        FoldJob {
+            map,
+            emitter: FoldEmitter::new(fold),
+            max_keys: 1 << 16,
+            encoded: String::new(),
+        }
+    }
+
+    /// A map partition emits its folded values once it holds `keys` keys, so that partitions
+    /// with many distinct keys don't hold all of them in memory; the values of a key may then be
+    /// emitted more than once per partition.
+    ///
+    /// Default: 65536
+    pub fn set_max_keys(mut self, keys: usize) -> FoldJob<A> {
+        self.max_keys = keys;
+        self
+    }
+
+    fn emit_folded(&mut self, em: &mut MEmitter) {
+        for (key, value) in self.emitter.folded.drain() {
+            self.encoded.clear();
+            value.encode(&mut self.encoded);
+            em.emit_str(&key, &self.encoded);
+        }
+    }
+}
+
+impl<A: Accumulator> Mapper for FoldJob<A> {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        (self.map)(&mut self.emitter, record);
+        for (record, reason) in self.emitter.rejected.drain(..) {
+            em.reject(&record, &reason);
+        }
+        if self.emitter.folded.len() >= self.max_keys {
+            self.emit_folded(em);
+        }
+    }
+
+    fn finish(&mut self, em: &mut MEmitter) {
+        self.emit_folded(em);
+    }
+}
+
+impl<A: Accumulator> Reducer for FoldJob<A> {
+    fn reduce(&mut self, em: &mut REmitter, records: MultiRecord) {
+        let mut result: Option<A> = None;
+        for encoded in records.values() {
+            let mut bytes = encoded.as_bytes();
+            let value = match A::decode(&mut bytes) {
+                Some(v) if bytes.is_empty() => v,
+                _ => return em.reject(records.key(), "invalid folded value"),
+            };
+            match result {
+                None => result = Some(value),
+                Some(ref mut r) => (self.emitter.fold)(r, value),
+            }
+        }
+        if let Some(value) = result {
+            let mut row = format!("{}\t", records.key());
+            value.format(&mut row);
+            em.emit(row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accumulator, FoldEmitter, FoldJob};
+    use controller::MRController;
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use record_types::Record;
+    use std::fs;
+
+    fn roundtrip<A: Accumulator + PartialEq + ::std::fmt::Debug>(value: A) -> usize {
+        let mut encoded = String::new();
+        value.encode(&mut encoded);
+        let mut bytes = encoded.as_bytes();
+        assert_eq!(A::decode(&mut bytes), Some(value));
+        assert!(bytes.is_empty());
+        encoded.len()
+    }
+
+    #[test]
+    fn test_accumulator_encoding() {
+        assert_eq!(roundtrip(1u64), 1);
+        assert_eq!(roundtrip(4096u64), 3);
+        assert_eq!(roundtrip(u64::MAX), 11);
+        assert_eq!(roundtrip(-1i64), 1);
+        roundtrip(i64::MIN);
+        roundtrip(-2.5f64);
+        assert_eq!(roundtrip((3u64, 1.5f64, -7i64)), 1 + 11 + 1);
+        assert_eq!(u64::decode(&mut &b"\x41"[..]), None);
+        assert_eq!(u64::decode(&mut &[0xc1, 0][..]), None);
+    }
+
+    fn word_mapper(e: &mut FoldEmitter<(u64, u64)>, r: Record) {
+        if r.value.is_empty() {
+            return e.reject(&r, "empty line");
+        }
+        for w in r.value.split_whitespace() {
+            e.emit(w, (1, w.len() as u64));
+        }
+    }
+
+    fn add(into: &mut (u64, u64), value: (u64, u64)) {
+        into.0 += value.0;
+        into.1 += value.1;
+    }
+
+    #[test]
+    fn test_fold_job() {
+        let input: Vec<String> = vec!["abc de", "de fghi", "", "abc abc de"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let params = MRParameters::new()
+            .set_concurrency(2, 1)
+            .set_file_locations(String::from("testdata/fold_map_"),
+                                String::from("testdata/fold_out_"));
+        let job = FoldJob::new(word_mapper, add).set_max_keys(2);
+        let stats = MRController::run(job.clone(),
+                                      job,
+                                      DefaultSharder,
+                                      params,
+                                      PosRecordIterator::new(input.into_iter()),
+                                      LinesSinkGenerator::new_to_files())
+            .stats;
+        assert_eq!(stats.records_malformed, 1);
+
+        let name = String::from("testdata/fold_out_0");
+        let result: Vec<String> = lines::new_from_file(&name).unwrap().collect();
+        assert_eq!(result, vec!["abc\t3\t9", "de\t3\t6", "fghi\t1\t4"]);
+        let _ = fs::remove_file(name);
+    }
+}
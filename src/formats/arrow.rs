@@ -0,0 +1,201 @@
+//! Delivers reduce output as Arrow RecordBatches over a channel instead of writing files
+//! (enabled by the `arrow` feature). This allows embedding localmr into a larger Rust analytics
+//! process without any output files.
+//!
+//! Like the Parquet sink, every value emitted by a reducer becomes one row, which is split into
+//! nullable string columns at a separator character (by default `key` and `value`, separated by
+//! a tab character).
+
+extern crate arrow_array;
+extern crate arrow_schema;
+
+use self::arrow_array::{ArrayRef, RecordBatch};
+use self::arrow_array::builder::StringBuilder;
+use self::arrow_schema::{DataType, Field, Schema};
+
+use formats::util::split_columns;
+use phases::output::SinkGenerator;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+/// A batch of output rows, produced by the reduce shard writing to output `shard` (the name the
+/// output file would have had).
+pub struct OutputBatch {
+    pub shard: String,
+    pub batch: RecordBatch,
+}
+
+/// A SinkGenerator whose sinks send RecordBatches to a channel.
+#[derive(Clone)]
+pub struct ArrowSinkGenerator {
+    schema: Arc<Schema>,
+    separator: char,
+    rows_per_batch: usize,
+    dest: Sender<OutputBatch>,
+}
+
+impl ArrowSinkGenerator {
+    /// Returns a generator producing batches with the given string columns; every value is split
+    /// at `separator` into at most `columns.len()` fields. The batches can be received from the
+    /// returned Receiver (during or after the job).
+    pub fn new(columns: Vec<String>,
+               separator: char)
+               -> (ArrowSinkGenerator, Receiver<OutputBatch>) {
+        let fields: Vec<Field> = columns.into_iter()
+            .map(|c| Field::new(c, DataType::Utf8, true))
+            .collect();
+        let (send, recv) = channel();
+        let gen = ArrowSinkGenerator {
+            schema: Arc::new(Schema::new(fields)),
+            separator,
+            rows_per_batch: 64 * 1024,
+            dest: send,
+        };
+        (gen, recv)
+    }
+
+    /// Returns a generator producing batches with the columns (key, value); the reducer should
+    /// emit values of the form `key<TAB>value`.
+    pub fn new_key_value() -> (ArrowSinkGenerator, Receiver<OutputBatch>) {
+        ArrowSinkGenerator::new(vec![String::from("key"), String::from("value")], '\t')
+    }
+
+    /// Sets the maximum number of rows per RecordBatch.
+    ///
+    /// Default 65536
+    pub fn set_rows_per_batch(mut self, n: usize) -> ArrowSinkGenerator {
+        self.rows_per_batch = n;
+        self
+    }
+}
+
+impl SinkGenerator for ArrowSinkGenerator {
+    type Sink = ArrowWriter;
+    fn new_output(&self, name: &String) -> Self::Sink {
+        ArrowWriter {
+            shard: name.clone(),
+            schema: self.schema.clone(),
+            separator: self.separator,
+            builders: self.schema.fields().iter().map(|_| StringBuilder::new()).collect(),
+            rows: 0,
+            rows_per_batch: self.rows_per_batch,
+            dest: self.dest.clone(),
+        }
+    }
+}
+
+/// Collects rows into RecordBatches and sends them once they are full, or when the writer is
+/// flushed or dropped.
+pub struct ArrowWriter {
+    shard: String,
+    schema: Arc<Schema>,
+    separator: char,
+    builders: Vec<StringBuilder>,
+    rows: usize,
+    rows_per_batch: usize,
+    dest: Sender<OutputBatch>,
+}
+
+impl ArrowWriter {
+    fn send_batch(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let columns: Vec<ArrayRef> = self.builders
+            .iter_mut()
+            .map(|b| Arc::new(b.finish()) as ArrayRef)
+            .collect();
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(io::Error::other)?;
+        self.rows = 0;
+
+        self.dest
+            .send(OutputBatch {
+                shard: self.shard.clone(),
+                batch,
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "batch receiver is gone"))
+    }
+}
+
+impl io::Write for ArrowWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let row = String::from_utf8_lossy(buf);
+        let fields = split_columns(&row, self.separator, self.builders.len());
+
+        for (builder, field) in self.builders.iter_mut().zip(fields) {
+            builder.append_option(field);
+        }
+        self.rows += 1;
+
+        if self.rows >= self.rows_per_batch {
+            self.send_batch()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Sends the buffered rows as a RecordBatch.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_batch()
+    }
+}
+
+impl Drop for ArrowWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.send_batch() {
+            println!("WARN: Couldn't send output batch of {}: {}", self.shard, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::arrow_array::{Array, StringArray};
+    use super::ArrowSinkGenerator;
+    use closure_mr::ClosureMapReducer;
+    use controller::MRController;
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use record_types::{MEmitter, REmitter, Record, MultiRecord};
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit(String::from(w), String::from("1"));
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{}\t{}", recs.key(), recs.values().len()));
+    }
+
+    #[test]
+    fn test_arrow_output() {
+        let input: Vec<String> = vec!["abc def", "abc"].into_iter().map(String::from).collect();
+        let (gen, batches) = ArrowSinkGenerator::new_key_value();
+        let params = MRParameters::new()
+            .set_concurrency(1, 2)
+            .set_file_locations(String::from("testdata/arrow_map_"),
+                                String::from("arrow_out_"));
+
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params,
+                          PosRecordIterator::new(input.into_iter()),
+                          gen);
+
+        let mut rows = Vec::new();
+        for b in batches.try_iter() {
+            assert!(b.shard.starts_with("arrow_out_"));
+            let keys = b.batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+            let values = b.batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+            for i in 0..b.batch.num_rows() {
+                assert!(!values.is_null(i));
+                rows.push(format!("{}={}", keys.value(i), values.value(i)));
+            }
+        }
+        rows.sort();
+        assert_eq!(rows, vec!["abc=2", "def=1"]);
+    }
+}
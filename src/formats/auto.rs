@@ -0,0 +1,191 @@
+//! Detects the format of input files, so that jobs (and tools) can read files without being told
+//! what they contain, e.g. the files of a directory holding text logs, CSV exports and WriteLogs
+//! written by earlier jobs side by side (see `open_auto()`).
+//!
+//! The format is chosen by the file extension if it is known, and by the first bytes of the file
+//! otherwise. Gzip-compressed files (with the extension `.gz` or starting with the gzip magic
+//! bytes) are decompressed if the `flate2` feature is enabled; the format of the decompressed
+//! contents is detected like that of uncompressed files.
+
+#[cfg(feature = "flate2")]
+extern crate flate2;
+
+use formats::error::FormatError;
+use formats::lines::LinesReader;
+use formats::schema::Schema;
+use formats::util::PosRecordIterator;
+use formats::writelog::WriteLogReader;
+use record_types::Record;
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// The formats recognized by `detect()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Text; every line is a record.
+    Lines,
+    /// A WriteLog; every entry is a record.
+    WriteLog,
+    /// Delimited text with a header line naming the columns, e.g. CSV or TSV files, separated by
+    /// the given character; every row after the header is a record.
+    Delimited(char),
+}
+
+/// The format of an input file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputFormat {
+    pub format: Format,
+    pub gzip: bool,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Returns the format indicated by the extension of `name`, and whether it is compressed.
+fn format_of_name(name: &str) -> (Option<Format>, bool) {
+    let (name, gzip) = match name.strip_suffix(".gz") {
+        Some(stripped) => (stripped, true),
+        None => (name, false),
+    };
+    let extension = Path::new(name).extension().and_then(|e| e.to_str()).unwrap_or("");
+    let format = match extension.to_ascii_lowercase().as_str() {
+        "wlg" | "wlog" => Some(Format::WriteLog),
+        "csv" => Some(Format::Delimited(',')),
+        "tsv" => Some(Format::Delimited('\t')),
+        "txt" | "log" | "lines" => Some(Format::Lines),
+        _ => None,
+    };
+    (format, gzip)
+}
+
+/// Guesses the format from the first bytes of the (uncompressed) contents: WriteLog entries
+/// start with a big-endian length, whose first byte is 0 for entries shorter than 16 MiB, while
+/// text doesn't contain zero bytes.
+fn format_of_contents(head: &[u8]) -> Format {
+    if head.first() == Some(&0) {
+        Format::WriteLog
+    } else {
+        Format::Lines
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn gunzip(src: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    let decoder = self::flate2::bufread::MultiGzDecoder::new(src);
+    Ok(Box::new(io::BufReader::new(decoder)))
+}
+
+#[cfg(not(feature = "flate2"))]
+fn gunzip(_: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput,
+                       "Reading gzip-compressed input requires the flate2 feature"))
+}
+
+/// Opens `path` and detects its format; the returned reader is positioned at the beginning of
+/// the (decompressed) contents.
+fn open(path: &String) -> io::Result<(InputFormat, Box<dyn BufRead + Send>)> {
+    let f = fs::File::open(path).map_err(|e| io::Error::from(FormatError::new(path, 0, 0, e)))?;
+    let mut src: Box<dyn BufRead + Send> = Box::new(io::BufReader::new(f));
+    let (format, mut gzip) = format_of_name(path);
+    if gzip || src.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        gzip = true;
+        src = gunzip(src)?;
+    }
+    let format = match format {
+        Some(format) => format,
+        None => format_of_contents(src.fill_buf()?),
+    };
+    Ok((InputFormat { format, gzip }, src))
+}
+
+/// Returns the format of `path`. Detecting the format of compressed files without known
+/// extension requires the `flate2` feature.
+pub fn detect(path: &String) -> io::Result<InputFormat> {
+    let (format, gzip) = format_of_name(path);
+    match format {
+        Some(format) => Ok(InputFormat { format, gzip }),
+        None => open(path).map(|(format, _)| format),
+    }
+}
+
+/// Opens `path` as an iterator over records, choosing the reader by the format of the file (see
+/// `detect()`). Records are keyed by their position, starting with 1 (like `PosRecordIterator`);
+/// the header line of delimited files is skipped (see `header()`).
+pub fn open_auto(path: &String) -> io::Result<Box<dyn Iterator<Item = Record> + Send>> {
+    let (format, src) = open(path)?;
+    Ok(match format.format {
+        Format::Lines => {
+            Box::new(PosRecordIterator::new(LinesReader::new(src).set_source(path, 0)))
+        }
+        Format::WriteLog => Box::new(PosRecordIterator::new(WriteLogReader::new(Box::new(src)))),
+        Format::Delimited(_) => {
+            let mut lines = LinesReader::new(src).set_source(path, 0);
+            lines.next();
+            Box::new(PosRecordIterator::new(lines))
+        }
+    })
+}
+
+/// Returns the schema given by the header line of a delimited file, or None if `path` is of
+/// another format or empty.
+pub fn header(path: &String) -> io::Result<Option<Schema>> {
+    let (format, src) = open(path)?;
+    match format.format {
+        Format::Delimited(separator) => {
+            let first = LinesReader::new(src).set_source(path, 0).next();
+            Ok(first.map(|h| Schema::from_header(&h, separator)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, InputFormat, detect, header, open_auto};
+    use formats::writelog::WriteLogWriter;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_open_auto() {
+        let text = String::from("testdata/auto_input.txt");
+        fs::write(&text, "abc\ndef\n").unwrap();
+        let csv = String::from("testdata/auto_input.csv");
+        fs::write(&csv, "name,count\nx,1\ny,2\n").unwrap();
+        let log = String::from("testdata/auto_input_log");
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&log, false).unwrap();
+            w.write_all(b"first").unwrap();
+            w.write_all(b"second").unwrap();
+        }
+        let gz = String::from("testdata/auto_input.txt.gz");
+        fs::write(&gz, [0x1f, 0x8b, 0x08, 0]).unwrap();
+
+        let values = |path: &String| -> Vec<String> {
+            open_auto(path).unwrap().map(|r| r.value).collect()
+        };
+        assert_eq!(values(&text), vec!["abc", "def"]);
+        assert_eq!(values(&csv), vec!["x,1", "y,2"]);
+        assert_eq!(values(&log), vec!["first", "second"]);
+        assert_eq!(open_auto(&log).unwrap().next().unwrap().key, "1");
+
+        assert_eq!(detect(&log).unwrap(),
+                   InputFormat {
+                       format: Format::WriteLog,
+                       gzip: false,
+                   });
+        assert_eq!(detect(&gz).unwrap(),
+                   InputFormat {
+                       format: Format::Lines,
+                       gzip: true,
+                   });
+        assert_eq!(header(&csv).unwrap().unwrap().index("count"), Some(1));
+        assert_eq!(header(&text).unwrap(), None);
+        assert!(open_auto(&String::from("testdata/auto_missing")).is_err());
+
+        for f in [text, csv, log, gz] {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Bloom filters of the keys of intermediate files, persisted as sidecars next to the files (see
+//! `MRParameters::set_intermediate_bloom_filter()`). When joining datasets, the reduce input
+//! readers of one side consult the filters of the other side and skip records whose keys can't
+//! match.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Write};
+
+use mapreducer::fnv1a_seeded;
+
+/// A Bloom filter over strings: `may_contain()` returns true for all inserted keys, and for a
+/// small fraction of other keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Returns a filter sized for `expected_keys` keys, using `bits_per_key` bits per key. 10 bits
+    /// per key result in about 1% false positives.
+    pub fn new(expected_keys: usize, bits_per_key: usize) -> BloomFilter {
+        let bits = cmp::max(64, expected_keys * bits_per_key);
+        // The optimal number of hash functions is ln(2) * bits per key.
+        let hashes = ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, 30);
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+
+    // Double hashing: The i-th bit of a key is h1 + i * h2.
+    fn bit_indices<'a>(&'a self, key: &str) -> impl Iterator<Item = usize> + 'a {
+        let h1 = fnv1a_seeded(1, key.as_bytes());
+        let h2 = fnv1a_seeded(2, key.as_bytes()) | 1;
+        let nbits = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        let indices: Vec<usize> = self.bit_indices(key).collect();
+        for i in indices {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_indices(key).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Writes the filter to `path`: the number of hash functions and the number of 64 bit words
+    /// (both as big-endian u32), followed by the words (big-endian).
+    pub fn save(&self, path: &String) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(8 + 8 * self.bits.len());
+        buf.extend_from_slice(&self.hashes.to_be_bytes());
+        buf.extend_from_slice(&(self.bits.len() as u32).to_be_bytes());
+        for w in &self.bits {
+            buf.extend_from_slice(&w.to_be_bytes());
+        }
+        fs::File::create(path)?.write_all(&buf)
+    }
+
+    pub fn load(path: &String) -> io::Result<BloomFilter> {
+        let mut buf = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut buf)?;
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid filter {}", path))
+        };
+        if buf.len() < 8 {
+            return Err(invalid());
+        }
+        let hashes = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let words = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        if words == 0 || buf.len() != 8 + 8 * words {
+            return Err(invalid());
+        }
+        let bits = buf[8..]
+            .chunks(8)
+            .map(|c| u64::from_be_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+            .collect();
+        Ok(BloomFilter { bits, hashes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+    use std::fs;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000, 10);
+        for i in 0..1000 {
+            filter.insert(&format!("key{}", i));
+        }
+        for i in 0..1000 {
+            assert!(filter.may_contain(&format!("key{}", i)));
+        }
+        let false_positives =
+            (0..1000).filter(|i| filter.may_contain(&format!("other{}", i))).count();
+        assert!(false_positives < 50);
+
+        let path = String::from("testdata/bloom_filter.bloom");
+        filter.save(&path).unwrap();
+        assert_eq!(BloomFilter::load(&path).unwrap(), filter);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,237 @@
+//! Compression of reduce outputs (see `MRParameters::set_output_compression()`), for jobs whose
+//! results are large and compress well. The sink generator writes every output as usual; once
+//! the output is complete, it is compressed to `<output>.gz` (gzip, requires the `flate2`
+//! feature) or `<output>.zst` (zstd, requires the `zstd` feature), and the uncompressed file is
+//! removed. Rotated part files (see `formats::limited`) are compressed the same way.
+//!
+//! `formats::output::read_reduce_outputs()` finds and decompresses compressed outputs.
+
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+use phases::output::reduce_output_part_name;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::thread;
+
+/// How reduce outputs are compressed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputCompression {
+    None,
+    /// gzip, to `<output>.gz` (requires the `flate2` feature).
+    Gzip,
+    /// zstd, to `<output>.zst` (requires the `zstd` feature).
+    Zstd,
+}
+
+const COMPRESSIONS: [OutputCompression; 2] = [OutputCompression::Gzip, OutputCompression::Zstd];
+
+impl OutputCompression {
+    /// Returns the extension appended to the names of compressed outputs, including the dot.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputCompression::None => "",
+            OutputCompression::Gzip => ".gz",
+            OutputCompression::Zstd => ".zst",
+        }
+    }
+
+    /// Returns the compression of the file `path`, by its extension.
+    pub fn of_path(path: &str) -> OutputCompression {
+        COMPRESSIONS.iter()
+            .cloned()
+            .find(|c| path.ends_with(c.extension()))
+            .unwrap_or(OutputCompression::None)
+    }
+
+    /// Returns whether the feature required by this compression is enabled.
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            OutputCompression::None => true,
+            OutputCompression::Gzip => cfg!(feature = "flate2"),
+            OutputCompression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// Writes an output with another sink, and compresses it when it is closed.
+pub struct CompressedSink<W: Write> {
+    sink: Option<W>,
+    name: String,
+    compression: OutputCompression,
+}
+
+impl<W: Write> CompressedSink<W> {
+    /// Returns a sink writing to `sink`, which writes the output file `name`. Panics if the
+    /// feature required by `compression` isn't enabled.
+    pub fn new(sink: W, name: &String, compression: OutputCompression) -> CompressedSink<W> {
+        if !compression.is_supported() {
+            panic!("{:?} compression of outputs isn't supported by this build", compression);
+        }
+        // Compressed files of an earlier run would be mistaken for this output.
+        let _ = fs::remove_file(format!("{}{}", name, compression.extension()));
+        for part in 1.. {
+            let part = reduce_output_part_name(name, part);
+            if fs::remove_file(format!("{}{}", part, compression.extension())).is_err() {
+                break;
+            }
+        }
+        CompressedSink {
+            sink: Some(sink),
+            name: name.clone(),
+            compression,
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedSink<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.sink {
+            Some(ref mut sink) => sink.write(data),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "output has been closed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.sink {
+            Some(ref mut sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for CompressedSink<W> {
+    fn drop(&mut self) {
+        self.sink = None;
+        // A partially written output is removed by the output guard of the failed partition.
+        if thread::panicking() {
+            return;
+        }
+        let parts = (1..).map(|part| reduce_output_part_name(&self.name, part));
+        let files = Some(self.name.clone()).into_iter().chain(parts);
+        for file in files.take_while(|f| Path::new(f).exists()) {
+            if let Err(e) = compress_file(&file, self.compression) {
+                println!("WARN: Couldn't compress output {}: {}", file, e);
+            }
+        }
+    }
+}
+
+/// Returns the compressed part files of the output `name` (see `formats::limited`), in order.
+pub fn compressed_parts(name: &str, compression: OutputCompression) -> Vec<String> {
+    (1..).map(|part| format!("{}{}", reduce_output_part_name(name, part), compression.extension()))
+        .take_while(|part| Path::new(part).exists())
+        .collect()
+}
+
+/// Returns the file of the output `name`: the file itself if it exists, or else its compressed
+/// version.
+pub fn find_output(name: &str) -> Option<String> {
+    if Path::new(name).exists() {
+        return Some(String::from(name));
+    }
+    COMPRESSIONS.iter()
+        .map(|c| format!("{}{}", name, c.extension()))
+        .find(|f| Path::new(f).exists())
+}
+
+/// Compresses the file `name` to `<name>.gz` or `<name>.zst`, and removes it.
+pub fn compress_file(name: &str, compression: OutputCompression) -> io::Result<()> {
+    if compression == OutputCompression::None {
+        return Ok(());
+    }
+    let compressed = format!("{}{}", name, compression.extension());
+    {
+        let mut src = fs::File::open(name)?;
+        let mut dst = io::BufWriter::new(fs::File::create(&compressed)?);
+        match compression {
+            OutputCompression::None => (),
+            OutputCompression::Gzip => gzip(&mut src, &mut dst)?,
+            OutputCompression::Zstd => zstd_compress(&mut src, &mut dst)?,
+        }
+        dst.flush()?;
+    }
+    fs::remove_file(name)
+}
+
+/// Opens the output file `path`, decompressing it according to its extension.
+pub fn open_output(path: &String) -> io::Result<Box<dyn Read + Send>> {
+    let f = fs::File::open(path)?;
+    match OutputCompression::of_path(path) {
+        OutputCompression::None => Ok(Box::new(f)),
+        OutputCompression::Gzip => gunzip(f),
+        OutputCompression::Zstd => zstd_decompress(f),
+    }
+}
+
+#[cfg(any(not(feature = "flate2"), not(feature = "zstd")))]
+fn unsupported(compression: OutputCompression) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported,
+                   format!("{:?} compression isn't supported by this build", compression))
+}
+
+#[cfg(feature = "flate2")]
+fn gzip(src: &mut fs::File, dst: &mut dyn Write) -> io::Result<()> {
+    let level = self::flate2::Compression::default();
+    let mut encoder = self::flate2::write::GzEncoder::new(dst, level);
+    io::copy(src, &mut encoder)?;
+    encoder.finish().map(|_| ())
+}
+
+#[cfg(not(feature = "flate2"))]
+fn gzip(_: &mut fs::File, _: &mut dyn Write) -> io::Result<()> {
+    Err(unsupported(OutputCompression::Gzip))
+}
+
+#[cfg(feature = "flate2")]
+fn gunzip(f: fs::File) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(self::flate2::read::MultiGzDecoder::new(io::BufReader::new(f))))
+}
+
+#[cfg(not(feature = "flate2"))]
+fn gunzip(_: fs::File) -> io::Result<Box<dyn Read + Send>> {
+    Err(unsupported(OutputCompression::Gzip))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(src: &mut fs::File, dst: &mut dyn Write) -> io::Result<()> {
+    self::zstd::stream::copy_encode(src, dst, 0)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: &mut fs::File, _: &mut dyn Write) -> io::Result<()> {
+    Err(unsupported(OutputCompression::Zstd))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(f: fs::File) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(self::zstd::stream::read::Decoder::new(f)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: fs::File) -> io::Result<Box<dyn Read + Send>> {
+    Err(unsupported(OutputCompression::Zstd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputCompression, find_output};
+    use std::fs;
+
+    #[test]
+    fn test_output_compression_names() {
+        assert_eq!(OutputCompression::of_path("out/output_0.gz"), OutputCompression::Gzip);
+        assert_eq!(OutputCompression::of_path("out/output_0.zst"), OutputCompression::Zstd);
+        assert_eq!(OutputCompression::of_path("out/output_0"), OutputCompression::None);
+
+        fs::write("testdata/compressed_names_0.zst", b"").unwrap();
+        assert_eq!(find_output("testdata/compressed_names_0"),
+                   Some(String::from("testdata/compressed_names_0.zst")));
+        assert_eq!(find_output("testdata/compressed_names_1"), None);
+        let _ = fs::remove_file("testdata/compressed_names_0.zst");
+    }
+}
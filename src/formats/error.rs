@@ -0,0 +1,78 @@
+//! Errors of the format readers, carrying the location of the bad data.
+//!
+//! Readers return a FormatError wrapped in an `io::Error` (of the same kind as the cause), so
+//! that it travels through the `io::Result`s of the readers and of `MRController` unchanged; use
+//! `FormatError::from_io()` to get at the location.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error that occurred while reading a file (or stream) at a known position.
+#[derive(Debug)]
+pub struct FormatError {
+    /// The file the error occurred in; "<stream>" if the reader doesn't read from a named file.
+    pub file: String,
+    /// The byte offset of the record that couldn't be read.
+    pub offset: u64,
+    /// The index of the record (line, entry) that couldn't be read, counted from 0 from where
+    /// the reader started.
+    pub record: usize,
+    pub cause: io::Error,
+}
+
+impl FormatError {
+    pub fn new(file: &str, offset: u64, record: usize, cause: io::Error) -> FormatError {
+        FormatError {
+            file: String::from(file),
+            offset,
+            record,
+            cause,
+        }
+    }
+
+    /// Returns the FormatError wrapped in `e`, if there is one.
+    pub fn from_io(e: &io::Error) -> Option<&FormatError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<FormatError>())
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}: record #{} at byte {}: {}",
+               self.file,
+               self.record,
+               self.offset,
+               self.cause)
+    }
+}
+
+impl Error for FormatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl From<FormatError> for io::Error {
+    fn from(e: FormatError) -> io::Error {
+        io::Error::new(e.cause.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatError;
+    use std::io;
+
+    #[test]
+    fn test_format_error() {
+        let cause = io::Error::new(io::ErrorKind::InvalidData, "bad length");
+        let e: io::Error = FormatError::new("testdata/x", 12, 3, cause).into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "testdata/x: record #3 at byte 12: bad length");
+        let inner = FormatError::from_io(&e).unwrap();
+        assert_eq!((inner.offset, inner.record), (12, 3));
+        assert!(FormatError::from_io(&io::Error::other("x")).is_none());
+    }
+}
@@ -0,0 +1,41 @@
+//! Flatbuffer values (enabled by the `flatbuffers` feature). Flatbuffers are read in place, so
+//! `FlatbufferCodec` doesn't convert values into another type: It verifies that a value is a
+//! valid buffer of the expected table, and passes the buffer on, to be accessed with
+//! `flatbuffers::root_unchecked()` (or the generated `root_as_*_unchecked()` function). Values
+//! that fail verification are treated as malformed by `codec::decode_records()`.
+
+extern crate flatbuffers;
+
+use self::flatbuffers::InvalidFlatbuffer;
+
+use codec::ValueCodec;
+
+/// Verifies that `bytes` is a valid flatbuffer, usually by calling `flatbuffers::root::<T>()`
+/// for the expected table type `T`.
+pub type VerifyF = fn(&[u8]) -> Result<(), InvalidFlatbuffer>;
+
+/// Verifies flatbuffers on decoding; see the module documentation.
+#[derive(Clone)]
+pub struct FlatbufferCodec {
+    verify: VerifyF,
+}
+
+impl FlatbufferCodec {
+    /// Verifies every decoded value with `verify`, e.g.
+    /// `|b| flatbuffers::root::<Event>(b).map(|_| ())`.
+    pub fn new(verify: VerifyF) -> FlatbufferCodec {
+        FlatbufferCodec { verify }
+    }
+}
+
+impl ValueCodec<Vec<u8>> for FlatbufferCodec {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        (self.verify)(bytes).map_err(|e| e.to_string())?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Appends a finished buffer, e.g. `FlatBufferBuilder::finished_data()`.
+    fn encode(&self, value: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(value);
+    }
+}
@@ -0,0 +1,176 @@
+//! Limits the size of reduce outputs (see `MRParameters::set_output_size_limit()`), so that a
+//! skewed job doesn't produce a single output file that downstream systems can't ingest. Once
+//! the bytes written to an output would exceed the limit, the `OutputLimitPolicy` applies:
+//!
+//! * `Rotate` continues in a new part file `<output>.1`, `<output>.2` etc. (see
+//!   `phases::output::reduce_output_part_name()`), each holding up to the limit;
+//! * `Compress` keeps writing, and gzip-compresses the output to `<output>.gz` when it is complete
+//!   (requires the `flate2` feature, and a sink generator writing files);
+//! * `Fail` fails the reduce partition.
+//!
+//! A single record larger than the limit is still written as a whole. The part files of an
+//! output shard are listed in `OutputShard::parts`.
+
+use formats::compressed::{OutputCompression, compress_file};
+use parameters::OutputLimitPolicy;
+use phases::output::{SinkGenerator, reduce_output_part_name};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Writes an output, applying a policy once its size exceeds a limit.
+pub struct LimitedSink<G: SinkGenerator> {
+    generator: G,
+    name: String,
+    limit: u64,
+    policy: OutputLimitPolicy,
+    sink: Option<G::Sink>,
+    // Bytes written to the current part.
+    written: u64,
+    // The number of the current part; 0 is the output itself.
+    part: usize,
+    exceeded: bool,
+}
+
+impl<G: SinkGenerator> LimitedSink<G> {
+    /// Returns a sink writing to the output `name` of `generator`, with the size limit `limit`.
+    /// Panics if `policy` is `Compress` without the `flate2` feature.
+    pub fn new(generator: G,
+               name: &String,
+               limit: u64,
+               policy: OutputLimitPolicy)
+               -> LimitedSink<G> {
+        if policy == OutputLimitPolicy::Compress && !OutputCompression::Gzip.is_supported() {
+            panic!("Compressing outputs requires the flate2 feature");
+        }
+        // Parts of an earlier run would be mistaken for parts of this output.
+        let _ = fs::remove_file(format!("{}.gz", name));
+        for part in 1.. {
+            if fs::remove_file(reduce_output_part_name(name, part)).is_err() {
+                break;
+            }
+        }
+        let sink = generator.new_output(name);
+        LimitedSink {
+            generator,
+            name: name.clone(),
+            limit,
+            policy,
+            sink: Some(sink),
+            written: 0,
+            part: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Returns the number of part files started after the output itself.
+    pub fn parts(&self) -> usize {
+        self.part
+    }
+}
+
+impl<G: SinkGenerator> Write for LimitedSink<G> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + data.len() as u64 > self.limit {
+            match self.policy {
+                OutputLimitPolicy::Fail => {
+                    panic!("Output {} exceeds the limit of {} bytes", self.name, self.limit)
+                }
+                OutputLimitPolicy::Rotate => {
+                    // Dropping the sink flushes and closes the current part.
+                    self.sink = None;
+                    self.part += 1;
+                    let name = reduce_output_part_name(&self.name, self.part);
+                    self.sink = Some(self.generator.new_output(&name));
+                    self.written = 0;
+                }
+                OutputLimitPolicy::Compress => self.exceeded = true,
+            }
+        }
+        self.written += data.len() as u64;
+        // The data is a whole record, which sinks may write with additional bytes (like a line
+        // ending).
+        match self.sink {
+            Some(ref mut sink) => sink.write(data).map(|_| data.len()),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "output has been closed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.sink {
+            Some(ref mut sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<G: SinkGenerator> Drop for LimitedSink<G> {
+    fn drop(&mut self) {
+        self.sink = None;
+        if self.exceeded {
+            if let Err(e) = compress_file(&self.name, OutputCompression::Gzip) {
+                println!("WARN: Couldn't compress output {}: {}", self.name, e);
+            }
+        }
+    }
+}
+
+/// Returns the files written for the output `name` after it exceeded its size limit: its part
+/// files, or the compressed output.
+pub fn output_parts(name: &str) -> Vec<String> {
+    let compressed = format!("{}.gz", name);
+    if Path::new(&compressed).exists() {
+        return vec![compressed];
+    }
+    (1..).map(|part| reduce_output_part_name(name, part))
+        .take_while(|part| Path::new(part).exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitedSink, output_parts};
+    use formats::lines::{self, LinesSinkGenerator};
+    use parameters::OutputLimitPolicy;
+    use std::fs;
+    use std::io::Write;
+    use std::panic;
+
+    #[test]
+    fn test_limited_sink() {
+        let name = String::from("testdata/limited_out");
+        {
+            let mut sink = LimitedSink::new(LinesSinkGenerator::new_to_files(),
+                                            &name,
+                                            8,
+                                            OutputLimitPolicy::Rotate);
+            for record in ["abcd", "efgh", "ijklmnopq", "r"].iter() {
+                sink.write_all(record.as_bytes()).unwrap();
+            }
+            assert_eq!(sink.parts(), 2);
+        }
+        assert_eq!(output_parts(&name), vec!["testdata/limited_out.1", "testdata/limited_out.2"]);
+        let read = |name: &str| -> Vec<String> {
+            let written = lines::new_from_file(&String::from(name)).unwrap().collect();
+            let _ = fs::remove_file(name);
+            written
+        };
+        assert_eq!(read("testdata/limited_out"), vec!["abcd", "efgh"]);
+        // A record larger than the limit is written as a whole.
+        assert_eq!(read("testdata/limited_out.1"), vec!["ijklmnopq"]);
+        assert_eq!(read("testdata/limited_out.2"), vec!["r"]);
+
+        let result = panic::catch_unwind(|| {
+            let mut sink = LimitedSink::new(LinesSinkGenerator::new_to_files(),
+                                            &String::from("testdata/limited_fail"),
+                                            8,
+                                            OutputLimitPolicy::Fail);
+            for record in ["abcd", "efgh", "ijkl"].iter() {
+                sink.write_all(record.as_bytes()).unwrap();
+            }
+        });
+        assert!(result.is_err());
+        let _ = fs::remove_file("testdata/limited_fail");
+    }
+}
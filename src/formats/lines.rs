@@ -3,20 +3,205 @@
 //! using the RecordIterator from formats::util, the necessary key/value
 //! iterator can be implemented.
 
+use formats::error::FormatError;
+use formats::retry::IoRetry;
+use formats::util::DurabilityGuard;
+use malformed::MalformedHandler;
+use parameters::{Durability, MRParameters, OversizedRecords};
 use phases::output::SinkGenerator;
+use stats::{InputStats, Stats};
 use std::fs;
 use std::io;
-use std::io::{Read, BufRead};
+use std::io::{Read, BufRead, Seek};
 
-type LinesIterator<Src> = io::Lines<io::BufReader<Src>>;
+/// What a LinesReader does with lines that aren't valid UTF-8 (see
+/// `MRParameters::set_invalid_utf8()`). Such lines are counted as decode errors in any case; to
+/// read lines without decoding them, use `LinesReader::bytes()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidUtf8 {
+    /// Skip the line, passing it to the malformed handler if there is one (see
+    /// `LinesReader::handle_malformed()`).
+    Skip,
+    /// Return the line with invalid sequences replaced by U+FFFD.
+    Lossy,
+    /// Fail the job (by panicking), naming the file and the line.
+    Fail,
+}
 
 pub struct LinesReader<Src: Read> {
-    src: Box<LinesIterator<Src>>,
+    src: Box<io::BufReader<Src>>,
+    malformed: Option<MalformedHandler>,
+    max_size: Option<(usize, OversizedRecords)>,
+    invalid_utf8: InvalidUtf8,
+
+    // Used for locating bad lines.
+    source: String,
+    offset: u64,
+    line: usize,
+    error: Option<FormatError>,
+
+    start: u64,
+    records: usize,
+    decode_errors: usize,
+}
+
+impl<Src: Read> LinesReader<Src> {
+    /// Returns a LinesReader reading from `src`.
+    pub fn new(src: Src) -> LinesReader<Src> {
+        LinesReader {
+            src: Box::new(io::BufReader::new(src)),
+            malformed: None,
+            max_size: None,
+            invalid_utf8: InvalidUtf8::Skip,
+            source: String::from("<stream>"),
+            offset: 0,
+            line: 0,
+            error: None,
+            start: 0,
+            records: 0,
+            decode_errors: 0,
+        }
+    }
+
+    /// Sets the name of the file read, and the byte offset in it at which reading starts; both
+    /// are used for locating bad lines in errors.
+    pub fn set_source(mut self, name: &str, offset: u64) -> LinesReader<Src> {
+        self.source = String::from(name);
+        self.offset = offset;
+        self.start = offset;
+        self
+    }
+
+    /// Returns the error that ended the iteration early, if reading failed.
+    pub fn error(&self) -> Option<&FormatError> {
+        self.error.as_ref()
+    }
+
+    /// Passes lines that are not valid UTF-8 to `handler` (usually `params.malformed`), instead
+    /// of skipping them silently.
+    pub fn handle_malformed(mut self, handler: MalformedHandler) -> LinesReader<Src> {
+        self.malformed = Some(handler);
+        self
+    }
+
+    /// Sets what happens with lines that aren't valid UTF-8.
+    ///
+    /// Default: InvalidUtf8::Skip
+    pub fn set_invalid_utf8(mut self, invalid_utf8: InvalidUtf8) -> LinesReader<Src> {
+        self.invalid_utf8 = invalid_utf8;
+        self
+    }
+
+    /// Applies the maximum record size (see `limit_record_size()`), the malformed policy and
+    /// `invalid_utf8` of a job to this reader.
+    pub fn for_job(self, params: &MRParameters) -> LinesReader<Src> {
+        self.handle_malformed(params.malformed.clone())
+            .set_invalid_utf8(params.invalid_utf8)
+            .limit_record_size(params)
+    }
+
+    /// Applies the maximum record size of the job with `params` (see
+    /// `MRParameters::set_max_record_size()`) to the lines while they are read: At most the
+    /// maximum size of a line is held in memory. Oversized lines that aren't truncated are
+    /// passed to `params.malformed`.
+    pub fn limit_record_size(mut self, params: &MRParameters) -> LinesReader<Src> {
+        self.max_size = params.max_record_size;
+        if let Some((_, OversizedRecords::Malformed)) = self.max_size {
+            self.malformed = Some(params.malformed.clone());
+        }
+        self
+    }
+
+    /// Returns an iterator over the lines as bytes, which are not decoded, e.g. for inputs in
+    /// other encodings.
+    pub fn bytes(self) -> ByteLines<Src> {
+        ByteLines { reader: self }
+    }
+
+    /// Reads the next line without line ending; returns it with its offset. Oversized lines are
+    /// truncated or skipped.
+    fn read_line(&mut self) -> Option<(u64, Vec<u8>)> {
+        loop {
+            let mut line = Vec::new();
+            let start = self.offset;
+            // One byte more than the maximum size is kept (besides the line ending), in order to
+            // tell whether the line is too long.
+            let max_size = self.max_size.map_or(usize::MAX, |(max, _)| max);
+            match read_line_prefix(&mut self.src, &mut line, max_size.saturating_add(3)) {
+                Ok(0) => return None,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.error = Some(FormatError::new(&self.source, start, self.line, e));
+                    return None;
+                }
+                Ok(n) => {
+                    self.offset += n as u64;
+                    self.line += 1;
+                }
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            }
+            if line.len() > max_size {
+                let length = self.offset - start;
+                line.truncate(max_size);
+                if let Some((_, OversizedRecords::Malformed)) = self.max_size {
+                    if let Some(ref handler) = self.malformed {
+                        let cause = io::Error::new(io::ErrorKind::InvalidData,
+                                                   format!("line of {} bytes exceeds the \
+                                                            maximum record size",
+                                                           length));
+                        let error = FormatError::new(&self.source, start, self.line - 1, cause);
+                        handler.handle(&line, &error.to_string());
+                    }
+                    continue;
+                }
+                // The cut may have split the last character.
+                if let Err(e) = ::std::str::from_utf8(&line) {
+                    if e.error_len().is_none() {
+                        line.truncate(e.valid_up_to());
+                    }
+                }
+            }
+            return Some((start, line));
+        }
+    }
+}
+
+/// Like `read_until(b'\n')`, but only keeps the first `keep` bytes of the line in `line`.
+/// Returns the number of bytes read.
+fn read_line_prefix<R: BufRead + ?Sized>(src: &mut R,
+                                         line: &mut Vec<u8>,
+                                         keep: usize)
+                                         -> io::Result<usize> {
+    let mut read = 0;
+    loop {
+        let (done, used) = {
+            let available = match src.fill_buf() {
+                Ok(available) => available,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let newline = available.iter().position(|&b| b == b'\n');
+            let used = newline.map_or(available.len(), |i| i + 1);
+            let room = keep.saturating_sub(line.len());
+            line.extend_from_slice(&available[..used.min(room)]);
+            (newline.is_some() || available.is_empty(), used)
+        };
+        src.consume(used);
+        read += used;
+        if done {
+            return Ok(read);
+        }
+    }
 }
 
 /// Returns a LinesReader reading lines from stdin.
 pub fn new_from_stdin() -> LinesReader<io::Stdin> {
-    LinesReader { src: Box::new(io::BufReader::new(io::stdin()).lines()) }
+    LinesReader::new(io::stdin())
 }
 
 /// Returns a LinesReader reading from the given file. If you have several
@@ -25,36 +210,122 @@ pub fn new_from_file(path: &String) -> io::Result<LinesReader<fs::File>> {
     fs::OpenOptions::new()
         .read(true)
         .open(path)
-        .map(move |f| LinesReader { src: Box::new(io::BufReader::new(f).lines()) })
+        .map(|f| LinesReader::new(f).set_source(path, 0))
 }
 
 /// Returns a LinesReader reading from all files in the given directory that have
 /// a given suffix. (This needs to use dynamic dispatch internally, because otherwise
 /// the type would need to represent the number of files that are used; the overhead however
-/// is low compared to disk accesses).
+/// is low compared to disk accesses). Fails if one of the files can't be opened.
 pub fn new_from_dir(path: &String, with_suffix: &String) -> io::Result<LinesReader<Box<Read>>> {
     let mut reader: Box<Read> = Box::new(io::empty());
     let dir = try!(fs::read_dir(path));
 
     for entry in dir {
-        let name;
-        match entry {
-            Err(e) => {
-                println!("Could not read file from {:?}: {}", path, e);
-                continue;
+        let name = String::from(&*entry?.path().to_string_lossy());
+        if name.ends_with(with_suffix) {
+            match fs::OpenOptions::new().read(true).open(&name) {
+                Err(e) => return Err(FormatError::new(&name, 0, 0, e).into()),
+                Ok(f) => reader = Box::new(reader.chain(f)),
             }
-            Ok(direntry) => name = direntry.path(),
         }
+    }
+    Ok(LinesReader::new(reader).set_source(path, 0))
+}
 
-        // ugh
-        if String::from(&*name.to_string_lossy()).ends_with(with_suffix) {
-            match fs::OpenOptions::new().read(true).open(name.clone()) {
-                Err(e) => println!("Could not open file {:?}: {}", name, e),
-                Ok(f) => reader = Box::new(reader.chain(f)),
+/// Returns the paths of all files in the directory `path` whose name ends with `with_suffix`,
+/// in sorted order.
+pub fn list_dir(path: &String, with_suffix: &String) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = String::from(&*entry.path().to_string_lossy());
+        if name.ends_with(with_suffix) {
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A part of a text file, consisting of the lines starting in the byte range [start; end).
+/// Splits are produced by `split_file()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileSplit {
+    pub path: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl FileSplit {
+    /// Returns the size of the split in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns a LinesReader reading the lines of this split. Splits of the same file can be read
+    /// independently from each other, e.g. by different threads.
+    pub fn lines(&self) -> io::Result<LinesReader<io::Take<fs::File>>> {
+        let mut f = fs::OpenOptions::new().read(true).open(&self.path)?;
+        f.seek(io::SeekFrom::Start(self.start))?;
+        Ok(LinesReader::new(f.take(self.end - self.start)).set_source(&self.path, self.start))
+    }
+}
+
+/// Splits a (large) text file into up to n parts of approximately equal size that can be read in
+/// parallel. The split points are placed at the beginning of a line: Starting at the byte offset
+/// k*size/n, the file is scanned forward to the next newline character. Empty splits are omitted.
+pub fn split_file(path: &String, n: usize) -> io::Result<Vec<FileSplit>> {
+    let len = fs::metadata(path)?.len();
+    let mut f = io::BufReader::new(fs::OpenOptions::new().read(true).open(path)?);
+    let mut bounds = vec![0];
+    let mut skipped = Vec::new();
+
+    for i in 1..n as u64 {
+        let target = len * i / n as u64;
+        if target <= *bounds.last().unwrap() {
+            continue;
+        }
+        // Start scanning at the byte before the target, so that a line beginning exactly at the
+        // target is not skipped.
+        f.seek(io::SeekFrom::Start(target - 1))?;
+        skipped.clear();
+        let bound = target - 1 + f.read_until(b'\n', &mut skipped)? as u64;
+
+        if bound >= len {
+            break;
+        }
+        if bound > *bounds.last().unwrap() {
+            bounds.push(bound);
+        }
+    }
+    bounds.push(len);
+
+    Ok(bounds.windows(2)
+        .filter(|b| b[0] < b[1])
+        .map(|b| {
+            FileSplit {
+                path: path.clone(),
+                start: b[0],
+                end: b[1],
             }
+        })
+        .collect())
+}
+
+/// Lines that aren't valid UTF-8 are counted as decode errors (and as records if they are
+/// returned lossily).
+impl<Src: Read> Stats for LinesReader<Src> {
+    fn stats(&self) -> InputStats {
+        InputStats {
+            records: self.records,
+            bytes: self.offset - self.start,
+            decode_errors: self.decode_errors,
         }
     }
-    Ok(LinesReader { src: Box::new(io::BufReader::new(reader).lines()) })
 }
 
 /// Iterate over the lines from a LinesReader.
@@ -62,47 +333,130 @@ impl<Src: Read> Iterator for LinesReader<Src> {
     type Item = String;
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.src.next() {
-                None => return None,
-                Some(Err(_)) => continue,
-                Some(Ok(s)) => return Some(s),
+            let (start, line) = self.read_line()?;
+            match String::from_utf8(line) {
+                Ok(s) => {
+                    self.records += 1;
+                    return Some(s);
+                }
+                Err(e) => {
+                    self.decode_errors += 1;
+                    let cause = io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8");
+                    let error = FormatError::new(&self.source, start, self.line - 1, cause);
+                    match self.invalid_utf8 {
+                        InvalidUtf8::Skip => {
+                            if let Some(ref handler) = self.malformed {
+                                handler.handle(e.as_bytes(), &error.to_string());
+                            }
+                        }
+                        InvalidUtf8::Lossy => {
+                            self.records += 1;
+                            return Some(String::from_utf8_lossy(e.as_bytes()).into_owned());
+                        }
+                        InvalidUtf8::Fail => panic!("{}", error),
+                    }
+                }
             }
         }
     }
 }
 
+/// The lines of a LinesReader as bytes (see `LinesReader::bytes()`).
+pub struct ByteLines<Src: Read> {
+    reader: LinesReader<Src>,
+}
+
+impl<Src: Read> ByteLines<Src> {
+    /// Returns the error that ended the iteration early, if reading failed.
+    pub fn error(&self) -> Option<&FormatError> {
+        self.reader.error()
+    }
+}
+
+impl<Src: Read> Stats for ByteLines<Src> {
+    fn stats(&self) -> InputStats {
+        self.reader.stats()
+    }
+}
+
+impl<Src: Read> Iterator for ByteLines<Src> {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let (_, line) = self.reader.read_line()?;
+        self.reader.records += 1;
+        Some(line)
+    }
+}
+
 /// Writer that separates the chunks written by '\n' characters.
 pub struct LinesWriter<W: io::Write> {
     file: W,
+    durability: DurabilityGuard,
+    io_retry: IoRetry,
 }
 
 impl LinesWriter<fs::File> {
     pub fn new_to_file(path: &String) -> io::Result<LinesWriter<fs::File>> {
         let f = try!(fs::OpenOptions::new().write(true).create(true).truncate(true).open(path));
-        Ok(LinesWriter { file: f })
+        Ok(LinesWriter::new_to_write(f))
+    }
+
+    /// Makes the file durable according to `durability` (see `MRParameters::set_durability()`)
+    /// when the writer is dropped, and periodically if requested.
+    ///
+    /// Default: Durability::None
+    pub fn set_durability(mut self, durability: Durability) -> io::Result<LinesWriter<fs::File>> {
+        self.durability = DurabilityGuard::new(&self.file, durability)?;
+        Ok(self)
     }
 }
 
 impl<W: io::Write> LinesWriter<W> {
     pub fn new_to_write(w: W) -> LinesWriter<W> {
-        LinesWriter { file: w }
+        LinesWriter {
+            file: w,
+            durability: DurabilityGuard::none(),
+            io_retry: IoRetry::default(),
+        }
+    }
+
+    /// Retries transient errors while writing according to `retry` (see `formats::retry`).
+    ///
+    /// Default: IoRetry::default()
+    pub fn set_io_retry(mut self, retry: IoRetry) -> LinesWriter<W> {
+        self.io_retry = retry;
+        self
     }
 }
 
 impl<W: io::Write> io::Write for LinesWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.file.write(buf).and(self.file.write(&['\n' as u8]))
+        self.io_retry.write_all(&mut self.file, buf)?;
+        self.io_retry.write_all(&mut self.file, b"\n")?;
+        self.durability.written(&mut self.file, buf.len() + 1)?;
+        Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
         self.file.flush()
     }
 }
 
+impl<W: io::Write> Drop for LinesWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.durability.close(&mut self.file) {
+            println!("WARN: Couldn't flush or sync lines output: {}", e);
+        }
+    }
+}
+
 /// An SinkGenerator type that uses a simple path as base
 /// and creates text files based on it.
 #[allow(dead_code)]
 #[derive(Clone)]
-pub struct LinesSinkGenerator;
+pub struct LinesSinkGenerator {
+    durability: Durability,
+    io_retry: IoRetry,
+}
 
 unsafe impl Send for LinesSinkGenerator {}
 
@@ -110,27 +464,60 @@ impl LinesSinkGenerator {
     /// Use either a path like `/a/b/c/` to generate files in a directory
     /// or `/a/b/c/file_prefix_` to create files with that prefix.
     pub fn new_to_files() -> LinesSinkGenerator {
-        LinesSinkGenerator {}
+        LinesSinkGenerator {
+            durability: Durability::FlushOnClose,
+            io_retry: IoRetry::default(),
+        }
+    }
+}
+
+impl LinesSinkGenerator {
+    fn open(&self, p: &String, append: bool) -> LinesWriter<fs::File> {
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .create(true)
+            .open(p);
+        match f.and_then(|f| LinesWriter::new_to_write(f).set_durability(self.durability)) {
+            Err(e) => panic!("Couldn't open lines output file {}: {}", p, e),
+            Ok(w) => w.set_io_retry(self.io_retry),
+        }
     }
 }
 
 impl SinkGenerator for LinesSinkGenerator {
     type Sink = LinesWriter<fs::File>;
     fn new_output(&self, p: &String) -> Self::Sink {
-        let f = fs::OpenOptions::new().write(true).truncate(true).create(true).open(p);
-        match f {
-            Err(e) => panic!("Couldn't open lines output file {}: {}", p, e),
-            Ok(f) => return LinesWriter { file: f },
-        }
+        self.open(p, false)
+    }
+
+    fn append_output(&self, p: &String) -> Option<Self::Sink> {
+        Some(self.open(p, true))
+    }
+
+    fn with_durability(mut self, durability: Durability) -> LinesSinkGenerator {
+        self.durability = durability;
+        self
+    }
+
+    fn with_io_retry(mut self, retry: IoRetry) -> LinesSinkGenerator {
+        self.io_retry = retry;
+        self
     }
 }
 
 #[cfg(test)]
 mod test {
-    use formats::lines;
+    use formats::lines::{self, InvalidUtf8};
+    use formats::writelog::WriteLogReader;
+    use malformed::{MalformedHandler, MalformedPolicy};
+    use parameters::{MRParameters, OversizedRecords};
     use phases::output::SinkGenerator;
+    use stats::{InputStats, Stats};
     use std::fs;
     use std::io::Write;
+    use std::panic::{self, AssertUnwindSafe};
 
     #[test]
     fn test_read_file() {
@@ -148,6 +535,75 @@ mod test {
         assert!(cnt > 5);
     }
 
+    #[test]
+    fn test_read_malformed() {
+        let path = String::from("testdata/lines_malformed");
+        fs::write(&path, b"abc\r\nd\xffef\nghi").unwrap();
+
+        let handler = MalformedHandler::new(MalformedPolicy::Skip);
+        let mut it = lines::new_from_file(&path).unwrap().handle_malformed(handler.clone());
+        assert_eq!(it.by_ref().collect::<Vec<String>>(), vec!["abc", "ghi"]);
+        assert_eq!(handler.count(), 1);
+        assert_eq!(it.stats(),
+                   InputStats {
+                       records: 2,
+                       bytes: 13,
+                       decode_errors: 1,
+                   });
+
+        // The dead-letter reason locates the line.
+        let dead_letter = String::from("testdata/lines_malformed.dead");
+        let handler = MalformedHandler::new(MalformedPolicy::DeadLetter(dead_letter.clone()));
+        let it = lines::new_from_file(&path).unwrap().handle_malformed(handler);
+        assert_eq!(it.count(), 2);
+        let mut entries = WriteLogReader::new_from_file(&dead_letter).unwrap();
+        assert_eq!(entries.read_vec().unwrap(), b"d\xffef");
+        assert_eq!(entries.read_vec().unwrap(),
+                   b"testdata/lines_malformed: record #1 at byte 5: invalid UTF-8");
+        let _ = fs::remove_file(&dead_letter);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_oversized() {
+        let path = String::from("testdata/lines_oversized");
+        fs::write(&path, "abcdef\r\nabcd\u{e9}f\nabcde\r\nxy").unwrap();
+
+        let params = MRParameters::new().set_max_record_size(5, OversizedRecords::Truncate);
+        let it = lines::new_from_file(&path).unwrap().limit_record_size(&params);
+        // Lines are cut at character boundaries.
+        assert_eq!(it.collect::<Vec<String>>(), vec!["abcde", "abcd", "abcde", "xy"]);
+
+        let params = MRParameters::new().set_max_record_size(5, OversizedRecords::Malformed);
+        let it = lines::new_from_file(&path).unwrap().limit_record_size(&params);
+        assert_eq!(it.collect::<Vec<String>>(), vec!["abcde", "xy"]);
+        assert_eq!(params.malformed.count(), 2);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_invalid_utf8() {
+        let path = String::from("testdata/lines_invalid_utf8");
+        fs::write(&path, b"abc\nd\xffef\nghi\n").unwrap();
+
+        let mut it = lines::new_from_file(&path).unwrap().set_invalid_utf8(InvalidUtf8::Lossy);
+        assert_eq!(it.by_ref().collect::<Vec<String>>(),
+                   vec!["abc", "d\u{fffd}ef", "ghi"]);
+        assert_eq!((it.stats().records, it.stats().decode_errors), (3, 1));
+
+        let mut bytes = lines::new_from_file(&path).unwrap().bytes();
+        assert_eq!(bytes.by_ref().collect::<Vec<Vec<u8>>>(),
+                   vec![b"abc".to_vec(), b"d\xffef".to_vec(), b"ghi".to_vec()]);
+        assert_eq!(bytes.stats().records, 3);
+
+        let strict = lines::new_from_file(&path).unwrap().set_invalid_utf8(InvalidUtf8::Fail);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| strict.count()));
+        let cause = result.unwrap_err();
+        assert_eq!(cause.downcast_ref::<String>().unwrap(),
+                   "testdata/lines_invalid_utf8: record #1 at byte 4: invalid UTF-8");
+        let _ = fs::remove_file(&path);
+    }
+
     #[test]
     fn test_read_dir() {
         let path = String::from("src/");
@@ -165,6 +621,32 @@ mod test {
         assert!(cnt > 300);
     }
 
+    #[test]
+    fn test_split_file() {
+        let path = String::from("testdata/split_file.txt");
+        {
+            let mut f = fs::File::create(&path).unwrap();
+            for i in 0..100 {
+                let _ = writeln!(f, "line {} {}", i, "x".repeat(i % 7));
+            }
+        }
+
+        let splits = lines::split_file(&path, 7).unwrap();
+        assert_eq!(splits.len(), 7);
+
+        let mut all = Vec::new();
+        for split in &splits {
+            let lines: Vec<String> = split.lines().unwrap().collect();
+            assert!(!lines.is_empty());
+            all.extend(lines);
+        }
+        let expected: Vec<String> = lines::new_from_file(&path).unwrap().collect();
+        assert_eq!(all, expected);
+
+        assert_eq!(lines::split_file(&path, 1000).unwrap().len(), 100);
+        let _ = fs::remove_file(path);
+    }
+
     #[test]
     fn test_write_lines() {
         let line = String::from("abc def hello world");
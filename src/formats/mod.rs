@@ -1,5 +1,28 @@
 //! Contains code for on-disk data structures and file formats.
 
+pub use formats::auto::open_auto;
+
+pub mod auto;
+pub mod bloom;
+pub mod compressed;
+pub mod error;
+pub mod limited;
 pub mod lines;
+pub mod output;
+pub mod output_index;
+pub mod partitioned;
+pub mod retry;
+pub mod schema;
+pub mod sink_pool;
+pub mod table;
 pub mod writelog;
 pub mod util;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "prost")]
+pub mod protobuf;
+#[cfg(feature = "flatbuffers")]
+pub mod flatbuffer;
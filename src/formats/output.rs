@@ -0,0 +1,185 @@
+//! Reading the outputs of a finished job, e.g. for consuming the results from Rust code or for
+//! using them as input of another job.
+
+use std::fs;
+use std::io::{self, Read};
+
+use formats::compressed::{OutputCompression, find_output, open_output};
+use formats::lines::{self, LinesReader};
+use formats::util::PosRecordIterator;
+use formats::writelog::WriteLogReader;
+use parameters::MRParameters;
+use record_types::Record;
+use shard_merge::ShardMergeIterator;
+use sort::DictComparableString;
+
+/// The format of reduce output shards, which depends on the SinkGenerator used by the job (see
+/// `MRParameters::set_reduce_output_format()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Detect the format of every shard from its contents.
+    Auto,
+    /// Text files as written by `lines::LinesSinkGenerator`.
+    Lines,
+    /// WriteLogs as written by `writelog::WriteLogGenerator`.
+    WriteLog,
+}
+
+// How many bytes are inspected when detecting the format of a shard.
+const DETECT_BYTES: usize = 64 * 1024;
+
+/// Returns whether the file at `path` looks like a WriteLog: The length prefixes of the entries
+/// at its beginning must be consistent with each other and with the file's length. (The first
+/// four bytes of a text file usually decode to a length larger than the file.)
+fn is_writelog(path: &String) -> io::Result<bool> {
+    let len = fs::metadata(path)?.len();
+    let mut head = Vec::with_capacity(DETECT_BYTES);
+    fs::File::open(path)?.take(DETECT_BYTES as u64).read_to_end(&mut head)?;
+    if head.is_empty() {
+        return Ok(false);
+    }
+
+    let mut pos = 0;
+    while pos + 4 <= head.len() {
+        let length = u32::from_be_bytes([head[pos], head[pos + 1], head[pos + 2], head[pos + 3]]);
+        pos += 4 + length as usize;
+        if pos as u64 > len {
+            return Ok(false);
+        }
+    }
+    Ok(pos as u64 == len || pos >= head.len())
+}
+
+/// Opens the text or WriteLog file `path` and returns an iterator over its lines (entries).
+/// With `OutputFormat::Auto`, the format is detected from the file's contents. Files compressed
+/// by `MRParameters::set_output_compression()` are decompressed.
+pub fn read_shard(path: &String,
+                  format: OutputFormat)
+                  -> io::Result<Box<dyn Iterator<Item = String>>> {
+    if OutputCompression::of_path(path) != OutputCompression::None {
+        return read_compressed_shard(path, format);
+    }
+    let writelog = match format {
+        OutputFormat::Auto => is_writelog(path)?,
+        OutputFormat::Lines => false,
+        OutputFormat::WriteLog => true,
+    };
+    if writelog {
+        Ok(Box::new(WriteLogReader::new_from_file(path)?))
+    } else {
+        Ok(Box::new(lines::new_from_file(path)?))
+    }
+}
+
+fn read_compressed_shard(path: &String,
+                         format: OutputFormat)
+                         -> io::Result<Box<dyn Iterator<Item = String>>> {
+    let mut src = open_output(path)?;
+    let mut head = Vec::new();
+    src.by_ref().take(DETECT_BYTES as u64).read_to_end(&mut head)?;
+    let writelog = match format {
+        // The length of the contents isn't known; but text doesn't contain zero bytes, while the
+        // length prefix of WriteLog entries shorter than 16 MiB starts with one.
+        OutputFormat::Auto => head.first() == Some(&0),
+        OutputFormat::Lines => false,
+        OutputFormat::WriteLog => true,
+    };
+    let src: Box<dyn Read + Send> = Box::new(io::Cursor::new(head).chain(src));
+    if writelog {
+        Ok(Box::new(WriteLogReader::new(src)))
+    } else {
+        Ok(Box::new(LinesReader::new(src)))
+    }
+}
+
+/// Opens the reduce output shards `<prefix>0`, `<prefix>1`, ... (usually
+/// `params.reduce_output_shard_prefix`), up to the first missing shard, and returns an iterator
+/// over the lines of each shard. The format of the shards is set by
+/// `MRParameters::set_reduce_output_format()`; compressed shards (`<prefix>0.gz` etc.) are
+/// decompressed. Returns an error if there is no shard.
+pub fn read_reduce_output_shards(prefix: &String,
+                                 params: &MRParameters)
+                                 -> io::Result<Vec<Box<dyn Iterator<Item = String>>>> {
+    let mut shards = Vec::new();
+    while let Some(path) = find_output(&format!("{}{}", prefix, shards.len())) {
+        shards.push(read_shard(&path, params.reduce_output_format)?);
+    }
+    if shards.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  format!("No reduce outputs found for {}", prefix)));
+    }
+    Ok(shards)
+}
+
+/// Reads all reduce output shards (see `read_reduce_output_shards()`) as one sequence.
+///
+/// The outputs are returned as records keyed by their position (like `PosRecordIterator`), with
+/// the output line as value, so that they can be used as input of another job. The shards are
+/// concatenated in shard order, which results in total order for range-partitioned jobs (see
+/// `mapreducer::RangeSharder`); otherwise, they can be merged in dictionary order (see
+/// `MRParameters::set_merge_reduce_outputs()`), which is useful if the output lines start with
+/// their key.
+pub fn read_reduce_outputs(prefix: &String,
+                           params: &MRParameters)
+                           -> io::Result<Box<dyn Iterator<Item = Record>>> {
+    let shards = read_reduce_output_shards(prefix, params)?;
+    if params.merge_reduce_outputs {
+        let mut sources = shards.into_iter().map(|s| s.map(DictComparableString::wrap));
+        let merged = ShardMergeIterator::build(&mut sources).map(DictComparableString::unwrap);
+        Ok(Box::new(PosRecordIterator::new(merged)))
+    } else {
+        Ok(Box::new(PosRecordIterator::new(shards.into_iter().flatten())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputFormat, is_writelog, read_reduce_outputs};
+    use formats::lines::LinesSinkGenerator;
+    use formats::writelog::WriteLogGenerator;
+    use parameters::MRParameters;
+    use phases::output::SinkGenerator;
+    use std::fs;
+    use std::io::Write;
+
+    fn write_shards<G: SinkGenerator>(gen: G, prefix: &str, shards: &[&[&str]]) {
+        for (i, lines) in shards.iter().enumerate() {
+            let mut out = gen.new_output(&format!("{}{}", prefix, i));
+            for l in lines.iter() {
+                let _ = out.write(l.as_bytes()).unwrap();
+            }
+        }
+    }
+
+    fn read(prefix: &str, params: MRParameters) -> Vec<String> {
+        read_reduce_outputs(&String::from(prefix), &params)
+            .unwrap()
+            .map(|r| format!("{}={}", r.key, r.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_reduce_outputs() {
+        let shards: &[&[&str]] = &[&["b 1", "d 2"], &["a 3", "c 4"], &[]];
+        write_shards(LinesSinkGenerator::new_to_files(), "testdata/rro_lines_", shards);
+        write_shards(WriteLogGenerator::new(), "testdata/rro_wlg_", shards);
+        assert!(!is_writelog(&String::from("testdata/rro_lines_0")).unwrap());
+        assert!(is_writelog(&String::from("testdata/rro_wlg_0")).unwrap());
+
+        for prefix in &["testdata/rro_lines_", "testdata/rro_wlg_"] {
+            assert_eq!(read(prefix, MRParameters::new()),
+                       vec!["1=b 1", "2=d 2", "3=a 3", "4=c 4"]);
+            assert_eq!(read(prefix, MRParameters::new().set_merge_reduce_outputs(true)),
+                       vec!["1=a 3", "2=b 1", "3=c 4", "4=d 2"]);
+        }
+        let params = MRParameters::new().set_reduce_output_format(OutputFormat::Lines);
+        assert_eq!(read("testdata/rro_lines_", params).len(), 4);
+        assert!(read_reduce_outputs(&String::from("testdata/rro_none_"), &MRParameters::new())
+            .is_err());
+
+        for i in 0..3 {
+            let _ = fs::remove_file(format!("testdata/rro_lines_{}", i));
+            let _ = fs::remove_file(format!("testdata/rro_wlg_{}", i));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Looking up keys in the output of a finished range-partitioned job (see
+//! `mapreducer::RangeSharder`), whose text output shards are sorted by key within and across
+//! shards. This turns the output of a job into a sorted dataset that can be queried directly.
+//!
+//! `OutputIndex::open()` reads every shard once and keeps the first key of every block of about
+//! `BLOCK_BYTES` bytes in memory; lookups binary-search the shard and the block containing a
+//! key, and only read from there on.
+
+use formats::error::FormatError;
+use formats::lines::LinesReader;
+use sort::dict_str_compare;
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, BufRead, Seek};
+use std::ops::{Bound, RangeBounds};
+
+/// The approximate size of the blocks of a shard that are indexed.
+pub const BLOCK_BYTES: u64 = 64 * 1024;
+
+/// Returns the key of an output line.
+pub type LineKeyF = fn(&str) -> &str;
+
+/// The default key of an output line: the text before the first space or tab, or the whole
+/// line.
+pub fn first_field(line: &str) -> &str {
+    line.split([' ', '\t']).next().unwrap_or(line)
+}
+
+struct Block {
+    first_key: String,
+    offset: u64,
+}
+
+struct ShardIndex {
+    path: String,
+    blocks: Vec<Block>,
+    last_key: String,
+}
+
+/// A sparse index over the output shards `<prefix>0`, `<prefix>1`, ... of a range-partitioned
+/// job. See the module documentation.
+pub struct OutputIndex {
+    // Only shards with at least one line.
+    shards: Vec<ShardIndex>,
+    key_of: LineKeyF,
+}
+
+impl OutputIndex {
+    /// Indexes the output shards at `prefix` (usually `params.reduce_output_shard_prefix`), up to
+    /// the first missing shard. The key of a line is its first field (see `first_field()`).
+    /// Returns an error if there is no shard, or if the lines are not sorted by key.
+    pub fn open(prefix: &str) -> io::Result<OutputIndex> {
+        OutputIndex::open_with(prefix, first_field)
+    }
+
+    /// Like `open()`, with the key of every line returned by `key_of`.
+    pub fn open_with(prefix: &str, key_of: LineKeyF) -> io::Result<OutputIndex> {
+        let mut index = OutputIndex {
+            shards: Vec::new(),
+            key_of,
+        };
+        let mut shard = 0;
+        loop {
+            let path = format!("{}{}", prefix, shard);
+            if fs::metadata(&path).is_err() {
+                break;
+            }
+            if let Some(s) = index.index_shard(path)? {
+                index.shards.push(s);
+            }
+            shard += 1;
+        }
+        if shard == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("No reduce outputs found for {}", prefix)));
+        }
+        Ok(index)
+    }
+
+    fn index_shard(&self, path: String) -> io::Result<Option<ShardIndex>> {
+        let mut f = io::BufReader::new(fs::File::open(&path)?);
+        let unsorted = |offset: u64| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Output {} is not sorted by key at offset {}", path, offset))
+        };
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut line = Vec::new();
+        let mut last_key = self.shards.last().map(|s| s.last_key.clone());
+        let (mut offset, mut next_block) = (0, 0);
+        loop {
+            line.clear();
+            let n = f.read_until(b'\n', &mut line)?;
+            if n == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            let key = (self.key_of)(text.trim_end_matches(['\n', '\r']));
+            if let Some(ref last) = last_key {
+                if dict_str_compare(last, key) == Ordering::Greater {
+                    return Err(unsorted(offset));
+                }
+            }
+            if offset >= next_block {
+                blocks.push(Block {
+                    first_key: String::from(key),
+                    offset,
+                });
+                next_block = offset + BLOCK_BYTES;
+            }
+            last_key = Some(String::from(key));
+            offset += n as u64;
+        }
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ShardIndex {
+            path: path.clone(),
+            blocks,
+            last_key: last_key.unwrap(),
+        }))
+    }
+
+    /// Returns all lines with key `key`.
+    pub fn get(&self, key: &str) -> io::Result<Vec<String>> {
+        let mut lines = self.range((Bound::Included(key), Bound::Included(key)));
+        let result = lines.by_ref().collect();
+        match lines.error.take() {
+            Some(e) => Err(e.into()),
+            None => Ok(result),
+        }
+    }
+
+    /// Returns the lines with keys in `range` (e.g. `"b".."d"`), in order. Only the blocks
+    /// containing keys in the range are read.
+    pub fn range<'a, R: RangeBounds<&'a str>>(&self, range: R) -> RangeIter {
+        let (start, end) = (owned(range.start_bound()), owned(range.end_bound()));
+
+        let mut shards = Vec::new();
+        let first = match start {
+            Bound::Included(ref k) | Bound::Excluded(ref k) => k.as_str(),
+            Bound::Unbounded => "",
+        };
+        let less = |k: &str| dict_str_compare(k, first) == Ordering::Less;
+        let first_shard = self.shards.partition_point(|s| less(&s.last_key));
+        for (i, s) in self.shards[first_shard..].iter().enumerate() {
+            // Lines with the first key may start in the block before the first block starting
+            // with a key not less than it.
+            let offset = if i == 0 {
+                let after = s.blocks.partition_point(|b| less(&b.first_key));
+                s.blocks[after.saturating_sub(1)].offset
+            } else {
+                0
+            };
+            shards.push((s.path.clone(), offset));
+        }
+        shards.reverse();
+        RangeIter {
+            shards,
+            reader: None,
+            key_of: self.key_of,
+            start,
+            end,
+            error: None,
+        }
+    }
+}
+
+fn owned(bound: Bound<&&str>) -> Bound<String> {
+    match bound {
+        Bound::Included(k) => Bound::Included(String::from(*k)),
+        Bound::Excluded(k) => Bound::Excluded(String::from(*k)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Iterates over the lines of a key range (see `OutputIndex::range()`).
+pub struct RangeIter {
+    // The shards still to read with the offset to start at, last shard first.
+    shards: Vec<(String, u64)>,
+    reader: Option<LinesReader<fs::File>>,
+    key_of: LineKeyF,
+    start: Bound<String>,
+    end: Bound<String>,
+    error: Option<FormatError>,
+}
+
+impl RangeIter {
+    /// Returns the error that ended the iteration early, if reading failed.
+    pub fn error(&self) -> Option<&FormatError> {
+        self.error.as_ref()
+    }
+
+    fn open_next(&mut self) -> bool {
+        let (path, offset) = match self.shards.pop() {
+            None => return false,
+            Some(s) => s,
+        };
+        let opened = fs::File::open(&path).and_then(|mut f| {
+            f.seek(io::SeekFrom::Start(offset))?;
+            Ok(f)
+        });
+        match opened {
+            Ok(f) => {
+                self.reader = Some(LinesReader::new(f).set_source(&path, offset));
+                true
+            }
+            Err(e) => {
+                self.error = Some(FormatError::new(&path, offset, 0, e));
+                false
+            }
+        }
+    }
+}
+
+impl Iterator for RangeIter {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        loop {
+            if self.reader.is_none() && (self.error.is_some() || !self.open_next()) {
+                return None;
+            }
+            let line = match self.reader.as_mut().unwrap().next() {
+                Some(line) => line,
+                None => {
+                    let reader = self.reader.take().unwrap();
+                    if let Some(e) = reader.error() {
+                        let cause = io::Error::new(e.cause.kind(), e.cause.to_string());
+                        self.error = Some(FormatError::new(&e.file, e.offset, e.record, cause));
+                    }
+                    continue;
+                }
+            };
+            let key = (self.key_of)(&line);
+            let past_end = match self.end {
+                Bound::Included(ref k) => dict_str_compare(key, k) == Ordering::Greater,
+                Bound::Excluded(ref k) => dict_str_compare(key, k) != Ordering::Less,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                self.shards.clear();
+                self.reader = None;
+                return None;
+            }
+            let before_start = match self.start {
+                Bound::Included(ref k) => dict_str_compare(key, k) == Ordering::Less,
+                Bound::Excluded(ref k) => dict_str_compare(key, k) != Ordering::Greater,
+                Bound::Unbounded => false,
+            };
+            if !before_start {
+                return Some(line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BLOCK_BYTES, OutputIndex};
+    use std::fs;
+    use std::io::Write;
+
+    fn write_shards(prefix: &str, shards: &[Vec<String>]) {
+        for (i, lines) in shards.iter().enumerate() {
+            let mut f = fs::File::create(format!("{}{}", prefix, i)).unwrap();
+            for l in lines.iter() {
+                writeln!(f, "{}", l).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_index() {
+        let prefix = "testdata/output_index_";
+        // Enough lines for several blocks per shard; "k0500" is repeated across a block
+        // boundary.
+        let line = |i: usize| format!("k{:04} {}", i, "x".repeat(200));
+        let mut first: Vec<String> = (0..1000).map(line).collect();
+        first.insert(500, line(500));
+        first.insert(500, line(500));
+        let second: Vec<String> = (1000..1400).map(line).collect();
+        write_shards(prefix, &[first, Vec::new(), second]);
+        assert!(1001 * 206 > 2 * BLOCK_BYTES as usize);
+
+        let index = OutputIndex::open(prefix).unwrap();
+        assert_eq!(index.get("k0500").unwrap().len(), 3);
+        assert_eq!(index.get("k0000").unwrap(), vec![line(0)]);
+        assert_eq!(index.get("k1000").unwrap(), vec![line(1000)]);
+        assert_eq!(index.get("k1399").unwrap(), vec![line(1399)]);
+        assert!(index.get("k2000").unwrap().is_empty());
+        assert!(index.get("a").unwrap().is_empty());
+
+        let keys = |lines: Vec<String>| -> Vec<String> {
+            lines.iter().map(|l| String::from(&l[..5])).collect()
+        };
+        assert_eq!(keys(index.range("k0998".."k1002").collect()),
+                   vec!["k0998", "k0999", "k1000", "k1001"]);
+        assert_eq!(keys(index.range("k1397"..).collect()), vec!["k1397", "k1398", "k1399"]);
+        assert_eq!(index.range(.."k0010").count(), 10);
+        assert_eq!(index.range("k0499"..="k0500").count(), 4);
+        assert_eq!(index.range(..).count(), 1402);
+
+        write_shards(prefix, &[vec![String::from("b 1"), String::from("a 2")]]);
+        assert!(OutputIndex::open(prefix).is_err());
+        for i in 0..3 {
+            let _ = fs::remove_file(format!("{}{}", prefix, i));
+        }
+        assert!(OutputIndex::open(prefix).is_err());
+    }
+}
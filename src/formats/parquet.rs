@@ -0,0 +1,203 @@
+//! Writes reduce output as Parquet files (enabled by the `parquet` feature), so that results can
+//! be loaded directly by tools like DuckDB, Spark or pandas.
+//!
+//! Every value written to a sink (i.e. every value emitted by a reducer) becomes one row. The
+//! value is split into string columns at a separator character; by default, the schema consists
+//! of the two columns `key` and `value`, separated by a tab character.
+
+extern crate parquet;
+
+use self::parquet::data_type::{ByteArray, ByteArrayType};
+use self::parquet::errors::ParquetError;
+use self::parquet::file::properties::WriterProperties;
+use self::parquet::file::writer::SerializedFileWriter;
+use self::parquet::schema::parser::parse_message_type;
+
+use formats::util::split_columns;
+use phases::output::SinkGenerator;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+fn to_io_error(e: ParquetError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// A SinkGenerator creating Parquet files with a schema of optional string columns.
+#[derive(Clone)]
+pub struct ParquetSinkGenerator {
+    columns: Vec<String>,
+    separator: char,
+    rows_per_group: usize,
+}
+
+impl ParquetSinkGenerator {
+    /// Writes rows consisting of `columns`; every value is split at `separator` into at most
+    /// `columns.len()` fields. Missing fields are written as null.
+    pub fn new(columns: Vec<String>, separator: char) -> ParquetSinkGenerator {
+        ParquetSinkGenerator {
+            columns,
+            separator,
+            rows_per_group: 64 * 1024,
+        }
+    }
+
+    /// Writes rows with the columns (key, value); the reducer should emit values of the form
+    /// `key<TAB>value`.
+    pub fn new_key_value() -> ParquetSinkGenerator {
+        ParquetSinkGenerator::new(vec![String::from("key"), String::from("value")], '\t')
+    }
+
+    /// Sets how many rows are buffered in memory and written as one row group.
+    ///
+    /// Default 65536
+    pub fn set_rows_per_group(mut self, n: usize) -> ParquetSinkGenerator {
+        self.rows_per_group = n;
+        self
+    }
+
+    fn schema(&self) -> String {
+        let mut schema = String::from("message localmr_output {\n");
+        for c in &self.columns {
+            schema.push_str(&format!("OPTIONAL BYTE_ARRAY {} (UTF8);\n", c));
+        }
+        schema.push('}');
+        schema
+    }
+
+    fn open(&self, path: &String) -> io::Result<ParquetWriter> {
+        let schema = Arc::new(parse_message_type(&self.schema()).map_err(to_io_error)?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let f = fs::OpenOptions::new().write(true).truncate(true).create(true).open(path)?;
+        let writer = SerializedFileWriter::new(f, schema, props).map_err(to_io_error)?;
+
+        Ok(ParquetWriter {
+            writer: Some(writer),
+            columns: vec![Vec::new(); self.columns.len()],
+            separator: self.separator,
+            rows: 0,
+            rows_per_group: self.rows_per_group,
+        })
+    }
+}
+
+impl SinkGenerator for ParquetSinkGenerator {
+    type Sink = ParquetWriter;
+    fn new_output(&self, path: &String) -> Self::Sink {
+        match self.open(path) {
+            Err(e) => panic!("Couldn't open parquet output file {}: {}", path, e),
+            Ok(w) => w,
+        }
+    }
+}
+
+/// Writer for a single Parquet file. Rows are buffered and written in row groups; the file is
+/// completed when the writer is dropped.
+pub struct ParquetWriter {
+    writer: Option<SerializedFileWriter<fs::File>>,
+    // Buffered fields, per column; None is null.
+    columns: Vec<Vec<Option<ByteArray>>>,
+    separator: char,
+    rows: usize,
+    rows_per_group: usize,
+}
+
+impl ParquetWriter {
+    fn write_row_group(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let writer = match self.writer {
+            Some(ref mut w) => w,
+            None => return Err(io::Error::other("parquet file closed")),
+        };
+        let mut row_group = writer.next_row_group().map_err(to_io_error)?;
+
+        for fields in self.columns.iter_mut() {
+            let mut column = match row_group.next_column().map_err(to_io_error)? {
+                Some(c) => c,
+                None => break,
+            };
+            let def_levels: Vec<i16> =
+                fields.iter().map(|f| if f.is_some() { 1 } else { 0 }).collect();
+            let values: Vec<ByteArray> = fields.drain(..).flatten().collect();
+
+            column.typed::<ByteArrayType>()
+                .write_batch(&values, Some(&def_levels), None)
+                .map_err(to_io_error)?;
+            column.close().map_err(to_io_error)?;
+        }
+        row_group.close().map_err(to_io_error)?;
+        self.rows = 0;
+        Ok(())
+    }
+}
+
+impl io::Write for ParquetWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let row = String::from_utf8_lossy(buf);
+        let fields = split_columns(&row, self.separator, self.columns.len());
+
+        for (column, field) in self.columns.iter_mut().zip(fields) {
+            column.push(field.map(|f| ByteArray::from(f.as_bytes().to_vec())));
+        }
+        self.rows += 1;
+
+        if self.rows >= self.rows_per_group {
+            self.write_row_group()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes the buffered rows as a row group.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_row_group()
+    }
+}
+
+impl Drop for ParquetWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_row_group() {
+            println!("WARN: Couldn't write parquet row group: {}", e);
+        }
+        if let Some(w) = self.writer.take() {
+            if let Err(e) = w.close() {
+                println!("WARN: Couldn't finish parquet file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parquet::file::reader::{FileReader, SerializedFileReader};
+    use super::ParquetSinkGenerator;
+    use phases::output::SinkGenerator;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_write_parquet() {
+        let path = String::from("testdata/parquet_output_0");
+        {
+            let gen = ParquetSinkGenerator::new_key_value().set_rows_per_group(2);
+            let mut w = gen.new_output(&path);
+            let _ = w.write(b"abc\t3");
+            let _ = w.write(b"def\t2\tx");
+            let _ = w.write(b"ghi");
+        }
+
+        let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+
+        let rows: Vec<String> = reader.get_row_iter(None)
+            .unwrap()
+            .map(|r| r.unwrap().to_string())
+            .collect();
+        assert_eq!(rows,
+                   vec!["{key: \"abc\", value: \"3\"}",
+                        "{key: \"def\", value: \"2\tx\"}",
+                        "{key: \"ghi\", value: null}"]);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,326 @@
+//! Partitioned outputs: Every record written by a reduce partition goes to the file of its
+//! partition label, as computed by a user function from the record (e.g. the date of an event),
+//! instead of to the single output file of the partition (see
+//! `MRParameters::set_output_partitioning()`). For the output `<dir>/<file>` of a reduce
+//! partition, the records labelled `<label>` are written to `<dir>/<label>/<file>`, e.g.
+//! `output/date=2024-05-01/part-0`; labels may contain `/` for nested partitions.
+//!
+//! Routed outputs split the results of a reduce partition by key instead (see
+//! `MRParameters::set_output_routing()`): The results of a key are written to the file of its
+//! route within the shard, `<dir>/<file>-<route>`, so that related keys (e.g. of the same date)
+//! land in predictable files.
+//!
+//! A reduce partition can write to many labels or routes; at most a given number of files are
+//! open at a time (see `formats::sink_pool::SinkPool`), so the sink generator must be able to
+//! append to its outputs.
+
+use formats::compressed::{CompressedSink, OutputCompression};
+use formats::limited::LimitedSink;
+use formats::sink_pool::{SinkPool, max_open_per_partition};
+use mapreducer::{OutputPartitionF, OutputRouteF};
+use parameters::{MRParameters, OutputLimitPolicy};
+use phases::output::{SinkGenerator, reduce_output_route_name};
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path};
+
+/// Writes every record to the file of its partition label.
+pub struct PartitionedSink<G: SinkGenerator> {
+    pool: SinkPool<G>,
+    label: OutputPartitionF,
+    // The directory and file name of the unpartitioned output.
+    dir: String,
+    file_name: String,
+    // The file of every label written to.
+    paths: HashMap<String, String>,
+}
+
+impl<G: SinkGenerator> PartitionedSink<G> {
+    /// Returns a sink partitioning the records written to `output`, keeping at most `max_open`
+    /// files open.
+    pub fn new(generator: G,
+               output: &String,
+               label: OutputPartitionF,
+               max_open: usize)
+               -> PartitionedSink<G> {
+        let path = Path::new(output);
+        let dir = match path.parent() {
+            Some(p) if p != Path::new("") => p.to_string_lossy().into_owned(),
+            _ => String::from("."),
+        };
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        PartitionedSink {
+            pool: SinkPool::new(generator, max_open),
+            label,
+            dir,
+            file_name: file_name.unwrap_or_default(),
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Returns the labels written to, sorted.
+    pub fn labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = self.paths.keys().cloned().collect();
+        labels.sort();
+        labels
+    }
+
+    /// Returns the file for `label`, creating its directory when the label is first seen.
+    fn path(&mut self, label: String) -> io::Result<String> {
+        if let Some(path) = self.paths.get(&label) {
+            return Ok(path.clone());
+        }
+        let valid = Path::new(&label).components().all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Invalid output partition label {:?}", label)));
+        }
+        let dir = format!("{}/{}", self.dir, label);
+        fs::create_dir_all(&dir)?;
+        let path = format!("{}/{}", dir, self.file_name);
+        self.paths.insert(label, path.clone());
+        Ok(path)
+    }
+}
+
+impl<G: SinkGenerator> Write for PartitionedSink<G> {
+    /// Writes a record as a whole to the file of its label.
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let label = (self.label)(String::from_utf8_lossy(data).trim_end_matches('\n'));
+        let path = self.path(label)?;
+        self.pool.write(&path, data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pool.flush()
+    }
+}
+
+/// Writes the results of a reduce partition to files chosen by their key (see `RoutedSink`).
+pub trait KeyRouter {
+    /// Writes `data`, a result of `key`, to the file of the key's route. Returns false if the
+    /// key has no route, and the result belongs to the output of the partition.
+    fn write_routed(&mut self, key: &str, data: &[u8]) -> io::Result<bool>;
+    /// Returns the files written to, sorted.
+    fn files(&self) -> Vec<String>;
+}
+
+/// Writes the results of every key to the file of its route (see
+/// `MRParameters::set_output_routing()`).
+pub struct RoutedSink<G: SinkGenerator> {
+    pool: SinkPool<G>,
+    route: OutputRouteF,
+    output: String,
+    files: BTreeSet<String>,
+    // The last key and its file, as the results of a key are written one after another.
+    last: Option<(String, Option<String>)>,
+}
+
+impl<G: SinkGenerator> RoutedSink<G> {
+    /// Returns a sink routing the results written for `output`, keeping at most `max_open` files
+    /// open.
+    pub fn new(generator: G,
+               output: &str,
+               route: OutputRouteF,
+               max_open: usize)
+               -> RoutedSink<G> {
+        RoutedSink {
+            pool: SinkPool::new(generator, max_open),
+            route,
+            output: String::from(output),
+            files: BTreeSet::new(),
+            last: None,
+        }
+    }
+
+    /// Returns a sink routing the results written for `output` with `generator`, if the job with
+    /// `params` routes its outputs.
+    pub fn for_job(generator: &G, output: &str, params: &MRParameters) -> Option<RoutedSink<G>> {
+        params.output_routing.map(|(route, max_open)| {
+            let max_open = if max_open == 0 {
+                max_open_per_partition(params.reducers)
+            } else {
+                max_open
+            };
+            RoutedSink::new(generator.clone(), output, route, max_open)
+        })
+    }
+
+    /// Returns the file of `key`, or None if it has no route.
+    fn file(&mut self, key: &str) -> io::Result<Option<String>> {
+        if let Some((ref last, ref file)) = self.last {
+            if last == key {
+                return Ok(file.clone());
+            }
+        }
+        let route = (self.route)(key);
+        let file = if route.is_empty() {
+            None
+        } else if route.starts_with('.') || route.contains(['/', '\\']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Invalid output route {:?}", route)));
+        } else {
+            Some(reduce_output_route_name(&self.output, &route))
+        };
+        self.last = Some((String::from(key), file.clone()));
+        Ok(file)
+    }
+}
+
+impl<G: SinkGenerator> KeyRouter for RoutedSink<G> {
+    fn write_routed(&mut self, key: &str, data: &[u8]) -> io::Result<bool> {
+        let file = match self.file(key)? {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        self.pool.write(&file, data)?;
+        if !self.files.contains(&file) {
+            self.files.insert(file);
+        }
+        Ok(true)
+    }
+
+    fn files(&self) -> Vec<String> {
+        self.files.iter().cloned().collect()
+    }
+}
+
+/// The output of a reduce partition: a single file, a file with a size limit, or one file per
+/// partition label; single and limited files may be compressed.
+pub enum ReduceSink<G: SinkGenerator> {
+    Single(G::Sink),
+    Limited(LimitedSink<G>),
+    Partitioned(PartitionedSink<G>),
+    Compressed(Box<CompressedSink<ReduceSink<G>>>),
+}
+
+impl<G: SinkGenerator> ReduceSink<G> {
+    /// Opens the output `name` with `generator`, partitioned if the job with `params` partitions
+    /// its outputs, or limited if it limits their size, and compressed if it compresses them.
+    pub fn open(generator: &G, name: &String, params: &MRParameters) -> ReduceSink<G> {
+        let compression = params.output_compression;
+        match params.output_partitioning {
+            None => {
+                let limit = match params.output_size_limit {
+                    // The output is compressed anyway.
+                    Some((_, OutputLimitPolicy::Compress)) if compression !=
+                                                              OutputCompression::None => None,
+                    limit => limit,
+                };
+                let sink = match limit {
+                    None => ReduceSink::Single(generator.new_output(name)),
+                    Some((limit, policy)) => {
+                        ReduceSink::Limited(LimitedSink::new(generator.clone(),
+                                                             name,
+                                                             limit,
+                                                             policy))
+                    }
+                };
+                match compression {
+                    OutputCompression::None => sink,
+                    _ => {
+                        let sink = CompressedSink::new(sink, name, compression);
+                        ReduceSink::Compressed(Box::new(sink))
+                    }
+                }
+            }
+            Some((label, max_open)) => {
+                let max_open = if max_open == 0 {
+                    max_open_per_partition(params.reducers)
+                } else {
+                    max_open
+                };
+                ReduceSink::Partitioned(PartitionedSink::new(generator.clone(),
+                                                             name,
+                                                             label,
+                                                             max_open))
+            }
+        }
+    }
+}
+
+impl<G: SinkGenerator> Write for ReduceSink<G> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match *self {
+            ReduceSink::Single(ref mut sink) => sink.write(data),
+            ReduceSink::Limited(ref mut sink) => sink.write(data),
+            ReduceSink::Partitioned(ref mut sink) => sink.write(data),
+            ReduceSink::Compressed(ref mut sink) => sink.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ReduceSink::Single(ref mut sink) => sink.flush(),
+            ReduceSink::Limited(ref mut sink) => sink.flush(),
+            ReduceSink::Partitioned(ref mut sink) => sink.flush(),
+            ReduceSink::Compressed(ref mut sink) => sink.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyRouter, PartitionedSink, RoutedSink};
+    use formats::lines::{self, LinesSinkGenerator};
+    use std::fs;
+    use std::io::Write;
+
+    fn first_word(record: &str) -> String {
+        String::from(record.split(' ').next().unwrap_or(""))
+    }
+
+    #[test]
+    fn test_partitioned_sink() {
+        let output = String::from("testdata/partitioned/part-0");
+        {
+            let mut sink =
+                PartitionedSink::new(LinesSinkGenerator::new_to_files(), &output, first_word, 1);
+            for record in ["date=2024-05-01 a", "date=2024-05-02 b", "date=2024-05-01 c"].iter() {
+                sink.write_all(record.as_bytes()).unwrap();
+            }
+            assert!(sink.write(b"../escape x").is_err());
+            sink.flush().unwrap();
+            assert_eq!(sink.labels(), vec!["date=2024-05-01", "date=2024-05-02"]);
+        }
+        let read = |label: &str| -> Vec<String> {
+            lines::new_from_file(&format!("testdata/partitioned/{}/part-0", label))
+                .unwrap()
+                .collect()
+        };
+        // The first partition has been reopened for appending.
+        assert_eq!(read("date=2024-05-01"), vec!["date=2024-05-01 a", "date=2024-05-01 c"]);
+        assert_eq!(read("date=2024-05-02"), vec!["date=2024-05-02 b"]);
+        let _ = fs::remove_dir_all("testdata/partitioned");
+    }
+
+    fn date(key: &str) -> String {
+        String::from(key.split('T').next().unwrap_or(""))
+    }
+
+    #[test]
+    fn test_routed_sink() {
+        let output = String::from("testdata/routed_0");
+        {
+            let mut sink = RoutedSink::new(LinesSinkGenerator::new_to_files(), &output, date, 1);
+            for key in ["2024-05-01T10", "2024-05-02T11", "2024-05-01T12"].iter() {
+                assert!(sink.write_routed(key, key.as_bytes()).unwrap());
+            }
+            assert!(!sink.write_routed("T13", b"T13").unwrap());
+            assert!(sink.write_routed("../escapeT", b"x").is_err());
+            assert_eq!(sink.files(),
+                       vec!["testdata/routed_0-2024-05-01", "testdata/routed_0-2024-05-02"]);
+        }
+        let read = |route: &str| -> Vec<String> {
+            let name = format!("testdata/routed_0-{}", route);
+            let lines = lines::new_from_file(&name).unwrap().collect();
+            let _ = fs::remove_file(name);
+            lines
+        };
+        assert_eq!(read("2024-05-01"), vec!["2024-05-01T10", "2024-05-01T12"]);
+        assert_eq!(read("2024-05-02"), vec!["2024-05-02T11"]);
+    }
+}
@@ -0,0 +1,46 @@
+//! Protocol buffer values (enabled by the `prost` feature): `ProstCodec` decodes and encodes
+//! messages generated by `prost`, e.g. for reading event logs stored as WriteLogs of serialized
+//! messages (see `codec::decode_records()`), or for emitting messages from reducers (see
+//! `REmitter::emit_encoded()`).
+
+extern crate prost;
+
+use self::prost::Message;
+
+use codec::ValueCodec;
+
+use std::marker::PhantomData;
+
+/// Decodes and encodes `prost` messages of type `M`.
+pub struct ProstCodec<M> {
+    _type: PhantomData<fn() -> M>,
+}
+
+impl<M> ProstCodec<M> {
+    pub fn new() -> ProstCodec<M> {
+        ProstCodec { _type: PhantomData }
+    }
+}
+
+impl<M> Default for ProstCodec<M> {
+    fn default() -> ProstCodec<M> {
+        ProstCodec::new()
+    }
+}
+
+impl<M> Clone for ProstCodec<M> {
+    fn clone(&self) -> ProstCodec<M> {
+        ProstCodec::new()
+    }
+}
+
+impl<M: Message + Default> ValueCodec<M> for ProstCodec<M> {
+    fn decode(&self, bytes: &[u8]) -> Result<M, String> {
+        M::decode(bytes).map_err(|e| e.to_string())
+    }
+
+    fn encode(&self, value: &M, out: &mut Vec<u8>) {
+        // Encoding only fails if the buffer can't grow, which a Vec always can.
+        value.encode(out).expect("Couldn't encode protocol buffer message");
+    }
+}
@@ -0,0 +1,201 @@
+//! Retries transient I/O errors (see `MRParameters::set_io_retry()`), so that a partition doesn't
+//! fail on the first hiccup of the file system:
+//!
+//! * `EINTR` (`io::ErrorKind::Interrupted`) is always retried right away;
+//! * `EAGAIN` (`io::ErrorKind::WouldBlock`) is retried a number of times after a short pause;
+//! * `ENOSPC` (`io::ErrorKind::StorageFull`) is retried for a while if requested, which helps when
+//!   the cleanup of another job is about to free space.
+//!
+//! WriteLogWriter, WriteLogReader and LinesWriter use an `IoRetry` for their I/O operations.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+// How often a full disk is checked again while waiting for space.
+const NO_SPACE_POLL: Duration = Duration::from_secs(1);
+
+/// A policy for retrying transient I/O errors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoRetry {
+    attempts: usize,
+    backoff: Duration,
+    no_space_wait: Option<Duration>,
+}
+
+impl Default for IoRetry {
+    /// Retries `EAGAIN` 5 times, 10 ms apart, and doesn't wait for space.
+    fn default() -> IoRetry {
+        IoRetry::new(5, Duration::from_millis(10))
+    }
+}
+
+impl IoRetry {
+    /// Returns a policy retrying `EAGAIN` up to `attempts` times, pausing `backoff` before every
+    /// attempt. A full disk isn't waited for.
+    pub fn new(attempts: usize, backoff: Duration) -> IoRetry {
+        IoRetry {
+            attempts,
+            backoff,
+            no_space_wait: None,
+        }
+    }
+
+    /// Returns a policy that only retries `EINTR`.
+    pub fn none() -> IoRetry {
+        IoRetry::new(0, Duration::from_secs(0))
+    }
+
+    /// Waits up to `wait` for space to be freed when an operation fails with `ENOSPC`, retrying
+    /// it every second.
+    pub fn set_no_space_wait(mut self, wait: Duration) -> IoRetry {
+        self.no_space_wait = Some(wait);
+        self
+    }
+
+    /// Runs `op` until it succeeds or fails with an error that isn't retried.
+    pub fn run<T, F: FnMut() -> io::Result<T>>(&self, mut op: F) -> io::Result<T> {
+        let mut attempts = 0;
+        let mut waited = Duration::from_secs(0);
+        loop {
+            let e = match op() {
+                Ok(t) => return Ok(t),
+                Err(e) => e,
+            };
+            match e.kind() {
+                io::ErrorKind::Interrupted => (),
+                io::ErrorKind::WouldBlock if attempts < self.attempts => {
+                    attempts += 1;
+                    thread::sleep(self.backoff);
+                }
+                io::ErrorKind::StorageFull if self.no_space_wait.is_some_and(|w| waited < w) => {
+                    let wait = self.no_space_wait.unwrap_or_default();
+                    if waited == Duration::from_secs(0) {
+                        println!("WARN: {}; waiting up to {:?} for space to be freed", e, wait);
+                    }
+                    let pause = cmp::min(NO_SPACE_POLL, wait - waited);
+                    thread::sleep(pause);
+                    waited += pause;
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Like `Write::write_all()`, but retries failed writes according to this policy.
+    pub fn write_all<W: Write + ?Sized>(&self, dest: &mut W, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.run(|| dest.write(buf))? {
+                0 => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write whole buffer"))
+                }
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A reader retrying the failed reads of `R` according to an `IoRetry`.
+pub struct RetryingReader<R: Read> {
+    inner: R,
+    retry: IoRetry,
+}
+
+impl<R: Read> RetryingReader<R> {
+    pub fn new(inner: R, retry: IoRetry) -> RetryingReader<R> {
+        RetryingReader { inner, retry }
+    }
+}
+
+impl<R: Read> Read for RetryingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.retry.run(|| inner.read(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoRetry, RetryingReader};
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+
+    // Fails with the given errors before writing or reading anything, and then handles at most
+    // two bytes per call.
+    struct Flaky {
+        errors: Vec<io::ErrorKind>,
+        data: Vec<u8>,
+    }
+
+    impl Flaky {
+        fn new(errors: &[io::ErrorKind]) -> Flaky {
+            Flaky {
+                errors: errors.iter().rev().cloned().collect(),
+                data: Vec::new(),
+            }
+        }
+
+        fn fail(&mut self) -> io::Result<()> {
+            match self.errors.pop() {
+                Some(kind) => Err(io::Error::from(kind)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.fail()?;
+            let n = buf.len().min(2);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail()?;
+            let n = buf.len().min(2).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_io_retry() {
+        use std::io::ErrorKind::{Interrupted, StorageFull, WouldBlock};
+        let retry = IoRetry::new(2, Duration::from_millis(1));
+
+        let mut dest = Flaky::new(&[Interrupted, WouldBlock, Interrupted, WouldBlock]);
+        retry.write_all(&mut dest, b"abcde").unwrap();
+        assert_eq!(dest.data, b"abcde");
+
+        // Retries are counted per operation.
+        let mut dest = Flaky::new(&[WouldBlock, WouldBlock, WouldBlock]);
+        assert_eq!(retry.write_all(&mut dest, b"abc").unwrap_err().kind(), WouldBlock);
+        assert!(IoRetry::none().write_all(&mut Flaky::new(&[WouldBlock]), b"abc").is_err());
+
+        let mut dest = Flaky::new(&[StorageFull, StorageFull]);
+        assert_eq!(retry.write_all(&mut dest, b"abc").unwrap_err().kind(), StorageFull);
+        let waiting = retry.set_no_space_wait(Duration::from_millis(20));
+        waiting.write_all(&mut dest, b"abc").unwrap();
+        assert_eq!(dest.data, b"abc");
+        // The wait is limited.
+        let mut dest = Flaky::new(&[StorageFull; 10]);
+        assert!(waiting.write_all(&mut dest, b"abc").is_err());
+
+        let mut src = Flaky::new(&[WouldBlock, Interrupted]);
+        src.data = b"abcde".to_vec();
+        let mut read = String::new();
+        RetryingReader::new(src, retry).read_to_string(&mut read).unwrap();
+        assert_eq!(read, "abcde");
+    }
+}
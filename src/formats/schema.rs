@@ -0,0 +1,166 @@
+//! An optional schema layer for delimited text records (TSV, CSV without quoting): A `Schema`
+//! names the columns of the record values. A `Projection` selects some of the columns; applied
+//! to the input with `ProjectedRecordIterator`, only the selected columns are copied, and the
+//! rest of every row is skipped without being materialized.
+//!
+//! Mappers can access the columns of projected rows by name with `Projection::field()`.
+
+use record_types::Record;
+use stats::{InputStats, Stats};
+
+use std::io;
+
+/// The names of the columns of delimited records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    names: Vec<String>,
+    separator: char,
+}
+
+impl Schema {
+    pub fn new(names: Vec<String>, separator: char) -> Schema {
+        Schema { names, separator }
+    }
+
+    /// Takes the column names from a header line.
+    pub fn from_header(header: &str, separator: char) -> Schema {
+        Schema::new(header.split(separator).map(String::from).collect(), separator)
+    }
+
+    pub fn names(&self) -> &Vec<String> {
+        &self.names
+    }
+
+    pub fn separator(&self) -> char {
+        self.separator
+    }
+
+    /// Returns the index of the column `name`.
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    /// Returns a projection to the columns `names`, in that order. Returns an error if a column
+    /// doesn't exist.
+    pub fn projection(&self, names: &[&str]) -> io::Result<Projection> {
+        let mut columns = Vec::with_capacity(names.len());
+        for name in names {
+            match self.index(name) {
+                Some(i) => columns.push(i),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("Unknown column {}", name)))
+                }
+            }
+        }
+        Ok(Projection {
+            columns,
+            schema: Schema::new(names.iter().map(|n| String::from(*n)).collect(),
+                                self.separator),
+            input_separator: self.separator,
+        })
+    }
+}
+
+/// A selection of columns of a Schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Projection {
+    // Indices of the selected columns in the input schema, in output order.
+    columns: Vec<usize>,
+    // The schema of projected rows.
+    schema: Schema,
+    input_separator: char,
+}
+
+impl Projection {
+    /// The schema of projected rows: the selected columns, in the requested order.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Projects a row of the input schema. Columns after the last selected one are not looked
+    /// at; missing columns are projected as empty strings.
+    pub fn project(&self, row: &str) -> String {
+        let last = match self.columns.iter().max() {
+            None => return String::new(),
+            Some(&l) => l,
+        };
+        let mut fields: Vec<&str> = Vec::with_capacity(last + 1);
+        fields.extend(row.splitn(last + 2, self.input_separator).take(last + 1));
+
+        let mut projected = String::with_capacity(self.columns
+            .iter()
+            .map(|&c| fields.get(c).map(|f| f.len()).unwrap_or(0) + 1)
+            .sum());
+        for (i, &c) in self.columns.iter().enumerate() {
+            if i > 0 {
+                projected.push(self.schema.separator);
+            }
+            projected.push_str(fields.get(c).cloned().unwrap_or(""));
+        }
+        projected
+    }
+
+    /// Returns the column `name` of a projected row.
+    pub fn field<'a>(&self, projected: &'a str, name: &str) -> Option<&'a str> {
+        let index = self.schema.index(name)?;
+        projected.split(self.schema.separator).nth(index)
+    }
+}
+
+/// Applies a Projection to the values of the records of an input iterator.
+pub struct ProjectedRecordIterator<I: Iterator<Item = Record>> {
+    i: I,
+    projection: Projection,
+}
+
+impl<I: Iterator<Item = Record>> ProjectedRecordIterator<I> {
+    pub fn new(it: I, projection: Projection) -> ProjectedRecordIterator<I> {
+        ProjectedRecordIterator {
+            i: it,
+            projection,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for ProjectedRecordIterator<I> {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        let r = self.i.next()?;
+        Some(Record {
+            value: self.projection.project(&r.value),
+            key: r.key,
+        })
+    }
+}
+
+impl<I: Iterator<Item = Record> + Stats> Stats for ProjectedRecordIterator<I> {
+    fn stats(&self) -> InputStats {
+        self.i.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProjectedRecordIterator, Schema};
+    use record_types::mk_rcrd;
+
+    #[test]
+    fn test_projection() {
+        let schema = Schema::from_header("time\tuser\tpath\tbytes\tagent", '\t');
+        assert_eq!(schema.index("bytes"), Some(3));
+        assert!(schema.projection(&["user", "size"]).is_err());
+
+        let projection = schema.projection(&["bytes", "user"]).unwrap();
+        let input = vec![mk_rcrd("1", "12:00\talice\t/index.html\t512\tcurl\t(extra)"),
+                         mk_rcrd("2", "12:01\tbob")];
+        let projected: Vec<String> = ProjectedRecordIterator::new(input.into_iter(),
+                                                                  projection.clone())
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(projected, vec!["512\talice", "\tbob"]);
+        assert_eq!(projection.field(&projected[0], "user"), Some("alice"));
+        assert_eq!(projection.field(&projected[0], "bytes"), Some("512"));
+        assert_eq!(projection.field(&projected[0], "time"), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! Bounds the number of open sinks of a partition that writes to many outputs (for example one
+//! file per category of records), so that the process stays below the OS limit on open files.
+//! Sinks beyond the limit are closed in least-recently-used order, and reopened in append mode
+//! when they are written to again (see `SinkGenerator::append_output()`).
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use formats::util::{open_files_limit, open_files_share};
+use phases::output::SinkGenerator;
+
+/// Open files that a `SinkPool` is assumed to be able to use if the OS limit is unknown.
+const DEFAULT_OPEN_FILES: usize = 256;
+
+/// Returns how many sinks each of `partitions` concurrently running partitions may keep open:
+/// Half of the job's share of the soft limit on open files of the process (as other files are
+/// open as well, see `formats::util::open_files_share()`), divided between the partitions, but at
+/// least one.
+pub fn max_open_per_partition(partitions: usize) -> usize {
+    let limit = open_files_share(open_files_limit().unwrap_or(DEFAULT_OPEN_FILES * 2));
+    ::std::cmp::max(1, limit / 2 / ::std::cmp::max(1, partitions))
+}
+
+/// A set of sinks created by a SinkGenerator, at most `max_open` of which are open at a time.
+/// The generator must support appending (see `SinkGenerator::append_output()`) if more than
+/// `max_open` locations are written to.
+pub struct SinkPool<G: SinkGenerator> {
+    generator: G,
+    max_open: usize,
+    // The open sinks, least recently used first.
+    open: Vec<(String, G::Sink)>,
+    // Locations that have been opened; they are appended to when reopened.
+    created: HashSet<String>,
+    reopened: usize,
+}
+
+impl<G: SinkGenerator> SinkPool<G> {
+    /// Returns a pool keeping at most `max_open` (at least one) sinks open.
+    pub fn new(generator: G, max_open: usize) -> SinkPool<G> {
+        SinkPool {
+            generator,
+            max_open: ::std::cmp::max(1, max_open),
+            open: Vec::new(),
+            created: HashSet::new(),
+            reopened: 0,
+        }
+    }
+
+    /// Returns the sink for `location`. A location is truncated when it is used for the first
+    /// time; if it has been closed since, it is reopened for appending. Panics if the generator
+    /// can't reopen the location.
+    pub fn get(&mut self, location: &String) -> &mut G::Sink {
+        match self.open.iter().position(|(l, _)| l == location) {
+            Some(i) => {
+                let entry = self.open.remove(i);
+                self.open.push(entry);
+            }
+            None => {
+                if self.open.len() >= self.max_open {
+                    // Dropping the sink flushes and closes it.
+                    self.open.remove(0);
+                }
+                let sink = if self.created.contains(location) {
+                    self.reopened += 1;
+                    match self.generator.append_output(location) {
+                        Some(sink) => sink,
+                        None => panic!("Sink generator can't reopen {} for appending", location),
+                    }
+                } else {
+                    self.created.insert(location.clone());
+                    self.generator.new_output(location)
+                };
+                self.open.push((location.clone(), sink));
+            }
+        }
+        &mut self.open.last_mut().unwrap().1
+    }
+
+    /// Writes `data` as a whole to the sink for `location` (see `get()`).
+    pub fn write(&mut self, location: &String, data: &[u8]) -> io::Result<usize> {
+        self.get(location).write(data)
+    }
+
+    /// Returns how many sinks are open.
+    pub fn open_sinks(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Returns how many times sinks have been reopened after being closed.
+    pub fn reopened(&self) -> usize {
+        self.reopened
+    }
+
+    /// Flushes all open sinks.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (_, sink) in self.open.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and closes all open sinks.
+    pub fn close_all(&mut self) {
+        self.open.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SinkPool, max_open_per_partition};
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::writelog::{WriteLogGenerator, WriteLogReader};
+    use std::fs;
+
+    #[test]
+    fn test_sink_pool() {
+        let names: Vec<String> =
+            (0..3).map(|i| format!("testdata/sink_pool_lines_{}", i)).collect();
+        let mut pool = SinkPool::new(LinesSinkGenerator::new_to_files(), 2);
+        for round in 0..3 {
+            for name in names.iter() {
+                pool.write(name, format!("{}", round).as_bytes()).unwrap();
+                assert!(pool.open_sinks() <= 2);
+            }
+        }
+        pool.close_all();
+        // Every write of the cycle evicts the sink written next.
+        assert_eq!(pool.reopened(), 6);
+        for name in names.iter() {
+            let written: Vec<String> = lines::new_from_file(name).unwrap().collect();
+            assert_eq!(written, vec!["0", "1", "2"]);
+            let _ = fs::remove_file(name);
+        }
+
+        let name = String::from("testdata/sink_pool_wlg");
+        let other = String::from("testdata/sink_pool_wlg_other");
+        let mut pool = SinkPool::new(WriteLogGenerator::new(), 1);
+        for entry in &["a", "b", "c"] {
+            pool.write(&name, entry.as_bytes()).unwrap();
+            pool.write(&other, entry.as_bytes()).unwrap();
+        }
+        pool.close_all();
+        let entries: Vec<String> = WriteLogReader::new_from_file(&name).unwrap().collect();
+        assert_eq!(entries, vec!["a", "b", "c"]);
+        let _ = fs::remove_file(name);
+        let _ = fs::remove_file(other);
+
+        assert!(max_open_per_partition(4) >= 1);
+    }
+}
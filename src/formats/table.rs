@@ -0,0 +1,467 @@
+//! Writes reduce output shards as immutable sorted tables, which can be queried point-wise with
+//! `TableReader::get()` after the job has finished, without reading whole files.
+//!
+//! Like the Parquet sink, every value emitted by a reducer is split at a separator character into
+//! key and value (by default, a tab character). The reducer must emit keys in dictionary order,
+//! which it does if the keys of its output are the keys it reduces.
+//!
+//! # Format
+//!
+//! A table consists of data blocks, an index block and a footer. Data blocks contain the
+//! entries, encoded like intermediate records (see `writelog::encode_record()`); a new block is
+//! started once a block has reached the block size. The index contains one entry per data block,
+//! with the block's last key as key and its offset and length (both big-endian u64) as value. The
+//! footer consists of the offset and length of the index, the number of entries (all big-endian
+//! u64) and `TABLE_MAGIC`.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+
+use formats::util::DurabilityGuard;
+use formats::writelog::{decode_record, encode_record};
+use mapreducer::Sharder;
+use parameters::Durability;
+use phases::output::SinkGenerator;
+use sort::dict_str_compare;
+
+/// The last bytes of every table.
+pub const TABLE_MAGIC: [u8; 8] = *b"lmrtable";
+const FOOTER_LENGTH: usize = 3 * 8 + 8;
+
+fn invalid(path: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid table {}: {}", path, what))
+}
+
+fn decode_u64(buf: &[u8]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(b)
+}
+
+// Returns the length of the entry at the beginning of `buf` (see `encode_record()`).
+fn entry_length(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let klen = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let vlen = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    let len = 8 + klen + vlen;
+    if len <= buf.len() { Some(len) } else { None }
+}
+
+/// A SinkGenerator writing sorted tables.
+#[derive(Clone)]
+pub struct TableSinkGenerator {
+    separator: char,
+    block_size: usize,
+    durability: Durability,
+}
+
+impl TableSinkGenerator {
+    /// Writes tables whose keys and values are separated by `separator` in the reducer's output.
+    pub fn new(separator: char) -> TableSinkGenerator {
+        TableSinkGenerator {
+            separator,
+            block_size: 4096,
+            durability: Durability::FlushOnClose,
+        }
+    }
+
+    /// Writes tables of values of the form `key<TAB>value`.
+    pub fn new_key_value() -> TableSinkGenerator {
+        TableSinkGenerator::new('\t')
+    }
+
+    /// Sets the size of data blocks. A lookup reads one block (or more if the block is smaller
+    /// than an entry); the index holds one key per block in memory.
+    ///
+    /// Default 4 KiB
+    pub fn set_block_size(mut self, size: usize) -> TableSinkGenerator {
+        self.block_size = size;
+        self
+    }
+}
+
+impl SinkGenerator for TableSinkGenerator {
+    type Sink = TableWriter<io::BufWriter<fs::File>>;
+    fn new_output(&self, path: &String) -> Self::Sink {
+        let f = fs::OpenOptions::new().write(true).truncate(true).create(true).open(path);
+        let w = f.and_then(|f| {
+            TableWriter::new(io::BufWriter::new(f), self.separator).set_durability(self.durability)
+        });
+        match w {
+            Err(e) => panic!("Couldn't open table output file {}: {}", path, e),
+            Ok(w) => w.set_block_size(self.block_size),
+        }
+    }
+
+    fn with_durability(mut self, durability: Durability) -> TableSinkGenerator {
+        self.durability = durability;
+        self
+    }
+}
+
+/// Writer for a single table. The table is completed by `finish()`, or when the writer is
+/// dropped.
+pub struct TableWriter<W: Write> {
+    dest: W,
+    separator: char,
+    block_size: usize,
+    block: Vec<u8>,
+    index: Vec<u8>,
+    frame: Vec<u8>,
+    offset: u64,
+    entries: u64,
+    last_key: Option<String>,
+    finished: bool,
+    durability: DurabilityGuard,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(dest: W, separator: char) -> TableWriter<W> {
+        TableWriter {
+            dest,
+            separator,
+            block_size: 4096,
+            block: Vec::new(),
+            index: Vec::new(),
+            frame: Vec::new(),
+            offset: 0,
+            entries: 0,
+            last_key: None,
+            finished: false,
+            durability: DurabilityGuard::none(),
+        }
+    }
+
+    /// See `TableSinkGenerator::set_block_size()`.
+    pub fn set_block_size(mut self, size: usize) -> TableWriter<W> {
+        self.block_size = size;
+        self
+    }
+
+    /// Adds an entry. Keys must be added in dictionary order.
+    pub fn add(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("table already finished"));
+        }
+        if let Some(ref last) = self.last_key {
+            if dict_str_compare(key, last) == Ordering::Less {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Key {:?} added after {:?}", key, last)));
+            }
+        }
+        encode_record(key.as_bytes(), value.as_bytes(), &mut self.frame);
+        self.block.extend_from_slice(&self.frame);
+        self.entries += 1;
+        match self.last_key {
+            Some(ref mut last) => {
+                last.clear();
+                last.push_str(key);
+            }
+            None => self.last_key = Some(String::from(key)),
+        }
+        if self.block.len() >= self.block_size {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.dest.write_all(&self.block)?;
+        self.durability.written(&mut self.dest, self.block.len())?;
+        let mut location = Vec::with_capacity(16);
+        location.extend_from_slice(&self.offset.to_be_bytes());
+        location.extend_from_slice(&(self.block.len() as u64).to_be_bytes());
+        let last_key = self.last_key.as_ref().map(|k| k.as_bytes()).unwrap_or(b"");
+        encode_record(last_key, &location, &mut self.frame);
+        self.index.extend_from_slice(&self.frame);
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the last block, the index and the footer. Entries can't be added afterwards.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_block()?;
+        self.dest.write_all(&self.index)?;
+        let mut footer = Vec::with_capacity(FOOTER_LENGTH);
+        footer.extend_from_slice(&self.offset.to_be_bytes());
+        footer.extend_from_slice(&(self.index.len() as u64).to_be_bytes());
+        footer.extend_from_slice(&self.entries.to_be_bytes());
+        footer.extend_from_slice(&TABLE_MAGIC);
+        self.dest.write_all(&footer)?;
+        self.dest.flush()?;
+        self.durability.close(&mut self.dest)
+    }
+}
+
+impl TableWriter<io::BufWriter<fs::File>> {
+    /// Makes the table durable according to `durability` (see `MRParameters::set_durability()`)
+    /// when it is finished, and periodically if requested.
+    ///
+    /// Default: Durability::None
+    pub fn set_durability(mut self,
+                          durability: Durability)
+                          -> io::Result<TableWriter<io::BufWriter<fs::File>>> {
+        self.durability = DurabilityGuard::new(self.dest.get_ref(), durability)?;
+        Ok(self)
+    }
+}
+
+impl<W: Write> Write for TableWriter<W> {
+    /// Adds the value `buf`, split into key and value at the separator. A value without
+    /// separator is added as key with an empty value.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let row = String::from_utf8_lossy(buf);
+        let mut fields = row.splitn(2, self.separator);
+        let key = fields.next().unwrap_or("");
+        let value = fields.next().unwrap_or("");
+        self.add(key, value)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dest.flush()
+    }
+}
+
+impl<W: Write> Drop for TableWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("WARN: Couldn't finish table: {}", e);
+        }
+    }
+}
+
+/// Looks up keys in a table written by TableWriter. Only the index is held in memory.
+pub struct TableReader {
+    path: String,
+    file: fs::File,
+    // (last key, offset, length) of every data block.
+    index: Vec<(String, u64, u64)>,
+    entries: u64,
+    block: Vec<u8>,
+}
+
+impl TableReader {
+    pub fn open(path: &String) -> io::Result<TableReader> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_LENGTH as u64 {
+            return Err(invalid(path, "no footer"));
+        }
+        let mut footer = [0; FOOTER_LENGTH];
+        file.seek(io::SeekFrom::Start(len - FOOTER_LENGTH as u64))?;
+        file.read_exact(&mut footer)?;
+        if footer[24..] != TABLE_MAGIC[..] {
+            return Err(invalid(path, "wrong magic number"));
+        }
+        let (index_offset, index_len) = (decode_u64(&footer[0..]), decode_u64(&footer[8..]));
+        if index_offset + index_len + FOOTER_LENGTH as u64 != len {
+            return Err(invalid(path, "wrong index location"));
+        }
+
+        let mut raw = vec![0; index_len as usize];
+        file.seek(io::SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut raw)?;
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < raw.len() {
+            let entry_len = entry_length(&raw[pos..]).ok_or_else(|| invalid(path, "bad index"))?;
+            let (key, location) = decode_record(&raw[pos..pos + entry_len])?;
+            if location.len() != 16 {
+                return Err(invalid(path, "bad index"));
+            }
+            index.push((String::from_utf8_lossy(key).into_owned(),
+                        decode_u64(location),
+                        decode_u64(&location[8..])));
+            pos += entry_len;
+        }
+
+        Ok(TableReader {
+            path: path.clone(),
+            file,
+            index,
+            entries: decode_u64(&footer[16..]),
+            block: Vec::new(),
+        })
+    }
+
+    /// Returns the number of entries in the table.
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Returns the value of `key`, or None if the table doesn't contain the key. If the reducer
+    /// emitted the key several times, the first value is returned.
+    pub fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        // Keys differing only in case are equal in dictionary order, and may span several blocks.
+        let mut i = self.index.partition_point(|b| dict_str_compare(&b.0, key) == Ordering::Less);
+        while i < self.index.len() {
+            let (offset, length) = (self.index[i].1, self.index[i].2);
+            self.block.resize(length as usize, 0);
+            self.file.seek(io::SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut self.block)?;
+
+            let mut pos = 0;
+            while pos < self.block.len() {
+                let entry_len = entry_length(&self.block[pos..])
+                    .ok_or_else(|| invalid(&self.path, "bad block"))?;
+                let (k, v) = decode_record(&self.block[pos..pos + entry_len])?;
+                pos += entry_len;
+                match dict_str_compare(&String::from_utf8_lossy(k), key) {
+                    Ordering::Less => continue,
+                    Ordering::Greater => return Ok(None),
+                    Ordering::Equal if k != key.as_bytes() => continue,
+                    Ordering::Equal => return Ok(Some(String::from_utf8_lossy(v).into_owned())),
+                }
+            }
+            if dict_str_compare(&self.index[i].0, key) == Ordering::Greater {
+                break;
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+}
+
+/// The tables of all shards of a job's output. A key is looked up in the shard that the job's
+/// sharder assigns it to.
+pub struct ShardedTable {
+    tables: Vec<TableReader>,
+}
+
+impl ShardedTable {
+    /// Opens the tables `<prefix>0` ... `<prefix><shards-1>` (usually
+    /// `params.reduce_output_shard_prefix` and `params.reducers`).
+    pub fn open(prefix: &String, shards: usize) -> io::Result<ShardedTable> {
+        let mut tables = Vec::with_capacity(shards);
+        for i in 0..shards {
+            tables.push(TableReader::open(&format!("{}{}", prefix, i))?);
+        }
+        Ok(ShardedTable { tables })
+    }
+
+    /// Returns the value of `key`; `sharder` must be the sharder that the job used.
+    pub fn get<S: Sharder>(&mut self, sharder: &mut S, key: &str) -> io::Result<Option<String>> {
+        let shard = sharder.shard_bytes(self.tables.len(), key.as_bytes());
+        self.tables[shard].get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShardedTable, TableReader, TableSinkGenerator};
+    use closure_mr::ClosureMapReducer;
+    use controller::MRController;
+    use formats::util::PosRecordIterator;
+    use mapreducer::RangeSharder;
+    use parameters::{Durability, MRParameters};
+    use phases::output::SinkGenerator;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_table() {
+        let path = String::from("testdata/table_0");
+        {
+            let mut w = TableSinkGenerator::new(' ').set_block_size(32).new_output(&path);
+            for i in 0..100 {
+                let _ = w.write(format!("key{:03} value {}", i, i).as_bytes()).unwrap();
+            }
+            // Keys equal in dictionary order.
+            let _ = w.write(b"zz 1").unwrap();
+            let _ = w.write(b"ZZ 2").unwrap();
+            let _ = w.write(b"zZ 3").unwrap();
+            assert!(w.write(b"abc out of order").is_err());
+        }
+
+        let mut table = TableReader::open(&path).unwrap();
+        assert_eq!(table.len(), 103);
+        assert!(table.index.len() > 10);
+        assert_eq!(table.get("key000").unwrap(), Some(String::from("value 0")));
+        assert_eq!(table.get("key057").unwrap(), Some(String::from("value 57")));
+        assert_eq!(table.get("key099").unwrap(), Some(String::from("value 99")));
+        assert_eq!(table.get("key100").unwrap(), None);
+        assert_eq!(table.get("abc").unwrap(), None);
+        assert_eq!(table.get("zZ").unwrap(), Some(String::from("3")));
+        assert_eq!(table.get("Zz").unwrap(), None);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_table_durability() {
+        let path = String::from("testdata/table_durable_0");
+        let gen = TableSinkGenerator::new(' ').set_block_size(16);
+        {
+            let mut w = gen.clone().new_output(&path);
+            for i in 0..10 {
+                let _ = w.write(format!("key{} {}", i, i).as_bytes()).unwrap();
+            }
+            // Blocks are buffered until the table is finished.
+            assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        }
+        let complete = fs::metadata(&path).unwrap().len();
+        {
+            let mut w = gen.with_durability(Durability::PeriodicFsync(32)).new_output(&path);
+            for i in 0..10 {
+                let _ = w.write(format!("key{} {}", i, i).as_bytes()).unwrap();
+            }
+            assert!(fs::metadata(&path).unwrap().len() >= 96);
+            w.finish().unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        }
+        assert_eq!(TableReader::open(&path).unwrap().get("key7").unwrap(),
+                   Some(String::from("7")));
+        let _ = fs::remove_file(path);
+    }
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{}\t{}", recs.key(), recs.values().len()));
+    }
+
+    #[test]
+    fn test_sharded_table() {
+        let lines = vec!["abc def", "def ghi", "abc abc xyz"];
+        let mut sharder = RangeSharder::new(vec![String::from("d"), String::from("h")]);
+        let params = MRParameters::new()
+            .set_concurrency(2, 3)
+            .set_file_locations(String::from("testdata/table_map_"),
+                                String::from("testdata/table_out_"));
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          sharder.clone(),
+                          params,
+                          PosRecordIterator::new(lines.into_iter().map(String::from)),
+                          TableSinkGenerator::new_key_value());
+
+        let mut table = ShardedTable::open(&String::from("testdata/table_out_"), 3).unwrap();
+        assert_eq!(table.get(&mut sharder, "abc").unwrap(), Some(String::from("3")));
+        assert_eq!(table.get(&mut sharder, "ghi").unwrap(), Some(String::from("1")));
+        assert_eq!(table.get(&mut sharder, "xyz").unwrap(), Some(String::from("1")));
+        assert_eq!(table.get(&mut sharder, "foo").unwrap(), None);
+        for i in 0..3 {
+            let _ = fs::remove_file(format!("testdata/table_out_{}", i));
+        }
+    }
+}
@@ -1,8 +1,108 @@
 //! Various iterators/adapters used for input/output formats.
 
 
+use parameters::Durability;
 use record_types::Record;
+use stats::{InputStats, Stats};
+use sort::{dict_str_compare, dict_string_compare};
+use std::cmp::Ordering;
 use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "regex")]
+extern crate regex;
+
+/// Cuts `s` to at most `len` bytes, at a character boundary.
+pub fn truncate_str(s: &mut String, len: usize) {
+    if s.len() > len {
+        let boundary = (0..len + 1).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0);
+        s.truncate(boundary);
+    }
+}
+
+/// Splits an output row into `n` fields at `separator`; the last field contains the remainder of
+/// the row. Missing fields are returned as None. Used by sinks writing columnar formats.
+pub fn split_columns(row: &str, separator: char, n: usize) -> Vec<Option<&str>> {
+    let mut fields = row.splitn(n, separator);
+    (0..n).map(|_| fields.next()).collect()
+}
+
+/// Returns the soft limit on open files of the process, or None if it is unlimited or unknown.
+#[cfg(unix)]
+pub fn open_files_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 ||
+       limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur as usize)
+}
+
+#[cfg(not(unix))]
+pub fn open_files_limit() -> Option<usize> {
+    None
+}
+
+/// Raises the soft limit on open files of the process towards `wanted`, as far as the hard limit
+/// allows, if it is lower. Returns the resulting limit (None if unlimited or unknown).
+#[cfg(unix)]
+pub fn raise_open_files_limit(wanted: usize) -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur != libc::RLIM_INFINITY && (limit.rlim_cur as usize) < wanted {
+        let raised = libc::rlimit {
+            rlim_cur: ::std::cmp::min(wanted as libc::rlim_t, limit.rlim_max),
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            println!("WARN: Couldn't raise the limit on open files: {}",
+                     io::Error::last_os_error());
+        }
+    }
+    open_files_limit()
+}
+
+#[cfg(not(unix))]
+pub fn raise_open_files_limit(_wanted: usize) -> Option<usize> {
+    None
+}
+
+// The jobs running in this process, which share its limit on open files.
+static RUNNING_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a job as running (see `open_files_share()`) until dropped.
+pub struct RunningJob;
+
+impl RunningJob {
+    pub fn start() -> RunningJob {
+        RUNNING_JOBS.fetch_add(1, AtomicOrdering::SeqCst);
+        RunningJob
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        RUNNING_JOBS.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+}
+
+/// Returns the share of the limit on open files `limit` that a job may use: the limit divided
+/// between the jobs running in this process, but at least one file.
+pub fn open_files_share(limit: usize) -> usize {
+    ::std::cmp::max(1, limit / ::std::cmp::max(1, RUNNING_JOBS.load(AtomicOrdering::SeqCst)))
+}
 
 /// Transforms an iterator<string> into an iterator<Record>. It yields
 /// records with the key being the position of the current record, starting with
@@ -38,6 +138,118 @@ impl<I: Iterator<Item = String>> Iterator for PosRecordIterator<I> {
     }
 }
 
+impl<I: Iterator<Item = String> + Stats> Stats for PosRecordIterator<I> {
+    fn stats(&self) -> InputStats {
+        self.i.stats()
+    }
+}
+
+/// Turns lines into records keyed by a part of the line, replacing mappers whose only job is
+/// extracting the key. `extract` returns the key and value of the record for a line, or None to
+/// skip the line; skipped lines are counted (see `skipped()`). See `keyed_by()` and, with the
+/// `regex` feature, `keyed_by_regex()`.
+pub struct KeyedRecordIterator<I, F>
+    where I: Iterator<Item = String>,
+          F: FnMut(&str) -> Option<(String, String)>
+{
+    i: I,
+    extract: F,
+    skipped: usize,
+}
+
+impl<I, F> KeyedRecordIterator<I, F>
+    where I: Iterator<Item = String>,
+          F: FnMut(&str) -> Option<(String, String)>
+{
+    pub fn new(it: I, extract: F) -> KeyedRecordIterator<I, F> {
+        KeyedRecordIterator {
+            i: it,
+            extract,
+            skipped: 0,
+        }
+    }
+
+    /// Returns how many lines have been skipped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<I, F> Iterator for KeyedRecordIterator<I, F>
+    where I: Iterator<Item = String>,
+          F: FnMut(&str) -> Option<(String, String)>
+{
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        for line in self.i.by_ref() {
+            match (self.extract)(&line) {
+                Some((key, value)) => return Some(Record { key, value }),
+                None => self.skipped += 1,
+            }
+        }
+        None
+    }
+}
+
+impl<I, F> Stats for KeyedRecordIterator<I, F>
+    where I: Iterator<Item = String> + Stats,
+          F: FnMut(&str) -> Option<(String, String)>
+{
+    fn stats(&self) -> InputStats {
+        self.i.stats()
+    }
+}
+
+/// Keys every line by `key_of(line)`; the value is the whole line. Lines for which `key_of`
+/// returns None are skipped.
+pub fn keyed_by<I, K>(it: I,
+                      mut key_of: K)
+                      -> KeyedRecordIterator<I, impl FnMut(&str) -> Option<(String, String)>>
+    where I: Iterator<Item = String>,
+          K: FnMut(&str) -> Option<String>
+{
+    KeyedRecordIterator::new(it, move |line| key_of(line).map(|key| (key, String::from(line))))
+}
+
+/// The value of records keyed by `keyed_by_regex()`.
+#[cfg(feature = "regex")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureValue {
+    /// The whole line.
+    Line,
+    /// The capture groups other than the key's that matched, separated by tab characters.
+    OtherCaptures,
+}
+
+/// Keys every line by the capture group `group` of `re` (enabled by the `regex` feature). Lines
+/// that don't match, or in which the group doesn't participate, are skipped.
+#[cfg(feature = "regex")]
+pub fn keyed_by_regex<I>(it: I,
+                         re: regex::Regex,
+                         group: usize,
+                         value: CaptureValue)
+                         -> KeyedRecordIterator<I, impl FnMut(&str) -> Option<(String, String)>>
+    where I: Iterator<Item = String>
+{
+    KeyedRecordIterator::new(it, move |line| {
+        let captures = re.captures(line)?;
+        let key = String::from(captures.get(group)?.as_str());
+        let value = match value {
+            CaptureValue::Line => String::from(line),
+            CaptureValue::OtherCaptures => {
+                let others: Vec<&str> = captures.iter()
+                    .enumerate()
+                    .skip(1)
+                    .filter(|&(i, _)| i != group)
+                    .filter_map(|(_, c)| c.map(|c| c.as_str()))
+                    .collect();
+                others.join("\t")
+            }
+        };
+        Some((key, value))
+    })
+}
+
 /// Another transformation of [string] -> [(string,string)]; however,
 /// this one always reads one value, treats it as key, and another one,
 /// treated as value.
@@ -67,3 +279,179 @@ impl<I: Iterator<Item = String>> Iterator for RecordReadIterator<I> {
         }
     }
 }
+
+impl<I: Iterator<Item = String> + Stats> Stats for RecordReadIterator<I> {
+    fn stats(&self) -> InputStats {
+        self.i.stats()
+    }
+}
+
+/// Applies a Durability policy to a file written through a (possibly buffering) writer. Writers
+/// call `written()` after writing to the file and `close()` once the file is complete; the guard
+/// flushes the writer and syncs the file as the policy requires.
+pub struct DurabilityGuard {
+    policy: Durability,
+    // A second handle of the file, for syncing; None if the policy doesn't sync.
+    file: Option<fs::File>,
+    unsynced: u64,
+}
+
+impl DurabilityGuard {
+    /// Returns a guard that does nothing.
+    pub fn none() -> DurabilityGuard {
+        DurabilityGuard {
+            policy: Durability::None,
+            file: None,
+            unsynced: 0,
+        }
+    }
+
+    /// Returns a guard applying `policy` to `file`.
+    pub fn new(file: &fs::File, policy: Durability) -> io::Result<DurabilityGuard> {
+        let file = match policy {
+            Durability::None | Durability::FlushOnClose => None,
+            Durability::FsyncOnClose | Durability::PeriodicFsync(_) => Some(file.try_clone()?),
+        };
+        Ok(DurabilityGuard {
+            policy,
+            file,
+            unsynced: 0,
+        })
+    }
+
+    /// Records that `n` bytes have been written through `writer`.
+    pub fn written<W: Write>(&mut self, writer: &mut W, n: usize) -> io::Result<()> {
+        if let Durability::PeriodicFsync(interval) = self.policy {
+            self.unsynced += n as u64;
+            if self.unsynced >= interval {
+                writer.flush()?;
+                if let Some(ref f) = self.file {
+                    f.sync_data()?;
+                }
+                self.unsynced = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes the file written through `writer`. Later calls do nothing.
+    pub fn close<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let policy = self.policy;
+        self.policy = Durability::None;
+        if policy == Durability::None {
+            return Ok(());
+        }
+        writer.flush()?;
+        if let Some(f) = self.file.take() {
+            f.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// A filter on the keys of intermediate records, applied while reading them in the reduce phase
+/// (see `MRParameters::set_reduce_key_filter()`). Keys are checked before the records are
+/// deserialized, so that records not matching the filter cost no allocations.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyFilter {
+    /// Keys starting with the given prefix (case-sensitive).
+    Prefix(String),
+    /// Keys in the range [start; end) in dictionary order; a bound of None means that the range is
+    /// open at that side.
+    Range(Option<String>, Option<String>),
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        match *self {
+            KeyFilter::Prefix(ref prefix) => key.starts_with(prefix.as_str()),
+            KeyFilter::Range(ref start, _) => {
+                let after_start = start.as_ref()
+                    .map(|s| dict_str_compare(key, s) != Ordering::Less)
+                    .unwrap_or(true);
+                after_start && !self.is_past(key)
+            }
+        }
+    }
+
+    /// Returns true if `key` and all keys sorted after it can't match the filter.
+    pub fn is_past(&self, key: &str) -> bool {
+        match *self {
+            KeyFilter::Prefix(_) => false,
+            KeyFilter::Range(_, ref end) => {
+                end.as_ref().map(|e| dict_str_compare(key, e) != Ordering::Less).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Restricts a sorted Iterator<Item=Record> to the keys in [start; end) (in dictionary order, as
+/// produced by the map phase). Records before `start` are skipped, and iteration stops at the
+/// first record at or after `end`. A bound of None means that the range is open at that side.
+pub struct KeyRangeIterator<I: Iterator<Item = Record>> {
+    i: I,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+impl<I: Iterator<Item = Record>> KeyRangeIterator<I> {
+    pub fn new(it: I, start: Option<String>, end: Option<String>) -> KeyRangeIterator<I> {
+        KeyRangeIterator {
+            i: it,
+            start,
+            end,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for KeyRangeIterator<I> {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let r = self.i.next()?;
+            if let Some(ref start) = self.start {
+                if dict_string_compare(&r.key, start) == Ordering::Less {
+                    continue;
+                }
+            }
+            if let Some(ref end) = self.end {
+                if dict_string_compare(&r.key, end) != Ordering::Less {
+                    return None;
+                }
+            }
+            // All following records are sorted after start.
+            self.start = None;
+            return Some(r);
+        }
+    }
+}
+
+impl<I: Iterator<Item = Record> + Stats> Stats for KeyRangeIterator<I> {
+    fn stats(&self) -> InputStats {
+        self.i.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyedRecordIterator, keyed_by};
+
+    #[test]
+    fn test_keyed_records() {
+        let lines = || {
+            vec!["GET /a 200", "POST /b 500", "-", "GET /c 404"].into_iter().map(String::from)
+        };
+        let mut it = keyed_by(lines(), |l| l.split(' ').nth(1).map(String::from));
+        let records: Vec<(String, String)> = it.by_ref().map(|r| (r.key, r.value)).collect();
+        assert_eq!(records[0], (String::from("/a"), String::from("GET /a 200")));
+        assert_eq!(records.len(), 3);
+        assert_eq!(it.skipped(), 1);
+
+        let status = KeyedRecordIterator::new(lines(), |l| {
+            let (rest, status) = l.rsplit_once(' ')?;
+            Some((String::from(status), String::from(rest)))
+        });
+        let keys: Vec<String> = status.map(|r| r.key).collect();
+        assert_eq!(keys, vec!["200", "500", "404"]);
+    }
+}
@@ -3,14 +3,27 @@
 
 #![allow(dead_code)]
 
-use std::io::{Result, Write, Read};
+use std::io::{Result, Write, Read, Seek};
 use std::boxed::Box;
+use std::collections::VecDeque;
 use std::io;
 use std::fs;
 use std::vec;
 use std::string;
+use std::sync::Arc;
 
+use formats::bloom::BloomFilter;
+use formats::error::FormatError;
+use formats::retry::{IoRetry, RetryingReader};
+use formats::util::{DurabilityGuard, KeyFilter};
+use mapreducer::ValueDecoderF;
+use parameters::Durability;
 use phases::output::SinkGenerator;
+use record_types::Record;
+use stats::{InputStats, Stats};
+
+#[cfg(unix)]
+extern crate libc;
 
 /// A length-prefixed record stream named for the original use case,
 /// which was to write a log of all write operations to a database.
@@ -30,13 +43,67 @@ use phases::output::SinkGenerator;
 /// files are indexed by IDX files describing offset and length of single entries,
 /// which is why we don't need length prefixes here.
 ///
+/// Every record (length prefix and data) is handed to the underlying Sink in one single write
+/// operation; this keeps records intact when several writers append to the same file. If batching
+/// is enabled (see `set_batching()`), several whole records are written at once.
+///
 pub struct WriteLogWriter<Sink: Write> {
     dest: Sink,
+    // Buffer used for assembling records before writing them.
+    frame: Vec<u8>,
+    // Buffer used for encoding (key, value) records.
+    record: Vec<u8>,
+    // Records in `frame` that haven't been written yet, and the limits for writing them.
+    batched: usize,
+    batch_records: usize,
+    batch_bytes: usize,
+    durability: DurabilityGuard,
+    io_retry: IoRetry,
 
     current_length: u64,
     records_written: u32,
 }
 
+/// Payload of the sync markers that writers may insert between entries (see
+/// `MRParameters::set_intermediate_sync_interval()`). A reader that has lost track of the entry
+/// boundaries because of a corrupt entry scans for the next marker and continues after it. The
+/// payload starts with an invalid record header, so it can't be mistaken for a (key, value) record.
+pub const SYNC_MARKER: [u8; 16] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'l', b'm', b'r',
+                                   b's', b'y', b'n', b'c', 0];
+
+/// Prefix of the entry that the map phase writes at the beginning of every intermediate file if
+/// a value version is set (see `MRParameters::set_intermediate_value_version()`); the version
+/// follows as big-endian u32. Like SYNC_MARKER, it starts with an invalid record header.
+pub const VERSION_MARKER: [u8; 12] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'l', b'm',
+                                      b'r', b'v'];
+
+/// Returns the payload of a version marker entry for `version` (see `VERSION_MARKER`).
+pub fn encode_version_marker(version: u32) -> Vec<u8> {
+    let mut marker = VERSION_MARKER.to_vec();
+    marker.extend_from_slice(&encode_u32(version));
+    marker
+}
+
+fn decode_version_marker(entry: &[u8]) -> Option<u32> {
+    if entry.len() == VERSION_MARKER.len() + 4 && entry.starts_with(&VERSION_MARKER) {
+        let v = &entry[VERSION_MARKER.len()..];
+        Some(decode_u32([v[0], v[1], v[2], v[3]]))
+    } else {
+        None
+    }
+}
+
+/// Returns the value version of the intermediate file `path`, which is 0 for files without
+/// version marker.
+pub fn read_value_version(path: &String) -> io::Result<u32> {
+    let mut reader = WriteLogReader::new_from_file(path)?;
+    reader.read_entry(&mut Vec::new())?;
+    Ok(reader.value_version())
+}
+
+// When recovering, entries longer than this are considered corrupt.
+const MAX_RECOVERABLE_ENTRY: usize = 1 << 28;
+
 fn encode_u32(val: u32) -> [u8; 4] {
     let mut buf: [u8; 4] = [0; 4];
 
@@ -47,6 +114,120 @@ fn encode_u32(val: u32) -> [u8; 4] {
     buf
 }
 
+/// Returns how many bytes a record with `len` bytes of payload takes up in a WriteLog.
+pub fn framed_length(len: usize) -> u64 {
+    4 + len as u64
+}
+
+/// Encodes a (key, value) record as it is stored in intermediate files: as a single WriteLog
+/// entry `kkkkvvvv<key><value>`, where k and v are the big-endian lengths of key and value. As key
+/// and value are written together, a failed write or a truncated file can't leave a key without
+/// its value (which would shift all following records); `decode_record()` validates the lengths.
+pub fn encode_record(key: &[u8], value: &[u8], frame: &mut Vec<u8>) {
+    frame.clear();
+    frame.extend_from_slice(&encode_u32(key.len() as u32));
+    frame.extend_from_slice(&encode_u32(value.len() as u32));
+    frame.extend_from_slice(key);
+    frame.extend_from_slice(value);
+}
+
+/// Splits an entry written by `encode_record()` into key and value.
+pub fn decode_record(entry: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    if entry.len() < 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Record of {} bytes has no header", entry.len())));
+    }
+    let klen = decode_u32([entry[0], entry[1], entry[2], entry[3]]) as usize;
+    let vlen = decode_u32([entry[4], entry[5], entry[6], entry[7]]) as usize;
+    if 8 + klen + vlen != entry.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Record lengths {}+{} don't match entry of {} bytes",
+                                          klen,
+                                          vlen,
+                                          entry.len())));
+    }
+    Ok((&entry[8..8 + klen], &entry[8 + klen..]))
+}
+
+/// Set instead of the key length in records that refer to the key of the previous record (see
+/// `encode_key_ref()`).
+const KEY_REF: u32 = 1 << 31;
+
+/// Encodes a record whose key is that of the previous record in the file, as `kkkkvvvv<value>`
+/// with k = `KEY_REF` (see `MRParameters::set_intermediate_key_dictionary()`). Such records are
+/// rejected by `decode_record()`, as the key length doesn't match the entry.
+pub fn encode_key_ref(value: &[u8], frame: &mut Vec<u8>) {
+    frame.clear();
+    frame.extend_from_slice(&encode_u32(KEY_REF));
+    frame.extend_from_slice(&encode_u32(value.len() as u32));
+    frame.extend_from_slice(value);
+}
+
+/// Set in the key length of records referring to their key by its index in the key dictionary
+/// of the file (see `encode_key_index()`); the lower bits hold the index.
+const KEY_INDEX: u32 = 1 << 30;
+
+/// The largest index that can be encoded by `encode_key_index()`.
+pub const MAX_KEY_INDEX: u32 = KEY_INDEX - 1;
+
+/// Encodes a record whose key is the `index`th key of the file's key dictionary, as
+/// `kkkkvvvv<value>` with k = `KEY_INDEX | index` (see
+/// `MRParameters::set_intermediate_key_encoding()`). Like records written by `encode_key_ref()`,
+/// they are rejected by `decode_record()`.
+pub fn encode_key_index(index: u32, value: &[u8], frame: &mut Vec<u8>) {
+    assert!(index <= MAX_KEY_INDEX, "Key index {} too large", index);
+    frame.clear();
+    frame.extend_from_slice(&encode_u32(KEY_INDEX | index));
+    frame.extend_from_slice(&encode_u32(value.len() as u32));
+    frame.extend_from_slice(value);
+}
+
+/// The key of a record decoded by `decode_keyed_record()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordKey<'a> {
+    Key(&'a [u8]),
+    /// The key of the previous record carrying its key (see `encode_key_ref()`).
+    Previous,
+    /// The key with this index in the file's key dictionary (see `encode_key_index()`).
+    Index(u32),
+}
+
+/// Like `decode_record()`, but also accepts records written by `encode_key_ref()` and
+/// `encode_key_index()`.
+pub fn decode_keyed_record(entry: &[u8]) -> io::Result<(RecordKey<'_>, &[u8])> {
+    if entry.len() < 8 {
+        return decode_record(entry).map(|(key, value)| (RecordKey::Key(key), value));
+    }
+    let klen = decode_u32([entry[0], entry[1], entry[2], entry[3]]);
+    let key = if klen == KEY_REF {
+        RecordKey::Previous
+    } else if klen & KEY_REF == 0 && klen & KEY_INDEX != 0 {
+        RecordKey::Index(klen & MAX_KEY_INDEX)
+    } else {
+        return decode_record(entry).map(|(key, value)| (RecordKey::Key(key), value));
+    };
+    let vlen = decode_u32([entry[4], entry[5], entry[6], entry[7]]) as usize;
+    if 8 + vlen != entry.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Record length {} doesn't match entry of {} bytes",
+                                          vlen,
+                                          entry.len())));
+    }
+    Ok((key, &entry[8..]))
+}
+
+/// Reads the key dictionary written next to an intermediate file (see
+/// `encode_key_index()`): a WriteLog holding one key per entry.
+pub fn read_key_dictionary(path: &String) -> io::Result<Vec<string::String>> {
+    let mut reader = WriteLogReader::new_from_file(path)?;
+    let mut keys = Vec::new();
+    let mut entry = Vec::new();
+    while reader.read_entry(&mut entry)? {
+        keys.push(string::String::from_utf8_lossy(&entry).into_owned());
+    }
+    Ok(keys)
+}
+
 fn decode_u32(buf: [u8; 4]) -> u32 {
     let mut val: u32 = 0;
 
@@ -62,6 +243,13 @@ impl<Sink: Write> WriteLogWriter<Sink> {
     pub fn new(dest: Sink) -> WriteLogWriter<Sink> {
         WriteLogWriter {
             dest: dest,
+            frame: Vec::new(),
+            record: Vec::new(),
+            batched: 0,
+            batch_records: 1,
+            batch_bytes: 0,
+            durability: DurabilityGuard::none(),
+            io_retry: IoRetry::default(),
             current_length: 0,
             records_written: 0,
         }
@@ -78,106 +266,448 @@ impl<Sink: Write> WriteLogWriter<Sink> {
             .map(move |f| WriteLogWriter::new(f))
     }
 
+    /// Collects records in memory and writes them to the sink once `records` records or `bytes`
+    /// bytes have been collected (or when the writer is flushed or dropped). This turns many small
+    /// writes into a few large ones. A limit of 0 is ignored; `set_batching(1, 0)` disables
+    /// batching.
+    ///
+    /// Default: disabled
+    pub fn set_batching(mut self, records: usize, bytes: usize) -> WriteLogWriter<Sink> {
+        self.batch_records = records;
+        self.batch_bytes = bytes;
+        self
+    }
+
+    /// Retries transient errors while writing according to `retry` (see `formats::retry`).
+    ///
+    /// Default: IoRetry::default()
+    pub fn set_io_retry(mut self, retry: IoRetry) -> WriteLogWriter<Sink> {
+        self.io_retry = retry;
+        self
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        if self.batched > 0 {
+            self.batched = 0;
+            let result = self.io_retry.write_all(&mut self.dest, &self.frame);
+            let written = self.frame.len();
+            self.frame.clear();
+            result?;
+            self.durability.written(&mut self.dest, written)?;
+        }
+        Ok(())
+    }
+
     /// Return how many (bytes,records) have been written.
     pub fn get_stats(&self) -> (u64, u32) {
         (self.current_length, self.records_written)
     }
+
+    /// Writes a sync marker entry (see `SYNC_MARKER`).
+    pub fn write_sync_marker(&mut self) -> Result<()> {
+        self.write(&SYNC_MARKER).map(|_| ())
+    }
+
+    /// Writes a (key, value) record as a single entry (see `encode_record()`).
+    pub fn write_record(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut record = ::std::mem::take(&mut self.record);
+        encode_record(key, value, &mut record);
+        let result = self.write(&record).map(|_| ());
+        self.record = record;
+        result
+    }
 }
+
+impl WriteLogWriter<fs::File> {
+    /// Makes the file durable according to `durability` (see `MRParameters::set_durability()`)
+    /// when the writer is dropped, and periodically if requested.
+    ///
+    /// Default: Durability::None
+    pub fn set_durability(mut self, durability: Durability) -> Result<WriteLogWriter<fs::File>> {
+        self.durability = DurabilityGuard::new(&self.dest, durability)?;
+        Ok(self)
+    }
+}
+
 impl<Sink: Write> Write for WriteLogWriter<Sink> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        // BUG: May not account the length in a correct way if the length prefix
-        // is written, but not the record.
-        let result = self.dest
-            .write(&encode_u32(buf.len() as u32)[0..4])
-            .and(self.dest.write(buf));
-        match result {
-            Err(_) => result,
-            Ok(_) => {
-                self.current_length += 4 + buf.len() as u64;
-                self.records_written += 1;
-                result
-            }
+        self.frame.extend_from_slice(&encode_u32(buf.len() as u32));
+        self.frame.extend_from_slice(buf);
+        self.batched += 1;
+
+        self.current_length += framed_length(buf.len());
+        self.records_written += 1;
+        if (self.batch_records > 0 && self.batched >= self.batch_records) ||
+           (self.batch_bytes > 0 && self.frame.len() >= self.batch_bytes) {
+            self.write_batch()?;
         }
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<()> {
+        self.write_batch()?;
         self.dest.flush()
     }
 }
 
+impl<Sink: Write> Drop for WriteLogWriter<Sink> {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_batch() {
+            println!("WARN: Couldn't write {} batched records: {}", self.batched, e);
+        }
+        if let Err(e) = self.durability.close(&mut self.dest) {
+            println!("WARN: Couldn't flush or sync WriteLog: {}", e);
+        }
+    }
+}
+
 /// Like LinesSinkGenerator, opens new WriteLogWriters that write
 /// to files with the name given to new_output(). That name is in general based on the MRParameters
 /// supplied to a mapreduce instance.
 #[derive(Clone)]
 pub struct WriteLogGenerator {
-    i: i32,
+    batch_records: usize,
+    batch_bytes: usize,
+    durability: Durability,
+    io_retry: IoRetry,
 }
 
 unsafe impl Send for WriteLogGenerator {}
 
 impl WriteLogGenerator {
     pub fn new() -> WriteLogGenerator {
-        WriteLogGenerator { i: 0 }
+        WriteLogGenerator {
+            batch_records: 1,
+            batch_bytes: 0,
+            durability: Durability::FlushOnClose,
+            io_retry: IoRetry::default(),
+        }
+    }
+
+    /// Returns a generator whose writers batch records (see `WriteLogWriter::set_batching()`).
+    pub fn with_batching(records: usize, bytes: usize) -> WriteLogGenerator {
+        WriteLogGenerator {
+            batch_records: records,
+            batch_bytes: bytes,
+            durability: Durability::FlushOnClose,
+            io_retry: IoRetry::default(),
+        }
+    }
+}
+
+impl WriteLogGenerator {
+    fn open(&self, path: &String, append: bool) -> WriteLogWriter<fs::File> {
+        let writer = WriteLogWriter::<fs::File>::new_to_file(path, append)
+            .and_then(|w| w.set_durability(self.durability));
+        match writer {
+            Err(e) => panic!("Could not open {}: {}", path, e),
+            Ok(w) => {
+                w.set_batching(self.batch_records, self.batch_bytes).set_io_retry(self.io_retry)
+            }
+        }
     }
 }
 
 impl SinkGenerator for WriteLogGenerator {
     type Sink = WriteLogWriter<fs::File>;
     fn new_output(&self, path: &String) -> Self::Sink {
-        let writer = WriteLogWriter::<fs::File>::new_to_file(path, false);
-        match writer {
+        self.open(path, false)
+    }
+
+    fn append_output(&self, path: &String) -> Option<Self::Sink> {
+        Some(self.open(path, true))
+    }
+
+    fn with_durability(mut self, durability: Durability) -> WriteLogGenerator {
+        self.durability = durability;
+        self
+    }
+
+    fn with_io_retry(mut self, retry: IoRetry) -> WriteLogGenerator {
+        self.io_retry = retry;
+        self
+    }
+}
+
+/// A file opened in append mode that is rotated once it grows beyond a given size: The full file
+/// is moved to the next unused name `<path>.1`, `<path>.2`, ... and a new, empty file is started
+/// at `<path>`. Rotation only happens between two write operations; as WriteLogWriter writes a
+/// whole record at once, records are never split across files.
+pub struct AppendingFile {
+    path: String,
+    file: fs::File,
+    // Estimated size of the file; other writers may have appended to it as well.
+    size: u64,
+    rotate_at: Option<u64>,
+}
+
+impl AppendingFile {
+    /// Opens (or creates) `path` for appending. If `rotate_at` is given, the file is rotated
+    /// before it would exceed that many bytes.
+    pub fn open(path: &String, rotate_at: Option<u64>) -> io::Result<AppendingFile> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AppendingFile {
+            path: path.clone(),
+            file,
+            size,
+            rotate_at,
+        })
+    }
+
+    /// Returns whether the file at `path` is still the open file, i.e. no other writer has
+    /// rotated it.
+    fn is_current(&self) -> io::Result<bool> {
+        match fs::metadata(&self.path) {
+            Ok(m) => Ok(same_file(&m, &self.file.metadata()?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Continues with the file at `path`.
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = file;
+        Ok(())
+    }
+
+    /// Moves the current file to the next unused rotation name and starts a new one, unless
+    /// another writer has done so already. Writers rotating the same file are serialized by a
+    /// lock on it.
+    fn rotate(&mut self) -> io::Result<()> {
+        lock_file(&self.file, true)?;
+        let result = self.rotate_locked();
+        let unlocked = lock_file(&self.file, false);
+        result?;
+        unlocked?;
+        self.reopen()
+    }
+
+    fn rotate_locked(&self) -> io::Result<()> {
+        if !self.is_current()? {
+            return Ok(());
+        }
+        let mut n = 1;
+        loop {
+            let rotated = format!("{}.{}", self.path, n);
+            // hard_link() fails instead of replacing an existing file, so concurrent writers
+            // can't clobber each other's rotated files.
+            match fs::hard_link(&self.path, &rotated) {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        fs::remove_file(&self.path)
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    true
+}
+
+/// Takes (or releases) an exclusive advisory lock on `file`.
+#[cfg(unix)]
+fn lock_file(file: &fs::File, lock: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let op = if lock { libc::LOCK_EX } else { libc::LOCK_UN };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_file(_: &fs::File, _: bool) -> io::Result<()> {
+    Ok(())
+}
+
+impl Write for AppendingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if let Some(max) = self.rotate_at {
+            if self.size > 0 && self.size + buf.len() as u64 > max {
+                // Other writers may have rotated the file already, or appended to it.
+                if self.is_current()? {
+                    self.size = self.file.metadata()?.len();
+                } else {
+                    self.reopen()?;
+                }
+                if self.size > 0 && self.size + buf.len() as u64 > max {
+                    self.rotate()?;
+                }
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Like WriteLogGenerator, but appends to existing files instead of truncating them, so that
+/// several controller invocations can add records to the same intermediate or output files.
+/// Optionally, files are rotated once they reach a given size (see AppendingFile). Files are
+/// neither flushed nor synced explicitly, regardless of `MRParameters::set_durability()`.
+#[derive(Clone)]
+pub struct AppendingWriteLogGenerator {
+    rotate_at: Option<u64>,
+}
+
+impl AppendingWriteLogGenerator {
+    /// Returns a generator rotating files at `rotate_at` bytes, or never if None.
+    pub fn new(rotate_at: Option<u64>) -> AppendingWriteLogGenerator {
+        AppendingWriteLogGenerator { rotate_at }
+    }
+}
+
+impl SinkGenerator for AppendingWriteLogGenerator {
+    type Sink = WriteLogWriter<AppendingFile>;
+    fn new_output(&self, path: &String) -> Self::Sink {
+        match AppendingFile::open(path, self.rotate_at) {
             Err(e) => panic!("Could not open {}: {}", path, e),
-            Ok(w) => w,
+            Ok(f) => WriteLogWriter::new(f),
         }
     }
+
+    fn append_output(&self, path: &String) -> Option<Self::Sink> {
+        Some(self.new_output(path))
+    }
 }
 
 /// A Reader for WriteLog files. (more information on WriteLog files is to
 /// be found above at WriteLogWriter).
 pub struct WriteLogReader {
-    src: Box<Read>,
+    src: Box<dyn Read + Send>,
     records_read: u32,
     bytes_read: usize,
+
+    // Used for reporting errors and skipped byte ranges.
+    source: String,
+    start_offset: u64,
+    entry_start: u64,
+    recover: bool,
+    skipped: Vec<(u64, u64)>,
+    // Entries that aren't valid UTF-8, when reading Strings.
+    invalid_entries: usize,
+    // Set by the last version marker.
+    value_version: u32,
 }
 
 impl WriteLogReader {
-    pub fn new(src: Box<Read + Send>) -> WriteLogReader {
+    pub fn new(src: Box<dyn Read + Send>) -> WriteLogReader {
         WriteLogReader {
             src: src,
             records_read: 0,
             bytes_read: 0,
+            source: String::from("<stream>"),
+            start_offset: 0,
+            entry_start: 0,
+            recover: false,
+            skipped: Vec::new(),
+            invalid_entries: 0,
+            value_version: 0,
         }
     }
 
     pub fn new_from_file(file: &String) -> io::Result<WriteLogReader> {
-        fs::OpenOptions::new()
-            .read(true)
-            .open(file)
-            .map(move |f| {
-                WriteLogReader::new(Box::new(io::BufReader::with_capacity(1024 * 1024, f)))
-            })
+        WriteLogReader::new_from_file_at(file, 0)
+    }
+
+    /// Opens a WriteLog file and starts reading at byte `offset`, which must be the beginning of
+    /// a record (for example an offset taken from an index sidecar).
+    pub fn new_from_file_at(file: &String, offset: u64) -> io::Result<WriteLogReader> {
+        let mut f = fs::OpenOptions::new().read(true).open(file)?;
+        if offset > 0 {
+            f.seek(io::SeekFrom::Start(offset))?;
+        }
+        let mut reader =
+            WriteLogReader::new(Box::new(io::BufReader::with_capacity(1024 * 1024, f)));
+        reader.source = file.clone();
+        reader.start_offset = offset;
+        Ok(reader)
+    }
+
+    /// Like `new_from_file_at()`, but stops reading at byte `end`, which must be the end of a
+    /// record; used for reading a segment of a file.
+    pub fn new_from_file_range(file: &String, offset: u64, end: u64) -> io::Result<WriteLogReader> {
+        let mut f = fs::OpenOptions::new().read(true).open(file)?;
+        f.seek(io::SeekFrom::Start(offset))?;
+        let segment = f.take(end.saturating_sub(offset));
+        let mut reader =
+            WriteLogReader::new(Box::new(io::BufReader::with_capacity(1024 * 1024, segment)));
+        reader.source = file.clone();
+        reader.start_offset = offset;
+        Ok(reader)
+    }
+
+    /// If set to true, `read_entry()` doesn't fail on a truncated entry or an entry with an
+    /// invalid length. Instead, it skips forward to the next sync marker (see `SYNC_MARKER`) and
+    /// continues reading there; the skipped byte ranges are logged and can be retrieved with
+    /// `skipped()`.
+    pub fn set_recover(mut self, recover: bool) -> WriteLogReader {
+        self.recover = recover;
+        self
+    }
+
+    /// Retries transient errors while reading according to `retry` (see `formats::retry`).
+    ///
+    /// Default: only EINTR is retried
+    pub fn set_io_retry(mut self, retry: IoRetry) -> WriteLogReader {
+        let src = ::std::mem::replace(&mut self.src, Box::new(io::empty()));
+        self.src = Box::new(RetryingReader::new(src, retry));
+        self
+    }
+
+    /// Sets the value version of the records read before the next version marker, e.g. when
+    /// starting to read in the middle of a file (see `read_value_version()`).
+    pub fn set_value_version(mut self, version: u32) -> WriteLogReader {
+        self.value_version = version;
+        self
+    }
+
+    /// Returns the value version of the entries read last (see `VERSION_MARKER`).
+    pub fn value_version(&self) -> u32 {
+        self.value_version
+    }
+
+    /// Returns the byte ranges [start; end) that have been skipped while recovering.
+    pub fn skipped(&self) -> &[(u64, u64)] {
+        &self.skipped
     }
 
-    /// Opens all files from a directory which end in suffix, and chains them together.
+    /// Returns the current offset in the underlying file.
+    pub fn offset(&self) -> u64 {
+        self.start_offset + self.bytes_read as u64
+    }
+
+    /// Opens all files from a directory which end in suffix, and chains them together. Fails if
+    /// one of the files can't be opened.
     pub fn new_from_dir(path: &String, suffix: &String) -> io::Result<WriteLogReader> {
-        let mut reader: Box<Read> = Box::new(io::empty());
+        let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
         let dir = try!(fs::read_dir(path));
 
         for entry in dir {
-            let name;
-            match entry {
-                Err(e) => {
-                    println!("Error opening {}: {}", path, e);
-                    continue;
-                }
-                Ok(direntry) => name = direntry.path(),
-            }
+            let name = entry?.path();
             if name.ends_with(suffix) {
                 match fs::OpenOptions::new().read(true).open(name.clone()) {
-                    Err(e) => {
-                        println!("Error opening {:?}: {}", name, e);
-                        continue;
-                    }
+                    Err(e) => return Err(FormatError::new(&name.to_string_lossy(), 0, 0, e).into()),
                     Ok(f) => {
                         reader =
                             Box::new(reader.chain(io::BufReader::with_capacity(1024 * 1024, f)))
@@ -185,15 +715,10 @@ impl WriteLogReader {
                 }
             }
         }
-        Ok(WriteLogReader {
-            src: reader,
-            records_read: 0,
-            bytes_read: 0,
-        })
-    }
-
-    pub fn get_stats(&self) -> (u32, usize) {
-        (self.records_read, self.bytes_read)
+        let mut log = WriteLogReader::new(Box::new(io::empty()));
+        log.src = reader;
+        log.source = path.clone();
+        Ok(log)
     }
 
     // Inlining saves us up to 400ns per record (1600ns vs 2000ns)
@@ -212,6 +737,7 @@ impl WriteLogReader {
                             return Ok(0);
                         }
                     } else if off + s < len {
+                        self.bytes_read += s;
                         off += s;
                     } else {
                         self.bytes_read += s;
@@ -222,6 +748,150 @@ impl WriteLogReader {
         }
     }
 
+    /// Reads the next entry into `buf`, reusing its allocation.
+    pub fn read_into(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        let mut lengthbuf = [0; 4];
+        self.read_bytes(&mut lengthbuf, 4)?;
+        let length = decode_u32(lengthbuf) as usize;
+        buf.resize(length, 0);
+        self.read_bytes(&mut buf[..], length)?;
+        self.records_read += 1;
+        Ok(())
+    }
+
+    /// Like `read_into()`, but returns Ok(false) if the log ends before the next entry. An error
+    /// is only returned if the entry is truncated or can't be read (and the reader isn't
+    /// recovering, see `set_recover()`); it wraps a FormatError locating the entry. Sync markers
+    /// are skipped.
+    pub fn read_entry(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        loop {
+            let start = self.offset();
+            match self.read_next_entry(buf) {
+                Ok(true) if buf[..] == SYNC_MARKER[..] => {
+                    self.records_read -= 1;
+                    continue;
+                }
+                Ok(true) if decode_version_marker(buf).is_some() => {
+                    self.value_version = decode_version_marker(buf).unwrap_or(0);
+                    self.records_read -= 1;
+                    continue;
+                }
+                Ok(true) => {
+                    self.entry_start = start;
+                    return Ok(true);
+                }
+                Ok(false) => return Ok(false),
+                Err(ref e) if self.recover => {
+                    if !self.resync(start, e)? {
+                        return Ok(false);
+                    }
+                }
+                Err(e) => return Err(self.error_at(start, e).into()),
+            }
+        }
+    }
+
+    /// Returns a FormatError for the entry starting at `offset`, which is the next entry unless
+    /// it has been read successfully.
+    pub fn error_at(&self, offset: u64, cause: io::Error) -> FormatError {
+        FormatError::new(&self.source, offset, self.record_at(offset), cause)
+    }
+
+    // The index of the entry starting at `offset`: either the entry read last, or the next one.
+    fn record_at(&self, offset: u64) -> usize {
+        if offset == self.entry_start && self.records_read > 0 {
+            self.records_read as usize - 1
+        } else {
+            self.records_read as usize
+        }
+    }
+
+    /// Returns the offset at which the entry last returned by `read_entry()` starts.
+    pub fn entry_start(&self) -> u64 {
+        self.entry_start
+    }
+
+    /// Skips forward to the next sync marker, after finding a corrupt entry at offset `start`.
+    /// The skipped byte range is logged (with the location of the corrupt entry) and recorded.
+    /// Returns false if the log ends before the next marker.
+    pub fn resync(&mut self, start: u64, cause: &io::Error) -> io::Result<bool> {
+        let mut marker = encode_u32(SYNC_MARKER.len() as u32).to_vec();
+        marker.extend_from_slice(&SYNC_MARKER);
+
+        let mut window = VecDeque::with_capacity(marker.len());
+        let mut byte = [0; 1];
+        let found = loop {
+            match self.src.read(&mut byte) {
+                Ok(0) => break false,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            self.bytes_read += 1;
+            if window.len() == marker.len() {
+                window.pop_front();
+            }
+            window.push_back(byte[0]);
+            if window.iter().eq(marker.iter()) {
+                break true;
+            }
+        };
+
+        let end = if found {
+            self.offset() - marker.len() as u64
+        } else {
+            self.offset()
+        };
+        println!("WARN: Skipping corrupt bytes [{}; {}) of {} (record #{}): {}",
+                 start,
+                 end,
+                 self.source,
+                 self.record_at(start),
+                 cause);
+        self.skipped.push((start, end));
+        Ok(found)
+    }
+
+    fn read_next_entry(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut lengthbuf = [0; 4];
+        let first = loop {
+            match self.src.read(&mut lengthbuf) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => break r?,
+            }
+        };
+        if first == 0 {
+            return Ok(false);
+        }
+        self.bytes_read += first;
+        if first < 4 {
+            self.read_bytes(&mut lengthbuf[first..], 4 - first)?;
+        }
+        let length = decode_u32(lengthbuf) as usize;
+        if self.recover && length > MAX_RECOVERABLE_ENTRY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Invalid entry length {}", length)));
+        }
+        buf.resize(length, 0);
+        self.read_bytes(&mut buf[..], length)?;
+        self.records_read += 1;
+        Ok(true)
+    }
+
+    /// Skips the next entry without allocating a buffer for it.
+    pub fn skip_entry(&mut self) -> io::Result<()> {
+        let mut lengthbuf = [0; 4];
+        self.read_bytes(&mut lengthbuf, 4)?;
+        let length = decode_u32(lengthbuf) as u64;
+        let skipped = io::copy(&mut (&mut self.src).take(length), &mut io::sink())?;
+        if skipped < length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Could not read enough data"));
+        }
+        self.bytes_read += skipped as usize;
+        self.records_read += 1;
+        Ok(())
+    }
+
     /// Reads as many bytes as necessary into a vector and returns it.
     /// This can of course take up much memory.
     pub fn read_vec(&mut self) -> io::Result<vec::Vec<u8>> {
@@ -250,6 +920,201 @@ impl WriteLogReader {
     }
 }
 
+/// Reads (key, value) records (see `encode_record()`) from a WriteLog, skipping the records whose
+/// key doesn't match a filter. Skipped records are never copied out of the read buffer, and keys
+/// are only copied if they match. The input is expected to be sorted by key, so that reading can
+/// stop once the keys are past the filter's range.
+///
+/// Intermediate files are only read by localmr itself, so a truncated or otherwise corrupt record
+/// means that the data is lost; the reader panics instead of silently returning fewer records,
+/// unless the WriteLogReader is recovering (see `WriteLogReader::set_recover()`): Then, reading
+/// continues at the next sync marker.
+pub struct FilteredRecordReader {
+    reader: WriteLogReader,
+    filter: Option<KeyFilter>,
+    join_filters: Vec<Arc<Vec<BloomFilter>>>,
+    entry: Vec<u8>,
+    // The key of the last record carrying its key, for records referring to it (see
+    // `encode_key_ref()`).
+    last_key: Option<Vec<u8>>,
+    // The key dictionary of the file, for records referring to keys by index (see
+    // `encode_key_index()`).
+    dictionary: Option<Arc<Vec<string::String>>>,
+    key_only: bool,
+    value_version: u32,
+    decoders: Vec<(u32, ValueDecoderF)>,
+}
+
+impl FilteredRecordReader {
+    pub fn new(reader: WriteLogReader, filter: Option<KeyFilter>) -> FilteredRecordReader {
+        FilteredRecordReader {
+            reader,
+            filter,
+            join_filters: Vec::new(),
+            entry: Vec::new(),
+            last_key: None,
+            dictionary: None,
+            key_only: false,
+            value_version: 0,
+            decoders: Vec::new(),
+        }
+    }
+
+    /// Converts the values of records written with a value version other than `version` with
+    /// the decoder registered for their version (see `MRParameters::add_value_decoder()`).
+    /// Panics on records of a version without decoder.
+    pub fn set_value_decoders(mut self,
+                              version: u32,
+                              decoders: Vec<(u32, ValueDecoderF)>)
+                              -> FilteredRecordReader {
+        self.value_version = version;
+        self.decoders = decoders;
+        self
+    }
+
+    fn decode_value(&self, value: string::String) -> string::String {
+        let version = self.reader.value_version();
+        if version == self.value_version {
+            return value;
+        }
+        match self.decoders.iter().find(|&&(v, _)| v == version) {
+            Some(&(_, decode)) => decode(value),
+            None => {
+                panic!("No decoder for values of version {} in {} (expected version {})",
+                       version,
+                       self.reader.source,
+                       self.value_version)
+            }
+        }
+    }
+
+    /// Sets the key dictionary of the file, which is needed for reading files written with
+    /// `KeyEncoding::Dictionary` (see `read_key_dictionary()`).
+    pub fn set_key_dictionary(mut self,
+                              dictionary: Option<Arc<Vec<string::String>>>)
+                              -> FilteredRecordReader {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Reads a WriteLog containing only keys (see `MRParameters::set_key_only()`); the records
+    /// are returned with empty values.
+    pub fn set_key_only(mut self, key_only: bool) -> FilteredRecordReader {
+        self.key_only = key_only;
+        self
+    }
+
+    /// Skips records whose key isn't contained in every one of the given sets of Bloom filters;
+    /// a set contains a key if one of its filters may contain it. When joining, every set holds
+    /// the filters of another side of the join (see `MRController::reduce_join()`).
+    pub fn set_join_filters(mut self, filters: Vec<Arc<Vec<BloomFilter>>>) -> FilteredRecordReader {
+        self.join_filters = filters;
+        self
+    }
+}
+
+impl Iterator for FilteredRecordReader {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            match self.reader.read_entry(&mut self.entry) {
+                Ok(false) => return None,
+                Ok(true) => (),
+                Err(e) => panic!("Couldn't read intermediate record: {}", e),
+            }
+            let decoded = match decode_keyed_record(&self.entry) {
+                Ok((RecordKey::Key(key), value)) => {
+                    let last_key = self.last_key.get_or_insert_with(Vec::new);
+                    last_key.clear();
+                    last_key.extend_from_slice(key);
+                    Ok((key, value))
+                }
+                Ok((RecordKey::Previous, value)) => {
+                    match self.last_key {
+                        Some(ref key) => Ok((&key[..], value)),
+                        None => {
+                            Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               "Record refers to the key of a missing record"))
+                        }
+                    }
+                }
+                Ok((RecordKey::Index(i), value)) => {
+                    match self.dictionary.as_ref().and_then(|d| d.get(i as usize)) {
+                        Some(key) => Ok((key.as_bytes(), value)),
+                        None => {
+                            Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("Record refers to key {} missing from \
+                                                        the key dictionary",
+                                                       i)))
+                        }
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let err = match decoded {
+                Ok((key, value)) => {
+                    let key = string::String::from_utf8_lossy(key);
+                    match self.filter {
+                        None => (),
+                        Some(ref f) if f.is_past(&key) => return None,
+                        Some(ref f) if !f.matches(&key) => continue,
+                        Some(_) => (),
+                    }
+                    let joinable = self.join_filters
+                        .iter()
+                        .all(|set| set.iter().any(|f| f.may_contain(&key)));
+                    if !joinable {
+                        continue;
+                    }
+
+                    let value = if self.key_only {
+                        string::String::new()
+                    } else {
+                        self.decode_value(string::String::from_utf8_lossy(value).into_owned())
+                    };
+                    return Some(Record {
+                        key: key.into_owned(),
+                        value,
+                    });
+                }
+                Err(e) => e,
+            };
+
+            // The entry boundaries may be wrong, too; continue at the next sync marker.
+            self.last_key = None;
+            let start = self.reader.entry_start();
+            if !self.reader.recover {
+                panic!("Corrupt intermediate record: {}", self.reader.error_at(start, err));
+            }
+            match self.reader.resync(start, &err) {
+                Ok(true) => continue,
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl WriteLogReader {
+    /// Returns an iterator over the entries as raw bytes, e.g. for outputs written with
+    /// `REmitter::emit_bytes()`. Like the iterator over Strings, it ends at the first entry that
+    /// can't be read.
+    pub fn into_entries(self) -> Entries {
+        Entries { reader: self }
+    }
+}
+
+/// Iterates over the entries of a WriteLog as byte vectors (see `WriteLogReader::into_entries()`).
+pub struct Entries {
+    reader: WriteLogReader,
+}
+
+impl Iterator for Entries {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.reader.read_vec().ok()
+    }
+}
+
 impl Iterator for WriteLogReader {
     type Item = String;
     fn next(&mut self) -> Option<String> {
@@ -262,12 +1127,39 @@ impl Iterator for WriteLogReader {
         }
 
         match convert_result {
-            Err(_) => None,
+            Err(_) => {
+                self.invalid_entries += 1;
+                None
+            }
             Ok(s) => Some(s),
         }
     }
 }
 
+/// Byte ranges skipped when recovering from corrupt entries (see `set_recover()`) count as
+/// decode errors, as do entries that aren't valid UTF-8 when reading Strings.
+impl Stats for WriteLogReader {
+    fn stats(&self) -> InputStats {
+        InputStats {
+            records: self.records_read as usize,
+            bytes: self.bytes_read as u64,
+            decode_errors: self.skipped.len() + self.invalid_entries,
+        }
+    }
+}
+
+impl Stats for FilteredRecordReader {
+    fn stats(&self) -> InputStats {
+        self.reader.stats()
+    }
+}
+
+impl Stats for Entries {
+    fn stats(&self) -> InputStats {
+        self.reader.stats()
+    }
+}
+
 impl Read for WriteLogReader {
     fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
         let mut lengthbuf = [0; 4];
@@ -299,12 +1191,21 @@ impl Read for WriteLogReader {
 
 #[cfg(test)]
 mod test {
-    use super::{encode_u32, decode_u32};
-    use super::{WriteLogWriter, WriteLogReader};
+    use super::{encode_u32, decode_u32, encode_record, decode_record, encode_key_ref,
+                encode_key_index, decode_keyed_record, RecordKey};
+    use super::{AppendingWriteLogGenerator, FilteredRecordReader, WriteLogWriter, WriteLogReader};
+    use super::{encode_version_marker, read_value_version};
+    use std::sync::Arc;
+    use stats::Stats;
+    use mapreducer::ValueDecoderF;
+    use formats::error::FormatError;
+    use formats::util::KeyFilter;
+    use phases::output::SinkGenerator;
     use std::vec;
-    use std::io::{Read, Write};
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::fs;
     use std::string;
+    use std::thread;
 
     #[test]
     fn test_u32_encoder() {
@@ -329,6 +1230,43 @@ mod test {
         assert_eq!(bytes, 2 * (4 + 3));
     }
 
+    // Records the sizes of the writes to it.
+    struct WriteSizes(Vec<usize>);
+
+    impl Write for WriteSizes {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_batched() {
+        let mut sizes = WriteSizes(Vec::new());
+        {
+            let mut w = WriteLogWriter::new(&mut sizes).set_batching(3, 0);
+            for _ in 0..4 {
+                let _ = w.write(b"abc").unwrap();
+            }
+            w.flush().unwrap();
+            let _ = w.write(b"abc").unwrap();
+        }
+        assert_eq!(sizes.0, vec![3 * 7, 7, 7]);
+
+        let mut sizes = WriteSizes(Vec::new());
+        {
+            let mut w = WriteLogWriter::new(&mut sizes).set_batching(0, 10);
+            for _ in 0..3 {
+                let _ = w.write(b"abc").unwrap();
+            }
+            assert_eq!(w.get_stats(), (3 * 7, 3));
+        }
+        assert_eq!(sizes.0, vec![2 * 7, 7]);
+    }
+
     #[test]
     fn test_write_read() {
         let filename = "writelog_test.wlg";
@@ -372,6 +1310,244 @@ mod test {
         let _ = fs::remove_file(filename);
     }
 
+    #[test]
+    fn test_filtered_record_reader() {
+        let path = String::from("testdata/writelog_filtered.wlg");
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&path, false).unwrap();
+            for k in ["aa", "ab", "b", "ba", "c"].iter() {
+                w.write_record(k.as_bytes(), format!("value_{}", k).as_bytes()).unwrap();
+            }
+            // Refers to the key of the previous record.
+            let mut frame = Vec::new();
+            encode_key_ref(b"value_c2", &mut frame);
+            w.write_all(&frame).unwrap();
+        }
+        let read = |filter| -> Vec<String> {
+            let reader = WriteLogReader::new_from_file(&path).unwrap();
+            FilteredRecordReader::new(reader, filter).map(|r| r.key + "=" + &r.value).collect()
+        };
+
+        assert_eq!(read(None).len(), 6);
+        assert_eq!(read(Some(KeyFilter::Prefix(String::from("c")))),
+                   vec!["c=value_c", "c=value_c2"]);
+        assert_eq!(read(Some(KeyFilter::Prefix(String::from("b")))),
+                   vec!["b=value_b", "ba=value_ba"]);
+        assert_eq!(read(Some(KeyFilter::Range(Some(String::from("ab")), Some(String::from("ba"))))),
+                   vec!["ab=value_ab", "b=value_b"]);
+
+        // Records referring to keys of a dictionary.
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&path, false).unwrap();
+            let mut frame = Vec::new();
+            for (i, v) in [(0, "1"), (0, "2"), (1, "3")].iter() {
+                encode_key_index(*i, v.as_bytes(), &mut frame);
+                w.write_all(&frame).unwrap();
+            }
+        }
+        let dictionary = Arc::new(vec![String::from("x"), String::from("y")]);
+        let reader = WriteLogReader::new_from_file(&path).unwrap();
+        let records: Vec<String> = FilteredRecordReader::new(reader, None)
+            .set_key_dictionary(Some(dictionary))
+            .map(|r| r.key + "=" + &r.value)
+            .collect();
+        assert_eq!(records, vec!["x=1", "x=2", "y=3"]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_record_framing() {
+        let mut frame = Vec::new();
+        encode_record(b"key", b"value", &mut frame);
+        assert_eq!(frame.len(), 8 + 3 + 5);
+        assert_eq!(decode_record(&frame).unwrap(), (&b"key"[..], &b"value"[..]));
+        assert!(decode_record(&frame[..10]).is_err());
+        assert!(decode_record(&frame[..4]).is_err());
+        assert_eq!(decode_keyed_record(&frame).unwrap(),
+                   (RecordKey::Key(&b"key"[..]), &b"value"[..]));
+        encode_key_ref(b"value", &mut frame);
+        assert_eq!(frame.len(), 8 + 5);
+        assert!(decode_record(&frame).is_err());
+        assert_eq!(decode_keyed_record(&frame).unwrap(), (RecordKey::Previous, &b"value"[..]));
+        assert!(decode_keyed_record(&frame[..10]).is_err());
+        encode_key_index(7, b"value", &mut frame);
+        assert_eq!(frame.len(), 8 + 5);
+        assert!(decode_record(&frame).is_err());
+        assert_eq!(decode_keyed_record(&frame).unwrap(), (RecordKey::Index(7), &b"value"[..]));
+
+        let path = String::from("testdata/writelog_framing.wlg");
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&path, false).unwrap();
+            w.write_record(b"a", b"1").unwrap();
+            w.write_record(b"b", b"").unwrap();
+        }
+        let mut reader = WriteLogReader::new_from_file(&path).unwrap();
+        let mut entry = Vec::new();
+        assert!(reader.read_entry(&mut entry).unwrap());
+        assert!(reader.read_entry(&mut entry).unwrap());
+        assert_eq!(decode_record(&entry).unwrap(), (&b"b"[..], &b""[..]));
+        assert!(!reader.read_entry(&mut entry).unwrap());
+
+        // A truncated record is an error, not the end of the file.
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+        let mut reader = WriteLogReader::new_from_file(&path).unwrap();
+        assert!(reader.read_entry(&mut entry).unwrap());
+        assert!(reader.read_entry(&mut entry).is_err());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_value_versions() {
+        let path = String::from("testdata/writelog_versions.wlg");
+        let offset;
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&path, false).unwrap();
+            let _ = w.write(&encode_version_marker(1)).unwrap();
+            w.write_record(b"a", b"1").unwrap();
+            offset = w.get_stats().0;
+            w.write_record(b"b", b"2").unwrap();
+        }
+        assert_eq!(read_value_version(&path).unwrap(), 1);
+
+        fn double(v: String) -> String {
+            v.repeat(2)
+        }
+        let read = |reader: WriteLogReader, version| -> Vec<String> {
+            FilteredRecordReader::new(reader, None)
+                .set_value_decoders(version, vec![(1, double as ValueDecoderF)])
+                .map(|r| r.key + "=" + &r.value)
+                .collect()
+        };
+        assert_eq!(read(WriteLogReader::new_from_file(&path).unwrap(), 1),
+                   vec!["a=1", "b=2"]);
+        assert_eq!(read(WriteLogReader::new_from_file(&path).unwrap(), 2),
+                   vec!["a=11", "b=22"]);
+        // Reading from the middle of the file, the version has to be set.
+        let reader = WriteLogReader::new_from_file_at(&path, offset).unwrap().set_value_version(1);
+        assert_eq!(read(reader, 2), vec!["b=22"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_recover() {
+        let path = String::from("testdata/writelog_recover.wlg");
+        let corrupt_at;
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&path, false).unwrap();
+            w.write_record(b"a", b"1").unwrap();
+            corrupt_at = w.get_stats().0;
+            w.write_record(b"b", b"2").unwrap();
+            w.write_record(b"c", b"3").unwrap();
+            w.write_sync_marker().unwrap();
+            w.write_record(b"d", b"4").unwrap();
+        }
+        // Overwrite the key length of record "b".
+        {
+            let mut f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            f.seek(SeekFrom::Start(corrupt_at + 4)).unwrap();
+            f.write_all(&[0x7f]).unwrap();
+        }
+        let read = |recover| -> Vec<String> {
+            let reader = WriteLogReader::new_from_file(&path).unwrap().set_recover(recover);
+            FilteredRecordReader::new(reader, None).map(|r| r.key + "=" + &r.value).collect()
+        };
+        assert_eq!(read(true), vec!["a=1", "d=4"]);
+
+        // Truncated files are recovered at the end, too.
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+        let mut reader = WriteLogReader::new_from_file(&path).unwrap().set_recover(true);
+        let mut entry = Vec::new();
+        let mut entries = 0;
+        while reader.read_entry(&mut entry).unwrap() {
+            entries += 1;
+        }
+        // The (invalid) record "b" is read as an entry; only decoding it fails.
+        assert_eq!(entries, 3);
+        assert_eq!(reader.skipped(), &[(corrupt_at + 2 * 14 + 20, len - 1)]);
+
+        // Without recovering, the error locates the truncated entry (sync markers don't count).
+        let mut reader = WriteLogReader::new_from_file(&path).unwrap();
+        let err = loop {
+            match reader.read_entry(&mut entry) {
+                Ok(true) => (),
+                Ok(false) => panic!("truncated entry not detected"),
+                Err(e) => break e,
+            }
+        };
+        let located = FormatError::from_io(&err).unwrap();
+        assert_eq!((&located.file, located.offset, located.record),
+                   (&path, corrupt_at + 2 * 14 + 20, 3));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_append_rotate() {
+        let filename = String::from("testdata/writelog_append.wlg");
+        let gen = AppendingWriteLogGenerator::new(Some(20));
+
+        // Two "invocations" appending to the same file.
+        for _ in 0..2 {
+            let mut w = gen.new_output(&filename);
+            let _ = w.write("abcdef".as_bytes());
+            let _ = w.write("ghijkl".as_bytes());
+        }
+
+        // Every file holds at most two records of 10 bytes each.
+        let mut records = Vec::new();
+        for name in &[format!("{}.1", filename), filename.clone()] {
+            let r = WriteLogReader::new_from_file(name).unwrap();
+            let recs: Vec<String> = r.collect();
+            assert_eq!(recs.len(), 2);
+            records.extend(recs);
+            let _ = fs::remove_file(name);
+        }
+        assert_eq!(records, vec!["abcdef", "ghijkl", "abcdef", "ghijkl"]);
+    }
+
+    #[test]
+    fn test_append_rotate_concurrently() {
+        let filename = String::from("testdata/writelog_append_concurrent.wlg");
+        let gen = AppendingWriteLogGenerator::new(Some(100));
+
+        let writers: Vec<_> = (0..2)
+            .map(|w| {
+                let gen = gen.clone();
+                let filename = filename.clone();
+                thread::spawn(move || {
+                    let mut writer = gen.new_output(&filename);
+                    for i in 0..200 {
+                        let _ = writer.write(format!("{}-{:03}", w, i).as_bytes());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // No record is lost, and a file is only rotated once, when it is full: Every file holds
+        // at most the records of two writers that both found it not full yet.
+        let names = (1..)
+            .map(|n| format!("{}.{}", filename, n))
+            .take_while(|name| fs::metadata(name).is_ok())
+            .chain(Some(filename.clone()));
+        let mut records = Vec::new();
+        for name in names {
+            let recs: Vec<String> = WriteLogReader::new_from_file(&name).unwrap().collect();
+            assert!(!recs.is_empty() && recs.len() <= 2 * 100 / 9, "{}: {:?}", name, recs);
+            records.extend(recs);
+            let _ = fs::remove_file(name);
+        }
+        records.sort();
+        let mut expected: Vec<String> = (0..2)
+            .flat_map(|w| (0..200).map(move |i| format!("{}-{:03}", w, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(records, expected);
+    }
+
     extern crate time;
     use self::time::PreciseTime;
 
@@ -435,8 +1611,9 @@ mod test {
                          start.to(end),
                          start.to(end) / N_ENTRIES as i32);
                 assert_eq!(i, N_ENTRIES);
-                assert_eq!(reader.get_stats(),
-                           (N_ENTRIES, (N_ENTRIES * 4 + N_ENTRIES * 3 * 16) as usize));
+                let stats = reader.stats();
+                assert_eq!((stats.records, stats.bytes),
+                           (N_ENTRIES as usize, (N_ENTRIES * 4 + N_ENTRIES * 3 * 16) as u64));
             }
         }
 
@@ -0,0 +1,147 @@
+//! Record headers: small pieces of metadata that a mapper attaches to an emitted value, and that
+//! the reducer reads back next to the value, e.g. the source of a record in a join, or a weight
+//! for a weighted aggregation (see `MEmitter::emit_with_header()` and
+//! `MultiRecord::values_with_headers()`).
+//!
+//! Headers are carried in-band, like the tags of co-group jobs: The header is prepended to the
+//! value as `\x01<tag>[\x1f<name>=<value>]*\x02`, so that an untagged header without metadata
+//! only takes two bytes per value, and intermediate files stay plain (key, value) records.
+//! Values with a header therefore compare differently in secondary sorts, and headers are lost in
+//! key-only jobs (see `MRParameters::set_key_only()`). Reducers that don't read headers see the
+//! encoded values.
+
+use std::str::FromStr;
+
+const START: char = '\u{1}';
+const END: char = '\u{2}';
+const SEPARATOR: char = '\u{1f}';
+
+/// A tag and a small map of named metadata values attached to an emitted value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecordHeader {
+    tag: Option<char>,
+    // Kept in the order the entries were set; headers are expected to hold few entries.
+    meta: Vec<(String, String)>,
+}
+
+impl RecordHeader {
+    pub fn new() -> RecordHeader {
+        RecordHeader::default()
+    }
+
+    /// Sets the tag, e.g. to mark the input a value comes from. The tag can't be one of the
+    /// control characters used by the encoding (`\x01`, `\x02` and `\x1f`).
+    pub fn set_tag(mut self, tag: char) -> RecordHeader {
+        assert!(!is_reserved(tag), "Invalid header tag {:?}", tag);
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Sets the metadata entry `name`, replacing an earlier value. Names can't contain `=`, and
+    /// neither names nor values can contain the control characters used by the encoding.
+    pub fn set<T: ToString>(mut self, name: &str, value: T) -> RecordHeader {
+        let value = value.to_string();
+        assert!(!name.is_empty() && !name.contains(|c| c == '=' || is_reserved(c)),
+                "Invalid header name {:?}",
+                name);
+        assert!(!value.contains(is_reserved), "Invalid header value {:?}", value);
+        match self.meta.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = value,
+            None => self.meta.push((String::from(name), value)),
+        }
+        self
+    }
+
+    pub fn tag(&self) -> Option<char> {
+        self.tag
+    }
+
+    /// Returns the metadata entry `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.meta.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the metadata entry `name` parsed as `T`, or None if it is missing or can't be
+    /// parsed.
+    pub fn get_as<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    /// Returns `value` with the header prepended.
+    pub fn attach(&self, value: &str) -> String {
+        let meta_len: usize = self.meta.iter().map(|(n, v)| n.len() + v.len() + 2).sum();
+        let mut encoded = String::with_capacity(value.len() + meta_len + 6);
+        encoded.push(START);
+        if let Some(tag) = self.tag {
+            encoded.push(tag);
+        }
+        for (name, v) in &self.meta {
+            encoded.push(SEPARATOR);
+            encoded.push_str(name);
+            encoded.push('=');
+            encoded.push_str(v);
+        }
+        encoded.push(END);
+        encoded.push_str(value);
+        encoded
+    }
+
+    /// Splits a value into its header and the value emitted by the mapper. Values without header
+    /// (or with a header that can't be decoded) are returned unchanged.
+    pub fn split(value: &str) -> (Option<RecordHeader>, &str) {
+        match RecordHeader::decode(value) {
+            Some((header, rest)) => (Some(header), rest),
+            None => (None, value),
+        }
+    }
+
+    fn decode(value: &str) -> Option<(RecordHeader, &str)> {
+        let encoded = value.strip_prefix(START)?;
+        let end = encoded.find(END)?;
+        let mut fields = encoded[..end].split(SEPARATOR);
+        let mut chars = fields.next()?.chars();
+        let tag = chars.next();
+        if chars.next().is_some() {
+            return None;
+        }
+        let mut meta = Vec::new();
+        for field in fields {
+            let (name, v) = field.split_once('=')?;
+            meta.push((String::from(name), String::from(v)));
+        }
+        Some((RecordHeader { tag, meta }, &encoded[end + END.len_utf8()..]))
+    }
+}
+
+fn is_reserved(c: char) -> bool {
+    c == START || c == END || c == SEPARATOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordHeader;
+
+    #[test]
+    fn test_record_header() {
+        let header = RecordHeader::new().set_tag('L').set("weight", 2.5).set("src", "db");
+        let value = header.attach("abc");
+        assert_eq!(value, "\u{1}L\u{1f}weight=2.5\u{1f}src=db\u{2}abc");
+        let (decoded, rest) = RecordHeader::split(&value);
+        let decoded = decoded.unwrap();
+        assert_eq!(rest, "abc");
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.tag(), Some('L'));
+        assert_eq!(decoded.get_as::<f64>("weight"), Some(2.5));
+        assert_eq!(decoded.get("src"), Some("db"));
+        assert_eq!(decoded.get("missing"), None);
+
+        // An empty header takes two bytes.
+        assert_eq!(RecordHeader::new().attach("x").len(), 3);
+        assert_eq!(RecordHeader::split(&RecordHeader::new().attach("")),
+                   (Some(RecordHeader::new()), ""));
+        // Replacing an entry keeps a single one.
+        assert_eq!(RecordHeader::new().set("a", 1).set("a", 2).attach(""), "\u{1}\u{1f}a=2\u{2}");
+        assert_eq!(RecordHeader::split("plain"), (None, "plain"));
+        assert_eq!(RecordHeader::split("\u{1}unterminated"), (None, "\u{1}unterminated"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Finds the most frequent keys of a job, in order to diagnose skew (one reduce partition taking
+//! much longer than the others) and unexpected key explosions (see
+//! `MRParameters::set_key_histogram()`).
+//!
+//! The reduce partitions count the records and bytes of every group they reduce in a
+//! `KeyHistogram`, which keeps the heaviest keys with the Space-Saving algorithm: A bounded
+//! number of keys is counted; when a new key arrives and no slot is free, it replaces the
+//! lightest key and inherits its counts. Counts are therefore upper bounds, but keys heavier than
+//! the replaced ones are never lost. The histograms of all partitions are merged into
+//! `JobStats::key_histogram` and written next to the outputs of the job.
+
+use sort::dict_string_compare;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+
+/// The number of records and bytes (keys and values) counted for a key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyCount {
+    pub key: String,
+    pub records: u64,
+    pub bytes: u64,
+}
+
+/// A Space-Saving sketch, ranking keys by records or by bytes.
+#[derive(Clone, Debug, PartialEq)]
+struct SpaceSaving {
+    capacity: usize,
+    by_bytes: bool,
+    counts: HashMap<String, KeyCount>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize, by_bytes: bool) -> SpaceSaving {
+        SpaceSaving {
+            capacity,
+            by_bytes,
+            counts: HashMap::new(),
+        }
+    }
+
+    fn weight(&self, count: &KeyCount) -> u64 {
+        if self.by_bytes { count.bytes } else { count.records }
+    }
+
+    fn add(&mut self, key: &str, records: u64, bytes: u64) {
+        if let Some(count) = self.counts.get_mut(key) {
+            count.records += records;
+            count.bytes += bytes;
+            return;
+        }
+        let mut count = KeyCount {
+            key: String::from(key),
+            records,
+            bytes,
+        };
+        if self.counts.len() >= self.capacity {
+            let lightest = match self.counts.values().min_by_key(|c| self.weight(c)) {
+                Some(c) => c.key.clone(),
+                None => return,
+            };
+            let replaced = self.counts.remove(&lightest).unwrap();
+            count.records += replaced.records;
+            count.bytes += replaced.bytes;
+        }
+        self.counts.insert(count.key.clone(), count);
+    }
+
+    /// Returns the `k` heaviest keys, heaviest first.
+    fn top(&self, k: usize) -> Vec<KeyCount> {
+        let mut top: Vec<KeyCount> = self.counts.values().cloned().collect();
+        top.sort_by(|a, b| {
+            self.weight(b).cmp(&self.weight(a)).then_with(|| dict_string_compare(&a.key, &b.key))
+        });
+        top.truncate(k);
+        top
+    }
+}
+
+/// The heaviest keys by records and by bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyHistogram {
+    k: usize,
+    by_records: SpaceSaving,
+    by_bytes: SpaceSaving,
+}
+
+impl KeyHistogram {
+    /// Keeps the `k` heaviest keys. More keys are counted internally, so that the counts of the
+    /// top keys are mostly exact.
+    pub fn new(k: usize) -> KeyHistogram {
+        let capacity = 4 * k.max(1);
+        KeyHistogram {
+            k,
+            by_records: SpaceSaving::new(capacity, false),
+            by_bytes: SpaceSaving::new(capacity, true),
+        }
+    }
+
+    /// Counts `records` records with `bytes` bytes for `key`.
+    pub fn add(&mut self, key: &str, records: u64, bytes: u64) {
+        self.by_records.add(key, records, bytes);
+        self.by_bytes.add(key, records, bytes);
+    }
+
+    /// Adds the counts of `other`.
+    pub fn merge(&mut self, other: &KeyHistogram) {
+        for count in other.by_records.counts.values() {
+            self.by_records.add(&count.key, count.records, count.bytes);
+        }
+        for count in other.by_bytes.counts.values() {
+            self.by_bytes.add(&count.key, count.records, count.bytes);
+        }
+    }
+
+    /// Returns the keys with the most records, most first.
+    pub fn top_by_records(&self) -> Vec<KeyCount> {
+        self.by_records.top(self.k)
+    }
+
+    /// Returns the keys with the most bytes, most first.
+    pub fn top_by_bytes(&self) -> Vec<KeyCount> {
+        self.by_bytes.top(self.k)
+    }
+
+    /// Writes the histogram to `path` as tab-separated lines of ranking, key, records and bytes,
+    /// where the ranking is `records` for the keys with the most records and `bytes` for those
+    /// with the most bytes.
+    pub fn write_to(&self, path: &String) -> io::Result<()> {
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        for (ranking, top) in [("records", self.top_by_records()),
+                               ("bytes", self.top_by_bytes())] {
+            for count in top {
+                writeln!(f, "{}\t{}\t{}\t{}", ranking, count.key, count.records, count.bytes)?;
+            }
+        }
+        f.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyCount, KeyHistogram};
+    use std::fs;
+
+    fn count(key: &str, records: u64, bytes: u64) -> KeyCount {
+        KeyCount {
+            key: String::from(key),
+            records,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn test_key_histogram() {
+        let mut h = KeyHistogram::new(2);
+        h.add("hot", 1000, 4000);
+        h.add("big", 10, 50000);
+        for i in 0..100 {
+            h.add(&format!("k{}", i), 3, 30);
+        }
+        h.add("hot", 1, 4);
+        assert_eq!(h.top_by_records()[0], count("hot", 1001, 4004));
+        assert_eq!(h.top_by_records().len(), 2);
+        assert_eq!(h.top_by_bytes()[0], count("big", 10, 50000));
+
+        // Histograms of different partitions are merged.
+        let mut other = KeyHistogram::new(2);
+        other.add("other", 2000, 10);
+        h.merge(&other);
+        // "other" replaced a light key, whose counts it inherited.
+        let top = h.top_by_records();
+        assert_eq!(top[0].key, "other");
+        assert!(top[0].records >= 2000 && top[0].records < 2100);
+        assert_eq!(top[1], count("hot", 1001, 4004));
+
+        let path = String::from("testdata/key_histogram");
+        h.write_to(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("records\tother\t"));
+        assert_eq!(lines[2], "bytes\tbig\t10\t50000");
+        let _ = fs::remove_file(path);
+    }
+}
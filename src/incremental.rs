@@ -0,0 +1,220 @@
+//! State of incremental jobs (see `MRController::run_incremental()`): For every input file, the
+//! state file records its modification time and size at the time it was mapped, and how many
+//! map partitions it resulted in. Files whose mtime and size didn't change since the last run
+//! are not mapped again; their intermediate files from the last run are used instead.
+
+use mapreducer::fnv1a_seeded;
+use phases::output::{map_bloom_name, map_dictionary_name, map_index_name, map_multiplexed_name,
+                     map_output_name};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The recorded state of one input file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileState {
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub size: u64,
+    /// How many map partitions (i.e. intermediate files per reduce shard) the file resulted in.
+    pub partitions: usize,
+}
+
+impl FileState {
+    /// Returns the current state of the file at `path`, with `partitions` set to 0.
+    pub fn of_file(path: &String) -> io::Result<FileState> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(FileState {
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            size: meta.len(),
+            partitions: 0,
+        })
+    }
+
+    /// Returns true if `other` describes the same version of the file (ignoring `partitions`).
+    pub fn unchanged(&self, other: &FileState) -> bool {
+        self.mtime_secs == other.mtime_secs && self.mtime_nanos == other.mtime_nanos &&
+        self.size == other.size
+    }
+}
+
+/// The state of an incremental job: The number of reducers used and the state of every input
+/// file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IncrementalState {
+    pub reducers: usize,
+    pub files: BTreeMap<String, FileState>,
+}
+
+impl IncrementalState {
+    pub fn new(reducers: usize) -> IncrementalState {
+        IncrementalState {
+            reducers,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Loads a state file. A missing state file results in an empty state (i.e. every input file
+    /// is mapped).
+    pub fn load(path: &String) -> io::Result<IncrementalState> {
+        let f = match fs::File::open(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(IncrementalState::default())
+            }
+            Err(e) => return Err(e),
+            Ok(f) => f,
+        };
+        let invalid = |line: &String| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Invalid line in state file {}: {}", path, line))
+        };
+
+        let mut state = IncrementalState::default();
+        for line in io::BufReader::new(f).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            if fields.len() == 2 && fields[0] == "reducers" {
+                state.reducers = fields[1].parse().map_err(|_| invalid(&line))?;
+            } else if fields.len() == 5 {
+                let file = FileState {
+                    mtime_secs: fields[1].parse().map_err(|_| invalid(&line))?,
+                    mtime_nanos: fields[2].parse().map_err(|_| invalid(&line))?,
+                    size: fields[3].parse().map_err(|_| invalid(&line))?,
+                    partitions: fields[4].parse().map_err(|_| invalid(&line))?,
+                };
+                state.files.insert(String::from(fields[0]), file);
+            } else if !line.is_empty() {
+                return Err(invalid(&line));
+            }
+        }
+        Ok(state)
+    }
+
+    /// Returns true if a run with `reducers` reducers over `files` would not map any file and
+    /// not remove any intermediate files, i.e. it would produce the same result as the last run.
+    pub fn is_current(&self, reducers: usize, files: &[String]) -> bool {
+        if self.reducers != reducers || self.files.len() != files.len() {
+            return false;
+        }
+        files.iter().all(|name| {
+            match (self.files.get(name), FileState::of_file(name)) {
+                (Some(previous), Ok(current)) => previous.unchanged(&current),
+                _ => false,
+            }
+        })
+    }
+
+    /// Writes the state file. The file is written to a temporary file first and then renamed,
+    /// so that an interrupted run doesn't leave a truncated state.
+    pub fn save(&self, path: &String) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+            writeln!(f, "reducers\t{}", self.reducers)?;
+            for (name, file) in self.files.iter() {
+                writeln!(f,
+                         "{}\t{}\t{}\t{}\t{}",
+                         name,
+                         file.mtime_secs,
+                         file.mtime_nanos,
+                         file.size,
+                         file.partitions)?;
+            }
+            f.flush()?;
+        }
+        fs::rename(tmp, path)
+    }
+}
+
+/// Configures the watch mode (see `MRController::run_watch()`).
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    pub dir: String,
+    pub with_suffix: String,
+    pub state_file: String,
+    pub interval: Duration,
+    pub max_batches: Option<usize>,
+}
+
+impl WatchOptions {
+    /// Watches the files in `dir` ending with `with_suffix`, keeping the incremental state in
+    /// `state_file`.
+    pub fn new(dir: String, with_suffix: String, state_file: String) -> WatchOptions {
+        WatchOptions {
+            dir,
+            with_suffix,
+            state_file,
+            interval: Duration::from_secs(5),
+            max_batches: None,
+        }
+    }
+
+    /// How often the directory is checked for new or changed files.
+    ///
+    /// Default: 5 seconds
+    pub fn set_interval(mut self, interval: Duration) -> WatchOptions {
+        self.interval = interval;
+        self
+    }
+
+    /// Stop after this many batches have been run.
+    ///
+    /// Default: None (run forever)
+    pub fn set_max_batches(mut self, n: usize) -> WatchOptions {
+        self.max_batches = Some(n);
+        self
+    }
+}
+
+/// Returns the location of the intermediate files of input file `path`.
+pub fn file_map_location(base: &String, path: &String) -> String {
+    format!("{}{:016x}", base, fnv1a_seeded(0, path.as_bytes()))
+}
+
+/// Removes the intermediate files (separate or multiplexed) and their sidecars that were written
+/// at `location`, e.g. for an input file.
+pub fn remove_intermediates(location: &String, partitions: usize, reducers: usize) {
+    for mpart in 0..partitions {
+        let name = map_multiplexed_name(location, mpart);
+        let _ = fs::remove_file(map_dictionary_name(&name));
+        let _ = fs::remove_file(name);
+        for rshard in 0..reducers {
+            let name = map_output_name(location, mpart, rshard);
+            let _ = fs::remove_file(map_index_name(&name));
+            let _ = fs::remove_file(map_bloom_name(&name));
+            let _ = fs::remove_file(map_dictionary_name(&name));
+            let _ = fs::remove_file(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileState, IncrementalState};
+    use std::fs;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let path = String::from("testdata/incremental_state_roundtrip");
+        let _ = fs::remove_file(&path);
+        assert_eq!(IncrementalState::load(&path).unwrap(),
+                   IncrementalState::default());
+
+        let mut state = IncrementalState::new(3);
+        state.files.insert(String::from("a/b.log"),
+                           FileState {
+                               mtime_secs: 1234,
+                               mtime_nanos: 56,
+                               size: 789,
+                               partitions: 2,
+                           });
+        state.save(&path).unwrap();
+        assert_eq!(IncrementalState::load(&path).unwrap(), state);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -11,6 +11,7 @@ pub struct InputCache {
     chunks_iter: linked_list::IntoIter<Vec<Record>>,
     chunk_iter: vec::IntoIter<Record>,
     len: usize,
+    bytes: usize,
 }
 
 impl InputCache {
@@ -49,6 +50,7 @@ impl InputCache {
         if chunklist.len() == 0 {
             InputCache {
                 len: 0,
+                bytes: 0,
                 chunks_iter: LinkedList::new().into_iter(),
                 chunk_iter: Vec::new().into_iter(),
             }
@@ -56,6 +58,7 @@ impl InputCache {
             let first_chunk_iterator = chunklist.pop_front().unwrap().into_iter();
             InputCache {
                 len: complete_length,
+                bytes: bytes_read,
                 chunks_iter: chunklist.into_iter(),
                 chunk_iter: first_chunk_iterator,
             }
@@ -65,6 +68,11 @@ impl InputCache {
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Returns how many bytes (keys and values) the cached records occupy.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
 }
 
 impl Iterator for InputCache {
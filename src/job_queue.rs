@@ -0,0 +1,532 @@
+//! A queue of independent batch jobs, e.g. for nightly processing: Jobs are described by
+//! `JobSpec`s (built in code or loaded from a spec file), run one after another or a few at a
+//! time, and their status is kept in a status file, which can be read while and after the queue
+//! runs (see `load_status()`).
+//!
+//! Every job is a mapreduce run over text files via `MRController`. As spec files can't contain
+//! code, the mapper and reducer of a job are referred to by name; the functions are registered
+//! with the queue under these names (see `JobQueue::register_mapper()`).
+//!
+//! Spec files consist of lines of tab-separated fields; every job starts with a `job <name>`
+//! line, followed by the lines `mapper <name>`, `reducer <name>`, `input <path>` (once per input
+//! file), `output <prefix>`, `intermediates <prefix>`, `concurrency <mappers> <reducers>` and
+//! `config <key> <value>` (once per configuration value). Only the mapper and reducer are
+//! required. Empty lines and lines starting with `#` are ignored.
+
+use closure_mr::ClosureMapReducer;
+use controller::MRController;
+use executor::ExecutorHandle;
+use formats::lines::{self, LinesSinkGenerator};
+use formats::util::PosRecordIterator;
+use mapreducer::{DefaultSharder, MapperF, ReducerF};
+use parameters::MRParameters;
+use stats::JobStats;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+extern crate scoped_threadpool;
+use self::scoped_threadpool::Pool;
+
+/// Describes a job of a `JobQueue`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobSpec {
+    pub name: String,
+    /// The names of the mapper and reducer functions registered with the queue.
+    pub mapper: String,
+    pub reducer: String,
+    /// The text files to read; every line is an input record.
+    pub inputs: Vec<String>,
+    pub map_location: String,
+    pub output_prefix: String,
+    pub mappers: usize,
+    pub reducers: usize,
+    pub config: Vec<(String, String)>,
+}
+
+impl JobSpec {
+    /// Creates a job named `name`, using the mapper and reducer registered as `mapper` and
+    /// `reducer`. Intermediate files are written to `<name>_map_`, outputs to `<name>_out_`.
+    pub fn new(name: &str, mapper: &str, reducer: &str) -> JobSpec {
+        JobSpec {
+            name: String::from(name),
+            mapper: String::from(mapper),
+            reducer: String::from(reducer),
+            inputs: Vec::new(),
+            map_location: format!("{}_map_", name),
+            output_prefix: format!("{}_out_", name),
+            mappers: 4,
+            reducers: 4,
+            config: Vec::new(),
+        }
+    }
+
+    pub fn add_input(mut self, path: &str) -> JobSpec {
+        self.inputs.push(String::from(path));
+        self
+    }
+
+    /// See `MRParameters::set_file_locations()`.
+    pub fn set_file_locations(mut self, map_location: &str, output_prefix: &str) -> JobSpec {
+        self.map_location = String::from(map_location);
+        self.output_prefix = String::from(output_prefix);
+        self
+    }
+
+    /// See `MRParameters::set_concurrency()`.
+    pub fn set_concurrency(mut self, mappers: usize, reducers: usize) -> JobSpec {
+        self.mappers = mappers;
+        self.reducers = reducers;
+        self
+    }
+
+    /// See `MRParameters::set_config()`.
+    pub fn set_config<V: ToString>(mut self, key: &str, value: V) -> JobSpec {
+        self.config.push((String::from(key), value.to_string()));
+        self
+    }
+
+    /// Returns the parameters of the job.
+    pub fn params(&self) -> MRParameters {
+        let mut params = MRParameters::new()
+            .set_concurrency(self.mappers, self.reducers)
+            .set_file_locations(self.map_location.clone(), self.output_prefix.clone());
+        for (key, value) in self.config.iter() {
+            params = params.set_config(key, value);
+        }
+        params
+    }
+}
+
+/// Reads the job specifications of a spec file (see the module documentation).
+pub fn load_specs(path: &String) -> io::Result<Vec<JobSpec>> {
+    let invalid = |line: &str| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("Invalid line in job spec file {}: {}", path, line))
+    };
+    let mut specs: Vec<JobSpec> = Vec::new();
+
+    for line in io::BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields[0] == "job" && fields.len() == 2 {
+            specs.push(JobSpec::new(fields[1], "", ""));
+            continue;
+        }
+        let spec = match specs.last_mut() {
+            None => return Err(invalid(&line)),
+            Some(spec) => spec,
+        };
+        match (fields[0], fields.len()) {
+            ("mapper", 2) => spec.mapper = String::from(fields[1]),
+            ("reducer", 2) => spec.reducer = String::from(fields[1]),
+            ("input", 2) => spec.inputs.push(String::from(fields[1])),
+            ("output", 2) => spec.output_prefix = String::from(fields[1]),
+            ("intermediates", 2) => spec.map_location = String::from(fields[1]),
+            ("concurrency", 3) => {
+                spec.mappers = fields[1].parse().map_err(|_| invalid(&line))?;
+                spec.reducers = fields[2].parse().map_err(|_| invalid(&line))?;
+            }
+            ("config", 3) => spec.config.push((String::from(fields[1]), String::from(fields[2]))),
+            _ => return Err(invalid(&line)),
+        }
+    }
+    if let Some(spec) = specs.iter().find(|s| s.mapper.is_empty() || s.reducer.is_empty()) {
+        return Err(invalid(&format!("job {} lacks a mapper or reducer", spec.name)));
+    }
+    Ok(specs)
+}
+
+/// The state of a job in a `JobQueue`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    fn name(&self) -> &'static str {
+        match *self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+/// The status of a job in a `JobQueue`, as kept in the status file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobStatus {
+    pub state: JobState,
+    /// The statistics of a successful job.
+    pub map_input_records: usize,
+    pub reduce_input_records: usize,
+    /// Why the job failed.
+    pub error: Option<String>,
+}
+
+impl JobStatus {
+    fn new(state: JobState) -> JobStatus {
+        JobStatus {
+            state,
+            map_input_records: 0,
+            reduce_input_records: 0,
+            error: None,
+        }
+    }
+}
+
+/// Reads a status file written by a `JobQueue` (see `JobQueue::set_status_file()`).
+pub fn load_status(path: &String) -> io::Result<BTreeMap<String, JobStatus>> {
+    let invalid = |line: &str| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("Invalid line in job status file {}: {}", path, line))
+    };
+    let mut jobs = BTreeMap::new();
+    for line in io::BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return Err(invalid(&line));
+        }
+        let state = match fields[1] {
+            "queued" => JobState::Queued,
+            "running" => JobState::Running,
+            "succeeded" => JobState::Succeeded,
+            "failed" => JobState::Failed,
+            _ => return Err(invalid(&line)),
+        };
+        let status = JobStatus {
+            state,
+            map_input_records: fields[2].parse().map_err(|_| invalid(&line))?,
+            reduce_input_records: fields[3].parse().map_err(|_| invalid(&line))?,
+            error: if fields[4].is_empty() {
+                None
+            } else {
+                Some(String::from(fields[4]))
+            },
+        };
+        jobs.insert(String::from(fields[0]), status);
+    }
+    Ok(jobs)
+}
+
+/// Runs batch jobs; see the module documentation.
+pub struct JobQueue {
+    mappers: BTreeMap<String, MapperF>,
+    reducers: BTreeMap<String, ReducerF>,
+    parallelism: usize,
+    status_file: Option<String>,
+    executor: Option<(ExecutorHandle, usize)>,
+    // Jobs submitted and not yet started.
+    queued: Mutex<Vec<JobSpec>>,
+    status: Mutex<BTreeMap<String, JobStatus>>,
+}
+
+impl Default for JobQueue {
+    fn default() -> JobQueue {
+        JobQueue::new()
+    }
+}
+
+impl JobQueue {
+    pub fn new() -> JobQueue {
+        JobQueue {
+            mappers: BTreeMap::new(),
+            reducers: BTreeMap::new(),
+            parallelism: 1,
+            status_file: None,
+            executor: None,
+            queued: Mutex::new(Vec::new()),
+            status: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// How many jobs may run at the same time.
+    ///
+    /// Default: 1 (jobs run one after another, in the order they were submitted)
+    pub fn set_parallelism(mut self, n: usize) -> JobQueue {
+        self.parallelism = n.max(1);
+        self
+    }
+
+    /// Writes the status of all jobs to `path` whenever it changes. The file is replaced
+    /// atomically, so that it can be read (with `load_status()`) at any time.
+    ///
+    /// Default: None (the status is only available from `status()`)
+    pub fn set_status_file(mut self, path: &str) -> JobQueue {
+        self.status_file = Some(String::from(path));
+        self
+    }
+
+    /// Runs all jobs on `executor`, with at most `max_concurrency` partitions per job (see
+    /// `MRParameters::set_executor()`).
+    ///
+    /// Default: None (every job starts its own threads)
+    pub fn set_executor(mut self, executor: ExecutorHandle, max_concurrency: usize) -> JobQueue {
+        self.executor = Some((executor, max_concurrency));
+        self
+    }
+
+    /// Registers a mapper under `name`, to be referred to by job specs.
+    pub fn register_mapper(&mut self, name: &str, mapper: MapperF) {
+        self.mappers.insert(String::from(name), mapper);
+    }
+
+    /// Registers a reducer under `name`, to be referred to by job specs.
+    pub fn register_reducer(&mut self, name: &str, reducer: ReducerF) {
+        self.reducers.insert(String::from(name), reducer);
+    }
+
+    /// Queues a job. Returns an error if a job of the same name has been submitted before, or if
+    /// its mapper or reducer isn't registered.
+    pub fn submit(&self, spec: JobSpec) -> io::Result<()> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidInput, what);
+        if !self.mappers.contains_key(&spec.mapper) {
+            return Err(invalid(format!("Job {}: Unknown mapper {}", spec.name, spec.mapper)));
+        }
+        if !self.reducers.contains_key(&spec.reducer) {
+            return Err(invalid(format!("Job {}: Unknown reducer {}", spec.name, spec.reducer)));
+        }
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.contains_key(&spec.name) {
+                return Err(invalid(format!("Duplicate job name {}", spec.name)));
+            }
+            status.insert(spec.name.clone(), JobStatus::new(JobState::Queued));
+            self.save_status(&status)?;
+        }
+        self.queued.lock().unwrap().push(spec);
+        Ok(())
+    }
+
+    /// Queues all jobs of the spec file `path`; returns how many there were. If one of them
+    /// can't be submitted, the jobs before it remain queued.
+    pub fn submit_file(&self, path: &String) -> io::Result<usize> {
+        let specs = load_specs(path)?;
+        let n = specs.len();
+        for spec in specs {
+            self.submit(spec)?;
+        }
+        Ok(n)
+    }
+
+    /// Returns the status of the job `name`, if it has been submitted.
+    pub fn status(&self, name: &str) -> Option<JobStatus> {
+        self.status.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns the status of all jobs by name.
+    pub fn statuses(&self) -> BTreeMap<String, JobStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Returns the names of the jobs in state `state`.
+    pub fn jobs_in_state(&self, state: JobState) -> Vec<String> {
+        self.status
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, s)| s.state == state)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Runs the queued jobs, starting them in the order they were submitted. A failing job
+    /// (returning an error or panicking) doesn't stop the other jobs; if any job failed, an error
+    /// naming the failed jobs is returned once all jobs have finished. Jobs submitted while the
+    /// queue runs are run as well.
+    pub fn run(&self) -> io::Result<()> {
+        let mut pool = Pool::new(self.parallelism as u32);
+        loop {
+            // Jobs may have been submitted while the previous ones ran.
+            let specs: Vec<JobSpec> = self.queued.lock().unwrap().drain(..).collect();
+            if specs.is_empty() {
+                break;
+            }
+            pool.scoped(|scope| {
+                for spec in specs {
+                    scope.execute(move || self.run_job(spec));
+                }
+            });
+        }
+
+        let failed = self.jobs_in_state(JobState::Failed);
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("Failed jobs: {}", failed.join(", "))))
+        }
+    }
+
+    fn run_job(&self, spec: JobSpec) {
+        self.update(&spec.name, JobStatus::new(JobState::Running));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run_mapreduce(&spec)));
+        let status = match result {
+            Ok(Ok(stats)) => {
+                JobStatus {
+                    state: JobState::Succeeded,
+                    map_input_records: stats.map_input_records,
+                    reduce_input_records: stats.reduce_input_records,
+                    error: None,
+                }
+            }
+            Ok(Err(e)) => failed(e.to_string()),
+            Err(cause) => {
+                let message = cause.downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| cause.downcast_ref::<&str>().map(|s| String::from(*s)))
+                    .unwrap_or_else(|| String::from("unknown cause"));
+                failed(format!("panicked: {}", message))
+            }
+        };
+        self.update(&spec.name, status);
+    }
+
+    fn run_mapreduce(&self, spec: &JobSpec) -> io::Result<JobStats> {
+        let mr = ClosureMapReducer::new(self.mappers[&spec.mapper], self.reducers[&spec.reducer]);
+        let mut params = spec.params();
+        if let Some((ref executor, cap)) = self.executor {
+            params = params.set_executor(executor.clone(), cap);
+        }
+        let mut inputs = Vec::with_capacity(spec.inputs.len());
+        for input in spec.inputs.iter() {
+            let reader = PosRecordIterator::new(lines::new_from_file(input)?.for_job(&params));
+            inputs.push(params.input_stats.collect(reader));
+        }
+        Ok(MRController::run_splits(mr.clone(),
+                                    mr,
+                                    DefaultSharder,
+                                    params,
+                                    inputs,
+                                    LinesSinkGenerator::new_to_files())
+            .stats)
+    }
+
+    fn update(&self, name: &str, job: JobStatus) {
+        let mut status = self.status.lock().unwrap();
+        status.insert(String::from(name), job);
+        if let Err(e) = self.save_status(&status) {
+            println!("WARN: Couldn't write job status file: {}", e);
+        }
+    }
+
+    fn save_status(&self, status: &BTreeMap<String, JobStatus>) -> io::Result<()> {
+        let path = match self.status_file {
+            None => return Ok(()),
+            Some(ref path) => path,
+        };
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+            for (name, job) in status.iter() {
+                let error = job.error.as_ref().map(|e| e.replace(['\t', '\n'], " "));
+                writeln!(f,
+                         "{}\t{}\t{}\t{}\t{}",
+                         name,
+                         job.state.name(),
+                         job.map_input_records,
+                         job.reduce_input_records,
+                         error.unwrap_or_default())?;
+            }
+            f.flush()?;
+        }
+        fs::rename(tmp, path)
+    }
+}
+
+fn failed(error: String) -> JobStatus {
+    JobStatus {
+        error: Some(error),
+        ..JobStatus::new(JobState::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobQueue, JobSpec, JobState, load_specs, load_status};
+    use formats::lines;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let min: usize = e.config().get_or("min_count", 1);
+        let key = recs.key().clone();
+        let count = recs.into_iter().count();
+        if count >= min {
+            e.emit(format!("{} {}", key, count));
+        }
+    }
+
+    fn read_outputs(prefix: &str, reducers: usize) -> Vec<String> {
+        let mut results = Vec::new();
+        for i in 0..reducers {
+            let path = format!("{}{}", prefix, i);
+            results.extend(lines::new_from_file(&path).unwrap());
+            let _ = fs::remove_file(path);
+        }
+        results.sort();
+        results
+    }
+
+    #[test]
+    fn test_job_queue() {
+        let input = "testdata/jobq_input.txt";
+        fs::write(input, "a b a\nc a b\n").unwrap();
+        let spec_file = String::from("testdata/jobq_specs");
+        fs::write(&spec_file,
+                  format!("# nightly\njob\tfrequent\nmapper\twords\nreducer\tcount\n\
+                           input\t{}\noutput\ttestdata/jobq_frequent_out_\n\
+                           intermediates\ttestdata/jobq_frequent_map_\nconcurrency\t2\t2\n\
+                           config\tmin_count\t2\n\njob\tmissing\nmapper\twords\n\
+                           reducer\tcount\ninput\ttestdata/jobq_missing\n",
+                          input))
+            .unwrap();
+
+        let specs = load_specs(&spec_file).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].config, vec![(String::from("min_count"), String::from("2"))]);
+
+        let status_file = String::from("testdata/jobq_status");
+        let mut queue = JobQueue::new().set_parallelism(2).set_status_file(&status_file);
+        queue.register_mapper("words", word_mapper);
+        queue.register_reducer("count", count_reducer);
+        queue.submit(JobSpec::new("all", "words", "count")
+                .add_input(input)
+                .set_concurrency(2, 1)
+                .set_file_locations("testdata/jobq_all_map_", "testdata/jobq_all_out_"))
+            .unwrap();
+        assert_eq!(queue.submit_file(&spec_file).unwrap(), 2);
+        assert!(queue.submit(JobSpec::new("all", "words", "count")).is_err());
+        assert!(queue.submit(JobSpec::new("other", "words", "sum")).is_err());
+        assert_eq!(queue.jobs_in_state(JobState::Queued).len(), 3);
+
+        assert!(queue.run().is_err());
+        assert_eq!(queue.status("all").unwrap().state, JobState::Succeeded);
+        assert_eq!(queue.status("all").unwrap().map_input_records, 2);
+        assert_eq!(queue.jobs_in_state(JobState::Failed), vec!["missing"]);
+        assert_eq!(load_status(&status_file).unwrap(), queue.statuses());
+
+        assert_eq!(read_outputs("testdata/jobq_all_out_", 1),
+                   vec!["a 3", "b 2", "c 1"]);
+        assert_eq!(read_outputs("testdata/jobq_frequent_out_", 2), vec!["a 3", "b 2"]);
+        for f in [input, spec_file.as_str(), status_file.as_str()] {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
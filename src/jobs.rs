@@ -0,0 +1,316 @@
+//! Ready-made jobs that use the machinery of the mapreduce implementation without requiring
+//! user-defined mappers or reducers.
+
+use formats::output::{OutputFormat, read_shard};
+use formats::writelog::{FilteredRecordReader, WriteLogReader, WriteLogWriter,
+                        encode_version_marker};
+use incremental::remove_intermediates;
+use mapreducer::Sharder;
+use parameters::MRParameters;
+use phases::output::{discover_map_partitions, load_key_dictionary, map_output_name};
+use priority;
+use record_types::Record;
+use shard_merge::ShardMergeIterator;
+
+pub use sort::{Comparer, dict_string_compare};
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::mpsc::channel;
+
+extern crate scoped_threadpool;
+use self::scoped_threadpool::Pool;
+
+/// A string ordered by a Comparer, for merging sorted runs.
+#[derive(Clone)]
+struct Ordered {
+    s: String,
+    cmp: Comparer<String>,
+}
+
+impl PartialEq for Ordered {
+    fn eq(&self, other: &Ordered) -> bool {
+        (self.cmp)(&self.s, &other.s) == Ordering::Equal
+    }
+}
+
+impl Eq for Ordered {}
+
+impl PartialOrd for Ordered {
+    fn partial_cmp(&self, other: &Ordered) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ordered {
+    fn cmp(&self, other: &Ordered) -> Ordering {
+        (self.cmp)(&self.s, &other.s)
+    }
+}
+
+fn run_name(params: &MRParameters, run: usize) -> String {
+    format!("{}sort-{}", params.map_output_location, run)
+}
+
+/// Sorts the lines of `input` by `comparer` (e.g. `dict_string_compare`) and writes them to the
+/// file `output`, one per line. Returns the number of lines written.
+///
+/// The input is cut into chunks of approximately `params.map_partition_size` bytes, which are
+/// sorted in parallel by `params.mappers` threads and spilled to sorted runs at
+/// `params.map_output_location`. The runs are then merged into the output. The sort is stable.
+pub fn external_sort<In: Iterator<Item = String>>(input: In,
+                                                  output: &String,
+                                                  comparer: Comparer<String>,
+                                                  params: &MRParameters)
+                                                  -> io::Result<usize> {
+    let mut pool = Pool::new(params.mappers as u32);
+    let (send, recv) = channel();
+    let mut runs = 0;
+
+    pool.scoped(|scope| {
+        let mut input = input.peekable();
+        while input.peek().is_some() {
+            let mut chunk = Vec::new();
+            let mut size = 0;
+            for line in input.by_ref() {
+                size += line.len();
+                chunk.push(line);
+                if size >= params.map_partition_size {
+                    break;
+                }
+            }
+
+            let name = run_name(params, runs);
+            let done = send.clone();
+            scope.execute(move || {
+                priority::apply_niceness(params.map_niceness);
+                chunk.sort_by(comparer);
+                let result = WriteLogWriter::<fs::File>::new_to_file(&name, false)
+                    .and_then(|mut w| {
+                        // Every write() call writes one entry (also for empty lines, unlike
+                        // write_all()).
+                        for line in chunk {
+                            let _ = w.write(line.as_bytes())?;
+                        }
+                        w.flush()
+                    });
+                let _ = done.send(result);
+            });
+            runs += 1;
+        }
+    });
+    drop(send);
+
+    let result = recv.iter().collect::<io::Result<Vec<()>>>().and_then(|_| {
+        let mut readers = Vec::with_capacity(runs);
+        for run in 0..runs {
+            readers.push(WriteLogReader::new_from_file(&run_name(params, run))?
+                .map(move |s| Ordered { s, cmp: comparer }));
+        }
+
+        write_merged(readers, output)
+    });
+
+    if !params.keep_temp_files {
+        for run in 0..runs {
+            let _ = fs::remove_file(run_name(params, run));
+        }
+    }
+    result
+}
+
+/// Merges the files `inputs`, each of which must be sorted by `comparer`, into the text file
+/// `output`, one line per line (or WriteLog entry) of the inputs; lines comparing equal are
+/// written in the order of the inputs. Returns the number of lines written.
+///
+/// Inputs may be text files or WriteLogs (e.g. reduce outputs or sorted runs); the format of
+/// every input is detected from its contents. Only one line per input is held in memory.
+pub fn merge_sorted_files(inputs: &[String],
+                          output: &String,
+                          comparer: Comparer<String>)
+                          -> io::Result<usize> {
+    let mut readers = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        readers.push(read_shard(input, OutputFormat::Auto)?
+            .map(move |s| Ordered { s, cmp: comparer }));
+    }
+    write_merged(readers, output)
+}
+
+/// Redistributes the intermediate files that a run with `reducers` reducers has kept at
+/// `location` (see `MRParameters::keep_temp_files()`) to the `params.reducers` shards of a job
+/// with `params` and `sharder`, so that the job can be reduced without re-running the map phase
+/// when only the number of reducers has changed (e.g. with `MRController::run_reduce_only()`).
+/// Returns the number of map partitions.
+///
+/// The shard files of every map partition are merged and split into the new shards, which are
+/// therefore sorted as well; `params.mappers` partitions are resharded at a time. The new files
+/// are written to `params.map_output_location`; if that is `location`, they replace the old
+/// files. They are written without index, Bloom filter or key dictionary sidecars, and their
+/// values are converted to `params.intermediate_value_version`. Multiplexed intermediate files
+/// (see `MRParameters::set_multiplexed_intermediates()`) can't be resharded.
+pub fn reshard_intermediates<S: Sharder>(location: &String,
+                                         reducers: usize,
+                                         sharder: S,
+                                         params: &MRParameters)
+                                         -> io::Result<usize> {
+    let partitions = discover_map_partitions(location, reducers)?;
+    let target = &params.map_output_location;
+    // The new files are written next to the old ones first, so that a failure leaves the old
+    // files intact.
+    let resharded = |part: usize, shard: usize| -> String {
+        format!("{}.reshard", map_output_name(target, part, shard))
+    };
+
+    let mut pool = Pool::new(params.mappers as u32);
+    let (send, recv) = channel();
+    pool.scoped(|scope| {
+        for part in 0..partitions {
+            let done = send.clone();
+            let mut sharder = sharder.clone();
+            scope.execute(move || {
+                priority::apply_niceness(params.map_niceness);
+                let outputs = (0..params.reducers).map(|shard| resharded(part, shard)).collect();
+                let result = reshard_partition(location, reducers, part, params, outputs, |key| {
+                    sharder.shard_bytes(params.reducers, key.as_bytes())
+                });
+                let _ = done.send(result);
+            });
+        }
+    });
+    drop(send);
+
+    let result = recv.iter().collect::<io::Result<Vec<()>>>();
+    if let Err(e) = result {
+        for part in 0..partitions {
+            for shard in 0..params.reducers {
+                let _ = fs::remove_file(resharded(part, shard));
+            }
+        }
+        return Err(e);
+    }
+    if location == target {
+        remove_intermediates(location, partitions, reducers);
+    } else {
+        // Sidecars of earlier files at the target would be taken for those of the new files.
+        remove_intermediates(target, partitions, params.reducers);
+    }
+    for part in 0..partitions {
+        for shard in 0..params.reducers {
+            fs::rename(resharded(part, shard), map_output_name(target, part, shard))?;
+        }
+    }
+    Ok(partitions)
+}
+
+/// Merges the `reducers` shard files of the map partition `part` at `location` and writes every
+/// record to the file in `outputs` that `shard_of` returns for its key.
+fn reshard_partition<F: FnMut(&String) -> usize>(location: &String,
+                                                 reducers: usize,
+                                                 part: usize,
+                                                 params: &MRParameters,
+                                                 outputs: Vec<String>,
+                                                 mut shard_of: F)
+                                                 -> io::Result<()> {
+    let mut inputs = Vec::with_capacity(reducers);
+    for shard in 0..reducers {
+        let name = map_output_name(location, part, shard);
+        let reader = WriteLogReader::new_from_file(&name)?
+            .set_recover(params.recover_intermediates)
+            .set_io_retry(params.io_retry);
+        inputs.push(FilteredRecordReader::new(reader, None)
+            .set_key_dictionary(load_key_dictionary(&name))
+            .set_key_only(params.key_only)
+            .set_value_decoders(params.intermediate_value_version, params.value_decoders.clone()));
+    }
+
+    // Every shard gets a file, even if no record is sharded to it.
+    let mut writers = Vec::with_capacity(outputs.len());
+    for name in outputs.iter() {
+        let mut writer = WriteLogWriter::<fs::File>::new_to_file(name, false)?
+            .set_durability(params.durability)?
+            .set_io_retry(params.io_retry);
+        if params.intermediate_value_version > 0 {
+            writer.write_all(&encode_version_marker(params.intermediate_value_version))?;
+        }
+        writers.push(writer);
+    }
+    let records: ShardMergeIterator<Record> = ShardMergeIterator::build(&mut inputs.into_iter());
+    for record in records {
+        let shard = shard_of(&record.key);
+        writers[shard].write_record(record.key.as_bytes(), record.value.as_bytes())?;
+    }
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn write_merged<It: Iterator<Item = Ordered>>(readers: Vec<It>,
+                                              output: &String)
+                                              -> io::Result<usize> {
+    let mut out = io::BufWriter::new(fs::File::create(output)?);
+    let mut lines = 0;
+    for line in ShardMergeIterator::build(&mut readers.into_iter()) {
+        out.write_all(line.s.as_bytes())?;
+        out.write_all(b"\n")?;
+        lines += 1;
+    }
+    out.flush()?;
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dict_string_compare, external_sort, merge_sorted_files};
+    use formats::lines;
+    use formats::writelog::WriteLogWriter;
+    use std::io::Write;
+    use parameters::MRParameters;
+    use std::fs;
+
+    #[test]
+    fn test_external_sort() {
+        let mut input: Vec<String> =
+            (0..1000).map(|i| format!("line {}", (i * 7919) % 1000)).collect();
+        input.push(String::new());
+        let mut expected = input.clone();
+        expected.sort_by(dict_string_compare);
+
+        let output = String::from("testdata/external_sort_out");
+        let params = MRParameters::new()
+            .set_concurrency(3, 1)
+            .set_partition_size(1000)
+            .set_file_locations(String::from("testdata/external_sort_"), String::from(""));
+        let lines_written = external_sort(input.into_iter(), &output, dict_string_compare, &params)
+            .unwrap();
+        assert_eq!(lines_written, 1001);
+
+        let result: Vec<String> = lines::new_from_file(&output).unwrap().collect();
+        assert_eq!(result, expected);
+        assert!(fs::metadata("testdata/external_sort_sort-0").is_err());
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn test_merge_sorted_files() {
+        let text = String::from("testdata/merge_text");
+        fs::write(&text, "b\nD\nf\n").unwrap();
+        let wlg = String::from("testdata/merge_wlg");
+        {
+            let mut w = WriteLogWriter::<fs::File>::new_to_file(&wlg, false).unwrap();
+            for line in &["a", "c", "e", "g"] {
+                let _ = w.write(line.as_bytes()).unwrap();
+            }
+        }
+        let output = String::from("testdata/merge_out");
+        let inputs = vec![text.clone(), wlg.clone()];
+        assert_eq!(merge_sorted_files(&inputs, &output, dict_string_compare).unwrap(), 7);
+        let result: Vec<String> = lines::new_from_file(&output).unwrap().collect();
+        assert_eq!(result, vec!["a", "b", "c", "D", "e", "f", "g"]);
+        for f in &[text, wlg, output] {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
@@ -2,17 +2,46 @@
 //! this is supposed to result in better data parallelization.
 //!
 
+pub mod aggregate;
+pub mod catalog;
 pub mod closure_mr;
+pub mod codec;
+pub mod cogroup;
 pub mod controller;
+pub mod dag;
+pub mod dataset;
+pub mod executor;
+pub mod fold;
 pub mod formats;
+pub mod header;
+pub mod histogram;
+pub mod incremental;
 pub mod input_cache;
+pub mod job_queue;
+pub mod jobs;
+pub mod malformed;
 pub mod mapreducer;
+pub mod memory;
+pub mod metrics;
+pub mod object_store;
 pub mod parameters;
+pub mod priority;
 pub mod record_types;
+pub mod reducer_state;
+pub mod sampling;
+pub mod stats;
+pub mod streaming;
+pub mod termination;
+pub mod testing;
+pub mod time_window;
+pub mod verify;
+pub mod warm_start;
 
+mod arena;
 mod phases;
 mod shard_merge;
 mod sort;
+mod trace;
 
 #[test]
 fn it_works() {}
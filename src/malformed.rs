@@ -0,0 +1,114 @@
+//! Handling of malformed records, i.e. input records that can't be decoded, and records that
+//! user code rejects (see `MEmitter::reject()` and `REmitter::reject()`). What happens with them
+//! is determined by a job-wide `MalformedPolicy`, set with `MRParameters::set_malformed_policy()`.
+
+use formats::writelog::WriteLogWriter;
+
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What to do with malformed records.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MalformedPolicy {
+    /// Drop the record; it is counted in `JobStats::records_malformed`.
+    Skip,
+    /// Fail the job (by panicking).
+    Fail,
+    /// Like Skip, but additionally write the original record and the reason to the dead-letter
+    /// file at the given path. The file is a WriteLog containing two entries per record: the
+    /// original bytes and the reason.
+    DeadLetter(String),
+}
+
+/// Applies a MalformedPolicy. Clones share the counter and the dead-letter file, so that a
+/// handler can be passed to input iterators and used by all partitions of a job.
+#[derive(Clone)]
+pub struct MalformedHandler {
+    policy: MalformedPolicy,
+    count: Arc<AtomicUsize>,
+    // Opened when the first record is written.
+    dead_letter: Arc<Mutex<Option<WriteLogWriter<fs::File>>>>,
+}
+
+impl MalformedHandler {
+    pub fn new(policy: MalformedPolicy) -> MalformedHandler {
+        MalformedHandler {
+            policy,
+            count: Arc::new(AtomicUsize::new(0)),
+            dead_letter: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn policy(&self) -> &MalformedPolicy {
+        &self.policy
+    }
+
+    /// Handles a malformed record with the original contents `original`, which was rejected for
+    /// `reason`.
+    pub fn handle(&self, original: &[u8], reason: &str) {
+        let path = match self.policy {
+            MalformedPolicy::Skip => None,
+            MalformedPolicy::Fail => {
+                panic!("Malformed record ({}): {}",
+                       reason,
+                       String::from_utf8_lossy(original))
+            }
+            MalformedPolicy::DeadLetter(ref path) => Some(path),
+        };
+        self.count.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(path) = path {
+            let mut dead_letter = self.dead_letter.lock().unwrap();
+            if dead_letter.is_none() {
+                match WriteLogWriter::<fs::File>::new_to_file(path, false) {
+                    Err(e) => panic!("Could not open dead-letter file {}: {}", path, e),
+                    Ok(w) => *dead_letter = Some(w),
+                }
+            }
+            if let Some(ref mut w) = *dead_letter {
+                let result = w.write(original)
+                    .and_then(|_| w.write(reason.as_bytes()))
+                    .and_then(|_| w.flush());
+                if let Err(e) = result {
+                    panic!("Could not write to dead-letter file {}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    /// Returns how many malformed records have been handled.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MalformedHandler, MalformedPolicy};
+    use formats::writelog::WriteLogReader;
+    use std::fs;
+
+    #[test]
+    fn test_dead_letter() {
+        let path = String::from("testdata/malformed_dead_letter");
+        let _ = fs::remove_file(&path);
+        let handler = MalformedHandler::new(MalformedPolicy::DeadLetter(path.clone()));
+        handler.handle(b"abc\xff", "invalid UTF-8");
+        handler.clone().handle(b"def", "too short");
+        assert_eq!(handler.count(), 2);
+
+        let mut reader = WriteLogReader::new_from_file(&path).unwrap();
+        assert_eq!(reader.read_vec().unwrap(), b"abc\xff".to_vec());
+        assert_eq!(reader.collect::<Vec<String>>(),
+                   vec!["invalid UTF-8", "def", "too short"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fail() {
+        MalformedHandler::new(MalformedPolicy::Fail).handle(b"abc", "bad");
+    }
+}
@@ -1,14 +1,30 @@
 //! The MapReducer trait and associated types.
 
 use record_types::{REmitter, MEmitter, Record, MultiRecord};
+use parameters::MRParameters;
+use sort::dict_string_compare;
 
 use std::clone::Clone;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::hash::{Hasher, SipHasher};
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex};
 
 /// Default sharding function.
+///
+/// Note that the SipHasher keys and algorithm are not guaranteed to be stable across Rust
+/// versions; use `StableSharder` if shard assignments must be reproducible between builds.
 pub fn _std_shard(n: usize, key: &String) -> usize {
+    _std_shard_bytes(n, key.as_bytes())
+}
+
+/// Default sharding function for raw keys; `_std_shard()` of a key is `_std_shard_bytes()` of its
+/// UTF-8 bytes.
+pub fn _std_shard_bytes(n: usize, key: &[u8]) -> usize {
     let mut h = SipHasher::new();
-    h.write(key.as_bytes());
+    h.write(key);
     h.finish() as usize % n
 }
 
@@ -22,6 +38,27 @@ pub type ReducerF = fn(&mut REmitter, MultiRecord);
 /// The first argument is the number of shards, the second one the key;
 /// the return value should be in [0; n).
 pub type SharderF = fn(usize, &String) -> usize;
+/// Like `SharderF`, but determining the shard from the raw bytes of the key (see `ByteSharder`).
+pub type ByteSharderF = fn(usize, &[u8]) -> usize;
+/// A predicate applied to intermediate records while they are merged in the reduce phase.
+/// Records for which it returns false are dropped before reaching the reducer.
+pub type FilterF = fn(&Record) -> bool;
+/// Converts an intermediate value written with an older value version to the current encoding
+/// (see `MRParameters::add_value_decoder()`).
+pub type ValueDecoderF = fn(String) -> String;
+/// Serializes a result emitted by the reducer for the key given as first argument, appending the
+/// bytes to be written to the output to the buffer (see `MRParameters::set_output_formatter()`).
+pub type OutputFormatterF = fn(&str, &str, &mut Vec<u8>);
+/// Returns the partition label of a record written by the reducer, e.g. `date=2024-05-01` (see
+/// `MRParameters::set_output_partitioning()`).
+pub type OutputPartitionF = fn(&str) -> String;
+/// Returns the route of a key reduced by the reducer, e.g. `2024-05-01` for the key
+/// `2024-05-01T10:00 /index.html`, or an empty string if the key has no route (see
+/// `MRParameters::set_output_routing()`).
+pub type OutputRouteF = fn(&str) -> String;
+/// Parses a line of a previous reduce output into its key and value, or returns None if it
+/// can't (see `MRParameters::set_warm_start()`).
+pub type OutputParseF = fn(&str) -> Option<(String, String)>;
 
 pub trait Mapper: Send + Clone {
     /// Takes one <key,value> pair and an emitter.
@@ -30,6 +67,13 @@ pub trait Mapper: Send + Clone {
     /// Note that this method takes a &mut self; you can use this to cache expensive objects
     /// between runs (but not between shards!)
     fn map(&mut self, em: &mut MEmitter, record: Record);
+
+    /// Called once after all records of a map partition have been passed to map(). Mappers that
+    /// buffer their input can emit the remaining results here.
+    /// The default implementation does nothing.
+    fn finish(&mut self, em: &mut MEmitter) {
+        let _ = em;
+    }
 }
 
 pub trait Reducer: Send + Clone {
@@ -39,6 +83,21 @@ pub trait Reducer: Send + Clone {
     /// Note that this method takes a &mut self; you can use this to cache expensive objects
     /// between runs (but not between shards!)
     fn reduce(&mut self, em: &mut REmitter, records: MultiRecord);
+
+    /// Declares that the reducer is associative: reducing the results of reducing parts of a
+    /// group (in order) yields the same as reducing the whole group. Large groups of associative
+    /// reducers can be reduced in chunks (see `MRParameters::set_reduce_chunk_values()`).
+    /// The default implementation returns false.
+    fn associative(&self) -> bool {
+        false
+    }
+
+    /// Called once after all groups of a reduce partition have been passed to reduce(). Reducers
+    /// that buffer their input can emit the remaining results here.
+    /// The default implementation does nothing.
+    fn finish(&mut self, em: &mut REmitter) {
+        let _ = em;
+    }
 }
 
 pub trait Sharder: Send + Clone {
@@ -48,6 +107,369 @@ pub trait Sharder: Send + Clone {
     fn shard(&mut self, n: usize, key: &String) -> usize {
         _std_shard(n, key)
     }
+
+    /// Like `shard()`, but takes the raw bytes of the key, so that keys need not be copied into
+    /// a String (or converted to UTF-8) to be sharded. The map phase shards its output with this
+    /// method; for UTF-8 keys, it must return the same shard as `shard()`.
+    /// The default implementation converts the key to a String (replacing invalid UTF-8) and calls
+    /// `shard()`; sharders hashing the bytes of the key should override it.
+    fn shard_bytes(&mut self, n: usize, key: &[u8]) -> usize {
+        self.shard(n, &String::from_utf8_lossy(key).into_owned())
+    }
+
+    /// If the sharder assigns contiguous key ranges to shards, returns the range [start; end)
+    /// (in dictionary order) of shard `shard` out of `n`; a bound of None means that the range is
+    /// open at that side. Reduce partitions use this to only read the relevant part of their
+    /// inputs.
+    /// The default implementation returns None, meaning that keys are not range-partitioned.
+    fn key_range(&self, n: usize, shard: usize) -> Option<(Option<String>, Option<String>)> {
+        let _ = (n, shard);
+        None
+    }
+
+    /// Returns a description of the partitioning that this sharder implements, if it assigns keys
+    /// to shards deterministically across runs and builds. Two sharders with equal descriptions
+    /// must assign every key to the same shard; this is used to decide whether existing
+    /// datasets need to be re-sharded (see `dataset::Dataset`).
+    /// The default implementation returns None (unknown partitioning).
+    fn partitioning(&self) -> Option<String> {
+        None
+    }
 }
 
+/// A Sharder using the default implementation (`_std_shard`).
+#[derive(Clone)]
 pub struct DefaultSharder;
+
+impl Sharder for DefaultSharder {
+    fn shard_bytes(&mut self, n: usize, key: &[u8]) -> usize {
+        _std_shard_bytes(n, key)
+    }
+}
+
+/// A Sharder calling a function on the raw bytes of every key (see `Sharder::shard_bytes()`),
+/// e.g. for binary keys that aren't valid UTF-8.
+#[derive(Clone)]
+pub struct ByteSharder {
+    f: ByteSharderF,
+}
+
+impl ByteSharder {
+    pub fn new(f: ByteSharderF) -> ByteSharder {
+        ByteSharder { f }
+    }
+}
+
+impl Sharder for ByteSharder {
+    fn shard(&mut self, n: usize, key: &String) -> usize {
+        (self.f)(n, key.as_bytes())
+    }
+
+    fn shard_bytes(&mut self, n: usize, key: &[u8]) -> usize {
+        (self.f)(n, key)
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Calculates the 64 bit FNV-1a hash of `data`, after hashing the little-endian bytes of `seed`.
+pub fn fnv1a_seeded(seed: u64, data: &[u8]) -> u64 {
+    let mut h = FNV_OFFSET_BASIS;
+    for b in seed.to_le_bytes().iter().chain(data.iter()) {
+        h ^= *b as u64;
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    h
+}
+
+/// A Sharder using a seeded FNV-1a hash of the key.
+///
+/// Stability guarantee: The shard of a key depends only on the key bytes, the seed and the
+/// number of shards; it does not change between runs, Rust versions, platforms or versions of
+/// this library (the test in this module pins the shards of a few keys). Use this sharder
+/// whenever intermediate files from different runs are combined (resumed or incremental jobs),
+/// when outputs of several runs are backfilled into the same layout, or when other systems
+/// need to find the output shard of a key (see `shard_for_key()`).
+#[derive(Clone)]
+pub struct StableSharder {
+    seed: u64,
+}
+
+impl StableSharder {
+    pub fn new(seed: u64) -> StableSharder {
+        StableSharder { seed }
+    }
+
+    /// Uses the seed set with `MRParameters::set_shard_seed()`.
+    pub fn from_params(params: &MRParameters) -> StableSharder {
+        StableSharder::new(params.shard_seed)
+    }
+}
+
+impl Sharder for StableSharder {
+    fn shard(&mut self, n: usize, key: &String) -> usize {
+        self.shard_bytes(n, key.as_bytes())
+    }
+
+    fn shard_bytes(&mut self, n: usize, key: &[u8]) -> usize {
+        (fnv1a_seeded(self.seed, key) % n as u64) as usize
+    }
+
+    fn partitioning(&self) -> Option<String> {
+        Some(format!("fnv1a:{}", self.seed))
+    }
+}
+
+/// Returns the reduce shard that a job with `params` assigns `key` to when it uses
+/// `StableSharder::from_params()`, i.e. the shard whose output file
+/// (`<reduce_output_shard_prefix><shard>`) contains the results for `key`. The result is stable
+/// as described for `StableSharder`.
+pub fn shard_for_key(params: &MRParameters, key: &str) -> usize {
+    (fnv1a_seeded(params.shard_seed, key.as_bytes()) % params.reducers as u64) as usize
+}
+
+/// A Sharder implementing range partitioning: The keys are assigned to shards according to a
+/// sorted list of split points (in dictionary order), so that the outputs of all shards
+/// concatenated are in total order. For n shards, n-1 split points should be given; shard i
+/// receives the keys in [splits[i-1]; splits[i]).
+#[derive(Clone)]
+pub struct RangeSharder {
+    splits: Vec<String>,
+}
+
+impl RangeSharder {
+    pub fn new(splits: Vec<String>) -> RangeSharder {
+        RangeSharder { splits }
+    }
+}
+
+impl Sharder for RangeSharder {
+    fn shard(&mut self, n: usize, key: &String) -> usize {
+        let shard = self.splits
+            .iter()
+            .take_while(|s| dict_string_compare(s, key) != Ordering::Greater)
+            .count();
+        if shard >= n { n - 1 } else { shard }
+    }
+
+    fn key_range(&self, n: usize, shard: usize) -> Option<(Option<String>, Option<String>)> {
+        let start = if shard == 0 {
+            None
+        } else {
+            self.splits.get(shard - 1).cloned()
+        };
+        let end = if shard + 1 >= n {
+            None
+        } else {
+            self.splits.get(shard).cloned()
+        };
+        Some((start, end))
+    }
+
+    fn partitioning(&self) -> Option<String> {
+        Some(format!("range:{}", self.splits.join("\u{1f}")))
+    }
+}
+
+/// A Sharder assigning keys to shards round-robin, in the order in which they are first seen:
+/// The first key goes to shard 0, the second distinct key to shard 1, and so on. All clones
+/// share the assignments, so that a key always goes to the same shard. The assignment is only
+/// deterministic if the keys arrive in a deterministic order, i.e. with a single mapper; all
+/// distinct keys are kept in memory.
+#[derive(Clone, Default)]
+pub struct RoundRobinSharder {
+    assigned: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl RoundRobinSharder {
+    pub fn new() -> RoundRobinSharder {
+        RoundRobinSharder::default()
+    }
+}
+
+impl Sharder for RoundRobinSharder {
+    fn shard(&mut self, n: usize, key: &String) -> usize {
+        let mut assigned = self.assigned.lock().unwrap();
+        if let Some(&i) = assigned.get(key) {
+            return i % n;
+        }
+        let i = assigned.len();
+        assigned.insert(key.clone(), i);
+        i % n
+    }
+}
+
+/// A Sharder looking up the shard of every key in an explicit table, e.g. in order to place
+/// outputs in the shards that an external system expects. Keys missing from the table are
+/// assigned to the default shard if one is set (see `set_default_shard()`), and by
+/// `StableSharder::new(0)` otherwise. Looking up a shard beyond the number of shards of the job
+/// panics, as the layout can't be matched.
+#[derive(Clone)]
+pub struct LookupSharder {
+    table: Arc<HashMap<String, usize>>,
+    default: Option<usize>,
+}
+
+impl LookupSharder {
+    pub fn new(table: HashMap<String, usize>) -> LookupSharder {
+        LookupSharder {
+            table: Arc::new(table),
+            default: None,
+        }
+    }
+
+    /// Loads the table from a file with one line per key, consisting of the key and the shard
+    /// number separated by a tab character.
+    pub fn load(path: &String) -> io::Result<LookupSharder> {
+        let mut table = HashMap::new();
+        for line in io::BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            let shard = line.rsplit_once('\t').and_then(|(k, s)| s.parse().ok().map(|s| (k, s)));
+            match shard {
+                Some((key, shard)) => table.insert(String::from(key), shard),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Invalid line in shard table {}: {}",
+                                                      path,
+                                                      line)))
+                }
+            };
+        }
+        Ok(LookupSharder::new(table))
+    }
+
+    /// Assigns keys missing from the table to `shard`.
+    pub fn set_default_shard(mut self, shard: usize) -> LookupSharder {
+        self.default = Some(shard);
+        self
+    }
+}
+
+impl Sharder for LookupSharder {
+    fn shard(&mut self, n: usize, key: &String) -> usize {
+        let shard = match (self.table.get(key), self.default) {
+            (Some(&shard), _) | (None, Some(shard)) => shard,
+            (None, None) => return StableSharder::new(0).shard(n, key),
+        };
+        if shard >= n {
+            panic!("Shard table assigns key {} to shard {}, but there are only {} shards",
+                   key,
+                   shard,
+                   n);
+        }
+        shard
+    }
+
+    fn partitioning(&self) -> Option<String> {
+        let sorted: BTreeMap<&String, &usize> = self.table.iter().collect();
+        let mut h = fnv1a_seeded(0, &[]);
+        for (key, shard) in sorted {
+            h = fnv1a_seeded(h, format!("{}\t{}\n", key, shard).as_bytes());
+        }
+        Some(format!("lookup:{:016x}:{:?}", h, self.default))
+    }
+}
+
+/// A Mapper that emits every input record unchanged.
+#[derive(Clone)]
+pub struct IdentityMapper;
+
+impl Mapper for IdentityMapper {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        em.emit(record.key, record.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteSharder, DefaultSharder, LookupSharder, RangeSharder, RoundRobinSharder,
+                Sharder, StableSharder, fnv1a_seeded, shard_for_key};
+    use parameters::MRParameters;
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn test_stable_sharder() {
+        // These values must never change; see the stability guarantee of StableSharder.
+        assert_eq!(fnv1a_seeded(0, b"abc"), 0xab20dcdb6214056b);
+        assert!(fnv1a_seeded(0, b"abc") != fnv1a_seeded(1, b"abc"));
+
+        let mut s = StableSharder::new(42);
+        let key = String::from("some key");
+        let shard = s.shard(7, &key);
+        assert!(shard < 7);
+        assert_eq!(shard, StableSharder::new(42).shard(7, &key));
+
+        // Golden shards; like the hash above, they must never change.
+        assert_eq!(fnv1a_seeded(42, b"some key"), 0x6764ae01e3fe5720);
+        let params = MRParameters::new().set_concurrency(4, 7).set_shard_seed(42);
+        let mut sharder = StableSharder::from_params(&params);
+        let golden =
+            [("", 0), ("a", 1), ("some key", 5), ("\u{e4}\u{f6}\u{fc}", 5), ("2024-01-01", 0)];
+        for &(k, shard) in golden.iter() {
+            assert_eq!(shard_for_key(&params, k), shard);
+            assert_eq!(sharder.shard(7, &String::from(k)), shard);
+        }
+    }
+
+    #[test]
+    fn test_round_robin_sharder() {
+        let mut s = RoundRobinSharder::new();
+        let keys = ["a", "b", "c", "a", "d", "b"];
+        let shards: Vec<usize> = keys.iter().map(|k| s.shard(3, &String::from(*k))).collect();
+        assert_eq!(shards, vec![0, 1, 2, 0, 0, 1]);
+        // Clones share the assignments.
+        assert_eq!(s.clone().shard(3, &String::from("c")), 2);
+        assert_eq!(s.clone().shard(3, &String::from("e")), 1);
+        assert!(s.partitioning().is_none());
+    }
+
+    #[test]
+    fn test_lookup_sharder() {
+        let path = String::from("testdata/shard_table");
+        fs::write(&path, "us\t0\neu\t2\nkey\twith tab\t1\n").unwrap();
+        let mut s = LookupSharder::load(&path).unwrap();
+        assert_eq!(s.shard(3, &String::from("eu")), 2);
+        assert_eq!(s.shard(3, &String::from("key\twith tab")), 1);
+        let unknown = String::from("apac");
+        assert_eq!(s.shard(3, &unknown), StableSharder::new(0).shard(3, &unknown));
+        assert_eq!(s.clone().set_default_shard(1).shard(3, &unknown), 1);
+        assert_eq!(s.partitioning(), LookupSharder::load(&path).unwrap().partitioning());
+        assert!(s.partitioning() != s.clone().set_default_shard(1).partitioning());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| s.shard(2, &String::from("eu"))));
+        assert!(result.is_err());
+
+        fs::write(&path, "us 0\n").unwrap();
+        assert!(LookupSharder::load(&path).is_err());
+        let _ = fs::remove_file(path);
+    }
+
+    fn first_byte(n: usize, key: &[u8]) -> usize {
+        key.first().map_or(0, |&b| b as usize % n)
+    }
+
+    #[test]
+    fn test_shard_bytes() {
+        // For UTF-8 keys, sharding the bytes gives the same shard as sharding the String.
+        fn check<S: Sharder>(mut s: S) {
+            for k in ["", "a", "some key", "\u{e4}\u{f6}\u{fc}", "zz"].iter() {
+                let key = String::from(*k);
+                assert_eq!(s.shard_bytes(5, key.as_bytes()), s.shard(5, &key));
+            }
+        }
+        check(DefaultSharder);
+        check(StableSharder::new(42));
+        check(RangeSharder::new(vec![String::from("b"), String::from("t")]));
+        check(ByteSharder::new(first_byte));
+
+        // Keys that aren't UTF-8 can be sharded without conversion.
+        let mut s = ByteSharder::new(first_byte);
+        assert_eq!(s.shard_bytes(4, &[0xff, 0xfe]), 3);
+        assert!(StableSharder::new(0).shard_bytes(4, &[0xff, 0xfe]) < 4);
+        // The default implementation replaces invalid UTF-8.
+        let mut range = RangeSharder::new(vec![String::from("m")]);
+        assert_eq!(range.shard_bytes(2, &[b'a', 0xff]), 0);
+    }
+}
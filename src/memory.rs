@@ -0,0 +1,245 @@
+//! Strict memory accounting (see `MRParameters::set_memory_budget()`): The structures holding
+//! records in memory account for their size in a `MemoryAccount` of a `MemoryBudget` shared by
+//! the jobs it is attached to, so that a job running out of memory is reported by name instead
+//! of being killed by the OS.
+//!
+//! Sizes are counted at the record level (keys and values, arenas and vectors of records), not
+//! by the allocator, and are therefore estimates. Map partitions account for their input and the
+//! pairs emitted so far; in-memory shuffle buffers (see `MRParameters::set_in_memory_shuffle()`)
+//! for the runs they buffer.
+//!
+//! The budget is enforced cooperatively: When the budget is exceeded, shuffle buffers spill their
+//! runs to disk, and map partitions wait (in `MemoryAccount::wait()`) until other partitions have
+//! released their memory. The map partition holding the most memory keeps running, so that
+//! waiting partitions can't block each other; if it alone exceeds the budget, it panics.
+
+use metrics::MetricsRegistry;
+use parameters::MRParameters;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Default)]
+struct Accounts {
+    // Name, held bytes and whether the account waits for memory, by account id.
+    held: HashMap<u64, (String, usize, bool)>,
+    next_id: u64,
+    total: usize,
+    peak: usize,
+}
+
+struct Budget {
+    limit: usize,
+    accounts: Mutex<Accounts>,
+    released: Condvar,
+}
+
+/// A limit on the memory held by the partitions of the jobs it is attached to. Clones refer to
+/// the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    budget: Arc<Budget>,
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryBudget({} of {} bytes held)", self.held(), self.limit())
+    }
+}
+
+impl MemoryBudget {
+    /// Returns a budget of `limit` bytes.
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            budget: Arc::new(Budget {
+                limit,
+                accounts: Mutex::new(Accounts::default()),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.budget.limit
+    }
+
+    /// Returns the bytes currently held by all accounts.
+    pub fn held(&self) -> usize {
+        self.budget.accounts.lock().unwrap().total
+    }
+
+    /// Returns the most bytes held at the same time.
+    pub fn peak(&self) -> usize {
+        self.budget.accounts.lock().unwrap().peak
+    }
+
+    /// Returns the names of the accounts holding memory and their bytes, most first.
+    pub fn holders(&self) -> Vec<(String, usize)> {
+        let accounts = self.budget.accounts.lock().unwrap();
+        let mut holders: Vec<(String, usize)> = accounts.held
+            .values()
+            .filter(|&&(_, held, _)| held > 0)
+            .map(|&(ref name, held, _)| (name.clone(), held))
+            .collect();
+        holders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        holders
+    }
+
+    /// Opens an account named `name` (e.g. `map #3`), holding no memory. The held bytes are
+    /// reported to `metrics`, if given.
+    pub fn account(&self, name: String, metrics: Option<MetricsRegistry>) -> MemoryAccount {
+        let mut accounts = self.budget.accounts.lock().unwrap();
+        let id = accounts.next_id;
+        accounts.next_id += 1;
+        accounts.held.insert(id, (name, 0, false));
+        MemoryAccount {
+            budget: self.clone(),
+            id,
+            held: 0,
+            metrics,
+        }
+    }
+}
+
+/// The memory held by one partition; it is released when the account is dropped.
+pub struct MemoryAccount {
+    budget: MemoryBudget,
+    id: u64,
+    held: usize,
+    metrics: Option<MetricsRegistry>,
+}
+
+impl MemoryAccount {
+    /// Opens an account for a partition of the job with `params`, if the job has a memory
+    /// budget.
+    pub fn for_job(params: &MRParameters, name: String) -> Option<MemoryAccount> {
+        params.memory_budget.as_ref().map(|b| b.account(name, params.metrics.clone()))
+    }
+
+    /// Returns the bytes held by this account.
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Sets the bytes held by this account.
+    pub fn set(&mut self, bytes: usize) {
+        let (total, peak) = {
+            let mut accounts = self.budget.budget.accounts.lock().unwrap();
+            accounts.total = accounts.total - self.held + bytes;
+            accounts.peak = accounts.peak.max(accounts.total);
+            if let Some(entry) = accounts.held.get_mut(&self.id) {
+                entry.1 = bytes;
+            }
+            (accounts.total, accounts.peak)
+        };
+        if bytes < self.held {
+            self.budget.budget.released.notify_all();
+        }
+        self.held = bytes;
+        if let Some(ref metrics) = self.metrics {
+            metrics.set_memory_held(total, peak);
+        }
+    }
+
+    /// Returns whether the memory held by all accounts exceeds the budget.
+    pub fn over_budget(&self) -> bool {
+        self.budget.held() > self.budget.limit()
+    }
+
+    /// Blocks while the budget is exceeded and another waiting account holds more memory than
+    /// this one. Panics if this account alone exceeds the budget.
+    pub fn wait(&self) {
+        let budget = &self.budget.budget;
+        let mut accounts = budget.accounts.lock().unwrap();
+        if let Some(entry) = accounts.held.get_mut(&self.id) {
+            entry.2 = true;
+        }
+        loop {
+            if accounts.total <= budget.limit {
+                return;
+            }
+            if self.held > budget.limit {
+                let name = accounts.held[&self.id].0.clone();
+                // Released before panicking, so that dropping the account doesn't find the lock
+                // poisoned.
+                drop(accounts);
+                panic!("{} holds {} bytes, more than the memory budget of {} bytes",
+                       name,
+                       self.held,
+                       budget.limit);
+            }
+            let largest = accounts.held
+                .iter()
+                .filter(|&(_, &(_, _, waits))| waits)
+                .max_by_key(|&(&id, &(_, held, _))| (held, id))
+                .map(|(&id, _)| id);
+            if largest == Some(self.id) {
+                return;
+            }
+            accounts = budget.released.wait(accounts).unwrap();
+        }
+    }
+}
+
+impl Drop for MemoryAccount {
+    fn drop(&mut self) {
+        self.set(0);
+        self.budget.budget.accounts.lock().unwrap().held.remove(&self.id);
+        self.budget.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBudget;
+    use metrics::MetricsRegistry;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+        let registry = MetricsRegistry::new();
+        let mut large = budget.account(String::from("map #0"), Some(registry.clone()));
+        let mut small = budget.account(String::from("map #1"), None);
+        large.set(80);
+        small.set(10);
+        assert!(!small.over_budget());
+        small.wait();
+        large.set(95);
+        assert_eq!(budget.held(), 105);
+        assert_eq!(budget.holders(),
+                   vec![(String::from("map #0"), 95), (String::from("map #1"), 10)]);
+        assert!(registry.render().contains("\nlocalmr_memory_held_bytes 105\n"));
+
+        // The largest account keeps running, the smaller one waits until memory is released.
+        large.wait();
+        let (done, waited) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            small.wait();
+            done.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(waited.try_recv().is_err());
+        large.set(20);
+        waited.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+        assert_eq!(budget.held(), 20);
+        assert_eq!(budget.peak(), 105);
+
+        drop(large);
+        assert_eq!(budget.held(), 0);
+        assert!(budget.holders().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "map #0 holds 150 bytes, more than the memory budget of 100 bytes")]
+    fn test_memory_budget_exceeded() {
+        let budget = MemoryBudget::new(100);
+        let mut account = budget.account(String::from("map #0"), None);
+        account.set(150);
+        account.wait();
+    }
+}
@@ -0,0 +1,205 @@
+//! A small metrics registry for embedding localmr into services. A registry can be shared by
+//! several jobs (see `MRParameters::set_metrics()`); its contents are rendered in the Prometheus
+//! text exposition format by `MetricsRegistry::render()`, so the host service can expose them
+//! on its own metrics endpoint.
+
+use stats::JobStats;
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Metrics {
+    jobs_run: u64,
+    records: u64,
+    bytes: u64,
+    active_workers: u64,
+    map_queue_depth: u64,
+    memory_held: u64,
+    memory_peak: u64,
+    last_job_duration: f64,
+    last_job_records_per_second: f64,
+    last_job_bytes_per_second: f64,
+}
+
+/// Collects metrics of the jobs it is attached to. Cloning a registry yields a handle to the
+/// same metrics.
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> MetricsRegistry {
+        MetricsRegistry::default()
+    }
+
+    /// Called when a map or reduce worker starts running.
+    pub fn worker_started(&self) {
+        self.metrics.lock().unwrap().active_workers += 1;
+    }
+
+    /// Called when a map or reduce worker has finished.
+    pub fn worker_finished(&self) {
+        let mut m = self.metrics.lock().unwrap();
+        m.active_workers = m.active_workers.saturating_sub(1);
+    }
+
+    /// Counts a worker as running until the returned guard is dropped, also if the worker
+    /// panics.
+    pub fn active_worker(&self) -> ActiveWorker {
+        self.worker_started();
+        ActiveWorker { registry: self.clone() }
+    }
+
+    /// Called when the number of map partitions waiting to be processed changes.
+    pub fn set_map_queue_depth(&self, depth: usize) {
+        self.metrics.lock().unwrap().map_queue_depth = depth as u64;
+    }
+
+    /// Called when the memory held in a memory budget changes (see `memory::MemoryBudget`).
+    pub fn set_memory_held(&self, held: usize, peak: usize) {
+        let mut m = self.metrics.lock().unwrap();
+        m.memory_held = held as u64;
+        m.memory_peak = peak as u64;
+    }
+
+    /// Records a finished job that took `duration` to run.
+    pub fn record_job(&self, stats: &JobStats, duration: Duration) {
+        let secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        let mut m = self.metrics.lock().unwrap();
+
+        m.jobs_run += 1;
+        m.records += stats.map_input_records as u64;
+        m.bytes += stats.map_input_bytes as u64;
+        m.last_job_duration = secs;
+        if secs > 0.0 {
+            m.last_job_records_per_second = stats.map_input_records as f64 / secs;
+            m.last_job_bytes_per_second = stats.map_input_bytes as f64 / secs;
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let m = self.metrics.lock().unwrap();
+        let mut out = String::new();
+
+        write_metric(&mut out,
+                     "localmr_jobs_total",
+                     "counter",
+                     "Number of mapreduce jobs run.",
+                     m.jobs_run as f64);
+        write_metric(&mut out,
+                     "localmr_input_records_total",
+                     "counter",
+                     "Number of input records processed by the map phase.",
+                     m.records as f64);
+        write_metric(&mut out,
+                     "localmr_input_bytes_total",
+                     "counter",
+                     "Number of input bytes processed by the map phase.",
+                     m.bytes as f64);
+        write_metric(&mut out,
+                     "localmr_active_workers",
+                     "gauge",
+                     "Number of currently running map and reduce workers.",
+                     m.active_workers as f64);
+        write_metric(&mut out,
+                     "localmr_map_queue_depth",
+                     "gauge",
+                     "Number of input partitions waiting for a mapper thread.",
+                     m.map_queue_depth as f64);
+        write_metric(&mut out,
+                     "localmr_memory_held_bytes",
+                     "gauge",
+                     "Bytes held by the partitions of jobs with a memory budget.",
+                     m.memory_held as f64);
+        write_metric(&mut out,
+                     "localmr_memory_peak_bytes",
+                     "gauge",
+                     "Most bytes held at the same time by the partitions of jobs with a memory \
+                      budget.",
+                     m.memory_peak as f64);
+        write_metric(&mut out,
+                     "localmr_last_job_duration_seconds",
+                     "gauge",
+                     "Duration of the last finished job.",
+                     m.last_job_duration);
+        write_metric(&mut out,
+                     "localmr_last_job_records_per_second",
+                     "gauge",
+                     "Input records processed per second by the last finished job.",
+                     m.last_job_records_per_second);
+        write_metric(&mut out,
+                     "localmr_last_job_bytes_per_second",
+                     "gauge",
+                     "Input bytes processed per second by the last finished job.",
+                     m.last_job_bytes_per_second);
+        out
+    }
+}
+
+/// Counts a running worker in the `localmr_active_workers` gauge while it is alive (see
+/// `MetricsRegistry::active_worker()`).
+pub struct ActiveWorker {
+    registry: MetricsRegistry,
+}
+
+impl Drop for ActiveWorker {
+    fn drop(&mut self) {
+        self.registry.worker_finished();
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetricsRegistry;
+    use stats::JobStats;
+    use std::panic;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let registry = MetricsRegistry::new();
+        let stats = JobStats {
+            map_input_records: 100,
+            map_input_bytes: 4000,
+            ..JobStats::new()
+        };
+
+        registry.clone().worker_started();
+        registry.record_job(&stats, Duration::from_secs(2));
+        registry.record_job(&stats, Duration::from_secs(4));
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE localmr_jobs_total counter\nlocalmr_jobs_total 2\n"));
+        assert!(text.contains("\nlocalmr_input_records_total 200\n"));
+        assert!(text.contains("\nlocalmr_active_workers 1\n"));
+        assert!(text.contains("\nlocalmr_last_job_duration_seconds 4\n"));
+        assert!(text.contains("\nlocalmr_last_job_bytes_per_second 1000\n"));
+    }
+
+    #[test]
+    fn test_active_worker() {
+        let registry = MetricsRegistry::new();
+        let worker = registry.active_worker();
+        assert!(registry.render().contains("\nlocalmr_active_workers 1\n"));
+        drop(worker);
+        assert!(registry.render().contains("\nlocalmr_active_workers 0\n"));
+
+        let panicking = registry.clone();
+        let result = panic::catch_unwind(move || {
+            let _worker = panicking.active_worker();
+            panic!("worker failed");
+        });
+        assert!(result.is_err());
+        assert!(registry.render().contains("\nlocalmr_active_workers 0\n"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Pluggable storage for intermediate and output files (see `MRParameters::set_object_store()`),
+//! for jobs whose temporary data doesn't fit on the local disk. All computation stays local, and
+//! files are still written and read locally, but only while they are in use:
+//!
+//! * A map partition puts its intermediate files (with their sidecars) into the store when it
+//!   has written them, and removes the local files.
+//! * A reduce partition gets the intermediate files of its shard before reading them, and
+//!   removes the local copies when it is done.
+//! * The intermediate objects are deleted when the job cleans up, unless temporary files are
+//!   kept (see `MRParameters::keep_temp_files()`).
+//! * With `StoredFiles::IntermediatesAndOutputs`, the reduce outputs are put into the store as
+//!   well, once they are complete.
+//!
+//! Objects are named like the local files (e.g. `/tmp/mr_map_-3.1`). `LocalStore` keeps them in
+//! a directory, e.g. on a larger or network-mounted disk; remote stores (like S3) can be used by
+//! implementing `ObjectStore`.
+
+use phases::output::{map_output_name, split_prefix};
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::panic::RefUnwindSafe;
+
+/// A store of named objects. Stores are shared by the partitions of a job, and are unwind-safe
+/// like the other parameters of a job.
+pub trait ObjectStore: Send + Sync + RefUnwindSafe {
+    /// Stores the contents of `src` as the object `name`, replacing an existing object.
+    fn put(&self, name: &str, src: &mut dyn Read) -> io::Result<()>;
+    /// Writes the contents of the object `name` to `dst`.
+    fn get(&self, name: &str, dst: &mut dyn Write) -> io::Result<()>;
+    /// Returns the names of the objects starting with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+    /// Deletes the object `name`. Deleting a missing object is not an error.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+/// Which files of a job are kept in its object store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoredFiles {
+    Intermediates,
+    IntermediatesAndOutputs,
+}
+
+/// A store keeping every object as a file in a directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalStore {
+    dir: String,
+}
+
+impl LocalStore {
+    /// Opens the store in `dir`, which is created if it doesn't exist.
+    pub fn open(dir: &str) -> io::Result<LocalStore> {
+        fs::create_dir_all(dir)?;
+        Ok(LocalStore { dir: String::from(dir) })
+    }
+
+    /// Returns the file of the object `name`; the directory is flat, so `/` (and `%`) are
+    /// escaped.
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.dir, name.replace('%', "%25").replace('/', "%2F"))
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn put(&self, name: &str, src: &mut dyn Read) -> io::Result<()> {
+        let path = self.path(name);
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+            io::copy(src, &mut f)?;
+            f.flush()?;
+        }
+        fs::rename(tmp, path)
+    }
+
+    fn get(&self, name: &str, dst: &mut dyn Write) -> io::Result<()> {
+        let mut f = fs::File::open(self.path(name))?;
+        io::copy(&mut f, dst).map(|_| ())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let file = entry?.file_name().to_string_lossy().into_owned();
+            if file.ends_with(".tmp") {
+                continue;
+            }
+            let name = file.replace("%2F", "/").replace("%25", "%");
+            if name.starts_with(prefix) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Puts the local file `path` into `store` as object of the same name, and removes the file.
+pub fn put_file(store: &dyn ObjectStore, path: &str) -> io::Result<()> {
+    store.put(path, &mut fs::File::open(path)?)?;
+    fs::remove_file(path)
+}
+
+/// Gets the object `name` from `store` into the local file of the same name.
+pub fn get_file(store: &dyn ObjectStore, name: &str) -> io::Result<()> {
+    let tmp = format!("{}.get", name);
+    {
+        let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+        if let Err(e) = store.get(name, &mut f).and_then(|_| f.flush()) {
+            drop(f);
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    }
+    fs::rename(tmp, name)
+}
+
+/// Puts the intermediate files written by map partition `mapper` to `location` into `store`,
+/// and removes them. Returns the number of files.
+pub fn put_map_outputs(store: &dyn ObjectStore,
+                       location: &String,
+                       mapper: usize)
+                       -> io::Result<usize> {
+    let prefix = format!("{}-{}.", location, mapper);
+    let (dir, file_prefix) = split_prefix(&prefix)?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let file = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(suffix) = file.strip_prefix(&file_prefix) {
+            files.push(format!("{}{}", prefix, suffix));
+        }
+    }
+    for file in &files {
+        put_file(store, file)?;
+    }
+    Ok(files.len())
+}
+
+/// Gets the intermediate files (with their sidecars) of reduce shard `shard` written by the
+/// `partitions` map partitions to `location` from `store`. Returns the local files, which the
+/// caller removes when it has read them.
+pub fn get_shard_inputs(store: &dyn ObjectStore,
+                        location: &String,
+                        partitions: usize,
+                        shard: usize)
+                        -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for part in 0..partitions {
+        let name = map_output_name(location, part, shard);
+        let sidecars = format!("{}.", name);
+        for object in store.list(&name)? {
+            // Other shards may share the prefix (e.g. 1 and 10).
+            if object == name || object.starts_with(&sidecars) {
+                get_file(store, &object)?;
+                files.push(object);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Deletes the objects starting with `prefix` from `store`. Returns the number of objects.
+pub fn delete_objects(store: &dyn ObjectStore, prefix: &str) -> io::Result<usize> {
+    let objects = store.list(prefix)?;
+    for object in &objects {
+        store.delete(object)?;
+    }
+    Ok(objects.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalStore, ObjectStore, get_shard_inputs, put_map_outputs};
+    use std::fs;
+
+    #[test]
+    fn test_local_store() {
+        let store = LocalStore::open("testdata/object_store").unwrap();
+        for name in ["testdata/os_map_-0.1", "testdata/os_map_-0.1.idx", "testdata/os_map_-0.10",
+                     "testdata/os_map_-1.1"]
+            .iter() {
+            fs::write(name, name.as_bytes()).unwrap();
+        }
+        let location = String::from("testdata/os_map_");
+        assert_eq!(put_map_outputs(&store, &location, 0).unwrap(), 3);
+        assert!(fs::metadata("testdata/os_map_-0.1").is_err());
+        assert_eq!(store.list("testdata/os_map_-0.1").unwrap(),
+                   vec!["testdata/os_map_-0.1",
+                        "testdata/os_map_-0.1.idx",
+                        "testdata/os_map_-0.10"]);
+
+        // Only the files of the shard are fetched, and those of partitions that have been put.
+        let files = get_shard_inputs(&store, &location, 2, 1).unwrap();
+        assert_eq!(files, vec!["testdata/os_map_-0.1", "testdata/os_map_-0.1.idx"]);
+        assert_eq!(fs::read_to_string("testdata/os_map_-0.1.idx").unwrap(),
+                   "testdata/os_map_-0.1.idx");
+        let mut contents = Vec::new();
+        store.get("testdata/os_map_-0.10", &mut contents).unwrap();
+        assert_eq!(contents, b"testdata/os_map_-0.10");
+
+        for file in files.iter().chain(Some(&String::from("testdata/os_map_-1.1"))) {
+            let _ = fs::remove_file(file);
+        }
+        assert_eq!(super::delete_objects(&store, "testdata/os_map_-").unwrap(), 3);
+        assert!(store.list("").unwrap().is_empty());
+        store.delete("testdata/os_map_-0.1").unwrap();
+        let _ = fs::remove_dir_all("testdata/object_store");
+    }
+}
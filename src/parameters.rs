@@ -1,21 +1,440 @@
 //! Parameters for a mapreduce process.
 //!
 
+#[cfg(feature = "sysinfo")]
+extern crate sysinfo;
+
+use catalog::Catalog;
+use executor::ExecutorHandle;
+use formats::compressed::OutputCompression;
+use formats::lines::InvalidUtf8;
+use formats::output::OutputFormat;
+use formats::retry::IoRetry;
+use formats::util::KeyFilter;
+use malformed::{MalformedHandler, MalformedPolicy};
+use mapreducer::{FilterF, OutputFormatterF, OutputParseF, OutputPartitionF, OutputRouteF,
+                 ValueDecoderF};
+use memory::MemoryBudget;
+use metrics::MetricsRegistry;
+use object_store::{ObjectStore, StoredFiles};
+use stats::{InputStatsCollector, MapSortTimings};
+use termination::Termination;
+use testing::FaultInjector;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Deduplication of the lines written by reduce partitions (see
+/// `MRParameters::set_output_dedup()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputDedup {
+    /// All lines are written.
+    Off,
+    /// A line is dropped if it is identical to one of the last n lines written by the reduce
+    /// partition.
+    PerShard(usize),
+    /// A line is dropped if it is identical to one of the last n lines written for the same key.
+    PerKey(usize),
+}
+
+/// Limits how many values a mapper may emit for a single input record (see
+/// `MRParameters::set_emit_limit()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmitLimit {
+    /// Mappers may emit any number of values.
+    Off,
+    /// Of the values emitted for an input record, a random sample of at most n is kept
+    /// (reservoir sampling), and a warning is printed.
+    Sample(usize),
+    /// The job fails (by panicking) when a mapper emits more than n values for an input record.
+    Fail(usize),
+}
+
+/// How output and intermediate files are made durable when they are completed (see
+/// `MRParameters::set_durability()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Files are not flushed explicitly; buffered data is written when writers are dropped, and
+    /// errors go unnoticed.
+    None,
+    /// Writers are flushed when a file is complete.
+    FlushOnClose,
+    /// Writers are flushed and the file is synced to disk (fsync) when it is complete.
+    FsyncOnClose,
+    /// Like FsyncOnClose, and additionally the file is synced whenever the given number of bytes
+    /// has been written since the last sync.
+    PeriodicFsync(u64),
+}
+
+/// What happens with input records larger than the maximum record size (see
+/// `MRParameters::set_max_record_size()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizedRecords {
+    /// The record is cut to the maximum size: its value, and its key if the key alone is too
+    /// large.
+    Truncate,
+    /// The record is a malformed record, handled according to the job's `MalformedPolicy`: it is
+    /// skipped and counted, written to the dead-letter file (only its first bytes, up to the
+    /// maximum size), or fails the job.
+    Malformed,
+}
+
+/// How keys are written to intermediate files (see
+/// `MRParameters::set_intermediate_key_encoding()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEncoding {
+    /// Every record carries its key.
+    Plain,
+    /// Only the first record of a run of records with the same key carries the key; the
+    /// following records refer to it.
+    Runs,
+    /// The distinct keys of every intermediate file are stored in a dictionary sidecar, and all
+    /// records refer to their key by its index in the dictionary.
+    Dictionary,
+}
+
+/// What happens to intermediate files left behind by a crashed run at the intermediate location
+/// of a job (see `MRParameters::set_stale_intermediates()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleIntermediates {
+    /// The files are left in place; a warning is logged.
+    Keep,
+    /// The files are removed.
+    Remove,
+    /// The files are renamed to `<name>.stale`, so that they are not read by later runs but
+    /// can be inspected.
+    Quarantine,
+}
+
+/// How map partitions sort their input records by key (see `MRParameters::set_map_input_sort()`).
+/// Either way, the records are mapped in dictionary order of their keys, and of several records
+/// with the same key, only the last one is mapped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapInputSort {
+    /// Insert the records into a BTreeMap. Duplicate keys are collapsed right away, which is
+    /// cheap for inputs with few distinct keys.
+    BTree,
+    /// Collect the records in a vector and sort it once, which avoids the per-record
+    /// allocations of the tree; usually faster for inputs with many distinct keys.
+    SortedVec,
+    /// Try both structures on the first map partitions of a job, and sort the inputs of the
+    /// remaining partitions with the one that took less time per record. The choice is logged,
+    /// and the timings are returned in `JobStats::map_input_sort`.
+    Auto,
+}
+
+/// The order in which the input splits of a job are mapped (see
+/// `MRParameters::set_map_scheduling()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapScheduling {
+    /// Splits are mapped in the order they are passed in.
+    Fifo,
+    /// Splits are mapped in the order of decreasing size, so that the longest ones start early
+    /// and the end of the map phase is filled with small ones. Splits of equal size are mapped
+    /// in the order they are passed in.
+    LargestFirst,
+    /// Splits are mapped in random order, e.g. to spread splits from the same disk.
+    Random,
+}
+
+/// Which of the keys grouped together case-insensitively is passed to the reducer (see
+/// `MRParameters::set_group_key_policy()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupKeyPolicy {
+    /// The key in lower case.
+    Lowercased,
+    /// The first key of the group, as emitted by the mapper.
+    FirstSeen,
+    /// The spelling of the key occurring most often in the group; of several equally frequent
+    /// ones, the first.
+    MostFrequent,
+}
+
+/// How the reduce phase groups the records of its inputs by key (see
+/// `MRParameters::set_reduce_strategy()`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReduceStrategy {
+    /// Merge the sorted inputs with a merge tree; keys are reduced in dictionary order.
+    SortMerge,
+    /// Collect all records of a reduce partition in a hash table, without comparing keys. The
+    /// records of a partition must fit into memory; keys are reduced in the order in which they
+    /// first appear in the inputs, and the values of a key are in input order.
+    HashAggregate,
+    /// Read the inputs one after another, without merging: The inputs must already be sorted
+    /// as a whole, e.g. a single input, or inputs holding consecutive key ranges in order.
+    AssumeSorted,
+    /// Hash the records of a reduce partition into the given number of bucket files next to the
+    /// intermediate files, and group one bucket at a time in a hash table, like
+    /// `HashAggregate`: Only a bucket must fit into memory. Keys are reduced bucket by bucket,
+    /// and the values of a key are in input order. As grouping doesn't depend on the order of
+    /// the inputs, map partitions don't sort their outputs (unless they write index sidecars,
+    /// see `MRParameters::set_intermediate_key_index()`).
+    HashBuckets(usize),
+}
+
+/// What happens when a reduce output exceeds its size limit (see
+/// `MRParameters::set_output_size_limit()` and `formats::limited`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputLimitPolicy {
+    /// Continue in a new part file (`<output>.1`, `<output>.2` etc.).
+    Rotate,
+    /// Compress the output to `<output>.gz` once it is complete (requires the `flate2` feature).
+    Compress,
+    /// Fail the reduce partition.
+    Fail,
+}
+
+/// Limits which intermediate files a job keeps when `MRParameters::keep_temp_files()` is set
+/// (see `MRParameters::set_temp_retention()`), so that debugging a large job doesn't require
+/// keeping all of its intermediate data.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TempRetention {
+    /// Only keep the intermediate files destined for reduce shards that failed (panicked); if
+    /// the job succeeds, all intermediate files are removed.
+    pub failed_only: bool,
+    /// Keep at most this many bytes of intermediate files (with their sidecars), in the order of
+    /// map partitions and shards; the remaining files are removed.
+    pub max_bytes: Option<u64>,
+}
+
+/// Presets of the performance-related parameters for common machine sizes (see
+/// `MRParameters::from_profile()`). Memory estimates assume that mappers hold their input
+/// partition and its output at the same time. Profiles are selected by name with
+/// `"laptop-8gb".parse::<Profile>()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    /// `laptop-8gb`: 4 mappers and reducers, 64 MiB partitions, in-memory shuffle of inputs up
+    /// to 16 MiB; about 1 GiB of memory.
+    Laptop8GB,
+    /// `workstation-64gb`: 16 mappers and reducers, 256 MiB partitions read two ahead, in-memory
+    /// shuffle of inputs up to 256 MiB, larger key buffers and output batches; about 12 GiB of
+    /// memory.
+    Workstation64GB,
+    /// `server-512gb`: 64 mappers and reducers, 1 GiB partitions read four ahead, in-memory
+    /// shuffle of inputs up to 2 GiB, larger key buffers and output batches; about 200 GiB of
+    /// memory.
+    Server512GB,
+}
+
+impl Profile {
+    /// Returns the name of the profile.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Profile::Laptop8GB => "laptop-8gb",
+            Profile::Workstation64GB => "workstation-64gb",
+            Profile::Server512GB => "server-512gb",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+    /// Parses the name of a profile (case-insensitively).
+    fn from_str(s: &str) -> Result<Profile, String> {
+        [Profile::Laptop8GB, Profile::Workstation64GB, Profile::Server512GB]
+            .iter()
+            .find(|p| p.name().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or_else(|| format!("Unknown profile {}", s))
+    }
+}
+
+/// A template for the names of the output shards of a job (see
+/// `MRParameters::set_output_name_template()`), e.g. `"{prefix}{job}/part-{shard:05}.txt"`. The
+/// placeholders are `{prefix}` (`reduce_output_shard_prefix`), `{job}` (see
+/// `MRParameters::set_job_name()`) and `{shard}`; `{shard:0N}` pads the shard number with zeros
+/// to N digits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Prefix,
+    Job,
+    Shard(usize),
+}
+
+impl NameTemplate {
+    /// Returns the name for `shard` of a job with the output prefix `prefix` and name `job`.
+    pub fn render(&self, prefix: &str, job: &str, shard: usize) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match *part {
+                TemplatePart::Literal(ref s) => name.push_str(s),
+                TemplatePart::Prefix => name.push_str(prefix),
+                TemplatePart::Job => name.push_str(job),
+                TemplatePart::Shard(width) => name.push_str(&format!("{:01$}", shard, width)),
+            }
+        }
+        name
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+    /// Parses a template; unknown placeholders and unbalanced braces are errors. Templates
+    /// without `{shard}` are rejected, as all shards would be written to the same file.
+    fn from_str(s: &str) -> Result<NameTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(TemplatePart::Literal(String::from(&rest[..open])));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in name template {}", s))?;
+            let part = match &rest[open + 1..open + close] {
+                "prefix" => TemplatePart::Prefix,
+                "job" => TemplatePart::Job,
+                "shard" => TemplatePart::Shard(0),
+                p => {
+                    match p.strip_prefix("shard:0").map(str::parse::<usize>) {
+                        Some(Ok(width)) => TemplatePart::Shard(width),
+                        _ => return Err(format!("Unknown placeholder {{{}}} in name template", p)),
+                    }
+                }
+            };
+            parts.push(part);
+            rest = &rest[open + close + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unbalanced braces in name template {}", s));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(String::from(rest)));
+        }
+        if !parts.iter().any(|p| matches!(*p, TemplatePart::Shard(_))) {
+            return Err(format!("Name template {} doesn't contain {{shard}}", s));
+        }
+        Ok(NameTemplate { parts })
+    }
+}
+
+/// Values parameterizing the mappers and reducers of a job at run time, e.g. thresholds or
+/// patterns (see `MRParameters::set_config()`). They are available through
+/// `MEmitter::config()` and `REmitter::config()`, and shared between all partitions of a job.
+#[derive(Clone, Debug, Default)]
+pub struct JobConfig {
+    values: Arc<BTreeMap<String, String>>,
+}
+
+impl JobConfig {
+    /// Returns the value set for `key`.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Returns the value set for `key`, parsed as `T`. Panics if the value can't be parsed, as
+    /// the job is misconfigured then.
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T>
+        where T::Err: Debug
+    {
+        self.get_str(key).map(|v| match v.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("Invalid value {:?} for configuration key {}: {:?}", v, key, e),
+        })
+    }
+
+    /// Like `get()`, but returns `default` if `key` isn't set.
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> T
+        where T::Err: Debug
+    {
+        self.get(key).unwrap_or(default)
+    }
+
+    fn set(&mut self, key: String, value: String) {
+        Arc::make_mut(&mut self.values).insert(key, value);
+    }
+}
+
 #[derive(Clone)]
 pub struct MRParameters {
     pub key_buffer_size: usize,
 
     pub mappers: usize,
     pub reducers: usize,
+    pub map_niceness: i32,
+    pub reduce_niceness: i32,
+    pub map_scheduling: MapScheduling,
+    pub key_sample_fraction: f64,
 
     pub map_partition_size: usize,
+    pub map_input_sort: MapInputSort,
+    pub map_queue_length: usize,
+    pub map_output_batch_records: usize,
+    pub map_output_batch_bytes: usize,
+    pub in_memory_shuffle_bytes: usize,
+    pub shuffle_memory_limit: usize,
 
     pub reduce_group_prealloc_size: usize,
     pub reduce_group_insensitive: bool,
+    pub reduce_group_key_policy: GroupKeyPolicy,
+    pub reduce_chunk_values: usize,
+    pub stable_merge: bool,
+    pub reduce_strategy: ReduceStrategy,
 
     pub map_output_location: String,
     pub keep_temp_files: bool,
     pub reduce_output_shard_prefix: String,
+    pub working_dir: Option<String>,
+    pub output_name_template: Option<NameTemplate>,
+    pub job_name: String,
+    pub reduce_output_format: OutputFormat,
+    pub merge_reduce_outputs: bool,
+    pub intermediate_key_index: bool,
+    pub multiplexed_intermediates: bool,
+    pub intermediate_bloom_bits: usize,
+    pub key_only: bool,
+    pub intern_keys: bool,
+    pub intermediate_key_encoding: KeyEncoding,
+    pub output_dedup: OutputDedup,
+    pub emit_limit: EmitLimit,
+    pub sample_output: usize,
+    pub key_histogram: usize,
+    pub intermediate_sync_interval: u64,
+    pub recover_intermediates: bool,
+    pub intermediate_value_version: u32,
+    pub value_decoders: Vec<(u32, ValueDecoderF)>,
+    pub durability: Durability,
+    pub io_retry: IoRetry,
+    pub stale_intermediates: StaleIntermediates,
+    pub temp_retention: TempRetention,
+
+    pub shuffle_filter: Option<FilterF>,
+    pub output_formatter: Option<OutputFormatterF>,
+    pub output_partitioning: Option<(OutputPartitionF, usize)>,
+    pub output_routing: Option<(OutputRouteF, usize)>,
+    pub warm_start: Option<(OutputParseF, Option<Duration>)>,
+    pub output_size_limit: Option<(u64, OutputLimitPolicy)>,
+    pub output_compression: OutputCompression,
+    pub reduce_key_filter: Option<KeyFilter>,
+    pub metrics: Option<MetricsRegistry>,
+    pub memory_budget: Option<MemoryBudget>,
+    pub object_store: Option<(Arc<dyn ObjectStore>, StoredFiles)>,
+    pub catalog: Option<Catalog>,
+    pub executor: Option<ExecutorHandle>,
+    pub executor_job_cap: usize,
+    pub config: JobConfig,
+    pub shard_seed: u64,
+    pub malformed: MalformedHandler,
+    pub max_record_size: Option<(usize, OversizedRecords)>,
+    pub invalid_utf8: InvalidUtf8,
+    pub fault_injector: Option<FaultInjector>,
+    /// Inputs wrapped with `input_stats.collect()` report their counters to the job's
+    /// statistics (see `JobStats::input`).
+    pub input_stats: InputStatsCollector,
+    /// Map partitions report how long sorting their inputs took to `map_sort_timings`, which
+    /// selects the structure with `MapInputSort::Auto`. Every job has its own.
+    pub map_sort_timings: MapSortTimings,
+    pub termination: Termination,
 
     // Internal parameters
     pub shard_id: usize,
@@ -28,16 +447,147 @@ impl MRParameters {
             key_buffer_size: 256,
             mappers: 4,
             reducers: 4,
+            map_niceness: 0,
+            reduce_niceness: 0,
+            map_scheduling: MapScheduling::Fifo,
+            key_sample_fraction: 0.01,
             map_partition_size: 100 * 1024 * 1024,
+            map_input_sort: MapInputSort::BTree,
+            map_queue_length: 1,
+            map_output_batch_records: 4096,
+            map_output_batch_bytes: 1024 * 1024,
+            in_memory_shuffle_bytes: 0,
+            shuffle_memory_limit: 0,
             reduce_group_prealloc_size: 1,
             reduce_group_insensitive: false,
+            reduce_group_key_policy: GroupKeyPolicy::Lowercased,
+            reduce_chunk_values: 0,
+            stable_merge: false,
+            reduce_strategy: ReduceStrategy::SortMerge,
             map_output_location: String::from("map_intermediate_"),
             keep_temp_files: false,
             reduce_output_shard_prefix: String::from("output_"),
+            working_dir: None,
+            output_name_template: None,
+            job_name: String::from("job"),
+            reduce_output_format: OutputFormat::Auto,
+            merge_reduce_outputs: false,
+            intermediate_key_index: false,
+            multiplexed_intermediates: false,
+            intermediate_bloom_bits: 0,
+            key_only: false,
+            intern_keys: false,
+            intermediate_key_encoding: KeyEncoding::Plain,
+            output_dedup: OutputDedup::Off,
+            emit_limit: EmitLimit::Off,
+            sample_output: 0,
+            key_histogram: 0,
+            intermediate_sync_interval: 1024 * 1024,
+            recover_intermediates: false,
+            intermediate_value_version: 0,
+            value_decoders: Vec::new(),
+            durability: Durability::FlushOnClose,
+            io_retry: IoRetry::default(),
+            stale_intermediates: StaleIntermediates::Remove,
+            temp_retention: TempRetention::default(),
+            shuffle_filter: None,
+            output_formatter: None,
+            output_partitioning: None,
+            output_routing: None,
+            warm_start: None,
+            output_size_limit: None,
+            output_compression: OutputCompression::None,
+            reduce_key_filter: None,
+            metrics: None,
+            memory_budget: None,
+            object_store: None,
+            catalog: None,
+            executor: None,
+            executor_job_cap: 0,
+            config: JobConfig::default(),
+            shard_seed: 0,
+            malformed: MalformedHandler::new(MalformedPolicy::Skip),
+            max_record_size: None,
+            invalid_utf8: InvalidUtf8::Skip,
+            fault_injector: None,
+            input_stats: InputStatsCollector::new(),
+            map_sort_timings: MapSortTimings::new(),
+            termination: Termination::new(),
             shard_id: 0,
         }
     }
 
+    /// Creates an instance with the preset `profile`, whose values can be overridden by the
+    /// setters like those of `new()`, e.g.
+    /// `MRParameters::from_profile(Profile::Laptop8GB).set_concurrency(2, 2)`.
+    pub fn from_profile(profile: Profile) -> MRParameters {
+        const MIB: usize = 1024 * 1024;
+        let params = MRParameters::new();
+        match profile {
+            Profile::Laptop8GB => {
+                params.set_concurrency(4, 4)
+                    .set_partition_size(64 * MIB)
+                    .set_in_memory_shuffle(16 * MIB)
+            }
+            Profile::Workstation64GB => {
+                params.set_concurrency(16, 16)
+                    .set_key_buffer_size(1024)
+                    .set_partition_size(256 * MIB)
+                    .set_map_queue_length(2)
+                    .set_map_output_batching(16384, 4 * MIB)
+                    .set_in_memory_shuffle(256 * MIB)
+            }
+            Profile::Server512GB => {
+                params.set_concurrency(64, 64)
+                    .set_key_buffer_size(4096)
+                    .set_partition_size(1024 * MIB)
+                    .set_map_queue_length(4)
+                    .set_map_output_batching(65536, 16 * MIB)
+                    .set_in_memory_shuffle(2048 * MIB)
+            }
+        }
+    }
+
+    /// Chooses the numbers of mappers and reducers and the partition size for this machine and
+    /// an input of about `input_bytes` bytes (if known), and prints the choices. Mappers use all
+    /// cores. Up to half of the available memory is spent on input partitions, of which every
+    /// mapper holds about two (its input and output) while `map_queue_length` more wait; the
+    /// memory is determined with the `sysinfo` feature, without it the default partition size
+    /// is kept. Small inputs are split into smaller partitions, so that every mapper gets work,
+    /// and into fewer reduce shards of at least 64 MiB. The values can be overridden by the
+    /// setters like those of `new()`.
+    pub fn auto(input_bytes: Option<u64>) -> MRParameters {
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        MRParameters::new().auto_tune(cores, available_memory(), input_bytes)
+    }
+
+    fn auto_tune(self,
+                 cores: usize,
+                 memory: Option<u64>,
+                 input_bytes: Option<u64>)
+                 -> MRParameters {
+        const MIB: u64 = 1024 * 1024;
+        let mut partition_size = self.map_partition_size as u64;
+        if let Some(memory) = memory {
+            let partitions = (2 * cores + self.map_queue_length) as u64;
+            partition_size = (memory / 2 / partitions).clamp(16 * MIB, 1024 * MIB);
+        }
+        let mut reducers = cores;
+        if let Some(bytes) = input_bytes {
+            partition_size = partition_size.min((bytes / cores as u64).max(MIB));
+            reducers = reducers.min((bytes / (64 * MIB)).max(1) as usize);
+        }
+        println!("INFO: Using {} mappers, {} reducers and partitions of {} MiB ({} cores, {} MiB \
+                  of memory available, {} MiB of input)",
+                 cores,
+                 reducers,
+                 partition_size / MIB,
+                 cores,
+                 memory.map_or(String::from("unknown"), |m| (m / MIB).to_string()),
+                 input_bytes.map_or(String::from("unknown"), |b| (b / MIB).to_string()));
+        self.set_concurrency(cores, reducers).set_partition_size(partition_size as usize)
+    }
+
     /// An implementation detail: When processing the data during the map phase, this
     /// parameter determines how many keys are processed in direct sequence. Heavily increasing
     /// this value increases memory usage.
@@ -48,6 +598,16 @@ impl MRParameters {
         self
     }
 
+    /// Chooses the structure map partitions sort their inputs with. Which one is faster depends
+    /// mostly on the number of distinct keys; `MapInputSort::Auto` measures both on the data of
+    /// the job.
+    ///
+    /// Default: MapInputSort::BTree
+    pub fn set_map_input_sort(mut self, sort: MapInputSort) -> MRParameters {
+        self.map_input_sort = sort;
+        self
+    }
+
     /// Determines how many parallel processes will be run. Mappers and reducers do in general
     /// not run at the same time (as the reducers need to wait for the map output). The number of
     /// reducers also determines the sharding of the map output data.
@@ -59,6 +619,47 @@ impl MRParameters {
         self
     }
 
+    /// Runs as many mappers and reducers as there are CPU cores, minus `reserved` (but at least
+    /// one), e.g. to leave a core for interactive work (see also `set_thread_niceness()`).
+    pub fn set_concurrency_all_cores_but(self, reserved: usize) -> MRParameters {
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let n = cores.saturating_sub(reserved).max(1);
+        self.set_concurrency(n, n)
+    }
+
+    /// Lowers the OS scheduling priority of the mapper and reducer threads to the given nice
+    /// values (1 to 19, where 19 is the lowest priority), so that long jobs don't slow down
+    /// other programs on the machine. On macOS, worker threads with a positive value run in the
+    /// background band instead. If the priority can't be lowered, a warning is printed. The
+    /// thread reading the input isn't affected.
+    ///
+    /// Default 0/0 (unchanged)
+    pub fn set_thread_niceness(mut self, map: i32, reduce: i32) -> MRParameters {
+        self.map_niceness = map;
+        self.reduce_niceness = reduce;
+        self
+    }
+
+    /// Sets the order in which input splits are mapped by `MRController::run_sized_splits()`.
+    /// With `MapScheduling::LargestFirst`, the largest splits are mapped first, which shortens
+    /// the map phase if the splits differ in size.
+    ///
+    /// Default: MapScheduling::Fifo
+    pub fn set_map_scheduling(mut self, scheduling: MapScheduling) -> MRParameters {
+        self.map_scheduling = scheduling;
+        self
+    }
+
+    /// Sets the fraction of the input records that `MRController::run_total_order()` maps in
+    /// order to sample the distribution of the intermediate keys. Larger samples result in more
+    /// evenly sized shards, but take longer to collect.
+    ///
+    /// Default: 0.01
+    pub fn set_key_sample_fraction(mut self, fraction: f64) -> MRParameters {
+        self.key_sample_fraction = fraction;
+        self
+    }
+
     /// This parameter determines the size of the chunks that the input is partitioned in
     /// before being processed by map shards. More memory usually also means faster processing;
     /// however, entire chunks are held in memory at once, so your available RAM is the limit.
@@ -72,11 +673,61 @@ impl MRParameters {
         self
     }
 
+    /// Input partitions are read ahead and put into a queue, from which idle mapper threads take
+    /// the next partition. This parameter determines how many partitions may wait in the queue;
+    /// every waiting partition is held in memory (see `set_partition_size()`). A longer queue
+    /// helps with inputs that are slow to read.
+    ///
+    /// Default 1
+    pub fn set_map_queue_length(mut self, n: usize) -> MRParameters {
+        self.map_queue_length = n;
+        self
+    }
+
+    /// Map partitions collect the records for every reduce shard in memory and write them to the
+    /// intermediate file in batches, once `records` records or `bytes` bytes have been collected
+    /// (whichever happens first; a limit of 0 is ignored). Larger batches mean fewer, larger
+    /// writes.
+    ///
+    /// Default 4096 records/1 MiB
+    pub fn set_map_output_batching(mut self, records: usize, bytes: usize) -> MRParameters {
+        self.map_output_batch_records = records;
+        self.map_output_batch_bytes = bytes;
+        self
+    }
+
+    /// Small jobs spend most of their time creating and removing intermediate files. If the
+    /// input of a job started by `MRController::run()` is smaller than `max_input_bytes` (keys
+    /// and values), no intermediate files are written: The map partitions send their sorted
+    /// output for every reduce shard over bounded channels to the reducer threads, which run at
+    /// the same time as the mappers. The input size serves as estimate of the intermediate size;
+    /// all intermediate records are held in memory until they are reduced. Larger inputs are
+    /// processed as usual. The input is read up to the limit before the job starts.
+    ///
+    /// Default 0 (disabled)
+    pub fn set_in_memory_shuffle(mut self, max_input_bytes: usize) -> MRParameters {
+        self.in_memory_shuffle_bytes = max_input_bytes;
+        self
+    }
+
+    /// Shuffles the intermediate records of `MRController::run()` in memory regardless of the
+    /// input size (see `set_in_memory_shuffle()`), but limits the records buffered by every
+    /// reducer to `max_bytes` (keys and values): Once a reducer's buffer exceeds the limit, it is
+    /// merged into a sorted run and spilled to a WriteLog at the map output location. The reduce
+    /// phase merges the spilled runs with the records remaining in memory; spilled runs are
+    /// removed afterwards. Also limits the buffers of `set_in_memory_shuffle()`.
+    ///
+    /// Default 0 (no limit; intermediate files are written unless the input is small)
+    pub fn set_shuffle_memory_limit(mut self, max_bytes: usize) -> MRParameters {
+        self.shuffle_memory_limit = max_bytes;
+        self
+    }
+
     /// prealloc_size: How big are the groups of keys in the reduce phase expected to be?
     /// (used for pre-allocating buffers). Default 1.
     ///
-    /// insensitive: Whether to group strings together that differ in case. When used, the first
-    /// encountered key will be supplied as key to the reduce function.
+    /// insensitive: Whether to group strings together that differ in case. When used, the key
+    /// supplied to the reduce function is chosen by `set_group_key_policy()`.
     /// BUG: This will not work correctly until the map phase delivers outputs in the correct order,
     /// i.e. dictionary order. The default Ord implementation for String treats lower and upper
     /// case very differently. Default: false.
@@ -89,13 +740,77 @@ impl MRParameters {
         self
     }
 
+    /// Chooses the key passed to the reducer for groups of keys that differ in case (see
+    /// `set_reduce_group_opts()`); lower-cased keys are often unsuitable for presentation. When
+    /// grouping case-insensitively, map partitions write such keys with the spelling they
+    /// emitted first, so the spellings of a group are those of the map partitions.
+    ///
+    /// Default: GroupKeyPolicy::Lowercased
+    pub fn set_group_key_policy(mut self, policy: GroupKeyPolicy) -> MRParameters {
+        self.reduce_group_key_policy = policy;
+        self
+    }
+
+    /// Passes groups of more than `values` values to associative reducers (see
+    /// `Reducer::associative()`) in chunks of `values` values, so that a heavily skewed key
+    /// doesn't have to fit into memory as a whole. The results emitted for every chunk but the
+    /// last are kept as text instead of being written; the last call gets the results of the
+    /// previous chunks followed by the values of the last chunk, and its results are written.
+    /// The reducer must therefore emit results that it accepts as values again, e.g. partial
+    /// sums. Chunks of case-insensitive groups (see `set_reduce_group_opts()`) are passed with
+    /// the lower-cased key. Reducers that aren't associative get whole groups.
+    ///
+    /// Default: 0 (whole groups)
+    pub fn set_reduce_chunk_values(mut self, values: usize) -> MRParameters {
+        self.reduce_chunk_values = values;
+        self
+    }
+
+    /// Whether the values of a key are passed to the reducer in the order of the map partitions
+    /// that emitted them, and in the order they were emitted within a partition (the map-side
+    /// sort is always stable). Algorithms like keeping only the first value of every key depend
+    /// on this. Otherwise, values of the same key are ordered by value.
+    ///
+    /// Map partitions are numbered in the order they are started, which is the input order
+    /// for `MRController::run()`. With the in-memory shuffle, the runs of the map partitions are
+    /// instead ordered by when they finished.
+    ///
+    /// Default: false
+    pub fn set_stable_merge(mut self, stable: bool) -> MRParameters {
+        self.stable_merge = stable;
+        self
+    }
+
+    /// Chooses how reduce partitions group their input records by key. `HashAggregate` saves
+    /// the comparisons of the merge when the output order doesn't matter and the partitions are
+    /// small; `HashBuckets` also saves sorting in the map phase, for partitions with very many
+    /// distinct keys that don't fit into memory; `AssumeSorted` skips merging inputs that are
+    /// sorted already. Case-insensitive grouping (see `set_reduce_group_opts()`) works with all
+    /// strategies.
+    ///
+    /// Default: ReduceStrategy::SortMerge
+    pub fn set_reduce_strategy(mut self, strategy: ReduceStrategy) -> MRParameters {
+        self.reduce_strategy = strategy;
+        self
+    }
+
+    /// Returns whether map partitions sort the records of their intermediate files by key, which
+    /// they don't if reduce partitions hash them into buckets (see `ReduceStrategy::HashBuckets`).
+    pub fn sorts_intermediates(&self) -> bool {
+        !matches!(self.reduce_strategy, ReduceStrategy::HashBuckets(_)) ||
+        self.intermediate_key_index
+    }
+
     /// map_out_prefix: A location that can be used for intermediate map outputs. For example,
     /// '/home/user/processing/tmp/'. (Note: Make sure that the location provides enough disk
-    /// space). Default: './output_' (will lead to ./output_0, ./output_1 etc.)
+    /// space). Default: './map_intermediate_' (will lead to ./map_intermediate_0.0 etc.)
     ///
     /// reduce_out_prefix: Path prefix for output files produced by the reduce phase, for example
     /// '/home/user/processing/output_'. (Note: Make sure that the location provides enough
-    /// disk space). Default: './map_intermediate_' (will lead to ./map_intermediate_0.0 etc.)
+    /// disk space). Default: './output_' (will lead to ./output_0, ./output_1 etc.)
+    ///
+    /// Jobs running concurrently in one process must use distinct locations; jobs sharing a
+    /// location run one after another.
     ///
     pub fn set_file_locations(mut self,
                               map_out_prefix: String,
@@ -106,6 +821,61 @@ impl MRParameters {
         self
     }
 
+    /// Resolves relative file locations (see `set_file_locations()`) against `dir` instead of the
+    /// current directory, which is shared by all jobs of the process, e.g. to run jobs submitted
+    /// by different users in directories of their own.
+    ///
+    /// Default: None (the current directory)
+    pub fn set_working_dir(mut self, dir: &str) -> MRParameters {
+        self.working_dir = Some(String::from(dir));
+        self
+    }
+
+    /// Returns the parameters with the relative file locations resolved against the working
+    /// directory, if one is set (see `set_working_dir()`).
+    pub fn resolve_locations(mut self) -> MRParameters {
+        if let Some(dir) = self.working_dir.take() {
+            for location in [&mut self.map_output_location,
+                             &mut self.reduce_output_shard_prefix] {
+                if Path::new(location.as_str()).is_relative() {
+                    *location = format!("{}/{}", dir.trim_end_matches('/'), location);
+                }
+            }
+        }
+        self
+    }
+
+    /// Gives the parameters a termination flag, malformed record counter (with the same policy)
+    /// and input statistics of their own. These are shared by clones, so that they can be
+    /// observed from other threads; a job started from a clone of parameters shared by several
+    /// jobs (e.g. defaults of a server) should be isolated, so that terminating or counting in one
+    /// job doesn't affect the others.
+    pub fn isolate(mut self) -> MRParameters {
+        self.termination = Termination::new();
+        self.malformed = MalformedHandler::new(self.malformed.policy().clone());
+        self.input_stats = InputStatsCollector::new();
+        self
+    }
+
+    /// Names the output shards after `template` instead of `<reduce_output_shard_prefix><shard>`,
+    /// e.g. to match the directory layout expected by downstream systems; missing directories are
+    /// created. The names are returned in `JobResult::outputs`. Intermediate files keep their
+    /// names, as the controller finds them by their names (e.g. to clean them up).
+    ///
+    /// Default: None
+    pub fn set_output_name_template(mut self, template: NameTemplate) -> MRParameters {
+        self.output_name_template = Some(template);
+        self
+    }
+
+    /// Sets the name of the job, used in output name templates.
+    ///
+    /// Default: "job"
+    pub fn set_job_name(mut self, name: &str) -> MRParameters {
+        self.job_name = String::from(name);
+        self
+    }
+
     /// If this is set to true, intermediate files, such as outputs from the map phase,
     /// will be kept.
     ///
@@ -115,6 +885,473 @@ impl MRParameters {
         self
     }
 
+    /// Restricts which intermediate files are kept if `keep_temp_files()` is set. If a reduce
+    /// partition fails, the job still fails, but only after the retention has been applied (and
+    /// the intermediate location has been released, so that the kept files aren't treated as
+    /// stale by the next run; see `set_stale_intermediates()`).
+    ///
+    /// Default: all files are kept
+    pub fn set_temp_retention(mut self, retention: TempRetention) -> MRParameters {
+        self.temp_retention = retention;
+        self
+    }
+
+    /// If this is set to true, the map phase writes an index sidecar (`<file>.idx`) next to
+    /// every intermediate file, mapping each key to the offset of its first record. When the
+    /// sharder is range-partitioning (see `Sharder::key_range()`), reduce partitions use the
+    /// index to seek directly to their key range instead of scanning the whole file.
+    ///
+    /// Default: false
+    pub fn set_intermediate_key_index(mut self, index: bool) -> MRParameters {
+        self.intermediate_key_index = index;
+        self
+    }
+
+    /// If this is set to true, every map partition writes a single intermediate file
+    /// (`<location>-<partition>.mux`) instead of one per reduce shard: The records of every
+    /// shard are written as one contiguous segment, and a footer records where the segments
+    /// are (see `phases::output::read_segment()`). Reduce partitions read only their segment.
+    /// This reduces the number of intermediate files from mappers × reducers to mappers, and
+    /// the map phase writes sequentially. Index and Bloom filter sidecars are still written per
+    /// shard. Jobs reducing existing intermediate files must use the same setting.
+    ///
+    /// Default: false
+    pub fn set_multiplexed_intermediates(mut self, multiplexed: bool) -> MRParameters {
+        self.multiplexed_intermediates = multiplexed;
+        self
+    }
+
+    /// If this is set to a value greater than 0, the map phase writes a Bloom filter of the keys
+    /// of every intermediate file to a sidecar (`<file>.bloom`), using `bits_per_key` bits per
+    /// key (10 bits result in about 1% false positives). `MRController::reduce_join()` uses the
+    /// filters to skip records that can't have a join partner before they are merged.
+    ///
+    /// Default: 0 (no filters)
+    pub fn set_intermediate_bloom_filter(mut self, bits_per_key: usize) -> MRParameters {
+        self.intermediate_bloom_bits = bits_per_key;
+        self
+    }
+
+    /// If this is set to true, the job works on keys only (e.g. for set operations): The values
+    /// emitted by mappers are discarded, and the intermediate files contain only the keys.
+    /// Reducers receive one empty value per emitted key.
+    ///
+    /// Default: false
+    pub fn set_key_only(mut self, key_only: bool) -> MRParameters {
+        self.key_only = key_only;
+        self
+    }
+
+    /// If this is set to true, map partitions store every distinct emitted key only once, and
+    /// sorting and sharding compare equal keys by their handle instead of comparing the strings.
+    /// This saves memory and time when a modest number of distinct (long) keys is emitted many
+    /// times, and costs a hash table lookup per emitted key otherwise.
+    ///
+    /// Default: false
+    pub fn set_intern_keys(mut self, intern: bool) -> MRParameters {
+        self.intern_keys = intern;
+        self
+    }
+
+    /// Sets how keys are written to intermediate files, which shrinks them considerably when
+    /// keys are long compared to values, or few distinct keys are emitted many times:
+    ///
+    /// * With `KeyEncoding::Runs`, as intermediate files are sorted, a key is written once per
+    ///   run of records with that key, and the following records of the run refer to it (see
+    ///   `formats::writelog::encode_key_ref()`). The first record after a sync marker always
+    ///   carries its key, so that reading can start at sync markers, index offsets and segments.
+    /// * With `KeyEncoding::Dictionary`, the distinct keys of every intermediate file are written
+    ///   to a sidecar (`<file>.dict`), and every record refers to its key by index (see
+    ///   `formats::writelog::encode_key_index()`), taking no key bytes at all. Reduce partitions
+    ///   load the dictionary of every file they read, so it should be small, i.e. the job should
+    ///   emit a modest number of distinct keys.
+    ///
+    /// Reduce partitions read all encodings regardless of this setting, but readers of older
+    /// versions can't read encoded files.
+    ///
+    /// Default: KeyEncoding::Plain
+    pub fn set_intermediate_key_encoding(mut self, encoding: KeyEncoding) -> MRParameters {
+        self.intermediate_key_encoding = encoding;
+        self
+    }
+
+    /// Drops duplicate output lines as they are written, e.g. for idempotent jobs re-run over
+    /// overlapping inputs. Lines are compared by their 64 bit hashes, which are kept for a window
+    /// of the last n lines; duplicates further apart than that are still written. The number of
+    /// dropped lines is counted in `JobStats::output_duplicates`.
+    ///
+    /// Default: OutputDedup::Off
+    pub fn set_output_dedup(mut self, dedup: OutputDedup) -> MRParameters {
+        self.output_dedup = dedup;
+        self
+    }
+
+    /// Guards against mappers that accidentally explode their output, e.g. by cross-producting
+    /// a bad input line into millions of pairs: Beyond `limit` values emitted for one input
+    /// record, values are sampled or the job fails. Values emitted by `Mapper::finish()` aren't
+    /// limited. Samples are drawn deterministically, depending on `set_shard_seed()`.
+    ///
+    /// Default: EmitLimit::Off
+    pub fn set_emit_limit(mut self, limit: EmitLimit) -> MRParameters {
+        self.emit_limit = limit;
+        self
+    }
+
+    /// If not 0, every map partition writes a uniform random sample of this many of its
+    /// intermediate pairs (as `key<TAB>value` lines) to
+    /// `<reduce_output_shard_prefix>sample-map.<partition>` once it has finished, and every
+    /// reduce partition a sample of its output records to
+    /// `<reduce_output_shard_prefix>sample.<shard>`. This allows inspecting the data of a
+    /// long-running job while it is still running. Samples depend on `set_shard_seed()`.
+    ///
+    /// Default: 0
+    pub fn set_sample_output(mut self, records: usize) -> MRParameters {
+        self.sample_output = records;
+        self
+    }
+
+    /// If not 0, the reduce phase finds the `top` keys with the most records and with the most
+    /// bytes (see `histogram::KeyHistogram`). They are returned in `JobStats::key_histogram` and
+    /// written to `<reduce_output_shard_prefix>key_histogram`, which helps with diagnosing skewed
+    /// jobs.
+    ///
+    /// Default: 0
+    pub fn set_key_histogram(mut self, top: usize) -> MRParameters {
+        self.key_histogram = top;
+        self
+    }
+
+    /// The format of the reduce outputs, for reading them back with
+    /// `formats::output::read_reduce_outputs()`. It must match the SinkGenerator that wrote them;
+    /// by default, it is detected from the contents of every shard.
+    ///
+    /// Default: OutputFormat::Auto
+    pub fn set_reduce_output_format(mut self, format: OutputFormat) -> MRParameters {
+        self.reduce_output_format = format;
+        self
+    }
+
+    /// Whether `formats::output::read_reduce_outputs()` merges the output shards in dictionary
+    /// order instead of concatenating them in shard order.
+    ///
+    /// Default: false
+    pub fn set_merge_reduce_outputs(mut self, merge: bool) -> MRParameters {
+        self.merge_reduce_outputs = merge;
+        self
+    }
+
+    /// The map phase writes a sync marker (see `formats::writelog::SYNC_MARKER`) into the
+    /// intermediate files whenever this many bytes have been written since the last marker.
+    /// Readers recovering from a corrupt record continue at the next marker; a smaller interval
+    /// means that less data is lost per corrupt record. 0 disables sync markers.
+    ///
+    /// Default: 1 MiB
+    pub fn set_intermediate_sync_interval(mut self, bytes: u64) -> MRParameters {
+        self.intermediate_sync_interval = bytes;
+        self
+    }
+
+    /// If this is set to true, reduce partitions that encounter a corrupt or truncated record in
+    /// an intermediate file skip forward to the next sync marker (see
+    /// `set_intermediate_sync_interval()`) and continue, instead of panicking. The skipped byte
+    /// ranges are logged.
+    ///
+    /// Default: false
+    pub fn set_recover_intermediates(mut self, recover: bool) -> MRParameters {
+        self.recover_intermediates = recover;
+        self
+    }
+
+    /// Tags the intermediate files written by the map phase with the version of the value
+    /// encoding (see `formats::writelog::VERSION_MARKER`). Intermediate files retained by
+    /// long-lived pipelines (see `MRController::run_incremental()`) may have been written with
+    /// an older encoding; their values are converted with the decoders registered by
+    /// `add_value_decoder()` before they reach the reducer. Files without tag have version 0.
+    ///
+    /// Default 0 (no tag)
+    pub fn set_intermediate_value_version(mut self, version: u32) -> MRParameters {
+        self.intermediate_value_version = version;
+        self
+    }
+
+    /// Registers a decoder converting the values of intermediate files with value version
+    /// `version` to the current encoding (see `set_intermediate_value_version()`). The reduce
+    /// phase panics on files of a version other than the current one without decoder.
+    pub fn add_value_decoder(mut self, version: u32, decoder: ValueDecoderF) -> MRParameters {
+        self.value_decoders.retain(|&(v, _)| v != version);
+        self.value_decoders.push((version, decoder));
+        self
+    }
+
+    /// Determines how intermediate files and the outputs of sinks writing files (lines,
+    /// WriteLogs, tables) are made durable once they are complete. Without syncing, completed
+    /// shards may be truncated after a power loss; syncing costs time, especially on spinning
+    /// disks. Errors while flushing or syncing are logged.
+    ///
+    /// Default: Durability::FlushOnClose
+    pub fn set_durability(mut self, durability: Durability) -> MRParameters {
+        self.durability = durability;
+        self
+    }
+
+    /// Determines how transient errors while writing and reading intermediate files and the
+    /// outputs of sinks writing files are retried (see `formats::retry`), e.g.
+    /// `IoRetry::default().set_no_space_wait(Duration::from_secs(60))` to wait for the cleanup
+    /// of another job when the disk is full.
+    ///
+    /// Default: IoRetry::default() (EAGAIN is retried 5 times; a full disk fails right away)
+    pub fn set_io_retry(mut self, retry: IoRetry) -> MRParameters {
+        self.io_retry = retry;
+        self
+    }
+
+    /// Jobs mark their intermediate location with the ID of the running process while the map
+    /// phase writes to it (see `phases::output::run_marker_name()`). If a job finds the marker
+    /// of a process that isn't running anymore, the intermediate files at the location were left
+    /// behind by a crashed run; this determines what happens to them (see
+    /// `controller::cleanup_stale()`).
+    ///
+    /// Default: StaleIntermediates::Remove
+    pub fn set_stale_intermediates(mut self, policy: StaleIntermediates) -> MRParameters {
+        self.stale_intermediates = policy;
+        self
+    }
+
+    /// Sets a predicate that is applied to the intermediate records while they are merged in the
+    /// reduce phase. Records for which it returns false are dropped (and counted in the
+    /// `JobStats`); this allows e.g. dropping blacklisted keys without changing mapper or reducer
+    /// code.
+    ///
+    /// Default: None (all records are passed to the reducer)
+    pub fn set_shuffle_filter(mut self, filter: FilterF) -> MRParameters {
+        self.shuffle_filter = Some(filter);
+        self
+    }
+
+    /// Restricts the reduce phase to the intermediate records whose keys match `filter`. Unlike
+    /// the shuffle filter, this is checked while reading the intermediate files, before the
+    /// records are deserialized; skipped records are therefore not counted in the `JobStats`.
+    ///
+    /// Default: None (all keys are reduced)
+    pub fn set_reduce_key_filter(mut self, filter: KeyFilter) -> MRParameters {
+        self.reduce_key_filter = Some(filter);
+        self
+    }
+
+    /// Sets a function that serializes the results emitted by the reducer before they are written
+    /// to the output, e.g. as JSON or with a custom separator. It is called with the key of the
+    /// group being reduced (empty for results emitted by `Reducer::finish()`) and the emitted
+    /// string; deduplication (see `set_output_dedup()`) applies to the emitted strings.
+    ///
+    /// Default: None (emitted strings are written unchanged)
+    pub fn set_output_formatter(mut self, formatter: OutputFormatterF) -> MRParameters {
+        self.output_formatter = Some(formatter);
+        self
+    }
+
+    /// Splits the output of every reduce partition by a label computed from each written record
+    /// (without its trailing newline): The records labelled `<label>` of the output
+    /// `<dir>/<file>` are written to `<dir>/<label>/<file>`, e.g. `output/date=2024-05-01/part-0`
+    /// (see `formats::partitioned`). At most `max_open` files are kept open per reduce partition
+    /// (0: derived from the limit on open files), so the output format must support appending.
+    /// Partitioned outputs are not registered in a catalog, and can't be read back with
+    /// `formats::output::read_reduce_outputs()`.
+    ///
+    /// Default: None (every reduce partition writes a single file)
+    pub fn set_output_partitioning(mut self,
+                                   label: OutputPartitionF,
+                                   max_open: usize)
+                                   -> MRParameters {
+        self.output_partitioning = Some((label, max_open));
+        self
+    }
+
+    /// Splits the output of every reduce partition by key: The results of the keys that `route`
+    /// maps to `<route>` are written to `<output>-<route>` instead of the partition's output,
+    /// e.g. `output_0-2024-05-01` (see `formats::partitioned`); the results of keys routed to an
+    /// empty string, and those emitted by `Reducer::finish()`, stay in the output. The routed
+    /// files are listed in `OutputShard::routes`. At most `max_open` files are kept open per
+    /// reduce partition (0: derived from the limit on open files), so the output format must
+    /// support appending. Routed files aren't limited in size or compressed.
+    ///
+    /// Default: None (all results are written to the output of their partition)
+    pub fn set_output_routing(mut self, route: OutputRouteF, max_open: usize) -> MRParameters {
+        self.output_routing = Some((route, max_open));
+        self
+    }
+
+    /// Merges the output shard written by the previous run into the input of every reduce
+    /// partition before the shard is overwritten: Its lines are parsed into records with
+    /// `parse`, and their values are tagged (see `warm_start`). Previous outputs older than
+    /// `max_age` (if given) are ignored. Applies to the outputs of reduce partitions, not to
+    /// their routed or partitioned files.
+    ///
+    /// Default: None (reduce partitions only read the intermediate files)
+    pub fn set_warm_start(mut self,
+                          parse: OutputParseF,
+                          max_age: Option<Duration>)
+                          -> MRParameters {
+        self.warm_start = Some((parse, max_age));
+        self
+    }
+
+    /// Limits the bytes written to every reduce output; once an output would exceed `bytes`,
+    /// `policy` applies (see `formats::limited`). Rotated part files are listed in
+    /// `OutputShard::parts`. The limit doesn't apply to partitioned outputs (see
+    /// `set_output_partitioning()`).
+    ///
+    /// Default: None (outputs are unlimited)
+    pub fn set_output_size_limit(mut self, bytes: u64, policy: OutputLimitPolicy) -> MRParameters {
+        self.output_size_limit = Some((bytes, policy));
+        self
+    }
+
+    /// Compresses every reduce output once it is complete, to `<output>.gz` or `<output>.zst`
+    /// (see `formats::compressed`); `OutputShard::path` names the compressed file, and
+    /// `formats::output::read_reduce_outputs()` decompresses it. This is independent of how
+    /// intermediate files are stored. Partitioned outputs (see `set_output_partitioning()`)
+    /// aren't compressed, and the `Compress` policy of `set_output_size_limit()` has no effect.
+    ///
+    /// Requires the `flate2` feature for `Gzip`, and the `zstd` feature for `Zstd`.
+    ///
+    /// Default: OutputCompression::None
+    pub fn set_output_compression(mut self, compression: OutputCompression) -> MRParameters {
+        if !compression.is_supported() {
+            panic!("{:?} compression of outputs isn't supported by this build", compression);
+        }
+        self.output_compression = compression;
+        self
+    }
+
+    /// Attaches a metrics registry to the job; the job reports its progress and statistics to
+    /// it. The same registry can be attached to several jobs.
+    ///
+    /// Default: None
+    pub fn set_metrics(mut self, registry: MetricsRegistry) -> MRParameters {
+        self.metrics = Some(registry);
+        self
+    }
+
+    /// Accounts for the memory held by the map partitions and in-memory shuffle buffers of the
+    /// job in `budget`, which can be shared by several jobs: When the budget is exceeded, shuffle
+    /// buffers spill and map partitions wait for memory to be released, and a map partition
+    /// exceeding the budget alone fails instead of the process running out of memory (see
+    /// `memory`). The held memory is reported to the metrics registry of the job.
+    ///
+    /// Default: None (memory isn't accounted)
+    pub fn set_memory_budget(mut self, budget: MemoryBudget) -> MRParameters {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Keeps the intermediate files of the job (and its outputs, if `files` says so) in `store`
+    /// instead of on the local disk, where they are only held while they are written or read
+    /// (see `object_store`). Can't be combined with multiplexed intermediates (see
+    /// `set_multiplexed_intermediates()`), which are read by all reduce partitions.
+    ///
+    /// Default: None (all files are kept locally)
+    pub fn set_object_store(mut self,
+                            store: Arc<dyn ObjectStore>,
+                            files: StoredFiles)
+                            -> MRParameters {
+        self.object_store = Some((store, files));
+        self
+    }
+
+    /// Registers the outputs of the job in `catalog` when the job has finished: the reduce
+    /// outputs as the job name (see `set_job_name()`), and the intermediate files, if they are
+    /// kept, as `<job name>.intermediates`. Outputs named by a template (see
+    /// `set_output_name_template()`) can't be described by a manifest and aren't registered.
+    ///
+    /// Default: None
+    pub fn set_catalog(mut self, catalog: Catalog) -> MRParameters {
+        self.catalog = Some(catalog);
+        self
+    }
+
+    /// Runs the map and reduce partitions of the job on the threads of `executor`, which can be
+    /// shared with other jobs, instead of starting threads for every phase. At most
+    /// `max_concurrency` partitions of the job run at the same time (0: no limit besides
+    /// `mappers`/`reducers` and the executor's threads); threads are shared fairly between the
+    /// jobs using the executor. The thread niceness of the job is ignored (see
+    /// `ExecutorHandle::new()`). Jobs shuffling in memory (see `set_in_memory_shuffle()`) still
+    /// start their own threads, as their mappers and reducers must run at the same time.
+    ///
+    /// Default: None (threads are started for every phase)
+    pub fn set_executor(mut self,
+                        executor: ExecutorHandle,
+                        max_concurrency: usize)
+                        -> MRParameters {
+        self.executor = Some(executor);
+        self.executor_job_cap = max_concurrency;
+        self
+    }
+
+    /// Sets the configuration value `key`, which mappers and reducers can read at run time (see
+    /// `JobConfig`). Values are stored as strings and parsed when they are read; setting a key
+    /// again replaces its value.
+    ///
+    /// Default: no values
+    pub fn set_config<V: ToString>(mut self, key: &str, value: V) -> MRParameters {
+        self.config.set(String::from(key), value.to_string());
+        self
+    }
+
+    /// Sets the seed of the hash function used by `StableSharder::from_params()`. Runs using the
+    /// same seed (and number of reducers) assign every key to the same shard, independent of the
+    /// Rust version or platform; this is required when intermediate files of different runs are
+    /// combined.
+    ///
+    /// Default: 0
+    pub fn set_shard_seed(mut self, seed: u64) -> MRParameters {
+        self.shard_seed = seed;
+        self
+    }
+
+    /// Sets the policy for malformed records: records that input iterators can't decode (if
+    /// they were given `params.malformed`, e.g. with `LinesReader::handle_malformed()`) and
+    /// records rejected by mappers or reducers. Their number is reported in
+    /// `JobStats::records_malformed`.
+    ///
+    /// Default: MalformedPolicy::Skip
+    pub fn set_malformed_policy(mut self, policy: MalformedPolicy) -> MRParameters {
+        self.malformed = MalformedHandler::new(policy);
+        self
+    }
+
+    /// Limits input records (key and value) to `max_bytes` bytes; larger ones are handled
+    /// according to `policy`. Map partitions check every input record before sorting it, which
+    /// works with all input formats. As a line has to be read completely to become a record,
+    /// `LinesReader`s should be limited as well (see `LinesReader::limit_record_size()`), so
+    /// that a huge line doesn't have to fit into memory.
+    ///
+    /// Default: None (unlimited)
+    pub fn set_max_record_size(mut self,
+                               max_bytes: usize,
+                               policy: OversizedRecords)
+                               -> MRParameters {
+        self.max_record_size = Some((max_bytes, policy));
+        self
+    }
+
+    /// Sets what happens with input lines that aren't valid UTF-8 when the controller reads text
+    /// files, e.g. in `MRController::run_total_order()` (see `LinesReader::set_invalid_utf8()`).
+    /// Skipped lines are passed to the malformed policy.
+    ///
+    /// Default: InvalidUtf8::Skip
+    pub fn set_invalid_utf8(mut self, invalid_utf8: InvalidUtf8) -> MRParameters {
+        self.invalid_utf8 = invalid_utf8;
+        self
+    }
+
+    /// For testing: Injects the faults set up in `injector` into the partitions of the job.
+    ///
+    /// Default: None
+    pub fn set_fault_injector(mut self, injector: FaultInjector) -> MRParameters {
+        self.fault_injector = Some(injector);
+        self
+    }
+
     /// For internal use: Sets the ID of the executing data chunk (for file naming etc.)
     ///
     pub fn set_shard_id(mut self, n: usize) -> MRParameters {
@@ -122,3 +1359,73 @@ impl MRParameters {
         self
     }
 }
+
+/// Returns the memory available for starting new applications in bytes, if it is known.
+#[cfg(feature = "sysinfo")]
+fn available_memory() -> Option<u64> {
+    let mut system = self::sysinfo::System::new();
+    system.refresh_memory();
+    match system.available_memory() {
+        0 => None,
+        bytes => Some(bytes),
+    }
+}
+
+#[cfg(not(feature = "sysinfo"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MRParameters, NameTemplate, Profile};
+
+    #[test]
+    fn test_auto_tune() {
+        const MIB: u64 = 1024 * 1024;
+        let params = MRParameters::new().auto_tune(32, Some(64 * 1024 * MIB), None);
+        assert_eq!((params.mappers, params.reducers), (32, 32));
+        // 32 GiB for 65 partitions.
+        assert_eq!(params.map_partition_size as u64, 32 * 1024 * MIB / 65);
+
+        // Small inputs are spread over all mappers, but only a few shards.
+        let params = MRParameters::new().auto_tune(32, Some(64 * 1024 * MIB), Some(320 * MIB));
+        assert_eq!((params.mappers, params.reducers), (32, 5));
+        assert_eq!(params.map_partition_size as u64, 10 * MIB);
+
+        // Without knowing the memory, the default partition size is kept.
+        let params = MRParameters::new().auto_tune(2, None, None);
+        assert_eq!((params.mappers, params.reducers), (2, 2));
+        assert_eq!(params.map_partition_size, MRParameters::new().map_partition_size);
+        assert!(MRParameters::auto(None).mappers >= 1);
+    }
+
+    #[test]
+    fn test_name_template() {
+        let template: NameTemplate = "{prefix}{job}/part-{shard:05}.txt".parse().unwrap();
+        assert_eq!(template.render("out/", "wordcount", 7), "out/wordcount/part-00007.txt");
+        let template: NameTemplate = "{shard}_{shard:02}".parse().unwrap();
+        assert_eq!(template.render("", "", 3), "3_03");
+
+        assert!("out_{shard".parse::<NameTemplate>().is_err());
+        assert!("out_{shard}}".parse::<NameTemplate>().is_err());
+        assert!("out_{mapper}.{shard}".parse::<NameTemplate>().is_err());
+        assert!("out_{shard:5}".parse::<NameTemplate>().is_err());
+        assert!("{prefix}{job}".parse::<NameTemplate>().is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!("laptop-8gb".parse(), Ok(Profile::Laptop8GB));
+        assert_eq!("Server-512GB".parse(), Ok(Profile::Server512GB));
+        assert!("desktop".parse::<Profile>().is_err());
+        for p in [Profile::Laptop8GB, Profile::Workstation64GB, Profile::Server512GB].iter() {
+            assert_eq!(p.name().parse(), Ok(*p));
+        }
+
+        let params = MRParameters::from_profile(Profile::Workstation64GB).set_concurrency(8, 2);
+        assert_eq!((params.mappers, params.reducers), (8, 2));
+        assert_eq!(params.map_partition_size, 256 * 1024 * 1024);
+        assert_eq!(params.key_buffer_size, 1024);
+    }
+}
@@ -3,14 +3,54 @@
 
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, btree_map};
+use std::fs;
 use std::io::Write;
+use std::mem;
+use std::time::Instant;
+use std::vec;
 
-use phases::output::SinkGenerator;
+use formats::bloom::BloomFilter;
+use formats::writelog::{MAX_KEY_INDEX, SYNC_MARKER, WriteLogWriter, encode_key_index,
+                        encode_key_ref, encode_record, encode_version_marker, framed_length};
+use phases::output::{OutputGuard, SinkGenerator, encode_segment_footer, map_bloom_name,
+                     map_dictionary_name, map_index_name, map_multiplexed_name, map_output_name,
+                     map_sample_name};
 use mapreducer::{Mapper, Sharder};
-use parameters::MRParameters;
+use formats::util::truncate_str;
+use memory::MemoryAccount;
+use parameters::{EmitLimit, KeyEncoding, MRParameters, MapInputSort, OversizedRecords};
+use arena::{ArenaStr, StrArena};
 use record_types::{Record, MEmitter};
-use sort::DictComparableString;
+use sampling::Reservoir;
+use testing::FaultPhase;
+use sort::{DictComparableString, dict_string_compare};
+use trace::{self, Step};
+
+/// The input records of a map partition, sorted by key with either structure (see
+/// `MapInputSort`).
+enum SortedInput {
+    Tree(btree_map::IntoIter<DictComparableString, String>),
+    Vec(vec::IntoIter<Record>),
+}
+
+impl Iterator for SortedInput {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        match *self {
+            SortedInput::Tree(ref mut records) => {
+                records.next().map(|(key, value)| {
+                    Record {
+                        key: key.unwrap(),
+                        value,
+                    }
+                })
+            }
+            SortedInput::Vec(ref mut records) => records.next(),
+        }
+    }
+}
 
 /// This is the base of the mapping phase. It contains an input
 /// and intermediary input and output forms.
@@ -26,8 +66,16 @@ pub struct MapPartition<M: Mapper,
     params: MRParameters,
     input: MapInput,
     sink: SinkGen,
-    sorted_input: BTreeMap<DictComparableString, String>,
-    sorted_output: BTreeMap<DictComparableString, Vec<String>>,
+    sorted_input: SortedInput,
+    // Emitted (key,value) pairs, stored in the emitter's arena; sorted before being written.
+    output: Vec<(ArenaStr, ArenaStr)>,
+    // Used for all records of the partition; its arena holds the keys and values of all pairs
+    // emitted in the partition.
+    emitter: MEmitter,
+    // Accounts for the memory held by the partition (see `MRParameters::set_memory_budget()`).
+    memory: Option<MemoryAccount>,
+    // Bytes of the input records not mapped yet.
+    input_bytes: usize,
 }
 
 impl<M: Mapper, S: Sharder, MapInput: Iterator<Item=Record>,
@@ -38,67 +86,125 @@ impl<M: Mapper, S: Sharder, MapInput: Iterator<Item=Record>,
                 sharder: S,
                 output: SinkGen)
                 -> MapPartition<M, S, MapInput, SinkGen> {
+        let emitter = MEmitter::for_job(&params);
+        let memory = MemoryAccount::for_job(&params, format!("map #{}", params.shard_id));
         MapPartition {
             m: mapper,
             sharder: sharder,
             params: params,
             input: input,
             sink: output,
-            sorted_input: BTreeMap::new(),
-            sorted_output: BTreeMap::new(),
+            sorted_input: SortedInput::Vec(Vec::new().into_iter()),
+            output: Vec::new(),
+            emitter,
+            memory,
+            input_bytes: 0,
         }
     }
     pub fn _run(mut self) {
-        self.sort_input();
-        self.do_map();
+        self.inject_faults();
+        {
+            let _span = trace::enter(Step::Map, self.params.shard_id);
+            self.sort_input();
+            self.do_map();
+        }
         self.write_output();
+        self.write_sample();
     }
 
-/// Sorts input into the sorted_input map, moving the records on the way
-/// (so no copying happens and memory consumption stays low-ish)
+/// Sorts input into sorted_input, moving the records on the way
+/// (so no copying happens and memory consumption stays low-ish).
+/// The time taken is reported to the job's sort timings.
     fn sort_input(&mut self) {
-        loop {
-            match self.input.next() {
-                None => break,
-                Some(record) => {
-                    self.sorted_input.insert(DictComparableString::DCS(record.key), record.value);
+        let timings = self.params.map_sort_timings.clone();
+        let sort = timings.select(self.params.map_input_sort);
+        let start = Instant::now();
+        let mut records = 0;
+        self.sorted_input = match sort {
+            MapInputSort::SortedVec => {
+                let mut sorted = Vec::new();
+                for record in &mut self.input {
+                    let record = match limit_record_size(record, &self.params) {
+                        None => continue,
+                        Some(record) => record,
+                    };
+                    self.input_bytes += record.key.len() + record.value.len();
+                    sorted.push(record);
                 }
+                records = sorted.len();
+                sorted.sort_by(|a, b| dict_string_compare(&a.key, &b.key));
+                // Like inserting into the tree: the first key is kept with the last value.
+                sorted.dedup_by(|later, kept| {
+                    if dict_string_compare(&later.key, &kept.key) != Ordering::Equal {
+                        return false;
+                    }
+                    kept.value = mem::take(&mut later.value);
+                    true
+                });
+                SortedInput::Vec(sorted.into_iter())
             }
-        }
+            MapInputSort::BTree | MapInputSort::Auto => {
+                let mut sorted = BTreeMap::new();
+                for record in &mut self.input {
+                    let record = match limit_record_size(record, &self.params) {
+                        None => continue,
+                        Some(record) => record,
+                    };
+                    self.input_bytes += record.key.len() + record.value.len();
+                    records += 1;
+                    sorted.insert(DictComparableString::DCS(record.key), record.value);
+                }
+                SortedInput::Tree(sorted.into_iter())
+            }
+        };
+        timings.record(sort, records, start.elapsed());
     }
 
 /// Executes the mapping phase.
     fn do_map(&mut self) {
-        let mut key_buffer = Vec::with_capacity(self.params.key_buffer_size);
-
-        loop {
-            for k in self.sorted_input.keys().take(self.params.key_buffer_size) {
-                key_buffer.push(k.clone())
+        let mut input = mem::replace(&mut self.sorted_input,
+                                     SortedInput::Vec(Vec::new().into_iter()));
+        let mut mapped = 0;
+        while !self.params.termination.is_terminated() {
+            let record = match input.next() {
+                None => break,
+                Some(record) => record,
+            };
+            self.input_bytes -= record.key.len() + record.value.len();
+            self.m.map(&mut self.emitter, record);
+            self.insert_result();
+            // Memory is accounted for after every key_buffer_size keys.
+            mapped += 1;
+            if mapped % self.params.key_buffer_size.max(1) == 0 {
+                self.account_memory();
             }
+        }
+        self.account_memory();
 
-            for k in &key_buffer[..] {
-                let val;
-                match self.sorted_input.remove(k) {
-                    None => continue,
-                    Some(v) => val = v,
-                }
-                let mut e = MEmitter::new();
-                self.m.map(&mut e,
-                            Record {
-                                key: k.clone().unwrap(),
-                                value: val,
-                            });
-                self.insert_result(e);
-            }
+        // Values emitted when finishing don't belong to a single input record.
+        self.emitter._set_emit_limit(EmitLimit::Off);
+        self.m.finish(&mut self.emitter);
+        self.insert_result();
+        self.account_memory();
+    }
 
-            if key_buffer.len() < self.params.key_buffer_size {
-                break;
-            }
-            key_buffer.clear();
+    /// Accounts for the input not mapped yet and the emitted pairs, waiting while the memory
+    /// budget of the job is exceeded (see `memory::MemoryAccount::wait()`).
+    fn account_memory(&mut self) {
+        let held = self.input_bytes + self.emitter._arena().capacity() +
+                   self.output.capacity() * mem::size_of::<(ArenaStr, ArenaStr)>();
+        if let Some(ref mut memory) = self.memory {
+            memory.set(held);
+            memory.wait();
         }
     }
 
     fn setup_output(&mut self) -> Vec<SinkGen::Sink> {
+        if self.params.multiplexed_intermediates {
+            let name = map_multiplexed_name(&self.params.map_output_location,
+                                            self.params.shard_id);
+            return vec![self.sink.new_output(&name)];
+        }
 // Set up sharded outputs.
         let mut outputs = Vec::new();
 
@@ -112,44 +218,379 @@ impl<M: Mapper, S: Sharder, MapInput: Iterator<Item=Record>,
         outputs
     }
 
+    fn setup_index(&self) -> Vec<WriteLogWriter<fs::File>> {
+        let mut indices = Vec::new();
+
+        for i in 0..self.params.reducers {
+            let name = map_index_name(&map_output_name(&self.params.map_output_location,
+                                                       self.params.shard_id,
+                                                       i));
+            let writer = WriteLogWriter::<fs::File>::new_to_file(&name, false)
+                .and_then(|w| w.set_durability(self.params.durability))
+                .map(|w| w.set_io_retry(self.params.io_retry));
+            match writer {
+                Err(e) => panic!("couldn't open map output index {}: {}", name, e),
+                Ok(w) => indices.push(w),
+            }
+        }
+        indices
+    }
+
+    /// Like `_run()`, but returns the sorted intermediate records of every reduce shard instead
+    /// of writing them to intermediate files (see `MRParameters::set_in_memory_shuffle()`).
+    /// Records not matching `params.reduce_key_filter` are dropped right away.
+    pub fn _run_in_memory(mut self) -> Vec<Vec<Record>> {
+        self.inject_faults();
+        {
+            let _span = trace::enter(Step::Map, self.params.shard_id);
+            self.sort_input();
+            self.do_map();
+        }
+        self.sort_output();
+        self.write_sample();
+
+        let _span = trace::enter(Step::ShuffleWrite, self.params.shard_id);
+        let arena = self.emitter._arena();
+        let mut runs = vec![Vec::new(); self.params.reducers];
+        let mut last_key = None;
+        let mut shard = 0;
+        let mut key_buf = String::new();
+        for &(key, v) in self.output.iter() {
+            let (k, v) = (arena.get(key), arena.get(v));
+            if !same_key(arena, last_key, key) {
+                last_key = Some(key);
+                key_buf.clear();
+                key_buf.push_str(k);
+                shard = self.sharder.shard_bytes(self.params.reducers, k.as_bytes());
+            }
+            if let Some(ref filter) = self.params.reduce_key_filter {
+                if !filter.matches(k) {
+                    continue;
+                }
+            }
+            runs[shard].push(Record {
+                key: key_buf.clone(),
+                value: String::from(v),
+            });
+        }
+        runs
+    }
+
+    /// Sorts the emitted pairs by key, keeping the values of a key in the order they were
+    /// emitted (the sort is stable; see `MRParameters::set_stable_merge()`). With
+    /// case-insensitive grouping, keys equal in dictionary order (e.g. "Abc" and "abc") are one
+    /// key of the partition, which is written with the spelling emitted first; otherwise, such
+    /// keys are ordered bytewise and every spelling keeps its own key.
+    fn sort_output(&mut self) {
+        if !self.params.sorts_intermediates() {
+            return;
+        }
+        let _span = trace::enter(Step::Sort, self.params.shard_id);
+        let arena = self.emitter._arena();
+        if !self.params.reduce_group_insensitive {
+            self.output.sort_by(|&(a, _), &(b, _)| {
+                arena.dict_compare(a, b).then_with(|| arena.get(a).cmp(arena.get(b)))
+            });
+            return;
+        }
+        self.output.sort_by(|&(a, _), &(b, _)| arena.dict_compare(a, b));
+        let mut first = None;
+        for pair in self.output.iter_mut() {
+            match first {
+                Some(key) if arena.dict_compare(key, pair.0) == Ordering::Equal => pair.0 = key,
+                _ => first = Some(pair.0),
+            }
+        }
+    }
+
+    /// Returns the names of the files written by `write_output()`.
+    fn output_files(&self) -> Vec<String> {
+        let location = &self.params.map_output_location;
+        let dictionary = self.params.intermediate_key_encoding == KeyEncoding::Dictionary;
+        let mut files = Vec::new();
+        if self.params.multiplexed_intermediates {
+            let name = map_multiplexed_name(location, self.params.shard_id);
+            if dictionary {
+                files.push(map_dictionary_name(&name));
+            }
+            files.push(name);
+        }
+        for i in 0..self.params.reducers {
+            let name = map_output_name(location, self.params.shard_id, i);
+            if self.params.intermediate_key_index {
+                files.push(map_index_name(&name));
+            }
+            if self.params.intermediate_bloom_bits > 0 {
+                files.push(map_bloom_name(&name));
+            }
+            if !self.params.multiplexed_intermediates {
+                if dictionary {
+                    files.push(map_dictionary_name(&name));
+                }
+                files.push(name);
+            }
+        }
+        files
+    }
+
     fn write_output(&mut self) {
+        // Partially written intermediate files are removed if writing fails.
+        let guard = OutputGuard::new(self.output_files());
         let mut outputs = self.setup_output();
+        // Index sidecars and the current offsets in the intermediate files. Intermediate files
+        // are always WriteLogs, so the offsets can be calculated from the record lengths.
+        let mut indices = if self.params.intermediate_key_index {
+            self.setup_index()
+        } else {
+            Vec::new()
+        };
+        let multiplexed = self.params.multiplexed_intermediates;
+        let mut offsets = vec![0; outputs.len()];
+        if self.params.intermediate_value_version > 0 {
+            let marker = encode_version_marker(self.params.intermediate_value_version);
+            for (out, offset) in outputs.iter_mut().zip(offsets.iter_mut()) {
+                if let Err(e) = out.write(&marker) {
+                    panic!("couldn't write map output: {}", e);
+                }
+                *offset += framed_length(marker.len());
+            }
+        }
+        self.sort_output();
+        let _span = trace::enter(Step::ShuffleWrite, self.params.shard_id);
+        // With multiplexed output, the pairs are written shard by shard (in key order within
+        // every shard); `order` holds the indices of the pairs in the order they are written.
+        let order = if multiplexed {
+            self.shard_order()
+        } else {
+            (0..self.output.len()).collect()
+        };
+        let arena = self.emitter._arena();
+        // The segment of every shard in the multiplexed file.
+        let mut segments = vec![None; self.params.reducers];
 
-        for (k, vs) in self.sorted_output.iter() {
-            let shard = self.sharder.shard(self.params.reducers, k.as_ref());
+        let mut last_key = None;
+        let mut shard = 0;
+        let mut frame = Vec::new();
+        // Bytes written since the last sync marker, per intermediate file.
+        let mut since_sync = vec![0; outputs.len()];
+        let sync_interval = self.params.intermediate_sync_interval;
+        // The distinct keys of every intermediate file, if Bloom filters are written.
+        let mut shard_keys = vec![Vec::new(); self.params.reducers];
+        let bloom_bits = self.params.intermediate_bloom_bits;
+        // The key of the last record written to every intermediate file, which the following
+        // records of the key refer to, and the key dictionaries of the files (see
+        // `MRParameters::set_intermediate_key_encoding()`).
+        let key_encoding = self.params.intermediate_key_encoding;
+        let mut written_keys = vec![None; outputs.len()];
+        let mut dictionaries = vec![Vec::new(); outputs.len()];
+        for i in order {
+            let (key, v) = self.output[i];
+            let (k, v) = (arena.get(key), arena.get(v));
+            if !same_key(arena, last_key, key) {
+                last_key = Some(key);
+                shard = self.sharder.shard_bytes(self.params.reducers, k.as_bytes());
+                if bloom_bits > 0 {
+                    shard_keys[shard].push(key);
+                }
 
-            for v in vs {
-                let r1 = outputs[shard].write(k.as_ref().as_bytes());
-                match r1 {
-                    Err(e) => panic!("couldn't write map output: {}", e),
-                    Ok(_) => (),
+                if !indices.is_empty() {
+                    let offset = offsets[if multiplexed { 0 } else { shard }].to_string();
+                    if let Err(e) = indices[shard].write_record(k.as_bytes(), offset.as_bytes()) {
+                        panic!("couldn't write map output index: {}", e);
+                    }
                 }
-                let r2 = outputs[shard].write(v.as_bytes());
-                match r2 {
-                    Err(e) => panic!("couldn't write map output: {}", e),
-                    Ok(_) => (),
+            }
+            let out = if multiplexed { 0 } else { shard };
+            if segments[shard].is_none() {
+                segments[shard] = Some((offsets[out], offsets[out]));
+            }
+
+            if sync_interval > 0 && since_sync[out] >= sync_interval {
+                if let Err(e) = outputs[out].write(&SYNC_MARKER) {
+                    panic!("couldn't write map output: {}", e);
                 }
+                offsets[out] += framed_length(SYNC_MARKER.len());
+                since_sync[out] = 0;
+                written_keys[out] = None;
+            }
+
+            // Key and value are written as one record; in key-only jobs, values are empty.
+            match key_encoding {
+                KeyEncoding::Runs if same_key(arena, written_keys[out], key) => {
+                    encode_key_ref(v.as_bytes(), &mut frame)
+                }
+                KeyEncoding::Plain | KeyEncoding::Runs => {
+                    encode_record(k.as_bytes(), v.as_bytes(), &mut frame);
+                    written_keys[out] = Some(key);
+                }
+                KeyEncoding::Dictionary => {
+                    let dictionary: &mut Vec<ArenaStr> = &mut dictionaries[out];
+                    if !same_key(arena, dictionary.last().cloned(), key) {
+                        dictionary.push(key);
+                    }
+                    let index = dictionary.len() - 1;
+                    if index > MAX_KEY_INDEX as usize {
+                        panic!("Too many distinct keys for the key dictionary of a map output");
+                    }
+                    encode_key_index(index as u32, v.as_bytes(), &mut frame);
+                }
+            }
+            offsets[out] += framed_length(frame.len());
+            since_sync[out] += framed_length(frame.len());
+            if let Err(e) = outputs[out].write(&frame) {
+                panic!("couldn't write map output: {}", e);
+            }
+            if let Some(ref mut segment) = segments[shard] {
+                segment.1 = offsets[out];
             }
         }
-    }
 
-    fn insert_result(&mut self, emitter: MEmitter) {
-        for r in emitter._get() {
-            let e;
-            {
-                e = self.sorted_output.remove(&DictComparableString::wrap(r.key.clone()));
+        if multiplexed {
+            let end = offsets[0];
+            let segments: Vec<_> = segments.into_iter().map(|s| s.unwrap_or((end, end))).collect();
+            if let Err(e) = outputs[0].write(&encode_segment_footer(&segments)) {
+                panic!("couldn't write map output: {}", e);
             }
+        }
+
+        // Write the last batches.
+        for out in outputs.iter_mut() {
+            if let Err(e) = out.flush() {
+                panic!("couldn't write map output: {}", e);
+            }
+        }
 
-            match e {
-                None => {
-                    self.sorted_output.insert(DictComparableString::wrap(r.key), vec![r.value]);
+        if key_encoding == KeyEncoding::Dictionary {
+            self.write_dictionaries(dictionaries);
+        }
+
+        if bloom_bits > 0 {
+            for (i, keys) in shard_keys.into_iter().enumerate() {
+                let mut filter = BloomFilter::new(keys.len(), bloom_bits);
+                for k in keys {
+                    filter.insert(arena.get(k));
                 }
-                Some(mut v) => {
-                    v.push(r.value);
-                    self.sorted_output.insert(DictComparableString::wrap(r.key), v);
+                let name = map_bloom_name(&map_output_name(&self.params.map_output_location,
+                                                           self.params.shard_id,
+                                                           i));
+                if let Err(e) = filter.save(&name) {
+                    panic!("couldn't write map output Bloom filter {}: {}", name, e);
                 }
             }
         }
+        guard.commit();
+    }
+
+    /// Writes the key dictionary of every intermediate file to its sidecar.
+    fn write_dictionaries(&self, dictionaries: Vec<Vec<ArenaStr>>) {
+        let arena = self.emitter._arena();
+        let location = &self.params.map_output_location;
+        for (i, keys) in dictionaries.into_iter().enumerate() {
+            let output = if self.params.multiplexed_intermediates {
+                map_multiplexed_name(location, self.params.shard_id)
+            } else {
+                map_output_name(location, self.params.shard_id, i)
+            };
+            let name = map_dictionary_name(&output);
+            let written = WriteLogWriter::<fs::File>::new_to_file(&name, false)
+                .and_then(|w| w.set_durability(self.params.durability))
+                .and_then(|mut w| {
+                    for k in keys {
+                        w.write_all(arena.get(k).as_bytes())?;
+                    }
+                    w.flush()
+                });
+            if let Err(e) = written {
+                panic!("couldn't write map output key dictionary {}: {}", name, e);
+            }
+        }
+    }
+
+    fn inject_faults(&self) {
+        if let Some(ref injector) = self.params.fault_injector {
+            injector.partition_started(FaultPhase::Map, self.params.shard_id);
+        }
+    }
+
+    /// Writes a sample of the emitted pairs (see `MRParameters::set_sample_output()`).
+    fn write_sample(&self) {
+        if self.params.sample_output == 0 {
+            return;
+        }
+        let arena = self.emitter._arena();
+        let seed = self.params.shard_seed ^ self.params.shard_id as u64;
+        let mut sample = Reservoir::new(self.params.sample_output, seed);
+        for &(k, v) in self.output.iter() {
+            sample.offer(|| format!("{}\t{}", arena.get(k), arena.get(v)));
+        }
+        let name = map_sample_name(&self.params);
+        if let Err(e) = sample.write_to(&name) {
+            println!("WARN: Couldn't write sample {}: {}", name, e);
+        }
+    }
+
+    /// Returns the indices of the sorted output pairs ordered by shard, keeping the key order
+    /// within every shard.
+    fn shard_order(&mut self) -> Vec<usize> {
+        let arena = self.emitter._arena();
+        let mut shards = Vec::with_capacity(self.output.len());
+        let mut last_key = None;
+        let mut shard = 0;
+        for &(key, _) in self.output.iter() {
+            let k = arena.get(key);
+            if last_key != Some(k) {
+                last_key = Some(k);
+                shard = self.sharder.shard_bytes(self.params.reducers, k.as_bytes());
+            }
+            shards.push(shard);
+        }
+        let mut order: Vec<usize> = (0..self.output.len()).collect();
+        order.sort_by_key(|&i| shards[i]);
+        order
+    }
+
+    fn insert_result(&mut self) {
+        let output = &mut self.output;
+        self.emitter._flush(|k, v| output.push((k, v)));
+    }
+}
+
+/// Applies the maximum record size of the job (see `MRParameters::set_max_record_size()`) to an
+/// input record; returns None if the record is dropped.
+fn limit_record_size(mut record: Record, params: &MRParameters) -> Option<Record> {
+    let (max, policy) = match params.max_record_size {
+        Some(limit) => limit,
+        None => return Some(record),
+    };
+    let size = record.key.len() + record.value.len();
+    if size <= max {
+        return Some(record);
+    }
+    match policy {
+        OversizedRecords::Truncate => {
+            truncate_str(&mut record.key, max);
+            let len = max - record.key.len();
+            truncate_str(&mut record.value, len);
+            Some(record)
+        }
+        OversizedRecords::Malformed => {
+            let mut original = record.key;
+            original.push('\t');
+            original.push_str(&record.value);
+            truncate_str(&mut original, max);
+            let reason = format!("record of {} bytes exceeds the maximum record size", size);
+            params.malformed.handle(original.as_bytes(), &reason);
+            None
+        }
+    }
+}
+
+/// Returns whether `key` equals `last`. Interned keys are equal if their handles are (see
+/// `MRParameters::set_intern_keys()`); other keys are compared by their contents.
+fn same_key(arena: &StrArena, last: Option<ArenaStr>, key: ArenaStr) -> bool {
+    match last {
+        None => false,
+        Some(last) => last == key || arena.get(last) == arena.get(key),
     }
 }
 
@@ -158,10 +599,12 @@ mod tests {
     use closure_mr::ClosureMapReducer;
     use formats::util::PosRecordIterator;
     use formats::lines::LinesSinkGenerator;
-    use phases::map::MapPartition;
+    use phases::map::{MapPartition, limit_record_size};
     use record_types::{MEmitter, REmitter, Record, MultiRecord};
-    use parameters::MRParameters;
+    use parameters::{MRParameters, MapInputSort, OversizedRecords};
+    use stats::MapSortTimings;
     use std::collections::LinkedList;
+    use std::time::Duration;
 
     fn mapper_func(e: &mut MEmitter, r: Record) {
         for w in r.value.split_whitespace() {
@@ -213,4 +656,128 @@ mod tests {
             // let _ = fs::remove_file(filename);
         }
     }
+
+    #[test]
+    fn test_limit_record_size() {
+        let limit = |k: &str, v: &str, params: &MRParameters| -> Option<(String, String)> {
+            let record = Record {
+                key: String::from(k),
+                value: String::from(v),
+            };
+            limit_record_size(record, params).map(|r| (r.key, r.value))
+        };
+        let pair = |k: &str, v: &str| Some((String::from(k), String::from(v)));
+        let params = MRParameters::new().set_max_record_size(3, OversizedRecords::Truncate);
+        assert_eq!(limit("k", "ab", &params), pair("k", "ab"));
+        assert_eq!(limit("k", "a\u{20ac}", &params), pair("k", "a"));
+        assert_eq!(limit("abcd", "e", &params), pair("abc", ""));
+
+        let params = MRParameters::new().set_max_record_size(3, OversizedRecords::Malformed);
+        assert_eq!(limit("k", "abc", &params), None);
+        assert_eq!(params.malformed.count(), 1);
+    }
+
+    fn identity_mapper(e: &mut MEmitter, r: Record) {
+        e.emit(r.key, r.value);
+    }
+
+    #[test]
+    fn test_map_input_sort() {
+        let input: Vec<Record> = [("b", "1"), ("A", "2"), ("a", "3"), ("c", "4"), ("b", "5")]
+            .iter()
+            .map(|&(k, v)| {
+                Record {
+                    key: String::from(k),
+                    value: String::from(v),
+                }
+            })
+            .collect();
+        let mut runs = Vec::new();
+        for sort in [MapInputSort::BTree, MapInputSort::SortedVec].iter() {
+            let params = MRParameters::new().set_concurrency(1, 1).set_map_input_sort(*sort);
+            let timings = params.map_sort_timings.clone();
+            let mp = MapPartition::_new(params,
+                                        input.clone().into_iter(),
+                                        ClosureMapReducer::new(identity_mapper, reducer_func),
+                                        ClosureMapReducer::new(identity_mapper, reducer_func),
+                                        get_output());
+            let run: Vec<(String, String)> = mp._run_in_memory()
+                .remove(0)
+                .into_iter()
+                .map(|r| (r.key, r.value))
+                .collect();
+            runs.push(run);
+            assert_eq!(timings.get().btree.records + timings.get().sorted_vec.records, 5);
+        }
+        // Of duplicate keys, the first spelling is mapped with the last value.
+        let expected: Vec<(String, String)> = [("A", "3"), ("b", "5"), ("c", "4")]
+            .iter()
+            .map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        assert_eq!(runs, vec![expected.clone(), expected]);
+    }
+
+    fn spelling_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit(String::from(w), String::from(w));
+        }
+    }
+
+    #[test]
+    fn test_map_output_key_spellings() {
+        let run_spellings = |insensitive| {
+            let input = vec![Record {
+                                 key: String::from("0"),
+                                 value: String::from("Abc b abc x ABC"),
+                             }];
+            let params = MRParameters::new()
+                .set_concurrency(1, 1)
+                .set_reduce_group_opts(1, insensitive);
+            let mp = MapPartition::_new(params,
+                                        input.into_iter(),
+                                        ClosureMapReducer::new(spelling_mapper, reducer_func),
+                                        ClosureMapReducer::new(spelling_mapper, reducer_func),
+                                        get_output());
+            mp._run_in_memory()
+                .remove(0)
+                .into_iter()
+                .map(|r| (r.key, r.value))
+                .collect::<Vec<(String, String)>>()
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect()
+        };
+        // Keys equal in dictionary order are written with the spelling emitted first, and their
+        // values keep the order in which they were emitted.
+        assert_eq!(run_spellings(true),
+                   pairs(&[("Abc", "Abc"), ("Abc", "abc"), ("Abc", "ABC"), ("b", "b"),
+                           ("x", "x")]));
+        // Grouping case-sensitively, every spelling is written as emitted.
+        assert_eq!(run_spellings(false),
+                   pairs(&[("ABC", "ABC"), ("Abc", "Abc"), ("abc", "abc"), ("b", "b"),
+                           ("x", "x")]));
+    }
+
+    #[test]
+    fn test_map_sort_selection() {
+        let timings = MapSortTimings::new();
+        assert_eq!(timings.select(MapInputSort::BTree), MapInputSort::BTree);
+        let mut tried = Vec::new();
+        for _ in 0..4 {
+            let sort = timings.select(MapInputSort::Auto);
+            let elapsed = if sort == MapInputSort::BTree { 300 } else { 100 };
+            timings.record(sort, 10, Duration::from_nanos(elapsed));
+            tried.push(sort);
+        }
+        assert_eq!(tried,
+                   vec![MapInputSort::BTree,
+                        MapInputSort::SortedVec,
+                        MapInputSort::BTree,
+                        MapInputSort::SortedVec]);
+        assert_eq!(timings.select(MapInputSort::Auto), MapInputSort::SortedVec);
+        let stats = timings.get();
+        assert_eq!(stats.selected, Some(MapInputSort::SortedVec));
+        assert_eq!((stats.btree.partitions, stats.btree.records, stats.btree.nanos), (2, 20, 600));
+        assert_eq!(stats.sorted_vec.nanos_per_record(), Some(10.0));
+    }
 }
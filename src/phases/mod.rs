@@ -1,4 +1,5 @@
 pub mod map;
 pub mod reduce;
+pub mod shuffle;
 
 pub mod output;
@@ -1,12 +1,90 @@
-use std::io;
-use formats::util::RecordReadIterator;
-use formats::writelog::WriteLogReader;
-use parameters::MRParameters;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use formats::bloom::BloomFilter;
+use formats::retry::IoRetry;
+use formats::util::KeyRangeIterator;
+use formats::writelog::{FilteredRecordReader, WriteLogReader, read_key_dictionary,
+                        read_value_version};
+use sort::dict_string_compare;
+use parameters::{Durability, MRParameters};
 
-fn map_output_name(base: &String, mapper: usize, shard: usize) -> String {
+/// Calculates the name of the intermediate file written by map partition `mapper` for reduce
+/// shard `shard`.
+pub fn map_output_name(base: &String, mapper: usize, shard: usize) -> String {
     format!("{}-{}.{}", base, mapper, shard)
 }
 
+/// Calculates the name of the multiplexed intermediate file written by map partition `mapper`
+/// (see `MRParameters::set_multiplexed_intermediates()`).
+pub fn map_multiplexed_name(base: &String, mapper: usize) -> String {
+    format!("{}-{}.mux", base, mapper)
+}
+
+/// Ends the footer of a multiplexed intermediate file.
+pub const MULTIPLEX_MAGIC: [u8; 8] = *b"LMRMUX01";
+
+/// Encodes the footer of a multiplexed intermediate file, which is written as its last WriteLog
+/// entry: The start and end offsets of the segment of every shard (as two big-endian u64 each),
+/// followed by the number of shards (big-endian u32) and `MULTIPLEX_MAGIC`.
+pub fn encode_segment_footer(segments: &[(u64, u64)]) -> Vec<u8> {
+    let mut footer = Vec::with_capacity(segments.len() * 16 + 12);
+    for &(start, end) in segments {
+        footer.extend_from_slice(&start.to_be_bytes());
+        footer.extend_from_slice(&end.to_be_bytes());
+    }
+    footer.extend_from_slice(&(segments.len() as u32).to_be_bytes());
+    footer.extend_from_slice(&MULTIPLEX_MAGIC);
+    footer
+}
+
+/// Returns the start and end offsets of the segment of shard `shard` in the multiplexed
+/// intermediate file `name`, from its footer (see `encode_segment_footer()`).
+pub fn read_segment(name: &String, shard: usize) -> io::Result<(u64, u64)> {
+    let invalid = |what: &str| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("Invalid multiplexed intermediate file {}: {}", name, what))
+    };
+    let mut f = fs::File::open(name)?;
+    let len = f.metadata()?.len();
+    if len < 12 {
+        return Err(invalid("too short"));
+    }
+    let mut tail = [0; 12];
+    f.seek(io::SeekFrom::Start(len - 12))?;
+    f.read_exact(&mut tail)?;
+    if tail[4..] != MULTIPLEX_MAGIC[..] {
+        return Err(invalid("missing footer"));
+    }
+    let shards = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]) as u64;
+    if shard as u64 >= shards || len < 12 + 16 * shards {
+        return Err(invalid(&format!("no segment for shard {} of {}", shard, shards)));
+    }
+    let mut entry = [0; 16];
+    f.seek(io::SeekFrom::Start(len - 12 - 16 * shards + 16 * shard as u64))?;
+    f.read_exact(&mut entry)?;
+    let mut start = [0; 8];
+    let mut end = [0; 8];
+    start.copy_from_slice(&entry[..8]);
+    end.copy_from_slice(&entry[8..]);
+    Ok((u64::from_be_bytes(start), u64::from_be_bytes(end)))
+}
+
+/// Calculates the name of the file that the in-memory shuffle of reduce shard `shard` spills
+/// its `n`-th run to (see `MRParameters::set_shuffle_memory_limit()`).
+pub fn shuffle_spill_name(base: &String, shard: usize, n: usize) -> String {
+    format!("{}spill-{}.{}", base, shard, n)
+}
+
+/// Calculates the name of the `n`-th bucket file of reduce shard `shard` (see
+/// `ReduceStrategy::HashBuckets`).
+pub fn hash_bucket_name(base: &String, shard: usize, n: usize) -> String {
+    format!("{}bucket-{}.{}", base, shard, n)
+}
+
 /// A type implementing SinkGenerator is used at the end of the reducer
 /// phase to write the output. Given a name, new() should return a new object
 /// that can be used to write the output of a reduce partition.
@@ -14,7 +92,7 @@ fn map_output_name(base: &String, mapper: usize, shard: usize) -> String {
 ///
 /// SinkGenerator types are used in general to determine the format of outputs; existing options
 /// are plain text files (LinesSinkGenerator) or length-prefixed binary files (WriteLogGenerator).
-pub trait SinkGenerator: Send + Clone {
+pub trait SinkGenerator: Send + Clone + 'static {
     type Sink: io::Write;
     /// Return a new intermediary file handle destined for reduce shard `shard` and requested by
     /// map shard `mapper`.
@@ -24,23 +102,397 @@ pub trait SinkGenerator: Send + Clone {
 
     /// Return a new file handle for `location`.
     fn new_output(&self, location: &String) -> Self::Sink;
+
+    /// Returns a file handle for `location` that appends to the existing file, or None if the
+    /// generator can't append to its outputs. Used by `formats::sink_pool::SinkPool` to reopen
+    /// sinks that it has closed. The default implementation returns None.
+    fn append_output(&self, location: &String) -> Option<Self::Sink> {
+        let _ = location;
+        None
+    }
+
+    /// Returns a generator whose sinks make their files durable according to `durability`. The
+    /// controller applies the job's policy (see `MRParameters::set_durability()`); generators
+    /// that don't write files ignore it.
+    fn with_durability(self, durability: Durability) -> Self {
+        let _ = durability;
+        self
+    }
+
+    /// Returns a generator whose sinks retry transient I/O errors according to `retry` (see
+    /// `MRParameters::set_io_retry()`). Generators that don't write files ignore it.
+    fn with_io_retry(self, retry: IoRetry) -> Self {
+        let _ = retry;
+        self
+    }
 }
 
-pub fn open_reduce_inputs(location: &String,
+/// Calculates the name of the index sidecar belonging to the intermediate file `name`.
+pub fn map_index_name(name: &String) -> String {
+    format!("{}.idx", name)
+}
+
+/// Calculates the name of the Bloom filter sidecar belonging to the intermediate file `name`.
+pub fn map_bloom_name(name: &String) -> String {
+    format!("{}.bloom", name)
+}
+
+/// Calculates the name of the key dictionary sidecar belonging to the intermediate file `name`
+/// (see `KeyEncoding::Dictionary`).
+pub fn map_dictionary_name(name: &String) -> String {
+    format!("{}.dict", name)
+}
+
+/// Loads the key dictionary of the intermediate file `name`, if it has one.
+pub fn load_key_dictionary(name: &String) -> Option<Arc<Vec<String>>> {
+    let dict_name = map_dictionary_name(name);
+    if !Path::new(&dict_name).exists() {
+        return None;
+    }
+    match read_key_dictionary(&dict_name) {
+        Ok(keys) => Some(Arc::new(keys)),
+        Err(e) => panic!("couldn't read key dictionary {}: {}", dict_name, e),
+    }
+}
+
+/// Loads the Bloom filters of the intermediate files at `location` destined for reduce shard
+/// `shard`. Returns None if a filter is missing or invalid, as the keys can't be checked then.
+pub fn load_bloom_filters(location: &String,
                           partitions: usize,
                           shard: usize)
-                          -> Vec<RecordReadIterator<WriteLogReader>> {
+                          -> Option<Vec<BloomFilter>> {
+    (0..partitions)
+        .map(|part| BloomFilter::load(&map_bloom_name(&map_output_name(location, part, shard))))
+        .collect::<io::Result<Vec<_>>>()
+        .ok()
+}
+
+/// Looks up the offset of the first record with a key at or after `start` in the index sidecar
+/// `index`. The index is a WriteLog of (key, offset) records sorted by key.
+fn lookup_index(index: &String, start: &String) -> io::Result<Option<u64>> {
+    let reader = WriteLogReader::new_from_file(index)?;
+    for entry in FilteredRecordReader::new(reader, None) {
+        if dict_string_compare(&entry.key, start) != Ordering::Less {
+            return match entry.value.parse() {
+                Ok(off) => Ok(Some(off)),
+                Err(_) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Invalid offset in {}", index)))
+                }
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Opens the intermediate files destined for reduce shard `shard`. If `range` is given, only
+/// the records with keys in that range are returned; if an index sidecar exists for a file, the
+/// reader seeks directly to the beginning of the range. Records not matching
+/// `params.reduce_key_filter` or one of the `join_filters` (see
+/// `FilteredRecordReader::set_join_filters()`) are skipped while reading.
+pub fn open_reduce_inputs(location: &String,
+                          partitions: usize,
+                          shard: usize,
+                          range: Option<(Option<String>, Option<String>)>,
+                          params: &MRParameters,
+                          join_filters: Vec<Arc<Vec<BloomFilter>>>)
+                          -> Vec<KeyRangeIterator<FilteredRecordReader>> {
     let mut inputs = Vec::new();
+    let (start, end) = range.unwrap_or((None, None));
 
     for part in 0..partitions {
-        let name = map_output_name(location, part, shard);
-        let wlg_reader = WriteLogReader::new_from_file(&name).unwrap();
-        inputs.push(RecordReadIterator::new(wlg_reader));
+        let shard_name = map_output_name(location, part, shard);
+        // The part of the file holding the records of the shard.
+        let (name, segment) = if params.multiplexed_intermediates {
+            let name = map_multiplexed_name(location, part);
+            match read_segment(&name, shard) {
+                Ok(segment) => (name, Some(segment)),
+                Err(e) => panic!("couldn't open map output {}: {}", name, e),
+            }
+        } else {
+            (shard_name.clone(), None)
+        };
+        let offset = match start {
+            Some(ref start) => {
+                // Index sidecars are named after the shard's intermediate file in any case.
+                match lookup_index(&map_index_name(&shard_name), start) {
+                    Ok(Some(off)) => off,
+                    // The index doesn't contain any key in the range.
+                    Ok(None) => {
+                        segment.map(|s| s.1)
+                            .unwrap_or_else(|| fs::metadata(&name).map(|m| m.len()).unwrap_or(0))
+                    }
+                    Err(_) => segment.map(|s| s.0).unwrap_or(0),
+                }
+            }
+            None => segment.map(|s| s.0).unwrap_or(0),
+        };
+        let wlg_reader = match segment {
+            Some((_, end)) => WriteLogReader::new_from_file_range(&name, offset, end),
+            None => WriteLogReader::new_from_file_at(&name, offset),
+        };
+        let mut wlg_reader = wlg_reader.unwrap()
+            .set_recover(params.recover_intermediates)
+            .set_io_retry(params.io_retry);
+        // The version marker at the beginning of the file is skipped.
+        if offset > 0 {
+            wlg_reader = wlg_reader.set_value_version(read_value_version(&name).unwrap_or(0));
+        }
+        let reader = FilteredRecordReader::new(wlg_reader, params.reduce_key_filter.clone())
+            .set_key_dictionary(load_key_dictionary(&name))
+            .set_key_only(params.key_only)
+            .set_value_decoders(params.intermediate_value_version, params.value_decoders.clone())
+            .set_join_filters(join_filters.clone());
+        inputs.push(KeyRangeIterator::new(reader, start.clone(), end.clone()));
     }
     inputs
 }
 
+/// Calculates the name of the file marking `location` as being written by a running job. It
+/// contains the ID of the process running the job.
+pub fn run_marker_name(location: &String) -> String {
+    format!("{}run", location)
+}
+
+/// Splits `pattern` (a path prefix) into its directory and the prefix of the file names.
+pub fn split_prefix(pattern: &String) -> io::Result<(PathBuf, String)> {
+    let pattern_path = Path::new(pattern);
+    let dir = match pattern_path.parent() {
+        Some(p) if p != Path::new("") => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    match pattern_path.file_name() {
+        Some(n) => Ok((dir, n.to_string_lossy().into_owned())),
+        None => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("Invalid intermediate location {}", pattern)))
+        }
+    }
+}
+
+/// Parses "<mapper>.<shard>", the end of an intermediate file name.
+fn parse_partition_shard(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, '.');
+    match (parts.next().map(str::parse::<usize>), parts.next().map(str::parse::<usize>)) {
+        (Some(Ok(mapper)), Some(Ok(shard))) => Some((mapper, shard)),
+        _ => None,
+    }
+}
+
+/// Lists the files at `location` named like intermediate files (including multiplexed ones),
+/// their index and Bloom filter sidecars, shuffle spills or hash buckets.
+pub fn list_intermediate_files(location: &String) -> io::Result<Vec<PathBuf>> {
+    let (dir, file_prefix) = split_prefix(location)?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&file_prefix) {
+            continue;
+        }
+        let rest = &name[file_prefix.len()..];
+        let rest =
+            rest.trim_end_matches(".idx").trim_end_matches(".bloom").trim_end_matches(".dict");
+        let numbers = if let Some(rest) = rest.strip_prefix('-') {
+            rest
+        } else if let Some(rest) = rest.strip_prefix("spill-") {
+            rest
+        } else if let Some(rest) = rest.strip_prefix("bucket-") {
+            rest
+        } else {
+            continue;
+        };
+        let multiplexed = numbers.strip_suffix(".mux").map(|n| n.parse::<usize>().is_ok());
+        if multiplexed == Some(true) || parse_partition_shard(numbers).is_some() {
+            files.push(dir.join(&name));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Finds the intermediate files that a previous run left at `location` (see
+/// `MRParameters::keep_temp_files()`) and returns how many map partitions produced them.
+/// Returns an error if no files are found or if the files don't form a complete set of
+/// `reducers` shards for every map partition.
+pub fn discover_map_partitions(location: &String, reducers: usize) -> io::Result<usize> {
+    let (dir, file_prefix) = split_prefix(&format!("{}-", location))?;
+
+    let mut found = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&file_prefix) {
+            continue;
+        }
+        if let Some(partition_shard) = parse_partition_shard(&name[file_prefix.len()..]) {
+            found.insert(partition_shard);
+        }
+    }
+
+    let partitions = match found.iter().map(|&(mapper, _)| mapper).max() {
+        None => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("No intermediate files found for {}", location)))
+        }
+        Some(m) => m + 1,
+    };
+
+    for mapper in 0..partitions {
+        for shard in 0..reducers {
+            if !found.contains(&(mapper, shard)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Missing intermediate file {}",
+                                                  map_output_name(location, mapper, shard))));
+            }
+        }
+    }
+    if found.len() != partitions * reducers {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Intermediate files at {} were not written for {} \
+                                           reducers",
+                                          location,
+                                          reducers)));
+    }
+    Ok(partitions)
+}
+
+/// Calculates the name of the sample of the intermediate pairs written by the map partition
+/// `params.shard_id` (see `MRParameters::set_sample_output()`).
+pub fn map_sample_name(params: &MRParameters) -> String {
+    format!("{}sample-map.{}", params.reduce_output_shard_prefix, params.shard_id)
+}
+
+/// Calculates the name of the sample of the output of reduce shard `params.shard_id`.
+pub fn reduce_sample_name(params: &MRParameters) -> String {
+    format!("{}sample.{}", params.reduce_output_shard_prefix, params.shard_id)
+}
+
+/// Calculates the name of the key histogram of a job (see `MRParameters::set_key_histogram()`).
+pub fn key_histogram_name(params: &MRParameters) -> String {
+    format!("{}key_histogram", params.reduce_output_shard_prefix)
+}
+
 /// Calculates the name of a reduce output shard from the parameters.
 pub fn get_reduce_output_name(params: &MRParameters) -> String {
-    format!("{}{}", params.reduce_output_shard_prefix, params.shard_id)
+    match params.output_name_template {
+        Some(ref template) => {
+            template.render(&params.reduce_output_shard_prefix, &params.job_name, params.shard_id)
+        }
+        None => format!("{}{}", params.reduce_output_shard_prefix, params.shard_id),
+    }
+}
+
+/// Returns the name of part `part` (counting from 1) of the reduce output `name`, written when
+/// the output exceeds its size limit (see `MRParameters::set_output_size_limit()`).
+pub fn reduce_output_part_name(name: &str, part: usize) -> String {
+    format!("{}.{}", name, part)
+}
+
+/// Returns the name of the file of the reduce output `name` holding the results of the keys
+/// routed to `route` (see `MRParameters::set_output_routing()`).
+pub fn reduce_output_route_name(name: &str, route: &str) -> String {
+    format!("{}-{}", name, route)
+}
+
+/// Like `get_reduce_output_name()`, but creates the directory of the output if the name is
+/// given by a template.
+pub fn create_reduce_output_name(params: &MRParameters) -> String {
+    let name = get_reduce_output_name(params);
+    if params.output_name_template.is_some() {
+        if let Some(dir) = Path::new(&name).parent().filter(|d| d != &Path::new("")) {
+            if let Err(e) = fs::create_dir_all(dir) {
+                println!("WARN: Couldn't create output directory {}: {}", dir.display(), e);
+            }
+        }
+    }
+    name
+}
+
+/// Removes the files written by a partition unless the partition completes: Partitions guard
+/// their outputs when they start writing and commit the guard once everything has been written.
+/// If the partition panics (or is dropped without being run), the guard is dropped uncommitted,
+/// and the partially written files are removed, so that they aren't mistaken for complete
+/// outputs by later runs or reducers.
+pub struct OutputGuard {
+    paths: Vec<String>,
+}
+
+impl OutputGuard {
+    pub fn new(paths: Vec<String>) -> OutputGuard {
+        OutputGuard { paths }
+    }
+
+    /// Keeps the files.
+    pub fn commit(mut self) {
+        self.paths.clear();
+    }
+}
+
+impl Drop for OutputGuard {
+    fn drop(&mut self) {
+        for path in self.paths.drain(..) {
+            if fs::remove_file(&path).is_ok() {
+                println!("WARN: Removed partially written output {}", path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formats::writelog::WriteLogWriter;
+
+    fn write_intermediate(name: &String, keys: &[&str]) {
+        let mut w = WriteLogWriter::<fs::File>::new_to_file(name, false).unwrap();
+        let mut idx = WriteLogWriter::<fs::File>::new_to_file(&map_index_name(name), false)
+            .unwrap();
+        for k in keys {
+            let (bytes, _) = w.get_stats();
+            idx.write_record(k.as_bytes(), bytes.to_string().as_bytes()).unwrap();
+            w.write_record(k.as_bytes(), b"value").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_open_reduce_inputs_range() {
+        let location = String::from("testdata/output_range_");
+        let name = map_output_name(&location, 0, 0);
+        write_intermediate(&name, &["a", "b", "c", "d", "e"]);
+
+        let mut inputs = open_reduce_inputs(&location,
+                                            1,
+                                            0,
+                                            Some((Some(String::from("b")),
+                                                  Some(String::from("d")))),
+                                            &MRParameters::new(),
+                                            Vec::new());
+        let keys: Vec<String> = inputs.remove(0).map(|r| r.key).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+
+        // Without index, the range is still respected.
+        let _ = fs::remove_file(map_index_name(&name));
+        let mut inputs = open_reduce_inputs(&location,
+                                            1,
+                                            0,
+                                            Some((Some(String::from("d")), None)),
+                                            &MRParameters::new(),
+                                            Vec::new());
+        let keys: Vec<String> = inputs.remove(0).map(|r| r.key).collect();
+        assert_eq!(keys, vec!["d", "e"]);
+
+        let _ = fs::remove_file(name);
+    }
+
+    #[test]
+    fn test_output_guard() {
+        let kept = String::from("testdata/guard_kept");
+        let removed = String::from("testdata/guard_removed");
+        fs::write(&kept, "complete").unwrap();
+        fs::write(&removed, "partial").unwrap();
+        OutputGuard::new(vec![kept.clone()]).commit();
+        drop(OutputGuard::new(vec![removed.clone(), String::from("testdata/guard_missing")]));
+        assert!(Path::new(&kept).exists());
+        assert!(!Path::new(&removed).exists());
+        let _ = fs::remove_file(kept);
+    }
 }
@@ -1,13 +1,33 @@
 //! Implements the Reduce phase.
 //!
 
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::io;
 use std::iter::Peekable;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
 
-use mapreducer::Reducer;
-use parameters::MRParameters;
-use record_types::{Record, MultiRecord, REmitter};
-use shard_merge::ShardMergeIterator;
+use histogram::KeyHistogram;
+use mapreducer::{Reducer, fnv1a_seeded};
+use object_store::{StoredFiles, put_file};
+use parameters::{GroupKeyPolicy, MRParameters, OutputDedup, ReduceStrategy};
+use formats::compressed::{OutputCompression, compressed_parts};
+use formats::limited::output_parts;
+use formats::partitioned::KeyRouter;
+use formats::sink_pool::{SinkPool, max_open_per_partition};
+use formats::writelog::{FilteredRecordReader, WriteLogGenerator, WriteLogReader, encode_record};
+use phases::output::{OutputGuard, SinkGenerator, get_reduce_output_name, hash_bucket_name,
+                     reduce_sample_name};
+use record_types::{EmittedValue, Record, MultiRecord, REmitter, compare_keys};
+use sampling::Reservoir;
+use shard_merge::{MergeCounters, ShardMergeIterator, build_stable};
+use sort::{Comparer, dict_string_compare};
+use stats::{JobStats, OutputShard};
+use testing::FaultPhase;
+use trace::{self, Step};
 
 pub struct ReducePartition<R: Reducer, InputIt: Iterator<Item = Record>, Sink: io::Write> {
     r: R,
@@ -16,6 +36,19 @@ pub struct ReducePartition<R: Reducer, InputIt: Iterator<Item = Record>, Sink: i
     // the files to the reduce shard itself.
     srcs: Vec<InputIt>,
     dstfile: Sink,
+    dedup: Option<DedupWindow>,
+    // Buffer for results serialized by the output formatter.
+    formatted: Vec<u8>,
+    // Describes what has been written to dstfile.
+    output: OutputShard,
+    // Sample of the output records, if enabled (see `MRParameters::set_sample_output()`).
+    sample: Option<Reservoir<String>>,
+    // The heaviest keys, if enabled (see `MRParameters::set_key_histogram()`).
+    histogram: Option<KeyHistogram>,
+    // Writes the results of routed keys (see `MRParameters::set_output_routing()`).
+    router: Option<Box<dyn KeyRouter>>,
+    // Removes the output if the partition doesn't complete; dropped after dstfile.
+    guard: Option<OutputGuard>,
 }
 
 impl<R: Reducer, InputIt: Iterator<Item = Record>, Sink: io::Write> ReducePartition<R,
@@ -33,53 +66,513 @@ impl<R: Reducer, InputIt: Iterator<Item = Record>, Sink: io::Write> ReducePartit
                srcs: Vec<InputIt>,
                outp: Sink)
                -> ReducePartition<R, InputIt, Sink> {
+        let dedup = match params.output_dedup {
+            OutputDedup::Off => None,
+            OutputDedup::PerShard(n) | OutputDedup::PerKey(n) => Some(DedupWindow::new(n)),
+        };
+        let output = OutputShard {
+            shard: params.shard_id,
+            path: get_reduce_output_name(&params),
+            ..OutputShard::default()
+        };
+        let sample = if params.sample_output > 0 {
+            let seed = params.shard_seed ^ params.shard_id as u64;
+            Some(Reservoir::new(params.sample_output, seed))
+        } else {
+            None
+        };
+        let histogram = if params.key_histogram > 0 {
+            Some(KeyHistogram::new(params.key_histogram))
+        } else {
+            None
+        };
         ReducePartition {
             r: r,
             params: params,
             srcs: srcs,
             dstfile: outp,
+            dedup,
+            formatted: Vec::new(),
+            output,
+            sample,
+            histogram,
+            router: None,
+            guard: None,
         }
     }
 
-    /// Run the Reduce partition.
-    pub fn _run(mut self) {
+    /// Writes the results of keys with a route to the files of `router` instead of the output.
+    pub fn set_output_router(mut self, router: Box<dyn KeyRouter>) -> ReducePartition<R,
+                                                                                       InputIt,
+                                                                                       Sink> {
+        self.router = Some(router);
+        self
+    }
+
+    /// Removes the files guarded by `guard` (usually the output file) if the partition panics
+    /// or is dropped before it has completed.
+    pub fn set_output_guard(mut self, guard: OutputGuard) -> ReducePartition<R, InputIt, Sink> {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Run the Reduce partition. Returns the statistics collected while reducing, and a
+    /// description of the output shard.
+    pub fn _run(mut self) -> (JobStats, OutputShard) {
+        let guard = self.guard.take();
+        if let Some(ref injector) = self.params.fault_injector {
+            injector.partition_started(FaultPhase::Reduce, self.params.shard_id);
+        }
         let mut inputs = Vec::new();
         inputs.append(&mut self.srcs);
         let mut it = inputs.into_iter();
 
         let params = self.params.clone();
+        let limited = params.output_size_limit.is_some() && params.output_partitioning.is_none();
+        let store = match params.object_store {
+            Some((ref store, StoredFiles::IntermediatesAndOutputs)) => Some(store.clone()),
+            _ => None,
+        };
+        let compression = match params.output_partitioning {
+            None => params.output_compression,
+            Some(_) => OutputCompression::None,
+        };
+        let filter = self.params.shuffle_filter;
+        let mut stats = JobStats::new();
+        let result;
 
-        self.reduce(RecordsToMultiRecords::new(ShardMergeIterator::build(&mut it), params))
+        let counters: Option<Rc<MergeCounters>>;
+        {
+            let merged: Box<dyn Iterator<Item = Record>> = match params.reduce_strategy {
+                ReduceStrategy::SortMerge if params.stable_merge => {
+                    let (merged, merge_counters) = build_stable(&mut it, compare_keys);
+                    counters = Some(merge_counters);
+                    merged
+                }
+                ReduceStrategy::SortMerge => {
+                    let (merged, merge_counters) = ShardMergeIterator::build_counted(&mut it);
+                    counters = Some(merge_counters);
+                    Box::new(merged)
+                }
+                ReduceStrategy::HashAggregate => {
+                    counters = None;
+                    Box::new(hash_group(it, params.reduce_group_insensitive).into_iter())
+                }
+                ReduceStrategy::AssumeSorted => {
+                    counters = None;
+                    Box::new(it.flatten())
+                }
+                ReduceStrategy::HashBuckets(buckets) => {
+                    counters = None;
+                    match write_buckets(it, buckets, &params) {
+                        Ok(buckets) => Box::new(BucketGroups::new(buckets, &params)),
+                        Err(e) => {
+                            panic!("couldn't write hash buckets of reduce shard {}: {}",
+                                   params.shard_id,
+                                   e)
+                        }
+                    }
+                }
+            };
+            let merged = merged.filter(|r| {
+                stats.reduce_input_records += 1;
+                match filter {
+                    Some(f) if !f(r) => {
+                        stats.records_filtered += 1;
+                        false
+                    }
+                    _ => true,
+                }
+            });
+            let chunk_values = if self.r.associative() {
+                params.reduce_chunk_values
+            } else {
+                0
+            };
+            let groups = RecordsToMultiRecords::new(merged, params).set_chunk_values(chunk_values);
+            result = self.reduce(groups);
+        }
+        stats.output_duplicates = result.0;
+        let mut output = result.1;
+        stats.key_histogram = result.2;
+        if let Some(counters) = counters {
+            stats.merge_inputs = counters.inputs();
+            stats.merge_comparisons = counters.comparisons();
+            stats.merge_tree_depth = counters.depth();
+            output.merge_exhaustion_order = counters.exhaustion_order();
+        }
+        // The output has been closed by reduce(), finishing its last part or compressing it.
+        if compression != OutputCompression::None {
+            output.parts = compressed_parts(&output.path, compression);
+            output.path.push_str(compression.extension());
+        } else if limited {
+            output.parts = output_parts(&output.path);
+        }
+        if let Some(guard) = guard {
+            guard.commit();
+        }
+        if let Some(store) = store {
+            for file in output.files().iter().filter(|f| Path::new(f).exists()) {
+                if let Err(e) = put_file(&*store, file) {
+                    panic!("couldn't put output {} into the object store: {}", file, e);
+                }
+            }
+        }
+        (stats, output)
     }
 
-    fn reduce<RecIt: Iterator<Item = Record>>(mut self, inp: RecordsToMultiRecords<RecIt>) {
-        use std::io::Write;
-
-        for multirec in inp {
-            let mut emitter = REmitter::new();
+    /// Reduces all groups; returns the number of duplicate output lines dropped, the
+    /// description of the output, and the key histogram.
+    fn reduce<RecIt: Iterator<Item = Record>>(mut self,
+                                              mut inp: RecordsToMultiRecords<RecIt>)
+                                              -> (usize, OutputShard, Option<KeyHistogram>) {
+        let per_key = matches!(self.params.output_dedup, OutputDedup::PerKey(_));
+        // A single emitter is used for all groups, so that its buffers are reused.
+        let mut emitter = REmitter::for_job(&self.params);
+        let shard_id = self.params.shard_id;
+        // The results of the incomplete chunks of the current run (see `next_chunk()`), with the
+        // number and size of the values they were reduced from.
+        let mut partial: Vec<(String, Vec<String>, usize, usize)> = Vec::new();
+        let insensitive = self.params.reduce_group_insensitive;
+        loop {
+            let (mut multirec, complete) = {
+                let _span = trace::enter(Step::Merge, shard_id);
+                match inp.next_chunk() {
+                    None => break,
+                    Some(m) => m,
+                }
+            };
+            let _span = trace::enter(Step::Reduce, shard_id);
+            // The values of a group are counted for the histogram before they are reduced.
+            let (mut records, mut bytes) = match self.histogram {
+                Some(_) => group_size(&multirec),
+                None => (0, 0),
+            };
+            let key = multirec.key();
+            if !complete || !partial.is_empty() {
+                // Case-insensitive groups are chunked with the lower-cased key.
+                let i = match partial.iter().position(|p| {
+                    p.0 == *key ||
+                    insensitive && dict_string_compare(&p.0, key) == Ordering::Equal
+                }) {
+                    Some(i) => i,
+                    None => {
+                        partial.push((key.clone(), Vec::new(), 0, 0));
+                        partial.len() - 1
+                    }
+                };
+                if !complete {
+                    let (_, ref mut results, ref mut n, ref mut size) = partial[i];
+                    *n += records;
+                    *size += bytes;
+                    self.r.reduce(&mut emitter, multirec);
+                    emitter._drain(|v| {
+                        results.push(String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    });
+                    continue;
+                }
+                let (_, mut values, n, size) = partial.swap_remove(i);
+                records += n;
+                bytes += size;
+                let key = key.clone();
+                values.extend(multirec);
+                multirec = MultiRecord::new(key, values);
+            }
+            match self.output.key_range {
+                None => {
+                    self.output.key_range = Some((multirec.key().clone(), multirec.key().clone()))
+                }
+                Some((_, ref mut last)) => last.clone_from(multirec.key()),
+            }
+            if let Some(ref mut histogram) = self.histogram {
+                histogram.add(multirec.key(), records as u64, bytes as u64);
+            }
+            if per_key {
+                if let Some(ref mut dedup) = self.dedup {
+                    dedup.clear();
+                }
+            }
             self.r.reduce(&mut emitter, multirec);
+            self.write_results(&mut emitter, false);
+            if self.params.termination.is_terminated() {
+                break;
+            }
+        }
+
+        self.r.finish(&mut emitter);
+        self.write_results(&mut emitter, true);
+        if let Some(router) = self.router.take() {
+            self.output.routes = router.files();
+        }
+        if let Some(ref sample) = self.sample {
+            let name = reduce_sample_name(&self.params);
+            if let Err(e) = sample.write_to(&name) {
+                println!("WARN: Couldn't write sample {}: {}", name, e);
+            }
+        }
+        (self.dedup.map(|d| d.dropped).unwrap_or(0), self.output, self.histogram)
+    }
+
+    /// Writes the results emitted for the last group, or by `Reducer::finish()` if `finished` is
+    /// set.
+    fn write_results(&mut self, emitter: &mut REmitter, finished: bool) {
+        let dstfile = &mut self.dstfile;
+        let mut router = if finished { None } else { self.router.as_mut() };
+        let dedup = &mut self.dedup;
+        let formatted = &mut self.formatted;
+        let sample = &mut self.sample;
+        let formatter = self.params.output_formatter;
+        let key = match self.output.key_range {
+            Some((_, ref last)) if !finished => last.as_str(),
+            _ => "",
+        };
+        let shard_id = self.params.shard_id;
+        let (mut records, mut bytes) = (0, 0);
+        emitter._drain(|result| {
+            if let Some(ref mut dedup) = *dedup {
+                if !dedup.insert(result.as_bytes()) {
+                    return;
+                }
+            }
+            let data = match (formatter, result) {
+                (Some(f), EmittedValue::Text(text)) => {
+                    formatted.clear();
+                    f(key, text, formatted);
+                    &formatted[..]
+                }
+                _ => result.as_bytes(),
+            };
+            let written = match router {
+                Some(ref mut router) => {
+                    match router.write_routed(key, data) {
+                        Ok(false) => dstfile.write(data),
+                        result => result.map(|_| data.len()),
+                    }
+                }
+                None => dstfile.write(data),
+            };
+            match written {
+                Ok(_) => {
+                    records += 1;
+                    bytes += data.len();
+                    if let Some(ref mut sample) = *sample {
+                        sample.offer(|| {
+                            String::from_utf8_lossy(data).trim_end_matches('\n').to_string()
+                        });
+                    }
+                }
+                Err(e) => println!("WARN: While reducing shard #{}: {}", shard_id, e),
+            }
+        });
+        self.output.records += records;
+        self.output.bytes += bytes;
+    }
+}
+
+/// Returns the number of values of `group` and their size, including the key.
+fn group_size(group: &MultiRecord) -> (usize, usize) {
+    let key = group.key().len();
+    (group.len(), group.values().iter().map(|v| key + v.len()).sum())
+}
+
+/// Remembers the last n lines written, in order to drop duplicates (see
+/// `MRParameters::set_output_dedup()`). Lines are looked up by hash, and compared with the lines
+/// of the same hash, so that a hash collision doesn't drop a line.
+struct DedupWindow {
+    size: usize,
+    order: VecDeque<u64>,
+    // The lines in the window by hash, oldest first.
+    seen: HashMap<u64, Vec<Vec<u8>>>,
+    dropped: usize,
+}
 
-            for result in emitter._get().into_iter() {
-                match self.dstfile.write(result.as_bytes()) {
-                    Err(e) => {
-                        println!("WARN: While reducing shard #{}: {}",
-                                 self.params.shard_id,
-                                 e)
+impl DedupWindow {
+    fn new(size: usize) -> DedupWindow {
+        DedupWindow {
+            size,
+            order: VecDeque::with_capacity(size),
+            seen: HashMap::with_capacity(size),
+            dropped: 0,
+        }
+    }
+
+    /// Returns false if `line` is a duplicate of a line in the window; otherwise, the line is
+    /// added to the window.
+    fn insert(&mut self, line: &[u8]) -> bool {
+        if self.size == 0 {
+            return true;
+        }
+        let hash = fnv1a_seeded(0, line);
+        if self.seen.get(&hash).is_some_and(|lines| lines.iter().any(|l| l == line)) {
+            self.dropped += 1;
+            return false;
+        }
+        if self.order.len() == self.size {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some(lines) = self.seen.get_mut(&oldest) {
+                    lines.remove(0);
+                    if lines.is_empty() {
+                        self.seen.remove(&oldest);
                     }
-                    Ok(_) => (),
                 }
             }
         }
+        self.order.push_back(hash);
+        self.seen.entry(hash).or_default().push(line.to_vec());
+        true
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+        self.seen.clear();
+    }
+}
+
+/// Groups the records of `inputs` by key in a hash table (see `ReduceStrategy::HashAggregate`),
+/// and returns them so that records of the same key are adjacent: Groups are ordered by the first
+/// appearance of their key, and records within a group by their order in the inputs.
+fn hash_group<It: Iterator<Item = Record>, ItIt: Iterator<Item = It>>(inputs: ItIt,
+                                                                      insensitive: bool)
+                                                                      -> Vec<Record> {
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<Vec<Record>> = Vec::new();
+    for record in inputs.flatten() {
+        let key = if insensitive {
+            record.key.to_ascii_lowercase()
+        } else {
+            record.key.clone()
+        };
+        let next = groups.len();
+        let i = *index.entry(key).or_insert(next);
+        if i == next {
+            groups.push(Vec::new());
+        }
+        groups[i].push(record);
+    }
+    groups.into_iter().flatten().collect()
+}
+
+// Seeds the hash distributing records to buckets, so that it is independent of the sharder.
+const BUCKET_SEED: u64 = 0x6275636b657473;
+
+/// Hashes the records of `inputs` into `buckets` bucket files (see
+/// `ReduceStrategy::HashBuckets`). Returns the names of the bucket files that records were
+/// written to.
+fn write_buckets<It, ItIt>(inputs: ItIt,
+                           buckets: usize,
+                           params: &MRParameters)
+                           -> io::Result<Vec<String>>
+    where It: Iterator<Item = Record>,
+          ItIt: Iterator<Item = It>
+{
+    let buckets = ::std::cmp::max(1, buckets);
+    let names: Vec<String> = (0..buckets)
+        .map(|n| hash_bucket_name(&params.map_output_location, params.shard_id, n))
+        .collect();
+    let generator = WriteLogGenerator::with_batching(params.map_output_batch_records,
+                                                     params.map_output_batch_bytes)
+        .with_io_retry(params.io_retry);
+    let mut pool = SinkPool::new(generator, max_open_per_partition(params.reducers));
+    let mut written = vec![false; buckets];
+    let mut frame = Vec::new();
+    let result = inputs.flatten()
+        .map(|record| {
+            let hash = if params.reduce_group_insensitive {
+                fnv1a_seeded(BUCKET_SEED, record.key.to_ascii_lowercase().as_bytes())
+            } else {
+                fnv1a_seeded(BUCKET_SEED, record.key.as_bytes())
+            };
+            let bucket = (hash % buckets as u64) as usize;
+            written[bucket] = true;
+            encode_record(record.key.as_bytes(), record.value.as_bytes(), &mut frame);
+            pool.write(&names[bucket], &frame)
+        })
+        .collect::<io::Result<Vec<usize>>>()
+        .and_then(|_| pool.flush());
+    // Dropping the sinks closes the bucket files.
+    drop(pool);
+    let names = names.into_iter().zip(written).filter(|&(_, w)| w).map(|(name, _)| name);
+    match result {
+        Ok(()) => Ok(names.collect()),
+        Err(e) => {
+            for name in names {
+                let _ = fs::remove_file(name);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Returns the records of the bucket files written by `write_buckets()` grouped by key, one
+/// bucket at a time (see `hash_group()`). Bucket files are removed once they have been read, or
+/// when the iterator is dropped.
+struct BucketGroups {
+    buckets: VecDeque<String>,
+    group: ::std::vec::IntoIter<Record>,
+    insensitive: bool,
+}
+
+impl BucketGroups {
+    fn new(buckets: Vec<String>, params: &MRParameters) -> BucketGroups {
+        BucketGroups {
+            buckets: buckets.into(),
+            group: Vec::new().into_iter(),
+            insensitive: params.reduce_group_insensitive,
+        }
+    }
+}
+
+impl Iterator for BucketGroups {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            if let Some(record) = self.group.next() {
+                return Some(record);
+            }
+            let name = self.buckets.pop_front()?;
+            let records: Vec<Record> = match WriteLogReader::new_from_file(&name) {
+                Ok(reader) => FilteredRecordReader::new(reader, None).collect(),
+                Err(e) => panic!("couldn't read hash bucket {}: {}", name, e),
+            };
+            let _ = fs::remove_file(&name);
+            self.group = hash_group(::std::iter::once(records.into_iter()), self.insensitive)
+                .into_iter();
+        }
+    }
+}
+
+impl Drop for BucketGroups {
+    fn drop(&mut self) {
+        for name in self.buckets.iter() {
+            let _ = fs::remove_file(name);
+        }
     }
 }
 
 /// Iterator adapter: Converts an Iterator<Item=Record> into an Iterator<Item=MultiRecord> by
 /// grouping subsequent records with identical key.
 /// The original iterator must yield records in sorted order (or at least in an order where
-/// identical items are adjacent).
+/// keys comparing equal are adjacent).
+///
+/// Runs of records are formed with the comparator the inputs are sorted by (dictionary order),
+/// so that grouping is consistent with the sort order: Keys that are equal in dictionary order
+/// but spelled differently (e.g. "abc" and "Abc") may be interleaved in the merged inputs. With
+/// case-insensitive grouping, they form one group; otherwise, every spelling forms one group,
+/// in the order in which the spellings first appear.
+///
+/// With a chunk size (see `MRParameters::set_reduce_chunk_values()`), runs with more values are
+/// returned in chunks by `next_chunk()`.
 pub struct RecordsToMultiRecords<It: Iterator<Item = Record>> {
     it: Peekable<It>,
     params: MRParameters,
+    compare: Comparer<String>,
+    // The remaining groups of the last run, with other spellings than its first key, and
+    // whether they are complete.
+    pending: VecDeque<(MultiRecord, bool)>,
+    chunk_values: usize,
+    // The keys returned in incomplete chunks of the current run (if grouping case-sensitively),
+    // or the spellings of the key seen in the chunks so far (otherwise).
+    chunked: Vec<(String, usize)>,
 }
 
 impl<It: Iterator<Item = Record>> RecordsToMultiRecords<It> {
@@ -87,42 +580,117 @@ impl<It: Iterator<Item = Record>> RecordsToMultiRecords<It> {
         RecordsToMultiRecords {
             it: it.peekable(),
             params: params,
+            compare: dict_string_compare,
+            pending: VecDeque::new(),
+            chunk_values: 0,
+            chunked: Vec::new(),
         }
     }
-}
 
-impl<It: Iterator<Item = Record>> Iterator for RecordsToMultiRecords<It> {
-    type Item = MultiRecord;
-    fn next(&mut self) -> Option<Self::Item> {
-        use std::ascii::AsciiExt;
+    /// Ends chunks after `values` values if the run continues (0: never).
+    fn set_chunk_values(mut self, values: usize) -> RecordsToMultiRecords<It> {
+        self.chunk_values = values;
+        self
+    }
+
+    // Whether a chunk of `values` values is full, and more records of the run of `key` follow.
+    fn chunk_full(&mut self, values: usize, key: &String) -> bool {
+        let compare = self.compare;
+        self.chunk_values > 0 && values >= self.chunk_values &&
+        self.it.peek().is_some_and(|r| compare(&r.key, key) == Ordering::Equal)
+    }
+
+    /// Returns the next group or chunk of a group, and whether it completes the group. The
+    /// chunks of a group are returned in order; after the last one, a group that got values
+    /// only in earlier chunks of the run is completed with an empty chunk.
+    pub fn next_chunk(&mut self) -> Option<(MultiRecord, bool)> {
+        if let Some(group) = self.pending.pop_front() {
+            return Some(group);
+        }
         let mut collection = Vec::with_capacity(self.params.reduce_group_prealloc_size);
-        let key: String;
-        match self.it.next() {
-            None => return None,
-            Some(r) => {
-                if self.params.reduce_group_insensitive {
-                    key = r.key[..].to_ascii_lowercase();
+        let first = self.it.next()?;
+        let compare = self.compare;
+        if !self.params.reduce_group_insensitive {
+            let key = first.key;
+            collection.push(first.value);
+            let mut others: Vec<(String, Vec<String>)> = Vec::new();
+            let mut values = 1;
+            let mut complete = true;
+            while let Some(r) = self.it.next_if(|r| compare(&r.key, &key) == Ordering::Equal) {
+                values += 1;
+                if r.key == key {
+                    collection.push(r.value);
                 } else {
-                    key = r.key
+                    match others.iter_mut().find(|o| o.0 == r.key) {
+                        Some(o) => o.1.push(r.value),
+                        None => others.push((r.key, vec![r.value])),
+                    }
+                }
+                if self.chunk_full(values, &key) {
+                    complete = false;
+                    break;
                 }
-                collection.push(r.value)
             }
-        }
-        loop {
-            match self.it.peek() {
-                None => break,
-                Some(r) => {
-                    if !self.params.reduce_group_insensitive && r.key != key {
-                        break;
-                    } else if self.params.reduce_group_insensitive &&
-                       r.key[..].to_ascii_lowercase() != key {
-                        break;
+            others.insert(0, (key, collection));
+            if complete {
+                for (key, _) in self.chunked.drain(..) {
+                    if !others.iter().any(|o| o.0 == key) {
+                        others.push((key, Vec::new()));
+                    }
+                }
+            } else {
+                for (key, _) in others.iter() {
+                    if !self.chunked.iter().any(|c| c.0 == *key) {
+                        self.chunked.push((key.clone(), 0));
                     }
                 }
             }
-            collection.push(self.it.next().unwrap().value);
+            self.pending
+                .extend(others.into_iter().map(|(k, v)| (MultiRecord::new(k, v), complete)));
+            return self.pending.pop_front();
+        }
+
+        let policy = self.params.reduce_group_key_policy;
+        let group = first.key.to_ascii_lowercase();
+        // The spellings of the key in the group with their counts, in order of appearance,
+        // including those of the previous chunks.
+        let mut originals = mem::take(&mut self.chunked);
+        match originals.iter_mut().find(|o| o.0 == first.key) {
+            Some(o) => o.1 += 1,
+            None => originals.push((first.key, 1)),
+        }
+        collection.push(first.value);
+        while let Some(r) = self.it.next_if(|r| compare(&r.key, &group) == Ordering::Equal) {
+            if policy == GroupKeyPolicy::MostFrequent {
+                match originals.iter_mut().find(|o| o.0 == r.key) {
+                    Some(o) => o.1 += 1,
+                    None => originals.push((r.key, 1)),
+                }
+            }
+            collection.push(r.value);
+            if self.chunk_full(collection.len(), &group) {
+                self.chunked = originals;
+                return Some((MultiRecord::new(group, collection), false));
+            }
         }
-        return Some(MultiRecord::new(key, collection));
+        let key = match policy {
+            GroupKeyPolicy::Lowercased => group,
+            GroupKeyPolicy::FirstSeen => originals.swap_remove(0).0,
+            GroupKeyPolicy::MostFrequent => {
+                // max_by_key() would return the last of several equally frequent keys.
+                let most = originals.iter().map(|o| o.1).max().unwrap();
+                let i = originals.iter().position(|o| o.1 == most).unwrap();
+                originals.swap_remove(i).0
+            }
+        };
+        Some((MultiRecord::new(key, collection), true))
+    }
+}
+
+impl<It: Iterator<Item = Record>> Iterator for RecordsToMultiRecords<It> {
+    type Item = MultiRecord;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|(group, _)| group)
     }
 }
 
@@ -132,10 +700,12 @@ mod tests {
 
     use closure_mr::ClosureMapReducer;
     use formats::lines::LinesSinkGenerator;
-    use phases::output::SinkGenerator;
-    use parameters::MRParameters;
+    use formats::writelog::{WriteLogGenerator, WriteLogReader};
+    use phases::output::{SinkGenerator, list_intermediate_files};
+    use parameters::{MRParameters, OutputDedup};
     use record_types::*;
 
+    use std::fs;
     use std::vec;
 
     fn get_records() -> Vec<Record> {
@@ -165,6 +735,91 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_group_key_policy() {
+        let records = vec![mk_rcrd("Abc", "1"),
+                           mk_rcrd("abc", "2"),
+                           mk_rcrd("ABC", "3"),
+                           mk_rcrd("abc", "4"),
+                           mk_rcrd("Xy", "5")];
+        let keys = |policy| -> Vec<String> {
+            let params = MRParameters::new()
+                .set_reduce_group_opts(2, true)
+                .set_group_key_policy(policy);
+            RecordsToMultiRecords::new(records.clone().into_iter(), params)
+                .map(|m| m.key().clone())
+                .collect()
+        };
+        assert_eq!(keys(GroupKeyPolicy::Lowercased), vec!["abc", "xy"]);
+        assert_eq!(keys(GroupKeyPolicy::FirstSeen), vec!["Abc", "Xy"]);
+        assert_eq!(keys(GroupKeyPolicy::MostFrequent), vec!["abc", "Xy"]);
+    }
+
+    #[test]
+    fn test_grouping_interleaved_spellings() {
+        // Merged inputs order equal keys by value, so spellings can be interleaved.
+        let records = vec![mk_rcrd("ab", "1"),
+                           mk_rcrd("AB", "2"),
+                           mk_rcrd("ab", "3"),
+                           mk_rcrd("Ab", "4"),
+                           mk_rcrd("AB", "5"),
+                           mk_rcrd("b", "6")];
+        let groups = |insensitive| -> Vec<(String, Vec<String>)> {
+            let params = MRParameters::new().set_reduce_group_opts(2, insensitive);
+            RecordsToMultiRecords::new(records.clone().into_iter(), params)
+                .map(|m| (m.key().clone(), m.values().clone()))
+                .collect()
+        };
+        let group = |k: &str, vs: &[&str]| -> (String, Vec<String>) {
+            (String::from(k), vs.iter().map(|v| String::from(*v)).collect())
+        };
+        assert_eq!(groups(false),
+                   vec![group("ab", &["1", "3"]),
+                        group("AB", &["2", "5"]),
+                        group("Ab", &["4"]),
+                        group("b", &["6"])]);
+        assert_eq!(groups(true),
+                   vec![group("ab", &["1", "2", "3", "4", "5"]), group("b", &["6"])]);
+    }
+
+    #[test]
+    fn test_grouping_chunks() {
+        let records = vec![mk_rcrd("ab", "1"),
+                           mk_rcrd("AB", "2"),
+                           mk_rcrd("ab", "3"),
+                           mk_rcrd("Ab", "4"),
+                           mk_rcrd("AB", "5"),
+                           mk_rcrd("b", "6")];
+        let chunks = |insensitive| -> Vec<(String, Vec<String>, bool)> {
+            let params = MRParameters::new().set_reduce_group_opts(2, insensitive);
+            let mut groups = RecordsToMultiRecords::new(records.clone().into_iter(), params)
+                .set_chunk_values(2);
+            let mut chunks = Vec::new();
+            while let Some((m, complete)) = groups.next_chunk() {
+                chunks.push((m.key().clone(), m.values().clone(), complete));
+            }
+            chunks
+        };
+        let chunk = |k: &str, vs: &[&str], complete| -> (String, Vec<String>, bool) {
+            (String::from(k), vs.iter().map(|v| String::from(*v)).collect(), complete)
+        };
+        // Spellings that only occurred in earlier chunks are completed with empty chunks.
+        assert_eq!(chunks(false),
+                   vec![chunk("ab", &["1"], false),
+                        chunk("AB", &["2"], false),
+                        chunk("ab", &["3"], false),
+                        chunk("Ab", &["4"], false),
+                        chunk("AB", &["5"], true),
+                        chunk("ab", &[], true),
+                        chunk("Ab", &[], true),
+                        chunk("b", &["6"], true)]);
+        assert_eq!(chunks(true),
+                   vec![chunk("ab", &["1", "2"], false),
+                        chunk("ab", &["3", "4"], false),
+                        chunk("ab", &["5"], true),
+                        chunk("b", &["6"], true)]);
+    }
+
     #[test]
     fn test_grouping_iterator_sensitive() {
         let records = get_records();
@@ -215,4 +870,285 @@ mod tests {
                                      dst.new_output(&String::from("testdata/result_0")));
         r._run();
     }
+
+    fn drop_xyz(r: &Record) -> bool {
+        r.key != "xyz"
+    }
+
+    #[test]
+    fn test_reduce_shuffle_filter() {
+        let mr = ClosureMapReducer::new(fake_mapper, test_reducer);
+        let params = MRParameters::new()
+            .set_shard_id(43)
+            .set_shuffle_filter(drop_xyz);
+        let srcs = vec![get_records().into_iter(), Vec::new().into_iter()];
+        let dst = LinesSinkGenerator::new_to_files();
+
+        let r = ReducePartition::new(mr,
+                                     params,
+                                     srcs,
+                                     dst.new_output(&String::from("testdata/result_filter_0")));
+        let (stats, output) = r._run();
+
+        assert_eq!(stats.reduce_input_records, 8);
+        assert_eq!(stats.records_filtered, 3);
+        assert_eq!((stats.merge_inputs, stats.merge_tree_depth), (2, 2));
+        // The empty input is exhausted first; records of a single input need no comparisons.
+        assert_eq!(output.merge_exhaustion_order, vec![1, 0]);
+        assert_eq!(stats.merge_comparisons, 0);
+        assert_eq!(output.shard, 43);
+        assert_eq!(output.path, "output_43");
+        assert_eq!(output.records, 5);
+        assert_eq!(output.bytes, "aaa: defabb: 111Abb: 112abbb: 113abc: xyz".len());
+        assert_eq!(output.key_range, Some((String::from("aaa"), String::from("abc"))));
+
+        let _ = ::std::fs::remove_file("testdata/result_filter_0");
+    }
+
+    fn dup_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(String::from("same"));
+        e.emit(String::from("same"));
+        e.emit(recs.key().clone());
+    }
+
+    #[test]
+    fn test_reduce_output_dedup() {
+        let run = |dedup| {
+            let mut out = Vec::new();
+            let stats = ReducePartition::new(ClosureMapReducer::new(fake_mapper, dup_reducer),
+                                             MRParameters::new().set_output_dedup(dedup),
+                                             vec![get_records().into_iter()],
+                                             &mut out)
+                ._run()
+                .0;
+            (String::from_utf8(out).unwrap(), stats.output_duplicates)
+        };
+
+        assert_eq!(run(OutputDedup::PerShard(10)),
+                   (String::from("sameaaaabbAbbabbbabcxyz"), 11));
+        assert_eq!(run(OutputDedup::PerKey(10)),
+                   (String::from("sameaaasameabbsameAbbsameabbbsameabcsamexyz"), 6));
+        assert_eq!(run(OutputDedup::Off).1, 0);
+    }
+
+    #[test]
+    fn test_dedup_window_collision() {
+        let mut window = DedupWindow::new(2);
+        assert!(window.insert(b"abc"));
+        // File "abc" under the hash of "abd", as if both lines had the same hash.
+        let (abc, abd) = (fnv1a_seeded(0, b"abc"), fnv1a_seeded(0, b"abd"));
+        let lines = window.seen.remove(&abc).unwrap();
+        window.seen.insert(abd, lines);
+        window.order = VecDeque::from(vec![abd]);
+
+        assert!(window.insert(b"abd"));
+        assert!(!window.insert(b"abd"));
+        // Evicts "abc", but not "abd" of the same hash.
+        assert!(window.insert(b"x"));
+        assert!(!window.insert(b"abd"));
+        assert_eq!(window.dropped, 2);
+    }
+
+    fn values_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{}:{};", recs.key(), recs.values().join(",")));
+    }
+
+    #[test]
+    fn test_reduce_stable_merge() {
+        let run = |stable| {
+            let srcs: Vec<_> = vec![vec![mk_rcrd("k", "c"), mk_rcrd("k", "z"), mk_rcrd("l", "2")],
+                                    vec![mk_rcrd("k", "b")],
+                                    vec![mk_rcrd("l", "1")],
+                                    vec![mk_rcrd("k", "a"), mk_rcrd("l", "0")]]
+                .into_iter()
+                .map(|v| v.into_iter())
+                .collect();
+            let mut out = Vec::new();
+            ReducePartition::new(ClosureMapReducer::new(fake_mapper, values_reducer),
+                                 MRParameters::new().set_stable_merge(stable),
+                                 srcs,
+                                 &mut out)
+                ._run();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run(true), "k:c,z,b,a;l:2,1,0;");
+        assert_eq!(run(false), "k:a,b,c,z;l:0,1,2;");
+    }
+
+    fn nesting_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("[{}]", recs.values().concat()));
+    }
+
+    #[test]
+    fn test_reduce_chunks() {
+        let run = |associative| {
+            let srcs = vec![vec![mk_rcrd("k", "a"),
+                                 mk_rcrd("k", "b"),
+                                 mk_rcrd("K", "c"),
+                                 mk_rcrd("k", "d"),
+                                 mk_rcrd("k", "e"),
+                                 mk_rcrd("l", "f")]
+                                .into_iter()];
+            let mut mr = ClosureMapReducer::new(fake_mapper, nesting_reducer);
+            mr.set_associative(associative);
+            let mut out = Vec::new();
+            let params = MRParameters::new()
+                .set_reduce_group_opts(2, true)
+                .set_group_key_policy(GroupKeyPolicy::FirstSeen)
+                .set_reduce_chunk_values(2)
+                .set_key_histogram(1);
+            let (stats, _) = ReducePartition::new(mr, params, srcs, &mut out)._run();
+            // The histogram counts the values of the group, not the results of its chunks.
+            let top = &stats.key_histogram.unwrap().top_by_records()[0];
+            assert_eq!((top.key.as_str(), top.records, top.bytes), ("k", 5, 10));
+            String::from_utf8(out).unwrap()
+        };
+        // The results of the chunks are reduced with the last chunk.
+        assert_eq!(run(true), "[[ab][cd]e][f]");
+        assert_eq!(run(false), "[abcde][f]");
+    }
+
+    #[test]
+    fn test_reduce_sample_output() {
+        let params = MRParameters::new()
+            .set_shard_id(3)
+            .set_sample_output(2)
+            .set_file_locations(String::from("testdata/sample_intermed_"),
+                                String::from("testdata/sample_result_"));
+        let name = reduce_sample_name(&params);
+        assert_eq!(name, "testdata/sample_result_sample.3");
+        let dst = LinesSinkGenerator::new_to_files();
+        ReducePartition::new(ClosureMapReducer::new(fake_mapper, values_reducer),
+                             params,
+                             vec![get_records().into_iter()],
+                             dst.new_output(&String::from("testdata/sample_result_3")))
+            ._run();
+
+        let output = fs::read_to_string("testdata/sample_result_3").unwrap();
+        let sample = fs::read_to_string(&name).unwrap();
+        assert_eq!(sample.lines().count(), 2);
+        assert!(sample.lines().all(|l| output.lines().any(|o| o == l)));
+        let _ = fs::remove_file("testdata/sample_result_3");
+        let _ = fs::remove_file(name);
+    }
+
+    fn run_strategy(strategy: ReduceStrategy,
+                    insensitive: bool,
+                    srcs: Vec<Vec<Record>>)
+                    -> (Vec<String>, JobStats) {
+        let mut out = Vec::new();
+        let params = MRParameters::new()
+            .set_file_locations(String::from("testdata/reduce_strategy_map_"),
+                                String::from("testdata/reduce_strategy_out_"))
+            .set_reduce_strategy(strategy)
+            .set_reduce_group_opts(1, insensitive)
+            .set_group_key_policy(GroupKeyPolicy::FirstSeen);
+        let stats = ReducePartition::new(ClosureMapReducer::new(fake_mapper, values_reducer),
+                                         params,
+                                         srcs.into_iter().map(|v| v.into_iter()).collect(),
+                                         &mut out)
+            ._run()
+            .0;
+        let groups = String::from_utf8(out)
+            .unwrap()
+            .split_terminator(';')
+            .map(String::from)
+            .collect();
+        (groups, stats)
+    }
+
+    #[test]
+    fn test_reduce_strategies() {
+        // Both sorted inputs contain the keys b and c.
+        let overlapping = || {
+            vec![vec![mk_rcrd("a", "1"), mk_rcrd("b", "2"), mk_rcrd("c", "3")],
+                 vec![mk_rcrd("B", "4"), mk_rcrd("c", "5"), mk_rcrd("d", "6")]]
+        };
+        let (groups, stats) = run_strategy(ReduceStrategy::SortMerge, false, overlapping());
+        assert_eq!(groups, vec!["a:1", "b:2", "B:4", "c:3,5", "d:6"]);
+        assert_eq!(stats.merge_inputs, 2);
+
+        // Hash aggregation groups like the merge, in the order in which keys first appear.
+        let (groups, stats) = run_strategy(ReduceStrategy::HashAggregate, false, overlapping());
+        assert_eq!(groups, vec!["a:1", "b:2", "c:3,5", "B:4", "d:6"]);
+        assert_eq!((stats.merge_inputs, stats.merge_comparisons), (0, 0));
+        let (groups, _) = run_strategy(ReduceStrategy::HashAggregate, true, overlapping());
+        assert_eq!(groups, vec!["a:1", "b:2,4", "c:3,5", "d:6"]);
+        let (sorted, _) = run_strategy(ReduceStrategy::SortMerge, true, overlapping());
+        assert_eq!(sorted, groups);
+
+        // Hash buckets group like hash aggregation, bucket by bucket.
+        let (mut groups, _) = run_strategy(ReduceStrategy::HashBuckets(3), false, overlapping());
+        groups.sort();
+        assert_eq!(groups, vec!["B:4", "a:1", "b:2", "c:3,5", "d:6"]);
+        let (mut groups, _) = run_strategy(ReduceStrategy::HashBuckets(2), true, overlapping());
+        groups.sort();
+        assert_eq!(groups, sorted);
+        assert!(list_intermediate_files(&String::from("testdata/reduce_strategy_map_"))
+            .unwrap()
+            .is_empty());
+
+        // Without merging, only adjacent keys are grouped.
+        let (groups, _) = run_strategy(ReduceStrategy::AssumeSorted, false, overlapping());
+        assert_eq!(groups, vec!["a:1", "b:2", "c:3", "B:4", "c:5", "d:6"]);
+        let consecutive = vec![vec![mk_rcrd("a", "1"), mk_rcrd("b", "2")],
+                               vec![mk_rcrd("b", "3"), mk_rcrd("c", "4")]];
+        let (groups, stats) = run_strategy(ReduceStrategy::AssumeSorted, false, consecutive);
+        assert_eq!(groups, vec!["a:1", "b:2,3", "c:4"]);
+        assert_eq!(stats.reduce_input_records, 4);
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(recs.values().len().to_string());
+    }
+
+    fn json_formatter(key: &str, result: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("{{\"key\":{:?},\"count\":{}}}\n", key, result).as_bytes());
+    }
+
+    #[test]
+    fn test_reduce_output_formatter() {
+        let mut out = Vec::new();
+        let output = ReducePartition::new(ClosureMapReducer::new(fake_mapper, count_reducer),
+                                          MRParameters::new()
+                                              .set_reduce_group_opts(1, true)
+                                              .set_output_formatter(json_formatter),
+                                          vec![get_records().into_iter()],
+                                          &mut out)
+            ._run()
+            .1;
+        let expected = "{\"key\":\"aaa\",\"count\":1}\n{\"key\":\"abb\",\"count\":2}\n\
+                        {\"key\":\"abbb\",\"count\":1}\n{\"key\":\"abc\",\"count\":1}\n\
+                        {\"key\":\"xyz\",\"count\":3}\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!((output.records, output.bytes), (5, expected.len()));
+    }
+
+    // Emits the key length and the number of values as two raw bytes, including a newline.
+    fn binary_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let (len, count) = (recs.key().len() as u8, recs.values().len() as u8);
+        e.emit_bytes(vec![len, b'\n', count, 0xff]);
+    }
+
+    #[test]
+    fn test_reduce_binary() {
+        let name = String::from("testdata/reduce_binary_0");
+        let output = ReducePartition::new(ClosureMapReducer::new(fake_mapper, binary_reducer),
+                                          MRParameters::new()
+                                              .set_reduce_group_opts(1, true)
+                                              .set_output_formatter(json_formatter),
+                                          vec![get_records().into_iter()],
+                                          WriteLogGenerator::new().new_output(&name))
+            ._run()
+            .1;
+        assert_eq!((output.records, output.bytes), (5, 20));
+
+        let entries: Vec<Vec<u8>> =
+            WriteLogReader::new_from_file(&name).unwrap().into_entries().collect();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[1], vec![3, b'\n', 2, 0xff]);
+        assert_eq!(entries[4], vec![3, b'\n', 3, 0xff]);
+        let _ = ::std::fs::remove_file(name);
+    }
 }
@@ -0,0 +1,170 @@
+//! Holds the intermediate records of a reduce shard in memory when shuffling without intermediate
+//! files (see `MRParameters::set_in_memory_shuffle()`). If a memory limit is set (see
+//! `MRParameters::set_shuffle_memory_limit()`), the buffered records are spilled to a WriteLog
+//! whenever they exceed the limit; the reduce phase merges the records still in memory with the
+//! spilled runs. With a memory budget (see `MRParameters::set_memory_budget()`), the buffered
+//! records are also spilled whenever the budget is exceeded.
+
+use std::fs;
+use std::io;
+
+use formats::writelog::{FilteredRecordReader, WriteLogReader, WriteLogWriter};
+use memory::MemoryAccount;
+use parameters::MRParameters;
+use phases::output::shuffle_spill_name;
+use record_types::{Record, compare_keys};
+use shard_merge::{ShardMergeIterator, build_stable};
+use trace::{self, Step};
+
+/// The sorted runs of intermediate records received by one reduce shard.
+pub struct ShuffleBuffer {
+    runs: Vec<Vec<Record>>,
+    // Bytes (keys and values) of the records in `runs`.
+    bytes: usize,
+    limit: usize,
+    location: String,
+    shard: usize,
+    batching: (usize, usize),
+    spills: Vec<String>,
+    spilled_bytes: u64,
+    stable: bool,
+    // Accounts for the buffered records until the buffer is dropped, as the reduce phase holds
+    // them after they have been returned by `inputs()`.
+    memory: Option<MemoryAccount>,
+}
+
+impl ShuffleBuffer {
+    /// Returns a buffer for reduce shard `shard`, with the memory limit of `params`.
+    pub fn new(params: &MRParameters, shard: usize) -> ShuffleBuffer {
+        ShuffleBuffer {
+            runs: Vec::new(),
+            bytes: 0,
+            limit: params.shuffle_memory_limit,
+            location: params.map_output_location.clone(),
+            shard,
+            batching: (params.map_output_batch_records, params.map_output_batch_bytes),
+            spills: Vec::new(),
+            spilled_bytes: 0,
+            stable: params.stable_merge,
+            memory: MemoryAccount::for_job(params, format!("shuffle #{}", shard)),
+        }
+    }
+
+    /// Adds a run of records sorted like the output of a map partition. Spills all buffered
+    /// runs if they exceed the memory limit or the memory budget afterwards.
+    pub fn add(&mut self, run: Vec<Record>) -> io::Result<()> {
+        self.bytes += run.iter().map(|r| r.key.len() + r.value.len()).sum::<usize>();
+        self.runs.push(run);
+        let over_budget = match self.memory {
+            Some(ref mut memory) => {
+                memory.set(self.bytes);
+                memory.over_budget()
+            }
+            None => false,
+        };
+        if (self.limit > 0 && self.bytes > self.limit) || over_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the buffered runs into one sorted WriteLog of intermediate records.
+    fn spill(&mut self) -> io::Result<()> {
+        let _span = trace::enter(Step::Spill, self.shard);
+        let name = shuffle_spill_name(&self.location, self.shard, self.spills.len());
+        let mut writer = WriteLogWriter::<fs::File>::new_to_file(&name, false)?
+            .set_batching(self.batching.0, self.batching.1);
+        self.spills.push(name);
+
+        let mut runs = ::std::mem::take(&mut self.runs).into_iter().map(Vec::into_iter);
+        // Spills are merged before the runs that arrive after them, so a stable merge of the runs
+        // keeps the records of a key in the order in which the runs were added.
+        let merged: Box<dyn Iterator<Item = Record>> = if self.stable {
+            build_stable(&mut runs, compare_keys).0
+        } else {
+            Box::new(ShardMergeIterator::build(&mut runs))
+        };
+        for r in merged {
+            writer.write_record(r.key.as_bytes(), r.value.as_bytes())?;
+        }
+        io::Write::flush(&mut writer)?;
+        self.spilled_bytes += writer.get_stats().0;
+        self.bytes = 0;
+        if let Some(ref mut memory) = self.memory {
+            memory.set(0);
+        }
+        Ok(())
+    }
+
+    /// Returns the files that runs have been spilled to.
+    pub fn spills(&self) -> &[String] {
+        &self.spills
+    }
+
+    /// Returns how many bytes have been spilled.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spilled_bytes
+    }
+
+    /// Returns the reduce inputs: the runs in memory and readers of the spilled runs.
+    pub fn inputs(&mut self) -> io::Result<Vec<Box<dyn Iterator<Item = Record>>>> {
+        let mut inputs: Vec<Box<dyn Iterator<Item = Record>>> = Vec::new();
+        for name in &self.spills {
+            let reader = WriteLogReader::new_from_file(name)?;
+            inputs.push(Box::new(FilteredRecordReader::new(reader, None)));
+        }
+        for run in ::std::mem::take(&mut self.runs) {
+            inputs.push(Box::new(run.into_iter()));
+        }
+        self.bytes = 0;
+        Ok(inputs)
+    }
+
+    /// Removes the spilled runs.
+    pub fn remove_spills(&mut self) {
+        for name in self.spills.drain(..) {
+            let _ = fs::remove_file(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShuffleBuffer;
+    use parameters::MRParameters;
+    use record_types::Record;
+    use shard_merge::ShardMergeIterator;
+    use std::fs;
+
+    fn run(keys: &[&str]) -> Vec<Record> {
+        keys.iter()
+            .map(|k| {
+                Record {
+                    key: String::from(*k),
+                    value: String::from("v"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spill() {
+        let params = MRParameters::new()
+            .set_shuffle_memory_limit(8)
+            .set_file_locations(String::from("testdata/shuffle_"), String::from("unused"));
+        let mut buffer = ShuffleBuffer::new(&params, 1);
+        buffer.add(run(&["b", "d"])).unwrap();
+        assert!(buffer.spills().is_empty());
+        buffer.add(run(&["a", "c", "e"])).unwrap();
+        assert_eq!(buffer.spills(), &[String::from("testdata/shuffle_spill-1.0")]);
+        assert_eq!(buffer.spilled_bytes(), 5 * (4 + 8 + 2));
+        buffer.add(run(&["a", "f"])).unwrap();
+        assert_eq!(buffer.spills().len(), 1);
+
+        let mut inputs = buffer.inputs().unwrap().into_iter();
+        let keys: Vec<_> = ShardMergeIterator::build(&mut inputs).map(|r| r.key).collect();
+        assert_eq!(keys, vec!["a", "a", "b", "c", "d", "e", "f"]);
+        buffer.remove_spills();
+        assert!(fs::metadata("testdata/shuffle_spill-1.0").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! Lowers the OS scheduling priority of worker threads (see
+//! `MRParameters::set_thread_niceness()`), so that long batch jobs don't starve interactive work
+//! on the same machine.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+extern crate libc;
+
+/// Lowers the scheduling priority of the calling thread to the nice value `niceness` (1 to 19).
+/// On Linux, the nice value applies to the calling thread only; on macOS, the thread is moved to
+/// the background band (regardless of the value). Unprivileged processes can't raise the
+/// priority of a thread again. Returns an error on other platforms.
+pub fn lower_thread_priority(niceness: i32) -> io::Result<()> {
+    set_thread_niceness(niceness)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_thread_niceness(niceness: i32) -> io::Result<()> {
+    // PRIO_PROCESS with a thread ID affects only that thread on Linux.
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, tid, niceness) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn set_thread_niceness(_niceness: i32) -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, libc::PRIO_DARWIN_BG) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn set_thread_niceness(_niceness: i32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "thread priorities aren't supported on this platform"))
+}
+
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Called by worker threads when they start: Lowers their priority if `niceness` is positive.
+/// Failures are reported once per process, and otherwise ignored.
+pub fn apply_niceness(niceness: i32) {
+    if niceness <= 0 {
+        return;
+    }
+    if let Err(e) = lower_thread_priority(niceness) {
+        if !WARNED.swap(true, Ordering::SeqCst) {
+            println!("WARN: Couldn't lower priority of worker threads: {}", e);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{apply_niceness, libc};
+    use std::thread;
+
+    #[test]
+    fn test_apply_niceness() {
+        let niceness = thread::spawn(|| {
+                apply_niceness(7);
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+                unsafe { libc::getpriority(libc::PRIO_PROCESS as _, tid) }
+            })
+            .join()
+            .unwrap();
+        assert!(niceness >= 7);
+    }
+}
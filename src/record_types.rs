@@ -1,7 +1,12 @@
-use std::collections::LinkedList;
 use std::cmp::{Eq, PartialEq, Ordering, PartialOrd};
 
+use arena::{ArenaStr, StrArena};
+use codec::ValueCodec;
+use header::RecordHeader;
+use malformed::MalformedHandler;
+use parameters::{EmitLimit, JobConfig, MRParameters};
 use sort;
+use termination::Termination;
 
 /// A (key,value) pair.
 #[derive(Clone, PartialEq, Eq)]
@@ -18,6 +23,12 @@ pub fn mk_rcrd(k: &str, v: &str) -> Record {
     }
 }
 
+/// Compares records by key only (in dictionary order), e.g. for a stable merge (see
+/// `shard_merge::build_stable()`).
+pub fn compare_keys(a: &Record, b: &Record) -> Ordering {
+    sort::dict_string_compare(&a.key, &b.key)
+}
+
 impl PartialOrd for Record {
     fn partial_cmp(&self, other: &Record) -> Option<Ordering> {
         Some(match sort::dict_string_compare(&self.key, &other.key) {
@@ -30,7 +41,7 @@ impl PartialOrd for Record {
 impl Ord for Record {
     fn cmp(&self, other: &Record) -> Ordering {
         match sort::dict_string_compare(&self.key, &other.key) {
-            Ordering::Equal => sort::dict_string_compare(&self.key, &other.value),
+            Ordering::Equal => sort::dict_string_compare(&self.value, &other.value),
             o => o,
         }
     }
@@ -58,6 +69,40 @@ impl MultiRecord {
     pub fn values<'a>(&'a self) -> &'a Vec<String> {
         &self.values
     }
+    /// Returns the number of values, e.g. for pre-allocating buffers or choosing an algorithm
+    /// before iterating over the values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    /// Returns the bounds on the number of values, like `Iterator::size_hint()`. Groups (or their
+    /// chunks, see `MRParameters::set_reduce_chunk_values()`) are read completely before they
+    /// are passed to the reducer, so the count is exact.
+    pub fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.values.len(), Some(self.values.len()))
+    }
+    /// Iterates over the values, split into the header attached by the mapper (see
+    /// `MEmitter::emit_with_header()`) and the value itself.
+    pub fn values_with_headers(&self) -> ValuesWithHeaders<'_> {
+        ValuesWithHeaders { values: self.values.iter() }
+    }
+}
+
+/// The values of a `MultiRecord` with their headers (see `MultiRecord::values_with_headers()`).
+pub struct ValuesWithHeaders<'a> {
+    values: ::std::slice::Iter<'a, String>,
+}
+
+impl<'a> Iterator for ValuesWithHeaders<'a> {
+    type Item = (Option<RecordHeader>, &'a str);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.values.next().map(|v| RecordHeader::split(v))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
 }
 
 impl PartialEq for MultiRecord {
@@ -83,39 +128,478 @@ impl IntoIterator for MultiRecord {
     }
 }
 
+// A (key,[value]) pair emitted by a mapper. Pairs emitted with `MEmitter::emit_str()` are
+// stored in the emitter's arena.
+enum Emitted {
+    Owned(String, Vec<String>),
+    Arena(ArenaStr, ArenaStr),
+}
+
+fn alloc_key(arena: &mut StrArena, intern: bool, key: &str) -> ArenaStr {
+    if intern { arena.intern(key) } else { arena.alloc(key) }
+}
+
 /// Emitter type used in the mapper phase; used to emit (key,value) pairs.
 pub struct MEmitter {
-    r: LinkedList<Record>,
+    // Emitted values, grouped by key as they were emitted.
+    r: Vec<Emitted>,
+    // Keys and values emitted by reference, copied back-to-back.
+    arena: StrArena,
+    key_only: bool,
+    // Whether keys are interned in the arena (see `MRParameters::set_intern_keys()`).
+    intern_keys: bool,
+    // Prepended to every emitted value (see `_set_value_tag()`).
+    value_tag: Option<char>,
+    malformed: Option<MalformedHandler>,
+    termination: Option<Termination>,
+    config: JobConfig,
+    emit_limit: EmitLimit,
+    // Values emitted since the last `_flush()`, i.e. for the current input record.
+    emitted: usize,
+    // xorshift state for sampling emitted values.
+    sample_state: u64,
 }
 
 impl MEmitter {
     pub fn new() -> MEmitter {
-        MEmitter { r: LinkedList::new() }
+        MEmitter {
+            r: Vec::new(),
+            arena: StrArena::new(),
+            key_only: false,
+            intern_keys: false,
+            value_tag: None,
+            malformed: None,
+            termination: None,
+            config: JobConfig::default(),
+            emit_limit: EmitLimit::Off,
+            emitted: 0,
+            sample_state: 1,
+        }
+    }
+    /// Returns an emitter for a partition of the job described by `params`.
+    pub fn for_job(params: &MRParameters) -> MEmitter {
+        MEmitter {
+            r: Vec::new(),
+            arena: StrArena::new(),
+            key_only: params.key_only,
+            intern_keys: params.intern_keys,
+            value_tag: None,
+            malformed: Some(params.malformed.clone()),
+            termination: Some(params.termination.clone()),
+            config: params.config.clone(),
+            emit_limit: params.emit_limit,
+            emitted: 0,
+            sample_state: (params.shard_seed ^ params.shard_id as u64) | 1,
+        }
     }
     pub fn emit(&mut self, key: String, val: String) {
-        self.r.push_back(Record {
-            key: key,
-            value: val,
-        })
+        if let Some(slot) = self.slot() {
+            let val = self.tag(val);
+            self.put(slot, Emitted::Owned(key, vec![val]))
+        }
+    }
+    /// Emits several values for the same key. A Vec of values is moved into the emitter
+    /// without copying or reallocating it, unless an emit limit is set.
+    pub fn emit_all<I: IntoIterator<Item = String>>(&mut self, key: String, values: I) {
+        if self.emit_limit != EmitLimit::Off {
+            for v in values {
+                self.emit(key.clone(), v);
+            }
+            return;
+        }
+        let values = match self.value_tag {
+            None => values.into_iter().collect(),
+            Some(_) => values.into_iter().map(|v| self.tag(v)).collect(),
+        };
+        self.r.push(Emitted::Owned(key, values))
+    }
+    /// Emits a (key,value) pair borrowed from the input record (or elsewhere). Instead of
+    /// allocating two Strings per pair, key and value are copied once into an arena kept for the
+    /// whole map partition. In key-only jobs, the value isn't copied at all.
+    pub fn emit_str(&mut self, key: &str, val: &str) {
+        let slot = match self.slot() {
+            None => return,
+            Some(slot) => slot,
+        };
+        let key = self.alloc_key(key);
+        let val = if self.key_only {
+            ArenaStr::empty()
+        } else if let Some(tag) = self.value_tag {
+            self.arena.alloc(&format!("{}{}", tag, val))
+        } else {
+            self.arena.alloc(val)
+        };
+        self.put(slot, Emitted::Arena(key, val))
+    }
+    /// Emits a (key,value) pair with a header that is passed on to the reducer with the value
+    /// (see `header` and `MultiRecord::values_with_headers()`).
+    pub fn emit_with_header(&mut self, key: String, val: &str, header: &RecordHeader) {
+        self.emit(key, header.attach(val))
+    }
+    /// Emits a key without value, e.g. in key-only jobs (see `MRParameters::set_key_only()`).
+    pub fn emit_key(&mut self, key: String) {
+        self.emit(key, String::new())
+    }
+    /// Rejects an input record that doesn't fulfill the expectations of the mapper; it is
+    /// handled according to the job's `MalformedPolicy`.
+    pub fn reject(&mut self, original: &Record, reason: &str) {
+        if let Some(ref handler) = self.malformed {
+            handler.handle(original.value.as_bytes(), reason);
+        }
+    }
+    /// Ends the job early: No more input records are mapped, and the records mapped so far are
+    /// reduced. The values emitted so far are kept.
+    pub fn terminate(&mut self) {
+        if let Some(ref termination) = self.termination {
+            termination.terminate();
+        }
+    }
+    /// Returns the configuration values of the job (see `MRParameters::set_config()`).
+    pub fn config(&self) -> &JobConfig {
+        &self.config
+    }
+    /// Prepends `tag` to all values emitted from now on, until it is reset with None. Used to
+    /// tell the inputs of a co-group job apart (see `cogroup::CoGroupMapper`).
+    pub fn _set_value_tag(&mut self, tag: Option<char>) {
+        self.value_tag = tag;
+    }
+    /// Sets the emit limit for the values emitted from now on (see
+    /// `MRParameters::set_emit_limit()`).
+    pub fn _set_emit_limit(&mut self, limit: EmitLimit) {
+        self.emit_limit = limit;
+    }
+    // Counts an emitted value and returns the index in `r` where it is to be stored, or None if
+    // it is dropped by sampling. While a limit is set, every entry of `r` holds one value.
+    fn slot(&mut self) -> Option<usize> {
+        self.emitted += 1;
+        match self.emit_limit {
+            EmitLimit::Sample(n) | EmitLimit::Fail(n) if self.emitted <= n => Some(self.r.len()),
+            EmitLimit::Off => Some(self.r.len()),
+            EmitLimit::Fail(n) => {
+                panic!("Mapper emitted more than {} values for a single input record", n)
+            }
+            EmitLimit::Sample(n) => {
+                self.sample_state ^= self.sample_state << 13;
+                self.sample_state ^= self.sample_state >> 7;
+                self.sample_state ^= self.sample_state << 17;
+                let i = (self.sample_state % self.emitted as u64) as usize;
+                if i < n { Some(i) } else { None }
+            }
+        }
+    }
+    fn put(&mut self, slot: usize, e: Emitted) {
+        if slot == self.r.len() {
+            self.r.push(e)
+        } else {
+            self.r[slot] = e
+        }
+    }
+    fn alloc_key(&mut self, key: &str) -> ArenaStr {
+        alloc_key(&mut self.arena, self.intern_keys, key)
+    }
+    fn tag(&self, val: String) -> String {
+        match self.value_tag {
+            None => val,
+            Some(tag) => {
+                let mut tagged = String::with_capacity(tag.len_utf8() + val.len());
+                tagged.push(tag);
+                tagged.push_str(&val);
+                tagged
+            }
+        }
+    }
+    /// Calls `f` for every (key,value) pair emitted since the last call. Owned pairs are moved
+    /// into the arena, which is kept, so that the map phase can use a single emitter (and arena)
+    /// per partition.
+    pub fn _flush<F: FnMut(ArenaStr, ArenaStr)>(&mut self, mut f: F) {
+        if let EmitLimit::Sample(n) = self.emit_limit {
+            if self.emitted > n {
+                println!("WARN: Mapper emitted {} values for a single input record; keeping a \
+                          sample of {}",
+                         self.emitted,
+                         n);
+            }
+        }
+        self.emitted = 0;
+        for e in self.r.drain(..) {
+            match e {
+                Emitted::Owned(key, values) => {
+                    let key = alloc_key(&mut self.arena, self.intern_keys, &key);
+                    for v in values {
+                        if self.key_only {
+                            f(key, ArenaStr::empty());
+                        } else {
+                            f(key, self.arena.alloc(&v));
+                        }
+                    }
+                }
+                Emitted::Arena(key, val) => f(key, val),
+            }
+        }
     }
-    pub fn _get(self) -> LinkedList<Record> {
+    /// Returns the arena containing the pairs passed on by `_flush()`.
+    pub fn _arena(&self) -> &StrArena {
+        &self.arena
+    }
+    pub fn _get(self) -> Vec<(String, Vec<String>)> {
+        let arena = self.arena;
         self.r
+            .into_iter()
+            .map(|e| match e {
+                Emitted::Owned(k, vs) => (k, vs),
+                Emitted::Arena(k, v) => {
+                    (String::from(arena.get(k)), vec![String::from(arena.get(v))])
+                }
+            })
+            .collect()
+    }
+}
+
+// A value emitted by a reducer, owned or as span in the emitter's arena.
+enum REmitted {
+    Owned(String),
+    All(Vec<String>),
+    Arena(ArenaStr),
+    Bytes(Vec<u8>),
+}
+
+/// A value emitted by a reducer, as passed to the callback of `REmitter::_drain()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmittedValue<'a> {
+    Text(&'a str),
+    /// Emitted by `REmitter::emit_bytes()`.
+    Binary(&'a [u8]),
+}
+
+impl<'a> EmittedValue<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        match *self {
+            EmittedValue::Text(s) => s.as_bytes(),
+            EmittedValue::Binary(b) => b,
+        }
     }
 }
 
 /// Emitter used in the reducer phase; used to emit values.
 pub struct REmitter {
-    r: LinkedList<String>,
+    r: Vec<REmitted>,
+    arena: StrArena,
+    malformed: Option<MalformedHandler>,
+    termination: Option<Termination>,
+    config: JobConfig,
+    shard: usize,
 }
 
 impl REmitter {
     pub fn new() -> REmitter {
-        REmitter { r: LinkedList::new() }
+        REmitter {
+            r: Vec::new(),
+            arena: StrArena::new(),
+            malformed: None,
+            termination: None,
+            config: JobConfig::default(),
+            shard: 0,
+        }
+    }
+    /// Returns an emitter for a partition of the job described by `params`.
+    pub fn for_job(params: &MRParameters) -> REmitter {
+        REmitter {
+            r: Vec::new(),
+            arena: StrArena::new(),
+            malformed: Some(params.malformed.clone()),
+            termination: Some(params.termination.clone()),
+            config: params.config.clone(),
+            shard: params.shard_id,
+        }
     }
     pub fn emit(&mut self, val: String) {
-        self.r.push_back(val)
+        self.r.push(REmitted::Owned(val))
     }
-    pub fn _get(self) -> LinkedList<String> {
-        self.r
+    /// Emits several values. A Vec of values is moved into the emitter without copying or
+    /// reallocating it.
+    pub fn emit_all<I: IntoIterator<Item = String>>(&mut self, values: I) {
+        self.r.push(REmitted::All(values.into_iter().collect()))
+    }
+    /// Emits a binary value, e.g. a serialized message. Binary values are written to the sink
+    /// unchanged (the output formatter isn't applied to them, see
+    /// `MRParameters::set_output_formatter()`); use a sink that keeps values apart without
+    /// relying on their contents, like `formats::writelog::WriteLogGenerator`, and read the
+    /// output with `WriteLogReader::into_entries()`.
+    pub fn emit_bytes(&mut self, val: Vec<u8>) {
+        self.r.push(REmitted::Bytes(val))
+    }
+    /// Emits `val` as binary value encoded by `codec` (see `emit_bytes()`).
+    pub fn emit_encoded<T, C: ValueCodec<T>>(&mut self, codec: &C, val: &T) {
+        let mut encoded = Vec::new();
+        codec.encode(val, &mut encoded);
+        self.emit_bytes(encoded)
+    }
+    /// Emits a borrowed value. It is copied into an arena that is reused for all groups of the
+    /// reduce partition, instead of being allocated as String.
+    pub fn emit_str(&mut self, val: &str) {
+        let val = self.arena.alloc(val);
+        self.r.push(REmitted::Arena(val))
+    }
+    /// Rejects a group of records that doesn't fulfill the expectations of the reducer; the key
+    /// is handled according to the job's `MalformedPolicy`.
+    pub fn reject(&mut self, key: &str, reason: &str) {
+        if let Some(ref handler) = self.malformed {
+            handler.handle(key.as_bytes(), reason);
+        }
+    }
+    /// Ends the job early: All reduce partitions stop after their current group. The values
+    /// emitted so far are kept.
+    pub fn terminate(&mut self) {
+        if let Some(ref termination) = self.termination {
+            termination.terminate();
+        }
+    }
+    /// Returns the configuration values of the job (see `MRParameters::set_config()`).
+    pub fn config(&self) -> &JobConfig {
+        &self.config
+    }
+    /// Returns the number of the reduce shard the emitter belongs to.
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+    /// Calls `f` for every value emitted since the last call, in order, and clears the emitter
+    /// (keeping its allocations).
+    pub fn _drain<F: FnMut(EmittedValue)>(&mut self, mut f: F) {
+        for e in self.r.drain(..) {
+            match e {
+                REmitted::Owned(ref v) => f(EmittedValue::Text(v)),
+                REmitted::All(ref vs) => vs.iter().for_each(|v| f(EmittedValue::Text(v))),
+                REmitted::Arena(v) => f(EmittedValue::Text(self.arena.get(v))),
+                REmitted::Bytes(ref v) => f(EmittedValue::Binary(v)),
+            }
+        }
+        self.arena.clear();
+    }
+    /// Returns the emitted values; binary values are converted lossily.
+    pub fn _get(mut self) -> Vec<String> {
+        let mut r = Vec::with_capacity(self.r.len());
+        self._drain(|v| r.push(String::from_utf8_lossy(v.as_bytes()).into_owned()));
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MEmitter, MultiRecord, REmitter};
+    use header::RecordHeader;
+    use parameters::{EmitLimit, MRParameters};
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn test_emit_all() {
+        let mut em = MEmitter::new();
+        em.emit(String::from("a"), String::from("1"));
+        let values = vec![String::from("2"), String::from("3")];
+        let ptr = values.as_ptr();
+        em.emit_all(String::from("b"), values);
+        let emitted = em._get();
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[1].1, vec!["2", "3"]);
+        // The Vec has been moved, not copied.
+        assert_eq!(emitted[1].1.as_ptr(), ptr);
+
+        let mut em = REmitter::new();
+        em.emit_all(vec![String::from("x")]);
+        em.emit_all(vec![String::from("y"), String::from("z")]);
+        assert_eq!(em._get(), vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn test_multi_record_len() {
+        let values = vec![String::from("1"), String::from("2"), String::from("3")];
+        let group = MultiRecord::new(String::from("k"), values);
+        assert_eq!(group.len(), 3);
+        assert!(!group.is_empty());
+        assert_eq!(group.size_hint(), (3, Some(3)));
+        let mut it = group.into_iter();
+        it.next();
+        assert_eq!(it.len(), 2);
+        assert!(MultiRecord::new(String::from("k"), Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_emit_with_header() {
+        let mut em = MEmitter::new();
+        em.emit_with_header(String::from("k"), "1", &RecordHeader::new().set_tag('L'));
+        em.emit(String::from("k"), String::from("2"));
+        let values = em._get().into_iter().flat_map(|(_, values)| values).collect();
+        let group = MultiRecord::new(String::from("k"), values);
+        let split: Vec<_> = group.values_with_headers().collect();
+        assert_eq!(split, vec![(Some(RecordHeader::new().set_tag('L')), "1"), (None, "2")]);
+    }
+
+    #[test]
+    fn test_emit_str() {
+        let line = String::from("abc def");
+        let mut em = MEmitter::new();
+        for w in line.split_whitespace() {
+            em.emit_str(w, "1");
+        }
+        em.emit(String::from("ghi"), String::from("2"));
+        let mut pairs = Vec::new();
+        em._flush(|k, v| pairs.push((k, v)));
+        let pairs: Vec<_> =
+            pairs.into_iter().map(|(k, v)| (em._arena().get(k), em._arena().get(v))).collect();
+        assert_eq!(pairs, vec![("abc", "1"), ("def", "1"), ("ghi", "2")]);
+        em.emit_str("jkl", "3");
+        let emitted = em._get();
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0], (String::from("jkl"), vec![String::from("3")]));
+
+        let mut em = REmitter::new();
+        em.emit_str("x");
+        em.emit(String::from("y"));
+        em.emit_str("z");
+        assert_eq!(em._get(), vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn test_emit_limit() {
+        let params = MRParameters::new().set_emit_limit(EmitLimit::Sample(3));
+        let mut em = MEmitter::for_job(&params);
+        for i in 0..100 {
+            em.emit_str("k", &i.to_string());
+        }
+        em.emit_all(String::from("l"), vec![String::from("x"); 10]);
+        let mut pairs = Vec::new();
+        em._flush(|k, v| pairs.push((k, v)));
+        assert_eq!(pairs.len(), 3);
+        let mut sample: Vec<&str> = pairs.iter().map(|&(_, v)| em._arena().get(v)).collect();
+        sample.dedup();
+        assert!(sample.len() > 1);
+        // The limit applies per input record, i.e. between flushes.
+        em.emit(String::from("m"), String::from("1"));
+        em.emit(String::from("m"), String::from("2"));
+        assert_eq!(em._get().len(), 2);
+
+        let params = MRParameters::new().set_emit_limit(EmitLimit::Fail(2));
+        let mut em = MEmitter::for_job(&params);
+        em.emit_key(String::from("a"));
+        em.emit_key(String::from("b"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| em.emit_key(String::from("c"))));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let params = MRParameters::new()
+            .set_config("threshold", 0.5)
+            .set_config("pattern", "^a+$")
+            .set_config("limit", 10)
+            .set_config("limit", 20);
+        let em = MEmitter::for_job(&params);
+        assert_eq!(em.config().get::<f64>("threshold"), Some(0.5));
+        assert_eq!(em.config().get_str("pattern"), Some("^a+$"));
+        assert_eq!(em.config().get::<usize>("missing"), None);
+        assert_eq!(em.config().get_or("missing", 3), 3);
+
+        let em = REmitter::for_job(&params);
+        assert_eq!(em.config().get::<u32>("limit"), Some(20));
+        assert_eq!(REmitter::new().config().get_str("limit"), None);
     }
 }
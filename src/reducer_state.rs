@@ -0,0 +1,281 @@
+//! Checkpointed reducer state for incremental aggregation: A `StatefulReducer` keeps a state per
+//! key (for example a running total), which is stored in a state store after every run. The
+//! next run only maps the new input; its groups are reduced together with the stored state of
+//! their key, instead of recomputing the aggregates from all input ever seen.
+//!
+//! The state store consists of one file per reduce shard, `<prefix><shard>` (see
+//! `state_shard_name()`), holding (key, state) records sorted by key in a WriteLog. As the
+//! stored state is looked up by the shard a key is reduced in, the number of reducers and the
+//! sharder must not change between runs, and the sharder must assign keys to shards stably (for
+//! example `StableSharder`).
+
+use controller::MRController;
+use formats::writelog::{FilteredRecordReader, WriteLogReader, WriteLogWriter};
+use mapreducer::{Mapper, Reducer, Sharder};
+use parameters::MRParameters;
+use phases::output::SinkGenerator;
+use record_types::{MultiRecord, REmitter, Record};
+use sort::dict_str_compare;
+use stats::JobResult;
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Write};
+use std::iter::Peekable;
+
+pub trait StatefulReducer: Send + Clone {
+    /// Called once per key with the state stored for it by the previous run (if any) and the
+    /// new values; returns the state to store for the key, or None to drop it. Keys that only
+    /// have a stored state are passed with empty `values`, so that the reducer can emit their
+    /// result again, or let the state expire.
+    fn reduce(&mut self,
+              em: &mut REmitter,
+              key: &str,
+              state: Option<String>,
+              values: Vec<String>)
+              -> Option<String>;
+}
+
+/// StatefulReducer::reduce() function type.
+pub type StatefulReducerF = fn(&mut REmitter, &str, Option<String>, Vec<String>)
+                               -> Option<String>;
+
+impl StatefulReducer for StatefulReducerF {
+    fn reduce(&mut self,
+              em: &mut REmitter,
+              key: &str,
+              state: Option<String>,
+              values: Vec<String>)
+              -> Option<String> {
+        self(em, key, state, values)
+    }
+}
+
+/// The (key, state) records of a state store file.
+pub type StateRecords = Box<dyn Iterator<Item = Record> + Send>;
+
+/// Returns the name of the state store file of `shard`.
+pub fn state_shard_name(prefix: &str, shard: usize) -> String {
+    format!("{}{}", prefix, shard)
+}
+
+/// Reads the state store file of `shard` as records sorted by key. A missing file results in
+/// an empty store (e.g. in the first run).
+pub fn read_state_shard(prefix: &str, shard: usize) -> io::Result<StateRecords> {
+    match WriteLogReader::new_from_file(&state_shard_name(prefix, shard)) {
+        Ok(reader) => Ok(Box::new(FilteredRecordReader::new(reader, None))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Box::new(::std::iter::empty())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reduces groups with a `StatefulReducer`, merging them with the state store of the shard in
+/// key order. The new state of a shard is written next to the old one, and replaces it once the
+/// shard has been reduced completely; a failed run leaves the previous state in place.
+pub struct Checkpointed<R: StatefulReducer> {
+    reducer: R,
+    prefix: String,
+    // The stored state of the shard, and the new state; opened when the first group is reduced.
+    previous: Option<Peekable<StateRecords>>,
+    next: Option<WriteLogWriter<fs::File>>,
+    shard: usize,
+}
+
+impl<R: StatefulReducer> Checkpointed<R> {
+    /// Keeps the state of `reducer` in the state store at `prefix`.
+    pub fn new(reducer: R, prefix: String) -> Checkpointed<R> {
+        Checkpointed {
+            reducer,
+            prefix,
+            previous: None,
+            next: None,
+            shard: 0,
+        }
+    }
+
+    fn open(&mut self, em: &REmitter) {
+        if self.next.is_some() {
+            return;
+        }
+        self.shard = em.shard();
+        let previous = match read_state_shard(&self.prefix, self.shard) {
+            Ok(previous) => previous,
+            Err(e) => panic!("couldn't read reducer state: {}", e),
+        };
+        self.previous = Some(previous.peekable());
+        let tmp = format!("{}.tmp", state_shard_name(&self.prefix, self.shard));
+        match WriteLogWriter::<fs::File>::new_to_file(&tmp, false) {
+            Ok(w) => self.next = Some(w),
+            Err(e) => panic!("couldn't write reducer state: {}", e),
+        }
+    }
+
+    /// Removes the stored state of `key` from the store, after reducing the stored keys before
+    /// it (all remaining keys if `key` is None) without new values.
+    fn take_state(&mut self, em: &mut REmitter, key: Option<&str>) -> Option<String> {
+        loop {
+            let order = match self.previous.as_mut().and_then(|p| p.peek()) {
+                None => return None,
+                Some(stored) => key.map(|k| dict_str_compare(&stored.key, k)),
+            };
+            if order == Some(Ordering::Greater) {
+                return None;
+            }
+            let stored = self.previous.as_mut().and_then(|p| p.next()).unwrap();
+            if order == Some(Ordering::Equal) {
+                return Some(stored.value);
+            }
+            self.reduce_key(em, &stored.key, Some(stored.value), Vec::new());
+        }
+    }
+
+    fn reduce_key(&mut self,
+                  em: &mut REmitter,
+                  key: &str,
+                  state: Option<String>,
+                  values: Vec<String>) {
+        if let Some(state) = self.reducer.reduce(em, key, state, values) {
+            if let Err(e) = self.next.as_mut().unwrap().write_record(key.as_bytes(),
+                                                                     state.as_bytes()) {
+                panic!("couldn't write reducer state: {}", e);
+            }
+        }
+    }
+}
+
+impl<R: StatefulReducer> Clone for Checkpointed<R> {
+    /// Clones the reducer; the state store is opened anew by every clone.
+    fn clone(&self) -> Checkpointed<R> {
+        Checkpointed::new(self.reducer.clone(), self.prefix.clone())
+    }
+}
+
+impl<R: StatefulReducer> Reducer for Checkpointed<R> {
+    fn reduce(&mut self, em: &mut REmitter, records: MultiRecord) {
+        self.open(em);
+        let key = records.key().clone();
+        let state = self.take_state(em, Some(&key));
+        self.reduce_key(em, &key, state, records.into_iter().collect());
+    }
+
+    fn finish(&mut self, em: &mut REmitter) {
+        self.open(em);
+        self.take_state(em, None);
+        self.previous = None;
+        let mut next = self.next.take().unwrap();
+        if let Err(e) = next.flush() {
+            panic!("couldn't write reducer state: {}", e);
+        }
+        drop(next);
+        let name = state_shard_name(&self.prefix, self.shard);
+        if let Err(e) = fs::rename(format!("{}.tmp", name), &name) {
+            panic!("couldn't write reducer state: {}", e);
+        }
+    }
+}
+
+/// Runs a job over new input `inp`, reducing it together with the state that previous runs
+/// have stored at `state_prefix` (see `Checkpointed`), and stores the new state there.
+pub fn run_checkpointed<M, In, R, S, Out>(mapper: M,
+                                          reducer: R,
+                                          sharder: S,
+                                          params: MRParameters,
+                                          inp: In,
+                                          state_prefix: String,
+                                          out: Out)
+                                          -> JobResult
+    where M: Mapper,
+          In: Iterator<Item = Record>,
+          R: StatefulReducer,
+          S: Sharder,
+          Out: SinkGenerator
+{
+    MRController::run(mapper,
+                      Checkpointed::new(reducer, state_prefix),
+                      sharder,
+                      params,
+                      inp,
+                      out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatefulReducerF, read_state_shard, run_checkpointed, state_shard_name};
+    use closure_mr::ClosureMapReducer;
+    use formats::lines::LinesSinkGenerator;
+    use formats::output::read_reduce_outputs;
+    use formats::util::PosRecordIterator;
+    use mapreducer::StableSharder;
+    use parameters::MRParameters;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+    use std::vec;
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn unused_reducer(_: &mut REmitter, _: MultiRecord) {}
+
+    fn running_count(e: &mut REmitter,
+                     key: &str,
+                     state: Option<String>,
+                     values: Vec<String>)
+                     -> Option<String> {
+        let total = state.map(|s| s.parse::<usize>().unwrap()).unwrap_or(0) + values.len();
+        e.emit(format!("{} {}", key, total));
+        // Words seen only once expire after a run without new occurrences.
+        if total == 1 && values.is_empty() {
+            None
+        } else {
+            Some(total.to_string())
+        }
+    }
+
+    fn input(lines: &[&str]) -> PosRecordIterator<vec::IntoIter<String>> {
+        let lines: Vec<String> = lines.iter().map(|l| String::from(*l)).collect();
+        PosRecordIterator::new(lines.into_iter())
+    }
+
+    fn run(lines: &[&str]) -> Vec<String> {
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_merge_reduce_outputs(true)
+            .set_file_locations(String::from("testdata/rstate_map_"),
+                                String::from("testdata/rstate_out_"));
+        let sharder = StableSharder::from_params(&params);
+        run_checkpointed(ClosureMapReducer::new(word_mapper, unused_reducer),
+                         running_count as StatefulReducerF,
+                         sharder,
+                         params.clone(),
+                         input(lines),
+                         String::from("testdata/rstate_state_"),
+                         LinesSinkGenerator::new_to_files());
+        read_reduce_outputs(&String::from("testdata/rstate_out_"), &params)
+            .unwrap()
+            .map(|r| r.value)
+            .collect()
+    }
+
+    #[test]
+    fn test_checkpointed_reducer() {
+        assert_eq!(run(&["a b a", "c"]), vec!["a 2", "b 1", "c 1"]);
+        assert_eq!(run(&["b d", "a"]), vec!["a 3", "b 2", "c 1", "d 1"]);
+        // c expired in the last run.
+        assert_eq!(run(&["e"]), vec!["a 3", "b 2", "d 1", "e 1"]);
+
+        let mut stored: Vec<String> = (0..2)
+            .flat_map(|s| read_state_shard("testdata/rstate_state_", s).unwrap())
+            .map(|r| format!("{}={}", r.key, r.value))
+            .collect();
+        stored.sort();
+        assert_eq!(stored, vec!["a=3", "b=2", "e=1"]);
+
+        for shard in 0..2 {
+            let _ = fs::remove_file(state_shard_name("testdata/rstate_state_", shard));
+            let _ = fs::remove_file(format!("testdata/rstate_out_{}", shard));
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Sampling the key distribution of a job in order to choose the split points of a
+//! range-partitioned job (see `mapreducer::RangeSharder`), so that its output is in total order
+//! and the shards receive about the same number of records.
+//!
+//! Keys can be sampled from the input (`sample_keys()`, given a function extracting the key of
+//! an input record), from the keys that the mapper generates for the input
+//! (`sample_mapped_keys()`), or from the intermediate files of a job that kept them
+//! (`sample_intermediates()`). `split_points()` then calculates the quantiles of the sample.
+//! `MRController::run_total_order()` does all of this for a job reading text files.
+//!
+//! `Reservoir` keeps a sample of fixed size, e.g. of the records of a job for inspection (see
+//! `MRParameters::set_sample_output()`).
+
+use mapreducer::{Mapper, RangeSharder};
+use parameters::MRParameters;
+use phases::output::open_reduce_inputs;
+use record_types::{MEmitter, Record};
+use sort::dict_string_compare;
+
+use std::fs;
+use std::io::{self, Write};
+
+/// Extracts the key that an input record will be sharded by.
+pub type KeyExtractorF = fn(&Record) -> String;
+
+/// Collects a random sample of the keys offered to it: Every key is kept with probability
+/// `fraction`. Duplicate keys are kept as often as they are sampled, so that frequent keys
+/// weigh more when calculating split points.
+pub struct KeySampler {
+    fraction: f64,
+    state: u64,
+    keys: Vec<String>,
+}
+
+impl KeySampler {
+    /// Samples `fraction` (in [0; 1]) of all keys. The same `seed` results in the same sample
+    /// of the same keys.
+    pub fn new(fraction: f64, seed: u64) -> KeySampler {
+        KeySampler {
+            fraction,
+            state: seed | 1,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Returns whether the next key is to be sampled.
+    fn take(&mut self) -> bool {
+        if self.fraction >= 1.0 {
+            return true;
+        }
+        // The upper 53 bits are used as a number in [0; 1).
+        ((xorshift(&mut self.state) >> 11) as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+
+    pub fn offer(&mut self, key: &str) {
+        if self.take() {
+            self.keys.push(String::from(key));
+        }
+    }
+
+    /// Returns the keys sampled so far.
+    pub fn keys(&self) -> &Vec<String> {
+        &self.keys
+    }
+
+    /// Returns the split points for `n` shards (see `split_points()`).
+    pub fn split_points(self, n: usize) -> Vec<String> {
+        split_points(self.keys, n)
+    }
+
+    /// Returns a sharder partitioning keys into `n` shards of about equal size, according to
+    /// the sample.
+    pub fn sharder(self, n: usize) -> RangeSharder {
+        RangeSharder::new(self.split_points(n))
+    }
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Keeps a uniform random sample of at most `size` of the items offered to it (reservoir
+/// sampling), regardless of how many items are offered.
+pub struct Reservoir<T> {
+    size: usize,
+    seen: u64,
+    state: u64,
+    items: Vec<T>,
+}
+
+impl<T> Reservoir<T> {
+    /// The same `seed` results in the same sample of the same items.
+    pub fn new(size: usize, seed: u64) -> Reservoir<T> {
+        Reservoir {
+            size,
+            seen: 0,
+            state: seed | 1,
+            items: Vec::new(),
+        }
+    }
+
+    /// Offers the item returned by `item`, which is only called if the item is sampled.
+    pub fn offer<F: FnOnce() -> T>(&mut self, item: F) {
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push(item());
+            return;
+        }
+        let i = (xorshift(&mut self.state) % self.seen) as usize;
+        if i < self.size {
+            self.items[i] = item();
+        }
+    }
+
+    /// Returns the number of items offered so far.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Returns the items sampled so far, in no particular order.
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl Reservoir<String> {
+    /// Writes the sampled items to `path`, one per line.
+    pub fn write_to(&self, path: &String) -> io::Result<()> {
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        for item in &self.items {
+            f.write_all(item.as_bytes())?;
+            f.write_all(b"\n")?;
+        }
+        f.flush()
+    }
+}
+
+/// Calculates the split points for `n` shards from a sample of keys: the keys at the quantiles
+/// 1/n, 2/n, ... of the sample in dictionary order. If a key makes up a large part of the
+/// sample, split points may coincide; they are only returned once, so that fewer than n-1 split
+/// points may be returned (and some shards receive no keys).
+pub fn split_points(mut keys: Vec<String>, n: usize) -> Vec<String> {
+    keys.sort_by(dict_string_compare);
+    let mut splits: Vec<String> = Vec::with_capacity(n.saturating_sub(1));
+    if keys.is_empty() {
+        return splits;
+    }
+    for i in 1..n {
+        let split = &keys[i * keys.len() / n];
+        // A split at the smallest key would leave the first shard empty.
+        if splits.last() != Some(split) && *split != keys[0] {
+            splits.push(split.clone());
+        }
+    }
+    splits
+}
+
+/// Samples `fraction` of the keys of the records of `input`, extracted with `key_of`.
+pub fn sample_keys<In: Iterator<Item = Record>>(input: In,
+                                                key_of: KeyExtractorF,
+                                                fraction: f64,
+                                                seed: u64)
+                                                -> KeySampler {
+    let mut sampler = KeySampler::new(fraction, seed);
+    for record in input {
+        sampler.offer(&key_of(&record));
+    }
+    sampler
+}
+
+/// Maps `fraction` of the records of `input` with `mapper` and samples all keys that it emits.
+/// This reflects the distribution of the intermediate keys without running the map phase on
+/// the entire input.
+pub fn sample_mapped_keys<M: Mapper, In: Iterator<Item = Record>>(mapper: &mut M,
+                                                                  input: In,
+                                                                  fraction: f64,
+                                                                  seed: u64)
+                                                                  -> KeySampler {
+    let mut records = KeySampler::new(fraction, seed);
+    let mut sampler = KeySampler::new(1.0, seed);
+    let mut em = MEmitter::new();
+    for record in input {
+        if records.take() {
+            mapper.map(&mut em, record);
+        }
+    }
+    mapper.finish(&mut em);
+    for (key, values) in em._get() {
+        for _ in values {
+            sampler.offer(&key);
+        }
+    }
+    sampler
+}
+
+/// Samples `fraction` of the keys in the intermediate files written by the `partitions` map
+/// partitions of a job with `params` (usually kept with `MRParameters::keep_temp_files()`).
+/// Like the reduce phase, this panics if an intermediate file can't be read.
+pub fn sample_intermediates(params: &MRParameters, partitions: usize, fraction: f64) -> KeySampler {
+    let mut sampler = KeySampler::new(fraction, params.shard_seed);
+    for shard in 0..params.reducers {
+        let inputs = open_reduce_inputs(&params.map_output_location,
+                                        partitions,
+                                        shard,
+                                        None,
+                                        params,
+                                        Vec::new());
+        for input in inputs {
+            for record in input {
+                sampler.offer(&record.key);
+            }
+        }
+    }
+    sampler
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeySampler, Reservoir, sample_keys, sample_mapped_keys, split_points};
+    use closure_mr::ClosureMapReducer;
+    use formats::util::PosRecordIterator;
+    use mapreducer::Sharder;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+
+    fn value_key(r: &Record) -> String {
+        r.value.clone()
+    }
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(recs.key().clone());
+    }
+
+    #[test]
+    fn test_split_points() {
+        let keys: Vec<String> = (0..100).rev().map(|i| format!("{:02}", i)).collect();
+        assert_eq!(split_points(keys.clone(), 4), vec!["25", "50", "75"]);
+        assert!(split_points(keys, 1).is_empty());
+        assert!(split_points(Vec::new(), 4).is_empty());
+
+        // Coinciding split points are only returned once.
+        let mut skewed = vec![String::from("a"), String::from("c")];
+        skewed.extend(vec![String::from("b"); 8]);
+        assert_eq!(split_points(skewed, 4), vec!["b"]);
+
+        let mut all = KeySampler::new(1.0, 1);
+        let mut half = KeySampler::new(0.5, 1);
+        for i in 0..1000 {
+            all.offer(&i.to_string());
+            half.offer(&i.to_string());
+        }
+        assert_eq!(all.keys().len(), 1000);
+        assert!(half.keys().len() > 400 && half.keys().len() < 600);
+        let mut sharder = all.sharder(2);
+        assert_eq!(sharder.shard(2, &String::from("1")), 0);
+        assert_eq!(sharder.shard(2, &String::from("999")), 1);
+    }
+
+    #[test]
+    fn test_reservoir() {
+        let mut small = Reservoir::new(10, 3);
+        for i in 0..5 {
+            small.offer(|| i);
+        }
+        assert_eq!(small.items(), &vec![0, 1, 2, 3, 4]);
+
+        let sample = |seed| {
+            let mut r = Reservoir::new(100, seed);
+            for i in 0..10000 {
+                r.offer(|| i);
+            }
+            r
+        };
+        let r = sample(3);
+        assert_eq!(r.seen(), 10000);
+        assert_eq!(r.items().len(), 100);
+        assert_eq!(r.items(), sample(3).items());
+        // Items from all over the input are sampled.
+        assert!(r.items().iter().filter(|&&i| i < 5000).count() > 30);
+        assert!(r.items().iter().filter(|&&i| i >= 5000).count() > 30);
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let input = || {
+            PosRecordIterator::new((0..1000).map(|i| format!("k{:03} x{:03}", i, i % 10)))
+        };
+        let sample = sample_keys(input(), value_key, 0.2, 7);
+        assert!(sample.keys().iter().all(|k| k.starts_with('k')));
+        assert_eq!(sample.keys(), sample_keys(input(), value_key, 0.2, 7).keys());
+
+        let mut mr = ClosureMapReducer::new(word_mapper, count_reducer);
+        let sample = sample_mapped_keys(&mut mr, input(), 1.0, 7);
+        assert_eq!(sample.keys().len(), 2000);
+        let splits = sample.split_points(2);
+        assert_eq!(splits, vec!["x000"]);
+    }
+}
@@ -4,8 +4,113 @@
 
 #![allow(dead_code)]
 
+use std::cell::{Cell, RefCell};
 use std::cmp::{Ord, Ordering};
 use std::iter;
+use std::rc::Rc;
+
+/// Counters of a merge, collected by iterators built with `ShardMergeIterator::build_counted()`.
+#[derive(Debug, Default)]
+pub struct MergeCounters {
+    inputs: Cell<usize>,
+    depth: Cell<usize>,
+    comparisons: Cell<u64>,
+    exhausted: RefCell<Vec<usize>>,
+}
+
+impl MergeCounters {
+    /// Returns the number of merged inputs.
+    pub fn inputs(&self) -> usize {
+        self.inputs.get()
+    }
+
+    /// Returns the number of levels of the merge tree; every element passes through this many
+    /// comparisons at most.
+    pub fn depth(&self) -> usize {
+        self.depth.get()
+    }
+
+    /// Returns how often elements have been compared so far.
+    pub fn comparisons(&self) -> u64 {
+        self.comparisons.get()
+    }
+
+    /// Returns the indices of the inputs (in the order they were passed to `build_counted()`)
+    /// that have been exhausted so far, in the order in which they ran out.
+    pub fn exhaustion_order(&self) -> Vec<usize> {
+        self.exhausted.borrow().clone()
+    }
+}
+
+/// Orders elements by `cmp` alone, e.g. records by key, ignoring everything else that `Ord`
+/// would compare (see `build_stable()`).
+#[derive(Clone)]
+pub struct Stable<T> {
+    pub value: T,
+    cmp: fn(&T, &T) -> Ordering,
+}
+
+impl<T> PartialEq for Stable<T> {
+    fn eq(&self, other: &Stable<T>) -> bool {
+        (self.cmp)(&self.value, &other.value) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Stable<T> {}
+
+impl<T> PartialOrd for Stable<T> {
+    fn partial_cmp(&self, other: &Stable<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Stable<T> {
+    fn cmp(&self, other: &Stable<T>) -> Ordering {
+        (self.cmp)(&self.value, &other.value)
+    }
+}
+
+/// Merges `sources`, which must be sorted by `cmp`, comparing elements with `cmp` only. The
+/// merge is stable: Elements that `cmp` considers equal are returned in the order of the sources
+/// they come from (in the order the sources are passed), and in their order within a source.
+/// This holds because every merge step prefers its left input on equal elements, and the merge
+/// tree keeps its inputs in order from left to right.
+///
+/// Returns the merged elements along with the counters of the merge (see `build_counted()`).
+pub fn build_stable<'a, T, It, ItIt>(sources: &mut ItIt,
+                                     cmp: fn(&T, &T) -> Ordering)
+                                     -> (Box<dyn Iterator<Item = T> + 'a>, Rc<MergeCounters>)
+    where T: Clone + 'a,
+          It: Iterator<Item = T> + 'a,
+          ItIt: Iterator<Item = It>
+{
+    let mut wrapped = sources.map(|it| it.map(move |value| Stable { value, cmp }));
+    let (merged, counters) = ShardMergeIterator::build_counted(&mut wrapped);
+    (Box::new(merged.map(|s| s.value)), counters)
+}
+
+/// An input of a counted merge; records when it is exhausted.
+struct CountedInput<It: Iterator> {
+    it: It,
+    index: usize,
+    done: bool,
+    counters: Rc<MergeCounters>,
+}
+
+impl<It: Iterator> Iterator for CountedInput<It> {
+    type Item = It::Item;
+    fn next(&mut self) -> Option<It::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.it.next();
+        if next.is_none() {
+            self.done = true;
+            self.counters.exhausted.borrow_mut().push(self.index);
+        }
+        next
+    }
+}
 
 /// See module description.
 /// This type uses dynamic instead of static dispatch because it realizes an arbitrary structure
@@ -16,6 +121,8 @@ pub struct ShardMergeIterator<'a, T: Ord> {
 
     left_peeked: Option<T>,
     right_peeked: Option<T>,
+
+    counters: Option<Rc<MergeCounters>>,
 }
 
 impl<'a, T: Ord + Clone> Iterator for ShardMergeIterator<'a, T> {
@@ -44,6 +151,9 @@ impl<'a, T: Ord + Clone> Iterator for ShardMergeIterator<'a, T> {
                 return r;
             }
             (Some(l), Some(r)) => {
+                if let Some(ref counters) = self.counters {
+                    counters.comparisons.set(counters.comparisons.get() + 1);
+                }
                 let cmp = l.cmp(&r);
                 if cmp == Ordering::Less || cmp == Ordering::Equal {
                     self.left_peeked = None;
@@ -66,25 +176,49 @@ impl<'a, T: Ord + Clone> ShardMergeIterator<'a, T> {
             right: Box::new(iter::empty()),
             left_peeked: None,
             right_peeked: None,
+            counters: None,
         }
     }
 
-    pub fn build<It: Iterator<Item = T>, ItIt: Iterator<Item = It>>(sources: &mut ItIt)
-                                                                    -> ShardMergeIterator<'a, T>
+    pub fn build<It, ItIt: Iterator<Item = It>>(sources: &mut ItIt) -> ShardMergeIterator<'a, T>
+        where T: 'a,
+              It: Iterator<Item = T> + 'a
+    {
+        ShardMergeIterator::_build(sources, None)
+    }
+
+    /// Like `build()`, but the merge counts comparisons and records which inputs are exhausted
+    /// in the returned counters.
+    pub fn build_counted<It, ItIt: Iterator<Item = It>>
+        (sources: &mut ItIt)
+         -> (ShardMergeIterator<'a, T>, Rc<MergeCounters>)
         where T: 'a,
-              It: 'a
+              It: Iterator<Item = T> + 'a
     {
-        ShardMergeIterator::_build(sources)
+        let counters = Rc::new(MergeCounters::default());
+        let mut inputs = sources.enumerate().map(|(index, it)| {
+            CountedInput {
+                it,
+                index,
+                done: false,
+                counters: counters.clone(),
+            }
+        });
+        let merged = ShardMergeIterator::_build(&mut inputs, Some(counters.clone()));
+        (merged, counters)
     }
 
     /// Takes multiple iterators of type It and generates one ShardedMergeIterator..
     /// (yes, iterator over a collection of iterators).
-    fn _build<It: Iterator<Item = T>, ItIt: Iterator<Item = It>>(sources: &mut ItIt)
-                                                                 -> ShardMergeIterator<'a, T>
+    fn _build<It, ItIt: Iterator<Item = It>>
+        (sources: &mut ItIt,
+         counters: Option<Rc<MergeCounters>>)
+         -> ShardMergeIterator<'a, T>
         where T: 'a,
-              It: 'a
+              It: Iterator<Item = T> + 'a
     {
         let mut merged: Vec<ShardMergeIterator<T>> = Vec::new();
+        let mut inputs = 0;
 
         // Initial merging: Merge pairs of input iterators together.
         loop {
@@ -93,18 +227,22 @@ impl<'a, T: Ord + Clone> ShardMergeIterator<'a, T> {
                 None => break,
                 Some(src) => src1 = src,
             }
+            inputs += 1;
             match sources.next() {
                 None => {
                     merged.push(ShardMergeIterator {
                         left: Box::new(src1),
                         right: Box::new(iter::empty()),
+                        counters: counters.clone(),
                         ..ShardMergeIterator::default()
                     })
                 }
                 Some(src) => {
+                    inputs += 1;
                     merged.push(ShardMergeIterator {
                         left: Box::new(src1),
                         right: Box::new(src),
+                        counters: counters.clone(),
                         ..ShardMergeIterator::default()
                     })
                 }
@@ -112,35 +250,55 @@ impl<'a, T: Ord + Clone> ShardMergeIterator<'a, T> {
         }
 
         // Recursively build the merge tree from the leaves.
-        ShardMergeIterator::merge(merged)
+        let (root, depth) = ShardMergeIterator::merge(merged, &counters);
+        if let Some(ref counters) = counters {
+            counters.inputs.set(inputs);
+            counters.depth.set(depth);
+        }
+        root
     }
 
     /// Merge multiple ShardMergeIterators, recursively (meaning it will result in a more or less
-    /// balanced merge sort tree).
-    fn merge(mut its: Vec<ShardMergeIterator<'a, T>>) -> ShardMergeIterator<'a, T>
+    /// balanced merge sort tree). Also returns the depth of the tree, counting the given
+    /// iterators as one level.
+    fn merge(mut its: Vec<ShardMergeIterator<'a, T>>,
+             counters: &Option<Rc<MergeCounters>>)
+             -> (ShardMergeIterator<'a, T>, usize)
         where T: 'a
     {
+        let counters = counters.clone();
         if its.len() == 0 {
-            ShardMergeIterator::default()
+            (ShardMergeIterator::default(), 1)
         } else if its.len() == 1 {
-            ShardMergeIterator { left: Box::new(its.remove(0)), ..ShardMergeIterator::default() }
+            (ShardMergeIterator {
+                left: Box::new(its.remove(0)),
+                counters,
+                ..ShardMergeIterator::default()
+            },
+             2)
         } else if its.len() == 2 {
             let it1 = its.remove(0);
             let it2 = its.remove(0);
-            ShardMergeIterator {
+            (ShardMergeIterator {
                 left: Box::new(it1),
                 right: Box::new(it2),
+                counters,
                 ..ShardMergeIterator::default()
-            }
+            },
+             2)
         } else {
             // its is left part, right is right part
             let split_at = its.len() / 2;
             let right = its.split_off(split_at);
-            ShardMergeIterator {
-                left: Box::new(ShardMergeIterator::merge(its)),
-                right: Box::new(ShardMergeIterator::merge(right)),
+            let (left, left_depth) = ShardMergeIterator::merge(its, &counters);
+            let (right, right_depth) = ShardMergeIterator::merge(right, &counters);
+            (ShardMergeIterator {
+                left: Box::new(left),
+                right: Box::new(right),
+                counters,
                 ..ShardMergeIterator::default()
-            }
+            },
+             1 + left_depth.max(right_depth))
         }
     }
 }
@@ -148,7 +306,7 @@ impl<'a, T: Ord + Clone> ShardMergeIterator<'a, T> {
 #[cfg(test)]
 mod tests {
     use std::vec;
-    use shard_merge::ShardMergeIterator;
+    use shard_merge::{ShardMergeIterator, build_stable};
 
     fn get_collection_1() -> vec::IntoIter<i32> {
         vec![1, 4, 5, 5, 6, 9, 11, 15, 15, 17, 18, 20].into_iter()
@@ -193,6 +351,42 @@ mod tests {
                    get_collection_5().len() + get_collection_6().len());
     }
 
+    #[test]
+    fn test_merge_counters() {
+        let inputs = vec![vec![1, 5, 9], vec![2, 3], vec![4, 10, 11, 12], vec![]];
+        let (it, counters) =
+            ShardMergeIterator::build_counted(&mut inputs.into_iter().map(|v| v.into_iter()));
+        assert_eq!((counters.inputs(), counters.depth()), (4, 2));
+        assert_eq!(it.collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 9, 10, 11, 12]);
+        assert_eq!(counters.exhaustion_order(), vec![3, 1, 0, 2]);
+        assert!(counters.comparisons() >= 8 && counters.comparisons() <= 16);
+
+        let (_, counters) =
+            ShardMergeIterator::build_counted(&mut (0..5).map(|_| vec![1].into_iter()));
+        assert_eq!(counters.depth(), 3);
+    }
+
+    fn compare_first(a: &(i32, usize), b: &(i32, usize)) -> ::std::cmp::Ordering {
+        a.0.cmp(&b.0)
+    }
+
+    #[test]
+    fn test_stable_merge() {
+        // Equal keys come out in the order of the inputs, then in their order within an input.
+        let inputs: Vec<Vec<(i32, usize)>> = (0..7)
+            .map(|i| vec![(1, 10 * i + 1), (1, 10 * i), (2, 10 * i)])
+            .collect();
+        let (it, counters) =
+            build_stable(&mut inputs.into_iter().map(|v| v.into_iter()), compare_first);
+        let merged: Vec<(i32, usize)> = it.collect();
+        let mut expected: Vec<(i32, usize)> = (0..7)
+            .flat_map(|i| vec![(1, 10 * i + 1), (1, 10 * i)])
+            .collect();
+        expected.extend((0..7).map(|i| (2, 10 * i)));
+        assert_eq!(merged, expected);
+        assert_eq!(counters.inputs(), 7);
+    }
+
     use formats::lines;
     use std::fmt;
     use std::io::Write;
@@ -18,6 +18,12 @@ pub fn default_generic_compare<T: Ord>(a: &T, b: &T) -> Ordering {
 /// (like coreutil sort)
 #[inline]
 pub fn dict_string_compare(a: &String, b: &String) -> Ordering {
+    dict_str_compare(a, b)
+}
+
+/// Like dict_string_compare, for string slices.
+#[inline]
+pub fn dict_str_compare(a: &str, b: &str) -> Ordering {
     let (mut charsa, mut charsb) = (a.chars(), b.chars());
     loop {
         match (charsa.next(), charsb.next()) {
@@ -0,0 +1,371 @@
+//! Statistics collected while running a mapreduce job, and the description of its outputs.
+
+use histogram::KeyHistogram;
+use parameters::MapInputSort;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How many map partitions of a job try each structure before `MapInputSort::Auto` selects one.
+const SORT_TRIAL_PARTITIONS: usize = 2;
+
+/// Counters describing a mapreduce job (see `JobResult`, returned by `MRController::run()`);
+/// the phases collect their own counters and merge them into the job-wide instance.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobStats {
+    /// How many map partitions were run.
+    pub map_partitions: usize,
+    /// How many records were read as input to the map phase.
+    pub map_input_records: usize,
+    /// How many bytes (keys and values) were read as input to the map phase.
+    pub map_input_bytes: usize,
+    /// The counters of the input readers whose statistics were collected with
+    /// `MRParameters::input_stats` (see `InputStatsCollector`).
+    pub input: InputStats,
+    /// How many intermediate records were read by the reduce phase (before filtering), i.e.
+    /// merged from the inputs of the reduce partitions.
+    pub reduce_input_records: usize,
+    /// How many inputs the reduce partitions merged, and how often they compared records while
+    /// merging. Compared to `reduce_input_records`, this shows how much of the reduce phase is
+    /// spent on comparisons.
+    pub merge_inputs: usize,
+    pub merge_comparisons: u64,
+    /// The largest number of levels of the merge tree of a reduce partition.
+    pub merge_tree_depth: usize,
+    /// How many intermediate records were dropped by the shuffle filter
+    /// (see `MRParameters::set_shuffle_filter()`).
+    pub records_filtered: usize,
+    /// How many records were malformed or rejected by user code
+    /// (see `MRParameters::set_malformed_policy()`).
+    pub records_malformed: usize,
+    /// How many duplicate output lines were dropped (see `MRParameters::set_output_dedup()`).
+    pub output_duplicates: usize,
+    /// How many runs of intermediate records were spilled to disk by the in-memory shuffle, and
+    /// how many bytes they took up (see `MRParameters::set_shuffle_memory_limit()`).
+    pub shuffle_spills: usize,
+    pub shuffle_spilled_bytes: u64,
+    /// Whether the job was terminated early (see `termination`), i.e. not all input records
+    /// have been processed.
+    pub truncated: bool,
+    /// The heaviest keys reduced, if requested with `MRParameters::set_key_histogram()`.
+    pub key_histogram: Option<KeyHistogram>,
+    /// How long the map partitions took to sort their inputs (see `MapInputSort`).
+    pub map_input_sort: MapSortStats,
+}
+
+impl JobStats {
+    pub fn new() -> JobStats {
+        JobStats::default()
+    }
+
+    /// Adds the counters of `other` to this instance.
+    pub fn merge(&mut self, other: &JobStats) {
+        self.map_partitions += other.map_partitions;
+        self.map_input_records += other.map_input_records;
+        self.map_input_bytes += other.map_input_bytes;
+        self.input.merge(&other.input);
+        self.reduce_input_records += other.reduce_input_records;
+        self.merge_inputs += other.merge_inputs;
+        self.merge_comparisons += other.merge_comparisons;
+        self.merge_tree_depth = self.merge_tree_depth.max(other.merge_tree_depth);
+        self.records_malformed += other.records_malformed;
+        self.output_duplicates += other.output_duplicates;
+        self.shuffle_spills += other.shuffle_spills;
+        self.shuffle_spilled_bytes += other.shuffle_spilled_bytes;
+        self.truncated |= other.truncated;
+        self.records_filtered += other.records_filtered;
+        match (&mut self.key_histogram, &other.key_histogram) {
+            (Some(histogram), Some(other)) => histogram.merge(other),
+            (none, Some(other)) => *none = Some(other.clone()),
+            _ => (),
+        }
+        self.map_input_sort.merge(&other.map_input_sort);
+    }
+}
+
+/// The time map partitions took to sort their inputs with one structure.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SortTiming {
+    pub partitions: usize,
+    pub records: usize,
+    pub nanos: u64,
+}
+
+impl SortTiming {
+    /// Returns the average time per record, or None if no records have been sorted.
+    pub fn nanos_per_record(&self) -> Option<f64> {
+        if self.records == 0 {
+            None
+        } else {
+            Some(self.nanos as f64 / self.records as f64)
+        }
+    }
+
+    fn merge(&mut self, other: &SortTiming) {
+        self.partitions += other.partitions;
+        self.records += other.records;
+        self.nanos += other.nanos;
+    }
+}
+
+/// The sort timings of the map partitions of a job, by structure (see `MapInputSort`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MapSortStats {
+    pub btree: SortTiming,
+    pub sorted_vec: SortTiming,
+    /// The structure selected by `MapInputSort::Auto`, once both have been tried.
+    pub selected: Option<MapInputSort>,
+}
+
+impl MapSortStats {
+    /// Adds the timings of `other` to this instance.
+    pub fn merge(&mut self, other: &MapSortStats) {
+        self.btree.merge(&other.btree);
+        self.sorted_vec.merge(&other.sorted_vec);
+        self.selected = self.selected.or(other.selected);
+    }
+}
+
+#[derive(Default)]
+struct SortTrials {
+    stats: MapSortStats,
+    // The partitions started with either structure while trying them.
+    started: [usize; 2],
+}
+
+/// Collects the sort timings of the map partitions of a job, and selects the structure they
+/// sort with (see `MRParameters::map_sort_timings`). Clones share the timings.
+#[derive(Clone, Default)]
+pub struct MapSortTimings {
+    trials: Arc<Mutex<SortTrials>>,
+}
+
+impl MapSortTimings {
+    pub fn new() -> MapSortTimings {
+        MapSortTimings::default()
+    }
+
+    /// Returns the structure a map partition sorts its input with if the job is configured to
+    /// use `sort`. With `MapInputSort::Auto`, partitions alternate between the structures until
+    /// both have been tried on a few partitions; then the one taking less time per record is
+    /// selected and logged.
+    pub fn select(&self, sort: MapInputSort) -> MapInputSort {
+        if sort != MapInputSort::Auto {
+            return sort;
+        }
+        let mut trials = self.trials.lock().unwrap();
+        if let Some(selected) = trials.stats.selected {
+            return selected;
+        }
+        let stats = trials.stats;
+        if let (Some(btree), Some(sorted_vec)) = (stats.btree.nanos_per_record(),
+                                                  stats.sorted_vec.nanos_per_record()) {
+            if stats.btree.partitions >= SORT_TRIAL_PARTITIONS &&
+               stats.sorted_vec.partitions >= SORT_TRIAL_PARTITIONS {
+                let (selected, nanos, other, other_nanos) = if sorted_vec < btree {
+                    (MapInputSort::SortedVec, sorted_vec, MapInputSort::BTree, btree)
+                } else {
+                    (MapInputSort::BTree, btree, MapInputSort::SortedVec, sorted_vec)
+                };
+                println!("INFO: Map partitions sort their inputs with {:?} ({:.0} ns per record; \
+                          {:.0} ns with {:?})",
+                         selected,
+                         nanos,
+                         other_nanos,
+                         other);
+                trials.stats.selected = Some(selected);
+                return selected;
+            }
+        }
+        let i = if trials.started[1] < trials.started[0] { 1 } else { 0 };
+        trials.started[i] += 1;
+        [MapInputSort::BTree, MapInputSort::SortedVec][i]
+    }
+
+    /// Records that a map partition sorted `records` records with `sort` in `elapsed`.
+    pub fn record(&self, sort: MapInputSort, records: usize, elapsed: Duration) {
+        let mut trials = self.trials.lock().unwrap();
+        let timing = match sort {
+            MapInputSort::BTree => &mut trials.stats.btree,
+            MapInputSort::SortedVec => &mut trials.stats.sorted_vec,
+            MapInputSort::Auto => return,
+        };
+        timing.merge(&SortTiming {
+            partitions: 1,
+            records,
+            nanos: elapsed.as_nanos() as u64,
+        });
+    }
+
+    /// Returns the timings collected so far.
+    pub fn get(&self) -> MapSortStats {
+        self.trials.lock().unwrap().stats
+    }
+}
+
+/// Counters of an input reader (see `Stats`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputStats {
+    /// How many records (e.g. lines or WriteLog entries) were returned.
+    pub records: usize,
+    /// How many bytes were read from the underlying file or stream, including framing like line
+    /// endings and skipped data.
+    pub bytes: u64,
+    /// How many records or byte ranges couldn't be decoded and were skipped, e.g. lines that
+    /// aren't valid UTF-8 or corrupt WriteLog entries.
+    pub decode_errors: usize,
+}
+
+impl InputStats {
+    /// Adds the counters of `other` to this instance.
+    pub fn merge(&mut self, other: &InputStats) {
+        self.records += other.records;
+        self.bytes += other.bytes;
+        self.decode_errors += other.decode_errors;
+    }
+
+    /// Returns the counters added since `earlier` was taken from the same totals.
+    pub fn since(&self, earlier: &InputStats) -> InputStats {
+        InputStats {
+            records: self.records - earlier.records,
+            bytes: self.bytes - earlier.bytes,
+            decode_errors: self.decode_errors - earlier.decode_errors,
+        }
+    }
+}
+
+/// Implemented by the readers of all input formats, and by the adapters turning them into
+/// records (like `PosRecordIterator`), which report the counters of the reader they wrap.
+pub trait Stats {
+    /// Returns the counters of everything read so far.
+    fn stats(&self) -> InputStats;
+}
+
+/// Sums up the counters of the inputs of a job. Clones share the counters, so that inputs read
+/// by different threads can report to the same collector (see `MRParameters::input_stats`); the
+/// controller adds what has been collected during a job to `JobStats::input`.
+#[derive(Clone, Default)]
+pub struct InputStatsCollector {
+    totals: Arc<Mutex<InputStats>>,
+}
+
+impl InputStatsCollector {
+    pub fn new() -> InputStatsCollector {
+        InputStatsCollector::default()
+    }
+
+    pub fn add(&self, stats: &InputStats) {
+        self.totals.lock().unwrap().merge(stats);
+    }
+
+    /// Returns the sum of the counters added so far.
+    pub fn get(&self) -> InputStats {
+        *self.totals.lock().unwrap()
+    }
+
+    /// Wraps `input` so that its counters are added to this collector once it is exhausted or
+    /// dropped.
+    pub fn collect<I: Iterator + Stats>(&self, input: I) -> Collected<I> {
+        Collected {
+            input,
+            collector: self.clone(),
+            reported: false,
+        }
+    }
+}
+
+/// An input reporting its counters to an `InputStatsCollector`
+/// (see `InputStatsCollector::collect()`).
+pub struct Collected<I: Iterator + Stats> {
+    input: I,
+    collector: InputStatsCollector,
+    reported: bool,
+}
+
+impl<I: Iterator + Stats> Collected<I> {
+    fn report(&mut self) {
+        if !self.reported {
+            self.reported = true;
+            self.collector.add(&self.input.stats());
+        }
+    }
+}
+
+impl<I: Iterator + Stats> Iterator for Collected<I> {
+    type Item = I::Item;
+    fn next(&mut self) -> Option<I::Item> {
+        let next = self.input.next();
+        if next.is_none() {
+            self.report();
+        }
+        next
+    }
+}
+
+impl<I: Iterator + Stats> Stats for Collected<I> {
+    fn stats(&self) -> InputStats {
+        self.input.stats()
+    }
+}
+
+impl<I: Iterator + Stats> Drop for Collected<I> {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
+
+/// An output shard written by the reduce phase.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputShard {
+    /// The reduce partition that wrote the shard.
+    pub shard: usize,
+    /// The name the output was created with (see `SinkGenerator::new_output()`); for sinks
+    /// writing files, this is the path of the file, or of the compressed file (see
+    /// `MRParameters::set_output_compression()`).
+    pub path: String,
+    /// How many results (e.g. lines) were written.
+    pub records: usize,
+    /// How many bytes of results were written, not counting what the sink adds (like line
+    /// endings).
+    pub bytes: usize,
+    /// The part files the output was continued in after exceeding its size limit, in order, or the
+    /// compressed output replacing it (see `MRParameters::set_output_size_limit()`).
+    pub parts: Vec<String>,
+    /// The files the results of routed keys were written to, sorted (see
+    /// `MRParameters::set_output_routing()`).
+    pub routes: Vec<String>,
+    /// The first and the last key reduced by the partition, or None if no key was reduced.
+    pub key_range: Option<(String, String)>,
+    /// The order in which the inputs of the partition (one per map partition and source) were
+    /// exhausted while merging them, by their index. Inputs that end early hold few keys of the
+    /// shard, e.g. with range partitioning.
+    pub merge_exhaustion_order: Vec<usize>,
+}
+
+impl OutputShard {
+    /// Returns the files written for the shard: the output, its part files and the files of
+    /// routed keys.
+    pub fn files(&self) -> Vec<String> {
+        Some(self.path.clone())
+            .into_iter()
+            .chain(self.parts.iter().cloned())
+            .chain(self.routes.iter().cloned())
+            .collect()
+    }
+}
+
+/// The result of a mapreduce job, returned by `MRController::run()`: The statistics of the job,
+/// and the output shards, ordered by shard number. Calling code can continue processing the
+/// outputs without reconstructing their names from `reduce_output_shard_prefix`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobResult {
+    pub stats: JobStats,
+    pub outputs: Vec<OutputShard>,
+}
+
+impl JobResult {
+    /// Returns the paths of all output shards, in shard order.
+    pub fn output_paths(&self) -> Vec<String> {
+        self.outputs.iter().map(|o| o.path.clone()).collect()
+    }
+}
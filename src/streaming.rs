@@ -0,0 +1,291 @@
+//! Mappers and reducers running an external command, compatible with the protocol of Hadoop
+//! Streaming: Records are written to the command's stdin as lines of the form
+//! `key<TAB>value`, and every line the command writes to stdout is a result. This allows using
+//! existing scripts (Python, awk, ...) as map or reduce functions.
+//!
+//! One process is started per map or reduce partition; it receives all records of the partition.
+//! For reducers, the records arrive sorted by key, like with Hadoop.
+
+use mapreducer::{Mapper, Reducer};
+use record_types::{MEmitter, REmitter, Record, MultiRecord};
+
+use std::io::{self, BufRead, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{Receiver, channel};
+use std::thread;
+
+/// Splits an output line of a streaming command into key and value at the first tab character.
+/// If there is no tab, the whole line is the key and the value is empty.
+pub fn parse_streaming_line(line: String) -> Record {
+    let tab = line.find('\t');
+    match tab {
+        None => {
+            Record {
+                key: line,
+                value: String::new(),
+            }
+        }
+        Some(i) => {
+            Record {
+                value: String::from(&line[i + 1..]),
+                key: {
+                    let mut k = line;
+                    k.truncate(i);
+                    k
+                },
+            }
+        }
+    }
+}
+
+/// A running streaming command. The output is read by a separate thread, so that the command
+/// never blocks on a full stdout pipe while we are writing to its stdin.
+struct StreamingProcess {
+    command: String,
+    child: Child,
+    stdin: Option<io::BufWriter<ChildStdin>>,
+    output: Receiver<String>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+impl StreamingProcess {
+    fn spawn(command: &String) -> io::Result<StreamingProcess> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().map(io::BufWriter::new);
+        let stdout = match child.stdout.take() {
+            Some(s) => s,
+            None => return Err(io::Error::other("no stdout")),
+        };
+
+        let (send, recv) = channel();
+        let reader = thread::spawn(move || {
+            for line in io::BufReader::new(stdout).lines() {
+                match line {
+                    Err(_) => break,
+                    Ok(l) => {
+                        if send.send(l).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(StreamingProcess {
+            command: command.clone(),
+            child,
+            stdin,
+            output: recv,
+            reader: Some(reader),
+        })
+    }
+
+    fn write_record(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match self.stdin {
+            Some(ref mut stdin) => writeln!(stdin, "{}\t{}", key, value),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed")),
+        }
+    }
+
+    /// Returns the output lines that are available without blocking.
+    fn available(&mut self) -> Vec<String> {
+        self.output.try_iter().collect()
+    }
+
+    /// Closes stdin, waits for the command to exit and returns the remaining output lines.
+    fn finish(&mut self) -> io::Result<Vec<String>> {
+        if let Some(mut stdin) = self.stdin.take() {
+            stdin.flush()?;
+        }
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let lines = self.available();
+
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("'{}' exited with {}", self.command, status)));
+        }
+        Ok(lines)
+    }
+}
+
+impl Drop for StreamingProcess {
+    fn drop(&mut self) {
+        // Only reached without finish() if the partition was aborted.
+        if self.reader.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// A Mapper that runs a shell command (via `sh -c`) for every map partition.
+/// Output lines are split into key and value at the first tab.
+pub struct StreamingMapper {
+    command: String,
+    process: Option<StreamingProcess>,
+}
+
+impl StreamingMapper {
+    pub fn new(command: &str) -> StreamingMapper {
+        StreamingMapper {
+            command: String::from(command),
+            process: None,
+        }
+    }
+
+    fn emit_lines(em: &mut MEmitter, lines: Vec<String>) {
+        for line in lines {
+            let r = parse_streaming_line(line);
+            em.emit(r.key, r.value);
+        }
+    }
+}
+
+/// Clones don't share the running process; every clone starts its own.
+impl Clone for StreamingMapper {
+    fn clone(&self) -> StreamingMapper {
+        StreamingMapper::new(&self.command)
+    }
+}
+
+impl Mapper for StreamingMapper {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        if self.process.is_none() {
+            match StreamingProcess::spawn(&self.command) {
+                Err(e) => panic!("couldn't start streaming mapper '{}': {}", self.command, e),
+                Ok(p) => self.process = Some(p),
+            }
+        }
+        if let Some(ref mut p) = self.process {
+            if let Err(e) = p.write_record(&record.key, &record.value) {
+                panic!("couldn't write to streaming mapper '{}': {}", self.command, e);
+            }
+            StreamingMapper::emit_lines(em, p.available());
+        }
+    }
+
+    fn finish(&mut self, em: &mut MEmitter) {
+        if let Some(mut p) = self.process.take() {
+            match p.finish() {
+                Err(e) => panic!("streaming mapper failed: {}", e),
+                Ok(lines) => StreamingMapper::emit_lines(em, lines),
+            }
+        }
+    }
+}
+
+/// A Reducer that runs a shell command (via `sh -c`) for every reduce partition. The command
+/// receives all (key, value) pairs of the partition sorted by key; every output line is emitted
+/// as result.
+pub struct StreamingReducer {
+    command: String,
+    process: Option<StreamingProcess>,
+}
+
+impl StreamingReducer {
+    pub fn new(command: &str) -> StreamingReducer {
+        StreamingReducer {
+            command: String::from(command),
+            process: None,
+        }
+    }
+}
+
+/// Clones don't share the running process; every clone starts its own.
+impl Clone for StreamingReducer {
+    fn clone(&self) -> StreamingReducer {
+        StreamingReducer::new(&self.command)
+    }
+}
+
+impl Reducer for StreamingReducer {
+    fn reduce(&mut self, em: &mut REmitter, records: MultiRecord) {
+        if self.process.is_none() {
+            match StreamingProcess::spawn(&self.command) {
+                Err(e) => panic!("couldn't start streaming reducer '{}': {}", self.command, e),
+                Ok(p) => self.process = Some(p),
+            }
+        }
+        if let Some(ref mut p) = self.process {
+            let key = records.key().clone();
+            for value in records {
+                if let Err(e) = p.write_record(&key, &value) {
+                    panic!("couldn't write to streaming reducer '{}': {}", self.command, e);
+                }
+            }
+            for line in p.available() {
+                em.emit(line);
+            }
+        }
+    }
+
+    fn finish(&mut self, em: &mut REmitter) {
+        if let Some(mut p) = self.process.take() {
+            match p.finish() {
+                Err(e) => panic!("streaming reducer failed: {}", e),
+                Ok(lines) => {
+                    for line in lines {
+                        em.emit(line);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamingMapper, StreamingReducer, parse_streaming_line};
+    use controller::MRController;
+    use formats::lines::{self, LinesSinkGenerator};
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use std::fs;
+
+    #[test]
+    fn test_parse_streaming_line() {
+        let r = parse_streaming_line(String::from("abc\tdef\tghi"));
+        assert_eq!((r.key.as_str(), r.value.as_str()), ("abc", "def\tghi"));
+        let r = parse_streaming_line(String::from("abc"));
+        assert_eq!((r.key.as_str(), r.value.as_str()), ("abc", ""));
+    }
+
+    #[test]
+    fn test_streaming_word_count() {
+        let input: Vec<String> = vec!["abc def", "def ghi", "abc abc"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let mapper = StreamingMapper::new("cut -f2 | tr ' ' '\\n' | sed 's/$/\\t1/'");
+        let reducer = StreamingReducer::new("awk -F'\\t' '{c[$1] += $2} END {for (k in c) print \
+                                             k \": \" c[k]}'");
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_file_locations(String::from("testdata/streaming_map_"),
+                                String::from("testdata/streaming_out_"));
+
+        MRController::run(mapper,
+                          reducer,
+                          DefaultSharder,
+                          params,
+                          PosRecordIterator::new(input.into_iter()),
+                          LinesSinkGenerator::new_to_files());
+
+        let mut result = Vec::new();
+        for i in 0..2 {
+            let name = format!("testdata/streaming_out_{}", i);
+            result.extend(lines::new_from_file(&name).unwrap());
+            let _ = fs::remove_file(name);
+        }
+        result.sort();
+        assert_eq!(result, vec!["abc: 3", "def: 2", "ghi: 1"]);
+    }
+}
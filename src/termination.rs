@@ -0,0 +1,38 @@
+//! Cooperative early termination of jobs. Mappers and reducers can end a job early with
+//! `MEmitter::terminate()`/`REmitter::terminate()`, e.g. once they've found what they were
+//! looking for; the job can also be terminated from another thread using
+//! `params.termination.terminate()`.
+//!
+//! Terminating during the map phase stops reading input and skips the remaining map partitions;
+//! the records mapped so far are reduced as usual. Terminating during the reduce phase stops all
+//! reduce partitions after their current group. In both cases, the job's `JobStats` are marked
+//! as `truncated`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag shared by all parts of a job. Clones refer to the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct Termination {
+    flag: Arc<AtomicBool>,
+}
+
+impl Termination {
+    pub fn new() -> Termination {
+        Termination::default()
+    }
+
+    /// Requests the termination of the job.
+    pub fn terminate(&self) {
+        self.flag.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Clears the flag; the controller does this when a phase has ended.
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,420 @@
+//! Helpers for testing pipelines: `TestTempLayout` places all intermediate and output files of
+//! a job in a directory of its own, so that tests running in parallel (like the threads of
+//! `cargo test`) don't overwrite each other's files at the default locations
+//! (`map_intermediate_*`, `output_*`). Within the directory, files are named deterministically.
+//!
+//! `FaultInjector` makes chosen partitions of a job fail or stall, and chosen outputs fail
+//! partway, so that the handling of failures can be tested deterministically.
+
+use controller::cleanup_stale;
+use formats::lines::{LinesSinkGenerator, LinesWriter};
+use formats::retry::IoRetry;
+use parameters::{Durability, MRParameters, StaleIntermediates};
+use phases::output::{SinkGenerator, list_intermediate_files};
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static LAYOUTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory holding the files of a test job: intermediate files at `<dir>/map_` and outputs
+/// at `<dir>/output_`.
+pub struct TestTempLayout {
+    dir: String,
+    remove: bool,
+}
+
+impl TestTempLayout {
+    /// Creates a new directory in the system's temporary directory, named after `name`, the
+    /// process and a counter, so that it is unique to the caller. It is removed with all files
+    /// when the layout is dropped.
+    pub fn new(name: &str) -> io::Result<TestTempLayout> {
+        let n = LAYOUTS.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("localmr-{}-{}-{}", name, process::id(), n));
+        let mut layout = TestTempLayout::in_dir(&dir.to_string_lossy())?;
+        layout.remove = true;
+        Ok(layout)
+    }
+
+    /// Uses the directory `dir` (e.g. one managed by a tempdir crate), creating it if it
+    /// doesn't exist. The directory is left in place when the layout is dropped.
+    pub fn in_dir(dir: &str) -> io::Result<TestTempLayout> {
+        fs::create_dir_all(dir)?;
+        Ok(TestTempLayout {
+            dir: String::from(dir.trim_end_matches('/')),
+            remove: false,
+        })
+    }
+
+    /// Returns the directory of the layout.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Returns the location of the intermediate files (see `MRParameters::set_file_locations()`).
+    pub fn map_location(&self) -> String {
+        format!("{}/map_", self.dir)
+    }
+
+    /// Returns the prefix of the output shards (see `MRParameters::set_file_locations()`).
+    pub fn output_prefix(&self) -> String {
+        format!("{}/output_", self.dir)
+    }
+
+    /// Returns `params` with the file locations set to this layout.
+    pub fn apply(&self, params: MRParameters) -> MRParameters {
+        params.set_file_locations(self.map_location(), self.output_prefix())
+    }
+
+    /// Returns the paths of the output shards of a job with `reducers` reducers.
+    pub fn output_paths(&self, reducers: usize) -> Vec<String> {
+        (0..reducers).map(|s| format!("{}{}", self.output_prefix(), s)).collect()
+    }
+
+    /// Returns the paths of the intermediate files left in the layout (e.g. by jobs keeping
+    /// their temporary files).
+    pub fn intermediate_files(&self) -> io::Result<Vec<PathBuf>> {
+        list_intermediate_files(&self.map_location())
+    }
+
+    /// Removes the intermediate files left in the layout; returns how many there were.
+    pub fn remove_intermediates(&self) -> io::Result<usize> {
+        cleanup_stale(&self.map_location(), StaleIntermediates::Remove)
+    }
+
+    /// Returns a generator writing text outputs into this layout (see `TestSinkGenerator`).
+    pub fn sink_generator(&self) -> TestSinkGenerator {
+        TestSinkGenerator {
+            dir: self.dir.clone(),
+            created: Arc::new(Mutex::new(Vec::new())),
+            lines: LinesSinkGenerator::new_to_files(),
+        }
+    }
+}
+
+impl Drop for TestTempLayout {
+    fn drop(&mut self) {
+        if self.remove {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Writes text files like `LinesSinkGenerator`, but only inside the directory of a
+/// `TestTempLayout`: Outputs requested outside of it are created in it instead, with `/` in
+/// their names replaced by `_`. It records the paths of all files it has created.
+#[derive(Clone)]
+pub struct TestSinkGenerator {
+    dir: String,
+    // Shared between the clones handed to the reduce partitions.
+    created: Arc<Mutex<Vec<String>>>,
+    lines: LinesSinkGenerator,
+}
+
+impl TestSinkGenerator {
+    fn place(&self, location: &str) -> String {
+        let path = if location.starts_with(&format!("{}/", self.dir)) {
+            String::from(location)
+        } else {
+            format!("{}/{}", self.dir, location.replace('/', "_"))
+        };
+        self.created.lock().unwrap().push(path.clone());
+        path
+    }
+
+    /// Returns the paths of the files created so far, sorted and without duplicates.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = self.created.lock().unwrap().clone();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+}
+
+impl SinkGenerator for TestSinkGenerator {
+    type Sink = LinesWriter<fs::File>;
+    fn new_output(&self, location: &String) -> Self::Sink {
+        self.lines.new_output(&self.place(location))
+    }
+
+    fn append_output(&self, location: &String) -> Option<Self::Sink> {
+        self.lines.append_output(&self.place(location))
+    }
+
+    fn with_durability(mut self, durability: Durability) -> TestSinkGenerator {
+        self.lines = self.lines.with_durability(durability);
+        self
+    }
+
+    fn with_io_retry(mut self, retry: IoRetry) -> TestSinkGenerator {
+        self.lines = self.lines.with_io_retry(retry);
+        self
+    }
+}
+
+/// The phase of a partition that a fault is injected into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPhase {
+    Map,
+    Reduce,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Fault {
+    Fail(FaultPhase, usize),
+    Delay(FaultPhase, usize, Duration),
+    ShortWrite(String, usize),
+}
+
+/// Injects faults into a job (see `MRParameters::set_fault_injector()`): Partitions fail (by
+/// panicking) or are delayed when they start, and outputs created by the generator returned by
+/// `sink_generator()` fail after writing a number of bytes. Every fault is injected only once,
+/// so that a partition or job that is run again succeeds. Clones share the faults.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Vec<Fault>>>,
+    injected: Arc<AtomicUsize>,
+}
+
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    fn add(self, fault: Fault) -> FaultInjector {
+        self.faults.lock().unwrap().push(fault);
+        self
+    }
+
+    /// Makes the partition `partition` of `phase` fail.
+    pub fn fail(self, phase: FaultPhase, partition: usize) -> FaultInjector {
+        self.add(Fault::Fail(phase, partition))
+    }
+
+    /// Delays the start of the partition `partition` of `phase`, e.g. to make it finish last.
+    pub fn delay(self, phase: FaultPhase, partition: usize, delay: Duration) -> FaultInjector {
+        self.add(Fault::Delay(phase, partition, delay))
+    }
+
+    /// Makes the output created for `location` accept only `bytes` bytes: The write exceeding
+    /// them is short, and all further writes fail.
+    pub fn short_write(self, location: &str, bytes: usize) -> FaultInjector {
+        self.add(Fault::ShortWrite(String::from(location), bytes))
+    }
+
+    /// Returns how many faults have been injected.
+    pub fn injected(&self) -> usize {
+        self.injected.load(Ordering::SeqCst)
+    }
+
+    /// Removes and returns the first fault matching `f`.
+    fn take<F: Fn(&Fault) -> bool>(&self, f: F) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let i = faults.iter().position(f)?;
+        self.injected.fetch_add(1, Ordering::SeqCst);
+        Some(faults.remove(i))
+    }
+
+    /// Called by the partitions of a job when they start; injects the faults for the partition.
+    pub fn partition_started(&self, phase: FaultPhase, partition: usize) {
+        let fault = self.take(|f| match *f {
+            Fault::Fail(p, n) | Fault::Delay(p, n, _) => p == phase && n == partition,
+            Fault::ShortWrite(..) => false,
+        });
+        match fault {
+            Some(Fault::Fail(..)) => {
+                panic!("injected failure of {:?} partition {}", phase, partition)
+            }
+            Some(Fault::Delay(_, _, delay)) => thread::sleep(delay),
+            _ => (),
+        }
+    }
+
+    /// Returns a generator creating the outputs of `inner`, of which those given to
+    /// `short_write()` fail.
+    pub fn sink_generator<G: SinkGenerator>(&self, inner: G) -> FaultSinkGenerator<G> {
+        FaultSinkGenerator {
+            injector: self.clone(),
+            inner,
+        }
+    }
+}
+
+/// Creates outputs that fail as set up in a `FaultInjector`.
+#[derive(Clone)]
+pub struct FaultSinkGenerator<G: SinkGenerator> {
+    injector: FaultInjector,
+    inner: G,
+}
+
+impl<G: SinkGenerator> FaultSinkGenerator<G> {
+    fn wrap(&self, location: &String, sink: G::Sink) -> FaultSink<G::Sink> {
+        let limit = match self.injector.take(|f| match *f {
+            Fault::ShortWrite(ref l, _) => l == location,
+            _ => false,
+        }) {
+            Some(Fault::ShortWrite(_, bytes)) => Some(bytes),
+            _ => None,
+        };
+        FaultSink { sink, limit }
+    }
+}
+
+impl<G: SinkGenerator> SinkGenerator for FaultSinkGenerator<G> {
+    type Sink = FaultSink<G::Sink>;
+    fn new_output(&self, location: &String) -> Self::Sink {
+        self.wrap(location, self.inner.new_output(location))
+    }
+
+    fn append_output(&self, location: &String) -> Option<Self::Sink> {
+        self.inner.append_output(location).map(|sink| self.wrap(location, sink))
+    }
+
+    fn with_durability(mut self, durability: Durability) -> FaultSinkGenerator<G> {
+        self.inner = self.inner.with_durability(durability);
+        self
+    }
+
+    fn with_io_retry(mut self, retry: IoRetry) -> FaultSinkGenerator<G> {
+        self.inner = self.inner.with_io_retry(retry);
+        self
+    }
+}
+
+/// An output of a `FaultSinkGenerator`.
+pub struct FaultSink<W: io::Write> {
+    sink: W,
+    // The bytes that may still be written, if limited.
+    limit: Option<usize>,
+}
+
+impl<W: io::Write> io::Write for FaultSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.limit {
+            None => self.sink.write(buf),
+            Some(0) => Err(io::Error::other("injected write failure")),
+            Some(ref mut limit) => {
+                let n = self.sink.write(&buf[..buf.len().min(*limit)])?;
+                *limit -= n;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjector, FaultPhase, TestTempLayout};
+    use closure_mr::ClosureMapReducer;
+    use controller::MRController;
+    use formats::lines;
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use phases::output::SinkGenerator;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+    use std::path::Path;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use std::time::Duration;
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        let key = recs.key().clone();
+        e.emit(format!("{} {}", key, recs.into_iter().count()));
+    }
+
+    fn run_job(words: &'static str) -> Vec<String> {
+        let layout = TestTempLayout::new("layout_test").unwrap();
+        let params = layout.apply(MRParameters::new().set_concurrency(2, 2).keep_temp_files(true));
+        let out = layout.sink_generator();
+        let input = PosRecordIterator::new(vec![String::from(words)].into_iter());
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params,
+                          input,
+                          out.clone());
+        assert_eq!(out.paths(), layout.output_paths(2));
+        assert_eq!(layout.intermediate_files().unwrap().len(), 2);
+        assert_eq!(layout.remove_intermediates().unwrap(), 2);
+
+        let mut results = Vec::new();
+        for path in layout.output_paths(2) {
+            results.extend(lines::new_from_file(&path).unwrap());
+        }
+        results.sort();
+        results
+    }
+
+    #[test]
+    fn test_temp_layout() {
+        // Jobs with the same parameters run in parallel without interfering.
+        let other = thread::spawn(|| run_job("a b a"));
+        assert_eq!(run_job("c c d"), vec!["c 2", "d 1"]);
+        assert_eq!(other.join().unwrap(), vec!["a 2", "b 1"]);
+
+        let layout = TestTempLayout::new("layout_test").unwrap();
+        let dir = String::from(layout.dir());
+        let out = layout.sink_generator();
+        drop(out.new_output(&String::from("elsewhere/out")));
+        assert_eq!(out.paths(), vec![format!("{}/elsewhere_out", dir)]);
+        drop(layout);
+        assert!(fs::metadata(dir).is_err());
+    }
+
+    #[test]
+    fn test_fault_injector() {
+        let layout = TestTempLayout::new("fault_test").unwrap();
+        let injector = FaultInjector::new()
+            .delay(FaultPhase::Map, 0, Duration::from_millis(1))
+            .fail(FaultPhase::Reduce, 1)
+            .short_write(&layout.output_paths(2)[0], 2);
+        let params = layout.apply(MRParameters::new()
+            .set_concurrency(1, 2)
+            .set_fault_injector(injector.clone()));
+        let out = injector.sink_generator(layout.sink_generator());
+        let run = || {
+            let input = PosRecordIterator::new(vec![String::from("a b a")].into_iter());
+            MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                              ClosureMapReducer::new(word_mapper, count_reducer),
+                              DefaultSharder,
+                              params.clone(),
+                              input,
+                              out.clone())
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(&run));
+        assert!(result.is_err());
+        assert_eq!(injector.injected(), 3);
+        // The output of the failed reduce partition has been removed.
+        assert!(!Path::new(&layout.output_paths(2)[1]).exists());
+
+        // Faults are injected once; running the job again succeeds.
+        run();
+        assert_eq!(injector.injected(), 3);
+        let mut results = Vec::new();
+        for path in layout.output_paths(2) {
+            results.extend(lines::new_from_file(&path).unwrap());
+        }
+        results.sort();
+        assert_eq!(results, vec!["a 2", "b 1"]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Grouping of log records by time windows: `TimeWindowMapper` parses a timestamp field of every
+//! input record and emits the record under the key of the window it falls into, so that the
+//! reducer receives all records of one window (e.g. one hour) together.
+//!
+//! Timestamps may be given as Unix timestamps (seconds, optionally with a fraction) or in the
+//! format `YYYY-MM-DD HH:MM:SS` (`T` may be used as separator, optionally followed by a fraction
+//! and a UTC offset `Z`, `+HH:MM` or `+HHMM`). Timezones are represented by fixed UTC offsets;
+//! daylight saving time is not taken into account.
+
+use mapreducer::Mapper;
+use record_types::{MEmitter, Record};
+
+/// A time window, and the timezone in which windows are aligned and keys are formatted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeWindow {
+    length: i64,
+    utc_offset: i64,
+}
+
+impl TimeWindow {
+    /// A window of `seconds` seconds, aligned in UTC.
+    pub fn new(seconds: u64) -> TimeWindow {
+        assert!(seconds > 0);
+        TimeWindow {
+            length: seconds as i64,
+            utc_offset: 0,
+        }
+    }
+
+    pub fn hourly() -> TimeWindow {
+        TimeWindow::new(3600)
+    }
+
+    pub fn daily() -> TimeWindow {
+        TimeWindow::new(24 * 3600)
+    }
+
+    /// Aligns the windows in the timezone `utc_offset` seconds east of UTC, so that for example
+    /// daily windows start at local midnight. Timestamps without explicit offset are interpreted
+    /// in this timezone as well.
+    ///
+    /// Default: 0 (UTC)
+    pub fn set_utc_offset(mut self, utc_offset: i32) -> TimeWindow {
+        self.utc_offset = utc_offset as i64;
+        self
+    }
+
+    /// Returns the key of the window containing `timestamp`, or None if it can't be parsed. The
+    /// key is the start of the window in local time, formatted like `2016-01-31T13:00:00+01:00`;
+    /// keys sort in chronological order.
+    pub fn window_key(&self, timestamp: &str) -> Option<String> {
+        let ts = parse_timestamp(timestamp, self.utc_offset)?;
+        let local = ts + self.utc_offset;
+        let start = local - local.rem_euclid(self.length);
+        Some(format_local(start, self.utc_offset))
+    }
+}
+
+/// Parses `s` into seconds since the Unix epoch. Timestamps without UTC offset are interpreted as
+/// local time in `default_offset` (seconds east of UTC).
+pub fn parse_timestamp(s: &str, default_offset: i64) -> Option<i64> {
+    let s = s.trim();
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b'-') {
+        let secs = match s.find('.') {
+            Some(i) => &s[..i],
+            None => s,
+        };
+        let secs: i64 = secs.parse().ok()?;
+        // Negative fractional timestamps would be rounded towards zero.
+        return if secs < 0 && s.contains('.') { Some(secs - 1) } else { Some(secs) };
+    }
+
+    let b = s.as_bytes();
+    if b.len() < 19 || b[4] != b'-' || b[7] != b'-' || (b[10] != b'T' && b[10] != b' ') ||
+       b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let num = |from: usize, to: usize| -> Option<i64> {
+        if s[from..to].bytes().all(|b| b.is_ascii_digit()) {
+            s[from..to].parse().ok()
+        } else {
+            None
+        }
+    };
+    let (year, month, day) = (num(0, 4)?, num(5, 7)?, num(8, 10)?);
+    let (hour, minute, second) = (num(11, 13)?, num(14, 16)?, num(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 ||
+       second > 60 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    if rest.starts_with('.') {
+        let digits = rest[1..].bytes().take_while(|b| b.is_ascii_digit()).count();
+        rest = &rest[1 + digits..];
+    }
+    let offset = match rest {
+        "" => default_offset,
+        "Z" | "z" => 0,
+        _ => parse_utc_offset(rest)?,
+    };
+
+    let days = days_from_civil(year, month, day);
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Parses `+HH:MM`, `+HHMM` or `+HH` (or the same with `-`) into seconds east of UTC.
+fn parse_utc_offset(s: &str) -> Option<i64> {
+    let sign = match s.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || (digits.len() != 2 && digits.len() != 4) ||
+       s[1..].len() > 5 {
+        return None;
+    }
+    let hours: i64 = digits[0..2].parse().ok()?;
+    let minutes: i64 = if digits.len() == 4 { digits[2..4].parse().ok()? } else { 0 };
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Formats `local` (seconds since the epoch, shifted to local time) and the UTC offset.
+fn format_local(local: i64, utc_offset: i64) -> String {
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let sign = if utc_offset < 0 { '-' } else { '+' };
+    let offset = utc_offset.abs();
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            sign,
+            offset / 3600,
+            offset % 3600 / 60)
+}
+
+// The following two functions convert between days since 1970-01-01 and dates in the proleptic
+// Gregorian calendar (see http://howardhinnant.github.io/date_algorithms.html).
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A Mapper emitting every input record with the key of the time window that the timestamp in
+/// column `field` of its value belongs to (columns are separated by `separator`). Records without
+/// a valid timestamp are dropped.
+#[derive(Clone)]
+pub struct TimeWindowMapper {
+    field: usize,
+    separator: char,
+    window: TimeWindow,
+}
+
+impl TimeWindowMapper {
+    pub fn new(field: usize, separator: char, window: TimeWindow) -> TimeWindowMapper {
+        TimeWindowMapper {
+            field,
+            separator,
+            window,
+        }
+    }
+}
+
+impl Mapper for TimeWindowMapper {
+    fn map(&mut self, em: &mut MEmitter, record: Record) {
+        let key = match record.value.split(self.separator).nth(self.field) {
+            None => return,
+            Some(ts) => self.window.window_key(ts),
+        };
+        if let Some(key) = key {
+            em.emit(key, record.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TimeWindow, parse_timestamp};
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1454245200", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("1454245200.75", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("2016-01-31T13:00:00Z", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("2016-01-31 13:00:00.123", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("2016-01-31T14:00:00+01:00", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("2016-01-31T08:30:00-0430", 0), Some(1454245200));
+        assert_eq!(parse_timestamp("2016-01-31T14:00:00", 3600), Some(1454245200));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z", 0), Some(-1));
+        assert_eq!(parse_timestamp("2016-13-31T14:00:00", 0), None);
+        assert_eq!(parse_timestamp("yesterday", 0), None);
+    }
+
+    #[test]
+    fn test_window_key() {
+        let hourly = TimeWindow::hourly();
+        assert_eq!(hourly.window_key("2016-01-31T13:59:59Z"),
+                   Some(String::from("2016-01-31T13:00:00+00:00")));
+        assert_eq!(hourly.window_key("1454245200"),
+                   Some(String::from("2016-01-31T13:00:00+00:00")));
+
+        // 23:30 UTC is already the next day in UTC+2, but not in UTC-05:30.
+        let ts = "2016-02-29T23:30:00Z";
+        assert_eq!(TimeWindow::daily().set_utc_offset(7200).window_key(ts),
+                   Some(String::from("2016-03-01T00:00:00+02:00")));
+        assert_eq!(TimeWindow::daily().set_utc_offset(-19800).window_key(ts),
+                   Some(String::from("2016-02-29T00:00:00-05:30")));
+        assert_eq!(TimeWindow::new(15 * 60).window_key("2016-01-31 13:44:00"),
+                   Some(String::from("2016-01-31T13:30:00+00:00")));
+        assert_eq!(hourly.window_key("-"), None);
+    }
+}
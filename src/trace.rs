@@ -0,0 +1,145 @@
+//! Instrumentation of the phases of a job (enabled by the `tracing` feature): The controller and
+//! the partitions enter spans of the `tracing` crate, so that a job can be profiled with a
+//! tracing subscriber (e.g. exporting to perfetto or a flame graph), and time can be attributed to
+//! mapping, sorting, writing intermediate files, merging and reducing instead of to one opaque
+//! thread pool.
+//!
+//! Phases are INFO spans, the steps of map and reduce partitions are DEBUG spans (with a
+//! `partition` field). Merging and reducing alternate for every group of records; they are TRACE
+//! spans, entered once per group. Partitions run on worker threads, so their spans have no
+//! parent. Without the feature, entering a span does nothing.
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+/// What a span measures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// The whole map phase (on the controller thread, which reads the input).
+    MapPhase,
+    /// The whole reduce phase.
+    ReducePhase,
+    /// Mapping the input records of a partition.
+    Map,
+    /// Sorting the output of a map partition.
+    Sort,
+    /// Writing the intermediate files of a map partition, or sending its output to the
+    /// reducers when shuffling in memory.
+    ShuffleWrite,
+    /// Spilling shuffled records to disk (see `MRParameters::set_shuffle_memory_limit()`).
+    Spill,
+    /// Merging the intermediate records of the next group.
+    Merge,
+    /// Reducing a group and writing its results.
+    Reduce,
+}
+
+/// Leaves the span when dropped.
+#[cfg(feature = "tracing")]
+pub struct Entered {
+    _span: self::tracing::span::EnteredSpan,
+}
+
+/// Leaves the span when dropped.
+#[cfg(not(feature = "tracing"))]
+pub struct Entered;
+
+/// Enters a span for `step` of `partition` (ignored for phases); the span is left when the
+/// returned guard is dropped.
+#[cfg(feature = "tracing")]
+pub fn enter(step: Step, partition: usize) -> Entered {
+    // Span names must be constant, so every step has its own call site.
+    let span = match step {
+        Step::MapPhase => tracing::info_span!("map_phase"),
+        Step::ReducePhase => tracing::info_span!("reduce_phase"),
+        Step::Map => tracing::debug_span!("map", partition),
+        Step::Sort => tracing::debug_span!("sort", partition),
+        Step::ShuffleWrite => tracing::debug_span!("shuffle_write", partition),
+        Step::Spill => tracing::debug_span!("spill", partition),
+        Step::Merge => tracing::trace_span!("merge", partition),
+        Step::Reduce => tracing::trace_span!("reduce", partition),
+    };
+    Entered { _span: span.entered() }
+}
+
+/// Enters a span for `step` of `partition` (ignored for phases); the span is left when the
+/// returned guard is dropped.
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub fn enter(_step: Step, _partition: usize) -> Entered {
+    Entered
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::tracing;
+    use super::tracing::{Event, Metadata, Subscriber};
+    use super::tracing::span::{Attributes, Id, Record};
+    use closure_mr::ClosureMapReducer;
+    use controller::MRController;
+    use formats::lines::LinesSinkGenerator;
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use record_types::{MEmitter, MultiRecord, REmitter};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    // Collects the names of all spans created.
+    struct SpanNames(Arc<Mutex<BTreeSet<&'static str>>>);
+
+    impl Subscriber for SpanNames {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut names = self.0.lock().unwrap();
+            names.insert(span.metadata().name());
+            Id::from_u64(names.len() as u64)
+        }
+        fn record(&self, _: &Id, _: &Record) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    fn word_mapper(e: &mut MEmitter, r: ::record_types::Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{} {}", recs.key(), recs.values().len()));
+    }
+
+    #[test]
+    fn test_spans() {
+        let names = Arc::new(Mutex::new(BTreeSet::new()));
+        // Worker threads don't inherit a thread-local subscriber.
+        tracing::subscriber::set_global_default(SpanNames(names.clone())).unwrap();
+
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_file_locations(String::from("testdata/trace_map_"),
+                                String::from("testdata/trace_out_"));
+        let lines = vec!["abc def", "def ghi"];
+        MRController::run(ClosureMapReducer::new(word_mapper, count_reducer),
+                          ClosureMapReducer::new(word_mapper, count_reducer),
+                          DefaultSharder,
+                          params,
+                          PosRecordIterator::new(lines.into_iter().map(String::from)),
+                          LinesSinkGenerator::new_to_files());
+
+        let names = names.lock().unwrap();
+        for name in &["map_phase", "map", "sort", "shuffle_write", "reduce_phase", "merge",
+                      "reduce"] {
+            assert!(names.contains(name), "no span {}", name);
+        }
+        for i in 0..2 {
+            let _ = fs::remove_file(format!("testdata/trace_out_{}", i));
+        }
+    }
+}
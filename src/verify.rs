@@ -0,0 +1,325 @@
+//! Side-by-side comparison of two jobs, e.g. the old and the new implementation of a reducer, for
+//! verifying that a refactoring or an upgrade of localmr doesn't change the results.
+//!
+//! The outputs are compared key by key: Every output line is expected to start with its key,
+//! followed by a separator (e.g. "key\tvalue"). As the reduce phase writes its groups in key
+//! order, every output shard is sorted by key; the shards of both jobs are merged and then
+//! compared in one pass, without holding the outputs in memory. Keys that differ only in case are
+//! not ordered with respect to each other, and are collected before being compared.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use std::iter::Peekable;
+
+use controller::MRController;
+use formats::lines::LinesSinkGenerator;
+use formats::output::read_reduce_output_shards;
+use mapreducer::{Mapper, Reducer, Sharder};
+use parameters::MRParameters;
+use record_types::Record;
+use shard_merge::ShardMergeIterator;
+use sort::dict_string_compare;
+
+/// How many differing keys are reported in detail by `Comparison::diffs`.
+pub const MAX_REPORTED_DIFFS: usize = 100;
+
+/// A key whose output lines differ between the two jobs. A side without lines for the key is
+/// empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyDiff {
+    pub key: String,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+}
+
+/// The result of comparing the outputs of two jobs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Comparison {
+    /// Keys with the same output lines on both sides (in any order).
+    pub matching_keys: usize,
+    /// Keys present on both sides, with different lines.
+    pub differing_keys: usize,
+    /// Keys present only in the output of the left job.
+    pub only_left: usize,
+    /// Keys present only in the output of the right job.
+    pub only_right: usize,
+    /// The first `MAX_REPORTED_DIFFS` keys that differ or are missing on one side.
+    pub diffs: Vec<KeyDiff>,
+}
+
+impl Comparison {
+    /// Returns whether both jobs produced the same outputs.
+    pub fn is_equal(&self) -> bool {
+        self.differing_keys == 0 && self.only_left == 0 && self.only_right == 0
+    }
+
+    fn add_block(&mut self, mut left: Block, right: Block) {
+        for (key, right_lines) in right {
+            let left_lines = left.remove(&key).unwrap_or_default();
+            self.add(key, left_lines, right_lines);
+        }
+        for (key, left_lines) in left {
+            self.add(key, left_lines, Vec::new());
+        }
+    }
+
+    fn add(&mut self, key: String, mut left: Vec<String>, mut right: Vec<String>) {
+        left.sort();
+        right.sort();
+        if left == right {
+            self.matching_keys += 1;
+            return;
+        }
+        if left.is_empty() {
+            self.only_right += 1;
+        } else if right.is_empty() {
+            self.only_left += 1;
+        } else {
+            self.differing_keys += 1;
+        }
+        if self.diffs.len() < MAX_REPORTED_DIFFS {
+            self.diffs.push(KeyDiff { key, left, right });
+        }
+    }
+}
+
+// The lines of keys equal in dictionary order (i.e., differing only in case), by key.
+type Block = BTreeMap<String, Vec<String>>;
+
+/// Groups the lines of merged output shards by key, checking that the keys are sorted.
+struct KeyGroups {
+    records: Peekable<Box<dyn Iterator<Item = Record>>>,
+    last: Option<String>,
+    prefix: String,
+}
+
+impl KeyGroups {
+    fn open(prefix: &String, params: &MRParameters, separator: char) -> io::Result<KeyGroups> {
+        let mut shards = read_reduce_output_shards(prefix, params)?.into_iter().map(|s| {
+            s.map(move |line| {
+                Record {
+                    key: String::from(line.split(separator).next().unwrap_or("")),
+                    value: line,
+                }
+            })
+        });
+        let merged: Box<dyn Iterator<Item = Record>> =
+            Box::new(ShardMergeIterator::build(&mut shards));
+        Ok(KeyGroups {
+            records: merged.peekable(),
+            last: None,
+            prefix: prefix.clone(),
+        })
+    }
+
+    /// Returns the lines of the next keys that are equal in dictionary order.
+    fn next_block(&mut self) -> io::Result<Option<Block>> {
+        let first = match self.records.next() {
+            None => return Ok(None),
+            Some(r) => r,
+        };
+        if let Some(ref last) = self.last {
+            if dict_string_compare(last, &first.key) != Ordering::Less {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Outputs at {} aren't sorted by key: {:?} \
+                                                   follows {:?}",
+                                                  self.prefix,
+                                                  first.key,
+                                                  last)));
+            }
+        }
+        self.last = Some(first.key.clone());
+        let mut block = Block::new();
+        block.insert(first.key, vec![first.value]);
+        while let Some(r) = self.next_if_equal() {
+            block.entry(r.key).or_default().push(r.value);
+        }
+        Ok(Some(block))
+    }
+
+    // Returns the next record if its key is equal to the last key in dictionary order.
+    fn next_if_equal(&mut self) -> Option<Record> {
+        let equal = match (self.records.peek(), self.last.as_ref()) {
+            (Some(r), Some(last)) => dict_string_compare(&r.key, last) == Ordering::Equal,
+            _ => false,
+        };
+        if equal {
+            self.records.next()
+        } else {
+            None
+        }
+    }
+}
+
+/// Compares the reduce outputs at `left_prefix` and `right_prefix` (see
+/// `formats::output::read_reduce_output_shards()`; `params` determines their format). The key of
+/// an output line is the part before the first `separator`. The two sides may have been written
+/// with different numbers of reducers.
+pub fn compare_outputs(left_prefix: &String,
+                       right_prefix: &String,
+                       params: &MRParameters,
+                       separator: char)
+                       -> io::Result<Comparison> {
+    let mut left = KeyGroups::open(left_prefix, params, separator)?;
+    let mut right = KeyGroups::open(right_prefix, params, separator)?;
+    let mut comparison = Comparison::default();
+
+    let mut l = left.next_block()?;
+    let mut r = right.next_block()?;
+    loop {
+        let order = match (&l, &r) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(lb), Some(rb)) => {
+                dict_string_compare(lb.keys().next().unwrap(), rb.keys().next().unwrap())
+            }
+        };
+        let (left_block, right_block) = match order {
+            Ordering::Less => (l.take().unwrap(), Block::new()),
+            Ordering::Greater => (Block::new(), r.take().unwrap()),
+            Ordering::Equal => (l.take().unwrap(), r.take().unwrap()),
+        };
+        comparison.add_block(left_block, right_block);
+        if l.is_none() {
+            l = left.next_block()?;
+        }
+        if r.is_none() {
+            r = right.next_block()?;
+        }
+    }
+    Ok(comparison)
+}
+
+/// Runs two jobs over the same input and compares their outputs (see `compare_outputs()`).
+/// `input` is called once per job and must return the same records every time.
+///
+/// Both jobs run with `params`; their intermediate files and outputs are written at the
+/// configured locations with "left_" and "right_" appended. The outputs are kept for
+/// inspection.
+pub fn compare_jobs<M1, R1, M2, R2, S, In, F>(left: (M1, R1),
+                                              right: (M2, R2),
+                                              sharder: S,
+                                              params: MRParameters,
+                                              input: F,
+                                              separator: char)
+                                              -> io::Result<Comparison>
+    where M1: Mapper,
+          R1: Reducer,
+          M2: Mapper,
+          R2: Reducer,
+          S: Sharder,
+          In: Iterator<Item = Record>,
+          F: Fn() -> In
+{
+    let side_params = |side: &str| {
+        params.clone().set_file_locations(format!("{}{}", params.map_output_location, side),
+                                          format!("{}{}",
+                                                  params.reduce_output_shard_prefix,
+                                                  side))
+    };
+    let (left_params, right_params) = (side_params("left_"), side_params("right_"));
+
+    MRController::run(left.0,
+                      left.1,
+                      sharder.clone(),
+                      left_params.clone(),
+                      input(),
+                      LinesSinkGenerator::new_to_files());
+    MRController::run(right.0,
+                      right.1,
+                      sharder,
+                      right_params.clone(),
+                      input(),
+                      LinesSinkGenerator::new_to_files());
+
+    compare_outputs(&left_params.reduce_output_shard_prefix,
+                    &right_params.reduce_output_shard_prefix,
+                    &params,
+                    separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyDiff, compare_jobs};
+    use closure_mr::ClosureMapReducer;
+    use formats::util::PosRecordIterator;
+    use mapreducer::DefaultSharder;
+    use parameters::MRParameters;
+    use record_types::{MEmitter, MultiRecord, REmitter, Record};
+    use std::fs;
+
+    fn word_mapper(e: &mut MEmitter, r: Record) {
+        for w in r.value.split_whitespace() {
+            e.emit_str(w, "1");
+        }
+    }
+
+    fn count_reducer(e: &mut REmitter, recs: MultiRecord) {
+        e.emit(format!("{}\t{}", recs.key(), recs.values().len()));
+    }
+
+    // Miscounts "def" and drops "ghi".
+    fn buggy_reducer(e: &mut REmitter, recs: MultiRecord) {
+        match recs.key().as_str() {
+            "def" => e.emit(String::from("def\t1")),
+            "ghi" => (),
+            k => e.emit(format!("{}\t{}", k, recs.values().len())),
+        }
+    }
+
+    #[test]
+    fn test_compare_jobs() {
+        let input = || {
+            let lines = vec!["abc def", "def ghi", "abc abc xyz"];
+            PosRecordIterator::new(lines.into_iter().map(String::from))
+        };
+        let params = MRParameters::new()
+            .set_concurrency(2, 2)
+            .set_file_locations(String::from("testdata/verify_map_"),
+                                String::from("testdata/verify_out_"));
+
+        let same = compare_jobs((ClosureMapReducer::new(word_mapper, count_reducer),
+                                 ClosureMapReducer::new(word_mapper, count_reducer)),
+                                (ClosureMapReducer::new(word_mapper, count_reducer),
+                                 ClosureMapReducer::new(word_mapper, count_reducer)),
+                                DefaultSharder,
+                                params.clone(),
+                                input,
+                                '\t')
+            .unwrap();
+        assert!(same.is_equal());
+        assert_eq!(same.matching_keys, 4);
+
+        let differing = compare_jobs((ClosureMapReducer::new(word_mapper, count_reducer),
+                                      ClosureMapReducer::new(word_mapper, count_reducer)),
+                                     (ClosureMapReducer::new(word_mapper, buggy_reducer),
+                                      ClosureMapReducer::new(word_mapper, buggy_reducer)),
+                                     DefaultSharder,
+                                     params,
+                                     input,
+                                     '\t')
+            .unwrap();
+        assert!(!differing.is_equal());
+        assert_eq!((differing.matching_keys, differing.differing_keys), (2, 1));
+        assert_eq!((differing.only_left, differing.only_right), (1, 0));
+        assert_eq!(differing.diffs,
+                   vec![KeyDiff {
+                            key: String::from("def"),
+                            left: vec![String::from("def\t2")],
+                            right: vec![String::from("def\t1")],
+                        },
+                        KeyDiff {
+                            key: String::from("ghi"),
+                            left: vec![String::from("ghi\t1")],
+                            right: vec![],
+                        }]);
+
+        for side in &["left_", "right_"] {
+            for i in 0..2 {
+                let _ = fs::remove_file(format!("testdata/verify_out_{}{}", side, i));
+            }
+        }
+    }
+}
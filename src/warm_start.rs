@@ -0,0 +1,146 @@
+//! Warm-starting reducers from the output of the previous run (see
+//! `MRParameters::set_warm_start()`), for idempotent rolling aggregations without an external
+//! state store: Before a reduce partition writes its output shard, the shard written by the
+//! previous run is parsed into (key, value) records, which are merged into the partition's input
+//! as an additional sorted input. Their values are tagged with `PREVIOUS_TAG`, so that the
+//! reducer can tell the previous results of a key from its new values (see `previous_value()`),
+//! e.g. to add the new counts of a key to its previous total. Keys that only have a previous
+//! result are reduced as well, so that the reducer can emit it again or let it expire.
+//!
+//! Unlike `reducer_state`, the previous results are read from the outputs themselves; the number
+//! of reducers and the sharder must not change between runs either.
+
+use formats::compressed::find_output;
+use formats::output::read_shard;
+use formats::writelog::{FilteredRecordReader, WriteLogReader, WriteLogWriter, encode_record};
+use parameters::MRParameters;
+use record_types::Record;
+use sort::dict_string_compare;
+
+use std::fs;
+use std::io::{self, Write};
+
+/// The prefix of the values read from the previous output.
+pub const PREVIOUS_TAG: &str = "previous:";
+
+/// Returns the value read from the previous output if `value` is tagged as such, without the
+/// tag.
+pub fn previous_value(value: &str) -> Option<&str> {
+    value.strip_prefix(PREVIOUS_TAG)
+}
+
+/// Returns the name of the file holding the previous records of the output `name` while it is
+/// reduced.
+pub fn previous_input_name(name: &str) -> String {
+    format!("{}.previous", name)
+}
+
+/// Reads the previous output `name` (or its compressed version) of a reduce partition of the
+/// job with `params`, if the job warm-starts and the output is recent enough, and writes its
+/// records, sorted by key and tagged, to a WriteLog that the partition reads as additional input
+/// (see `previous_input_name()`). Returns the name of the WriteLog, which the partition removes
+/// when it is done.
+pub fn prepare_previous_input(name: &str,
+                              params: &MRParameters)
+                              -> io::Result<Option<String>> {
+    let (parse, max_age) = match params.warm_start {
+        Some(warm_start) => warm_start,
+        None => return Ok(None),
+    };
+    let path = match find_output(name) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    if let Some(max_age) = max_age {
+        let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+        if age > max_age {
+            return Ok(None);
+        }
+    }
+
+    let mut records = Vec::new();
+    let mut invalid = 0;
+    for line in read_shard(&path, params.reduce_output_format)? {
+        match parse(&line) {
+            Some((key, value)) => records.push(Record {
+                key,
+                value: format!("{}{}", PREVIOUS_TAG, value),
+            }),
+            None => invalid += 1,
+        }
+    }
+    if invalid > 0 {
+        println!("WARN: Skipped {} lines of previous output {} that couldn't be parsed",
+                 invalid,
+                 path);
+    }
+    // Outputs are usually written in key order already; the sort is stable, so that the values
+    // of a key keep their order.
+    records.sort_by(|a, b| dict_string_compare(&a.key, &b.key));
+
+    let previous = previous_input_name(name);
+    let mut writer = WriteLogWriter::<fs::File>::new_to_file(&previous, false)?
+        .set_io_retry(params.io_retry);
+    let mut frame = Vec::new();
+    for record in records {
+        encode_record(record.key.as_bytes(), record.value.as_bytes(), &mut frame);
+        writer.write_all(&frame)?;
+    }
+    writer.flush()?;
+    Ok(Some(previous))
+}
+
+/// Like `prepare_previous_input()`, but also opens the written WriteLog for reading.
+pub fn open_previous_input(name: &str,
+                           params: &MRParameters)
+                           -> io::Result<Option<(String, FilteredRecordReader)>> {
+    let previous = match prepare_previous_input(name, params)? {
+        Some(previous) => previous,
+        None => return Ok(None),
+    };
+    let reader = WriteLogReader::new_from_file(&previous)?.set_io_retry(params.io_retry);
+    let reader = FilteredRecordReader::new(reader, params.reduce_key_filter.clone());
+    Ok(Some((previous, reader)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prepare_previous_input, previous_value};
+    use formats::writelog::{FilteredRecordReader, WriteLogReader};
+    use parameters::MRParameters;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn parse_count(line: &str) -> Option<(String, String)> {
+        let (key, count) = line.split_once(' ')?;
+        Some((String::from(key), String::from(count)))
+    }
+
+    #[test]
+    fn test_prepare_previous_input() {
+        let name = String::from("testdata/warm_out_0");
+        let params = MRParameters::new().set_warm_start(parse_count, None);
+        assert_eq!(prepare_previous_input(&name, &params).unwrap(), None);
+
+        fs::write(&name, "def 2\nabc 3\ninvalid\n").unwrap();
+        assert_eq!(prepare_previous_input(&name, &MRParameters::new()).unwrap(), None);
+        let previous = prepare_previous_input(&name, &params).unwrap().unwrap();
+        let reader = WriteLogReader::new_from_file(&previous).unwrap();
+        let records: Vec<(String, String)> = FilteredRecordReader::new(reader, None)
+            .map(|r| (r.key, r.value))
+            .collect();
+        assert_eq!(records,
+                   vec![(String::from("abc"), String::from("previous:3")),
+                        (String::from("def"), String::from("previous:2"))]);
+        assert_eq!(previous_value(&records[0].1), Some("3"));
+        assert_eq!(previous_value("3"), None);
+        let _ = fs::remove_file(previous);
+
+        // Outputs older than the maximum age are ignored.
+        thread::sleep(Duration::from_millis(20));
+        let params = params.set_warm_start(parse_count, Some(Duration::from_millis(1)));
+        assert_eq!(prepare_previous_input(&name, &params).unwrap(), None);
+        let _ = fs::remove_file(name);
+    }
+}